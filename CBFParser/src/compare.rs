@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+use common::schema::{OvdECU, variant::ECUVariantDefinition, diag::{dtc::ECUDTC, service::Service}};
+use serde::Serialize;
+
+// Compares the catalogs (Services, DIDs and DTCs) of two converted CBF files,
+// in order to see what changed between two software versions of an ECU.
+// Entries are matched by variant and name, so a renamed entry shows as removed and added.
+
+#[derive(Debug, Copy, Clone, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EntryKind {
+    Variant,
+    Service,
+    DID,
+    DTC,
+}
+
+/// An entry only present in one of the two files
+#[derive(Debug, Serialize)]
+pub struct CatalogEntry {
+    pub kind: EntryKind,
+    pub variant: String,
+    pub name: String,
+}
+
+/// An entry present in both files, but with a different definition
+#[derive(Debug, Serialize)]
+pub struct ChangedEntry {
+    pub kind: EntryKind,
+    pub variant: String,
+    pub name: String,
+    /// Human readable description of each difference
+    pub changes: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CatalogDiff {
+    pub old_ecu: String,
+    pub new_ecu: String,
+    pub added: Vec<CatalogEntry>,
+    pub removed: Vec<CatalogEntry>,
+    pub changed: Vec<ChangedEntry>,
+}
+
+impl CatalogDiff {
+    pub fn print_summary(&self) {
+        println!("Comparing {} -> {}", self.old_ecu, self.new_ecu);
+        println!("{} added, {} removed, {} changed", self.added.len(), self.removed.len(), self.changed.len());
+        for e in self.added.iter() {
+            println!("  + {:?} {} -> {}", e.kind, e.variant, e.name);
+        }
+        for e in self.removed.iter() {
+            println!("  - {:?} {} -> {}", e.kind, e.variant, e.name);
+        }
+        for e in self.changed.iter() {
+            println!("  * {:?} {} -> {}", e.kind, e.variant, e.name);
+            for c in e.changes.iter() {
+                println!("      {}", c);
+            }
+        }
+    }
+
+    fn diff_entries<T, F>(&mut self, kind: EntryKind, variant: &str, old: &BTreeMap<String, T>, new: &BTreeMap<String, T>, compare: F)
+    where F: Fn(&T, &T) -> Vec<String> {
+        for (name, old_entry) in old.iter() {
+            match new.get(name) {
+                Some(new_entry) => {
+                    let changes = compare(old_entry, new_entry);
+                    if !changes.is_empty() {
+                        self.changed.push(ChangedEntry { kind, variant: variant.into(), name: name.clone(), changes })
+                    }
+                },
+                None => self.removed.push(CatalogEntry { kind, variant: variant.into(), name: name.clone() })
+            }
+        }
+        for name in new.keys().filter(|n| !old.contains_key(*n)) {
+            self.added.push(CatalogEntry { kind, variant: variant.into(), name: name.clone() })
+        }
+    }
+}
+
+/// Returns the data identifier a service reads, formatted as hex.
+/// None if the service is not a ReadDataByIdentifier (UDS) or ReadDataByLocalIdentifier (KWP2000) request
+fn get_did(s: &Service) -> Option<String> {
+    match s.payload.as_slice() {
+        [0x22, hi, lo, ..] => Some(format!("0x{:02X}{:02X}", hi, lo)),
+        [0x21, id, ..] => Some(format!("0x{:02X}", id)),
+        _ => None
+    }
+}
+
+fn compare_services(old: &Service, new: &Service) -> Vec<String> {
+    let mut changes = Vec::new();
+    if old.payload != new.payload {
+        changes.push(format!("Payload {:02X?} -> {:02X?}", old.payload, new.payload));
+    }
+    if old.description != new.description {
+        changes.push(format!("Description '{}' -> '{}'", old.description, new.description));
+    }
+    if old.input_params != new.input_params {
+        changes.push(format!("Input parameters {:?} -> {:?}", param_names(&old.input_params), param_names(&new.input_params)));
+    }
+    if old.output_params != new.output_params {
+        changes.push(format!("Output parameters {:?} -> {:?}", param_names(&old.output_params), param_names(&new.output_params)));
+    }
+    if old.preconditions != new.preconditions {
+        changes.push(format!("Preconditions '{}' -> '{}'", old.preconditions, new.preconditions));
+    }
+    changes
+}
+
+fn compare_dids(old: &Service, new: &Service) -> Vec<String> {
+    let mut changes = Vec::new();
+    if old.name != new.name {
+        changes.push(format!("Read by service '{}' -> '{}'", old.name, new.name));
+    }
+    if old.output_params != new.output_params {
+        changes.push(format!("Layout {:?} -> {:?}", param_names(&old.output_params), param_names(&new.output_params)));
+    }
+    changes
+}
+
+fn compare_dtcs(old: &ECUDTC, new: &ECUDTC) -> Vec<String> {
+    let mut changes = Vec::new();
+    if old.summary != new.summary {
+        changes.push(format!("Summary '{}' -> '{}'", old.summary, new.summary));
+    }
+    if old.description != new.description {
+        changes.push(format!("Description '{}' -> '{}'", old.description, new.description));
+    }
+    changes
+}
+
+fn param_names(params: &[common::schema::diag::service::Parameter]) -> Vec<&str> {
+    params.iter().map(|p| p.name.as_str()).collect()
+}
+
+fn diff_variant(diff: &mut CatalogDiff, old: &ECUVariantDefinition, new: &ECUVariantDefinition) {
+    let services = |v: &ECUVariantDefinition| -> BTreeMap<String, Service> {
+        v.services.iter().map(|s| (s.name.clone(), s.clone())).collect()
+    };
+    let dids = |v: &ECUVariantDefinition| -> BTreeMap<String, Service> {
+        v.services.iter().filter_map(|s| get_did(s).map(|d| (d, s.clone()))).collect()
+    };
+    let dtcs = |v: &ECUVariantDefinition| -> BTreeMap<String, ECUDTC> {
+        v.errors.iter().map(|e| (e.error_name.clone(), e.clone())).collect()
+    };
+    diff.diff_entries(EntryKind::Service, &old.name, &services(old), &services(new), compare_services);
+    diff.diff_entries(EntryKind::DID, &old.name, &dids(old), &dids(new), compare_dids);
+    diff.diff_entries(EntryKind::DTC, &old.name, &dtcs(old), &dtcs(new), compare_dtcs);
+}
+
+/// Compares every variant of two ECUs. Variants only present in one of the ECUs
+/// are reported as a whole, rather than listing every entry in them
+pub fn compare_ecus(old: &OvdECU, new: &OvdECU) -> CatalogDiff {
+    let mut diff = CatalogDiff {
+        old_ecu: old.name.clone(),
+        new_ecu: new.name.clone(),
+        ..Default::default()
+    };
+    let variants = |e: &OvdECU| -> BTreeMap<String, ECUVariantDefinition> {
+        e.variants.iter().map(|v| (v.name.clone(), v.clone())).collect()
+    };
+    let old_variants = variants(old);
+    let new_variants = variants(new);
+    for (name, old_variant) in old_variants.iter() {
+        match new_variants.get(name) {
+            Some(new_variant) => diff_variant(&mut diff, old_variant, new_variant),
+            None => diff.removed.push(CatalogEntry { kind: EntryKind::Variant, variant: name.clone(), name: name.clone() })
+        }
+    }
+    for name in new_variants.keys().filter(|n| !old_variants.contains_key(*n)) {
+        diff.added.push(CatalogEntry { kind: EntryKind::Variant, variant: name.clone(), name: name.clone() })
+    }
+    diff
+}
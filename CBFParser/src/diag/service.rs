@@ -1,4 +1,4 @@
-use common::raf::Raf;
+use common::{raf::Raf, schema::diag::service::{RequiredSession, ServicePreconditions}};
 use crate::{caesar::{CaesarError, PoolTuple, creader}, ctf::ctf_header::CTFLanguage, ecu::{ECU, com_param::ComParameter}};
 use super::preparation::Preparation;
 
@@ -191,5 +191,43 @@ impl Service {
     pub (crate) fn get_byte_count(&self) -> usize {
         self.request_bytes.count
     }
+
+    pub fn get_service_type(&self) -> ServiceType {
+        match self.data_class_service_type {
+            5 => ServiceType::Data,
+            7 => ServiceType::Download,
+            10 => ServiceType::DiagnosticFunction,
+            19 => ServiceType::DiagnosticJob,
+            21 => ServiceType::Session,
+            22 => ServiceType::StoredData,
+            23 => ServiceType::Routine,
+            24 => ServiceType::IoControl,
+            _ => ServiceType::Unknown
+        }
+    }
+
+    /// Extracts what the ECU must be set up for before the service can be ran.
+    ///
+    /// The security level comes straight from the service definition. CBF does not store the
+    /// session a service needs, so it is implied from the service type and security level:
+    /// * Downloads need the programming session
+    /// * Routines, IO controls and anything needing security access need the extended session
+    pub fn get_preconditions(&self) -> ServicePreconditions {
+        let security_level = if self.security_access_level > 0 && self.security_access_level <= 0xFF {
+            Some(self.security_access_level as u8)
+        } else {
+            None
+        };
+        let session = match self.get_service_type() {
+            ServiceType::Download => Some(RequiredSession::Programming),
+            ServiceType::Routine | ServiceType::IoControl => Some(RequiredSession::Extended),
+            _ if security_level.is_some() => Some(RequiredSession::Extended),
+            _ => None
+        };
+        ServicePreconditions {
+            session,
+            security_level
+        }
+    }
     // For converting to param tyoe only!
 }
\ No newline at end of file
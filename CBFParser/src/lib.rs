@@ -0,0 +1,195 @@
+use caesar::container::Container;
+use common::raf::Raf;
+use common::schema::{OvdECU, variant::{ECUVariantDefinition, ECUVariantPattern}, diag::{dtc::ECUDTC, service::{Service, Parameter}}};
+use ecu::ECU;
+
+mod caesar;
+pub mod compare;
+mod ctf;
+mod ecu;
+mod diag;
+pub mod odx;
+pub mod output;
+pub mod validate;
+
+pub use caesar::{CaesarError, Result};
+
+// Parsing of CBF files, usable by anything that needs to read them
+// (The CLI, the OVD app, tests) without running the cbf_parser binary.
+
+/// A parsed CBF file
+#[derive(Debug, Clone)]
+pub struct CbfFile {
+    /// ECUs described by the file, converted to OVD's JSON schema
+    pub ecus: Vec<OvdECU>,
+}
+
+/// Parses the contents of a CBF file
+pub fn parse_cbf(bytes: &[u8]) -> Result<CbfFile> {
+    parse_cbf_with_strings(bytes, None)
+}
+
+/// Parses the contents of a CBF file, replacing its strings with ones from
+/// a strings CSV file (See [dump_cbf_strings]). Used for translated CBF files
+pub fn parse_cbf_with_strings(bytes: &[u8], strings_path: Option<String>) -> Result<CbfFile> {
+    let mut br = Raf::from_bytes(bytes, common::raf::RafByteOrder::LE);
+    let (mut container, reader) = Container::new(&mut br)?;
+    if let Some(p) = strings_path {
+        container.load_strings(p);
+    }
+    container.read_ecus(reader)?;
+    Ok(CbfFile {
+        ecus: container.ecus.iter().map(decode_ecu).collect()
+    })
+}
+
+/// Writes the strings of a CBF file to a CSV file, so they can be translated
+pub fn dump_cbf_strings(bytes: &[u8], out_path: String) -> Result<()> {
+    let mut br = Raf::from_bytes(bytes, common::raf::RafByteOrder::LE);
+    let (container, _) = Container::new(&mut br)?;
+    container.dump_strings(out_path);
+    Ok(())
+}
+
+fn decode_ecu(e: &ECU) -> OvdECU {
+    println!("Converting ECU {}", e.qualifier);
+
+    let mut ecu = OvdECU {
+        name: e.qualifier.clone(),
+        description: e.name.clone().unwrap_or("".into()),
+        variants: Vec::new()
+    };
+
+    for variant in e.variants.iter() {
+        if variant.qualifier == e.qualifier {
+            continue
+        }
+
+        let mut ecu_variant = ECUVariantDefinition {
+            name: variant.qualifier.clone(),
+            description: variant.name.clone().unwrap_or("".into()),
+            patterns: Vec::new(),
+            errors: Vec::new(),
+            services: Vec::new()
+        };
+        
+        variant.variant_patterns.iter().for_each(|p| {
+            ecu_variant.patterns.push(
+                ECUVariantPattern {
+                    vendor: p.vendor_name.clone(),
+                    vendor_id: p.get_vendor_id()as u32,
+                }
+            );
+        });
+
+        variant.dtcs.iter().for_each(|e| {
+            let error = ECUDTC {
+                description: e.description.clone().unwrap_or("".into()),
+                error_name: e.qualifier.clone(),
+                summary: e.reference.clone().unwrap_or("".into()),
+            };
+            //if !error.error_name.is_empty() {
+            ecu_variant.errors.push(error)
+            //}
+        });
+
+
+        variant.services.iter().for_each(|s| {
+            if s.qualifier == "ACT_IO10_Idle_Speed" {
+                println!("{:#?}", s)
+            }
+            let mut service = Service {
+                name: s.qualifier.clone(),
+                description: s.name.clone().unwrap_or("".into()),
+                //input_type: DataType::None,
+                payload: s.req_bytes.clone(),
+                input_params: Vec::new(),
+                output_params: Vec::new(),
+                preconditions: s.get_preconditions()
+            };
+
+            let mut tmp: Vec<Vec<u8>> = Vec::new();
+            s.input_preparations.iter().for_each(|p| {
+                if let Some(pres) = &p.presentation {
+                    if let Some(data_fmt) = pres.create(p) {
+                        let mut param = Parameter {
+                            name: p.qualifier.clone(),
+                            unit: pres.display_unit.clone().unwrap_or("".into()),
+                            start_bit: p.bit_pos,
+                            length_bits: p.size_in_bits as usize,
+                            byte_order: common::schema::diag::service::ParamByteOrder::BigEndian,
+                            data_format: data_fmt,
+                            limits: None,
+                            signed: false,
+
+                        };
+                        if let Some(name) = pres.description.clone() {
+                            param.name = name;
+                        }
+                        tmp.push(p.dump.clone());
+                        service.input_params.push(param);
+                    }
+                }
+            });
+
+            s.output_preparations.iter().for_each(|p| {
+                if let Some(pres) = &p.presentation {
+                    if let Some(data_fmt) = pres.create(p) {
+                        let mut param = Parameter {
+                            name: p.qualifier.clone(),
+                            unit: pres.display_unit.clone().unwrap_or("".into()),
+                            start_bit: p.bit_pos,
+                            length_bits: p.size_in_bits as usize,
+                            byte_order: common::schema::diag::service::ParamByteOrder::BigEndian,
+                            data_format: data_fmt,
+                            limits: None,
+                            signed: false,
+
+                        };
+                        if let Some(name) = pres.description.clone() {
+                            param.name = name;
+                        }
+                        service.output_params.push(param);
+                    }
+                }
+                
+            });
+
+            // For CBF, it appears input params are repeated in the payload.
+            // Delete them
+            //delete_input_params(&service.payload, &mut service.input_params, tmp);
+
+            // Only add if we have a valid payload (Functions like {{INITIALIZATION}} are ignored)
+            if !service.payload.is_empty() {
+                ecu_variant.services.push(service);
+            }
+        });
+
+        ecu.variants.push(ecu_variant);
+    }
+    ecu
+}
+
+fn delete_input_params(payload: &[u8], v: &mut Vec<Parameter>, dumps: Vec<Vec<u8>>) {
+    let mut to_delete : Vec<usize> = Vec::new();
+
+    for (pos, param) in v.iter().enumerate() {
+        if param.length_bits == 8 {
+            // Full byte, check
+            let idx =  param.start_bit/8;
+
+            if let Some(b) = payload.get(idx) {
+                if let Some(x) = dumps[pos].get(0) {
+                    if b == x {
+                        to_delete.push(pos)
+                    }
+                }
+            }
+        }
+    }
+
+    for (pos, entry) in to_delete.iter().enumerate() {
+        let real_idx = *entry - pos;
+        v.remove(real_idx);
+    }
+}
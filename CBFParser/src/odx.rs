@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use common::schema::{OvdECU, variant::{ECUVariantDefinition, ECUVariantPattern}, diag::{DataFormat, StringEncoding, TableData, dtc::ECUDTC, service::{Service, Parameter, ParamByteOrder}}};
+use xml::reader::{EventReader, XmlEvent};
+
+// Parsing of ODX (ISO 22901) files, and PDX files (A zip of ODX files).
+// Only the subset OVD can use is read: The diagnostic layers (Base and ECU variants),
+// their services with the data object properties (DOPs) of their parameters, and DTC tables.
+// The result is converted to the same OvdECU schema the CBF parser produces.
+
+#[derive(Debug)]
+pub enum OdxError {
+    /// The file is not valid XML
+    XmlError(String),
+    /// The PDX archive could not be read
+    ArchiveError(String),
+    /// The file has no base or ECU variant diagnostic layers
+    NoDiagLayers,
+}
+
+pub type Result<T> = std::result::Result<T, OdxError>;
+
+/// Minimal XML element tree. ODX references elements by ID anywhere in the
+/// document, so the whole document is loaded before converting it
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    attrs: HashMap<String, String>,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element {
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item=&'a Element> + 'a {
+        self.children.iter().filter(move |c| c.name == name)
+    }
+
+    /// Follows a path of child element names, EG: "DIAG-CODED-TYPE/BIT-LENGTH"
+    fn path(&self, path: &str) -> Option<&Element> {
+        path.split('/').try_fold(self, |e, name| e.child(name))
+    }
+
+    /// Trimmed text of the element at `path`, None if it is missing or empty
+    fn text_at(&self, path: &str) -> Option<String> {
+        self.path(path).map(|e| e.text.trim().to_string()).filter(|t| !t.is_empty())
+    }
+
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.get(name).map(|s| s.as_str())
+    }
+
+    fn short_name(&self) -> String {
+        self.text_at("SHORT-NAME").unwrap_or_default()
+    }
+
+    fn long_name(&self) -> String {
+        self.text_at("LONG-NAME").unwrap_or_default()
+    }
+
+    /// All elements below this one (Including itself) with the name
+    fn find_all<'a>(&'a self, name: &str, out: &mut Vec<&'a Element>) {
+        if self.name == name {
+            out.push(self)
+        }
+        self.children.iter().for_each(|c| c.find_all(name, out))
+    }
+}
+
+fn read_xml(bytes: &[u8]) -> Result<Element> {
+    let mut stack: Vec<Element> = vec![Element::default()];
+    for event in EventReader::new(bytes) {
+        match event.map_err(|e| OdxError::XmlError(e.to_string()))? {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                stack.push(Element {
+                    name: name.local_name,
+                    // Attributes such as xsi:type are stored without their namespace
+                    attrs: attributes.into_iter().map(|a| (a.name.local_name, a.value)).collect(),
+                    children: Vec::new(),
+                    text: String::new()
+                })
+            },
+            XmlEvent::EndElement { .. } => {
+                let e = stack.pop().unwrap();
+                stack.last_mut().unwrap().children.push(e);
+            },
+            XmlEvent::Characters(s) | XmlEvent::CData(s) => stack.last_mut().unwrap().text.push_str(&s),
+            _ => {}
+        }
+    }
+    let mut root = stack.pop().unwrap();
+    root.children.pop().ok_or(OdxError::XmlError("Document has no root element".into()))
+}
+
+/// Parses coded values, which are decimal in ODX, but some tools write them as hex
+fn parse_int(s: &str) -> Option<u64> {
+    let s = s.trim();
+    match s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok()
+    }
+}
+
+fn parse_float(e: Option<&Element>) -> Option<f32> {
+    e.and_then(|x| x.text.trim().parse().ok())
+}
+
+/// Elements that can be referenced by ID-REF or by SHORT-NAME (SNREF)
+#[derive(Default)]
+struct Index<'a> {
+    by_id: HashMap<String, &'a Element>,
+    by_name: HashMap<String, &'a Element>,
+}
+
+impl<'a> Index<'a> {
+    fn new(docs: &'a [Element]) -> Self {
+        let mut index = Self::default();
+        let mut all = Vec::new();
+        for d in docs {
+            ["DIAG-LAYER-CONTAINER", "BASE-VARIANT", "ECU-VARIANT", "REQUEST", "POS-RESPONSE",
+            "DATA-OBJECT-PROP", "DTC-DOP", "UNIT", "DIAG-SERVICE"].iter().for_each(|n| d.find_all(n, &mut all));
+        }
+        for e in all {
+            if let Some(id) = e.attr("ID") {
+                index.by_id.insert(id.to_string(), e);
+            }
+            if e.name == "DATA-OBJECT-PROP" || e.name == "DTC-DOP" {
+                index.by_name.insert(e.short_name(), e);
+            }
+        }
+        index
+    }
+
+    /// Resolves a `<X-REF ID-REF=".."/>` or `<X-SNREF SHORT-NAME=".."/>` child of `e`
+    fn resolve(&self, e: &Element, ref_name: &str) -> Option<&'a Element> {
+        if let Some(r) = e.child(&format!("{}-REF", ref_name)) {
+            return r.attr("ID-REF").and_then(|id| self.by_id.get(id)).copied()
+        }
+        e.child(&format!("{}-SNREF", ref_name))
+            .and_then(|r| r.attr("SHORT-NAME"))
+            .and_then(|n| self.by_name.get(n))
+            .copied()
+    }
+}
+
+/// Converts a DOP's computation method to OVD's data format. None if it cannot be decoded by OVD
+fn create_data_format(dop: &Element, bit_len: usize) -> Option<DataFormat> {
+    let base_type = dop.path("DIAG-CODED-TYPE").and_then(|t| t.attr("BASE-DATA-TYPE")).unwrap_or("A_UINT32");
+    match base_type {
+        "A_ASCIISTRING" => return Some(DataFormat::String(StringEncoding::ASCII)),
+        "A_UTF8STRING" => return Some(DataFormat::String(StringEncoding::Utf8)),
+        "A_UNICODE2STRING" => return Some(DataFormat::String(StringEncoding::Utf16)),
+        "A_BYTEFIELD" => return Some(DataFormat::HexDump),
+        _ => {}
+    }
+    let compu = dop.child("COMPU-METHOD")?;
+    let scales: Vec<&Element> = compu.path("COMPU-INTERNAL-TO-PHYS/COMPU-SCALES")
+        .map(|s| s.children("COMPU-SCALE").collect())
+        .unwrap_or_default();
+    match compu.text_at("CATEGORY")?.as_str() {
+        "IDENTICAL" => Some(DataFormat::Identical),
+        // SCALE-LINEAR is not supported by OVD, so use its first section like the CBF parser does
+        "LINEAR" | "SCALE-LINEAR" => {
+            let coeffs = scales.first()?.child("COMPU-RATIONAL-COEFFS")?;
+            let num: Vec<f32> = coeffs.child("COMPU-NUMERATOR")?.children("V").filter_map(|v| v.text.trim().parse::<f32>().ok()).collect();
+            let den = coeffs.path("COMPU-DENOMINATOR/V").and_then(|v| v.text.trim().parse::<f32>().ok()).unwrap_or(1.0);
+            Some(DataFormat::Linear {
+                multiplier: num.get(1).copied().unwrap_or(1.0) / den,
+                offset: num.get(0).copied().unwrap_or(0.0) / den
+            })
+        },
+        "TEXTTABLE" => {
+            let table: Vec<TableData> = scales.iter().filter_map(|s| {
+                let start = parse_float(s.child("LOWER-LIMIT"))?;
+                Some(TableData {
+                    name: s.text_at("COMPU-CONST/VT")?,
+                    start,
+                    end: parse_float(s.child("UPPER-LIMIT")).unwrap_or(start)
+                })
+            }).collect();
+            if bit_len == 1 {
+                let name_of = |v: f32| table.iter().find(|t| t.start == v).map(|t| t.name.clone());
+                Some(DataFormat::Bool { pos_name: name_of(1.0), neg_name: name_of(0.0) })
+            } else {
+                Some(table)
+                    .filter(|t| !t.is_empty())
+                    .map(DataFormat::Table)
+            }
+        },
+        other => {
+            eprintln!("WARNING. Unsupported COMPU-METHOD {} in DOP {}", other, dop.short_name());
+            None
+        }
+    }
+}
+
+/// True if a DOP's coded value is a two's complement signed number
+fn base_type_is_signed(dop: &Element) -> bool {
+    dop.path("DIAG-CODED-TYPE").and_then(|t| t.attr("BASE-DATA-TYPE")) == Some("A_INT32")
+}
+
+/// Length of a DOP's coded value in bits. None for lengths only known once a response is read
+fn get_bit_length(dop: &Element) -> Option<usize> {
+    let coded = dop.child("DIAG-CODED-TYPE")?;
+    if let Some(len) = coded.text_at("BIT-LENGTH") {
+        return len.parse().ok()
+    }
+    // MIN-MAX-LENGTH-TYPE (Strings and byte fields) - Use the maximum length
+    coded.text_at("MAX-LENGTH").and_then(|l| l.parse::<usize>().ok()).map(|l| l * 8)
+}
+
+fn create_param(index: &Index, p: &Element) -> Option<Parameter> {
+    let dop = index.resolve(p, "DOP")?;
+    let length_bits = get_bit_length(dop)?;
+    let byte_pos = parse_int(&p.text_at("BYTE-POSITION")?)? as usize;
+    let bit_pos = p.text_at("BIT-POSITION").and_then(|b| parse_int(&b)).unwrap_or(0) as usize;
+    let byte_order = match dop.path("DIAG-CODED-TYPE").and_then(|t| t.attr("IS-HIGHLOW-BYTE-ORDER")) {
+        Some("false") => ParamByteOrder::LittleEndian,
+        _ => ParamByteOrder::BigEndian
+    };
+    let unit = index.resolve(dop, "UNIT")
+        .map(|u| u.text_at("DISPLAY-NAME").unwrap_or(u.short_name()))
+        .unwrap_or_default();
+    let name = match p.long_name() {
+        n if n.is_empty() => p.short_name(),
+        n => n
+    };
+    Some(Parameter {
+        name,
+        unit,
+        start_bit: byte_pos * 8 + bit_pos,
+        length_bits,
+        byte_order,
+        data_format: create_data_format(dop, length_bits)?,
+        limits: None,
+        signed: base_type_is_signed(dop)
+    })
+}
+
+fn is_param_type(p: &Element, t: &str) -> bool {
+    p.attr("type") == Some(t)
+}
+
+/// Builds the request payload from the CODED-CONST parameters of a REQUEST (The SID, sub-function, DID...)
+fn create_payload(request: &Element) -> Vec<u8> {
+    let mut payload: Vec<u8> = Vec::new();
+    if let Some(params) = request.child("PARAMS") {
+        for p in params.children("PARAM").filter(|p| is_param_type(p, "CODED-CONST")) {
+            let pos = p.text_at("BYTE-POSITION").and_then(|b| parse_int(&b)).unwrap_or(0) as usize;
+            let bits = p.text_at("DIAG-CODED-TYPE/BIT-LENGTH").and_then(|b| b.parse::<usize>().ok()).unwrap_or(8);
+            let value = p.text_at("CODED-VALUE").and_then(|v| parse_int(&v)).unwrap_or(0);
+            let len = (bits + 7) / 8;
+            if payload.len() < pos + len {
+                payload.resize(pos + len, 0);
+            }
+            for i in 0..len {
+                payload[pos + i] = (value >> (8 * (len - i - 1))) as u8;
+            }
+        }
+    }
+    payload
+}
+
+fn create_service(index: &Index, s: &Element) -> Service {
+    let mut service = Service {
+        name: s.short_name(),
+        description: s.long_name(),
+        payload: Vec::new(),
+        input_params: Vec::new(),
+        output_params: Vec::new(),
+        preconditions: Default::default()
+    };
+    if let Some(request) = index.resolve(s, "REQUEST") {
+        service.payload = create_payload(request);
+        if let Some(params) = request.child("PARAMS") {
+            service.input_params = params.children("PARAM")
+                .filter(|p| is_param_type(p, "VALUE"))
+                .filter_map(|p| create_param(index, p))
+                .collect();
+        }
+    }
+    let response = s.path("POS-RESPONSE-REFS/POS-RESPONSE-REF")
+        .and_then(|r| r.attr("ID-REF"))
+        .and_then(|id| index.by_id.get(id));
+    if let Some(params) = response.and_then(|r| r.child("PARAMS")) {
+        service.output_params = params.children("PARAM")
+            .filter(|p| is_param_type(p, "VALUE"))
+            .filter_map(|p| create_param(index, p))
+            .collect();
+    }
+    service
+}
+
+fn create_dtcs(layer: &Element) -> Vec<ECUDTC> {
+    let mut dops = Vec::new();
+    layer.find_all("DTC-DOP", &mut dops);
+    dops.iter()
+        .filter_map(|d| d.child("DTCS"))
+        .flat_map(|d| d.children("DTC"))
+        .map(|dtc| ECUDTC {
+            // Match the format OVD reads DTCs as (EG: P2001), if the file has it
+            error_name: dtc.text_at("DISPLAY-TROUBLE-CODE")
+                .or(dtc.text_at("TROUBLE-CODE").and_then(|c| parse_int(&c)).map(|c| format!("{:04X}", c)))
+                .unwrap_or(dtc.short_name()),
+            summary: dtc.short_name(),
+            description: dtc.text_at("TEXT").unwrap_or_default()
+        }).collect()
+}
+
+/// Variant identification values, read from the ECU (EG: Diagnostic version) to pick the variant
+fn create_patterns(layer: &Element) -> Vec<ECUVariantPattern> {
+    let mut params = Vec::new();
+    layer.find_all("MATCHING-PARAMETER", &mut params);
+    params.iter().filter_map(|p| {
+        Some(ECUVariantPattern {
+            vendor: p.path("DIAG-COMM-SNREF")
+                .and_then(|s| s.attr("SHORT-NAME"))
+                .unwrap_or("ODX")
+                .to_string(),
+            vendor_id: parse_int(&p.text_at("EXPECTED-VALUE")?)? as u32
+        })
+    }).collect()
+}
+
+/// Services of a layer, including ones inherited from its parent layers
+fn get_layer_services<'a>(index: &Index<'a>, layer: &'a Element) -> Vec<&'a Element> {
+    let mut services: Vec<&Element> = Vec::new();
+    if let Some(parents) = layer.child("PARENT-REFS") {
+        for r in parents.children("PARENT-REF") {
+            let not_inherited: Vec<String> = r.path("NOT-INHERITED-DIAG-COMMS")
+                .map(|n| n.children("NOT-INHERITED-DIAG-COMM")
+                    .filter_map(|c| c.child("DIAG-COMM-SNREF").and_then(|s| s.attr("SHORT-NAME")).map(String::from))
+                    .collect())
+                .unwrap_or_default();
+            if let Some(parent) = r.attr("ID-REF").and_then(|id| index.by_id.get(id).copied()) {
+                get_layer_services(index, parent).into_iter()
+                    .filter(|s| !not_inherited.contains(&s.short_name()))
+                    .for_each(|s| services.push(s));
+            }
+        }
+    }
+    if let Some(comms) = layer.child("DIAG-COMMS") {
+        for s in comms.children("DIAG-SERVICE") {
+            // Services in this layer override inherited ones with the same name
+            services.retain(|x| x.short_name() != s.short_name());
+            services.push(s);
+        }
+        // Services referenced from other layers
+        for r in comms.children("DIAG-COMM-REF") {
+            if let Some(s) = r.attr("ID-REF").and_then(|id| index.by_id.get(id).copied()) {
+                services.retain(|x| x.short_name() != s.short_name());
+                services.push(s);
+            }
+        }
+    }
+    services
+}
+
+fn create_variant<'a>(index: &Index<'a>, layer: &'a Element) -> ECUVariantDefinition {
+    println!("Converting variant {}", layer.short_name());
+    let mut variant = ECUVariantDefinition {
+        name: layer.short_name(),
+        description: layer.long_name(),
+        patterns: create_patterns(layer),
+        errors: Vec::new(),
+        services: Vec::new()
+    };
+    // DTCs are usually defined in the base variant, so inherit them
+    let mut layers = vec![layer];
+    if let Some(parents) = layer.child("PARENT-REFS") {
+        parents.children("PARENT-REF")
+            .filter_map(|r| r.attr("ID-REF").and_then(|id| index.by_id.get(id).copied()))
+            .for_each(|p| layers.push(p));
+    }
+    for l in layers {
+        for dtc in create_dtcs(l) {
+            if !variant.errors.iter().any(|e| e.error_name == dtc.error_name) {
+                variant.errors.push(dtc)
+            }
+        }
+    }
+    // Only add services with a valid payload, like the CBF parser
+    variant.services = get_layer_services(index, layer).into_iter()
+        .map(|s| create_service(index, s))
+        .filter(|s| !s.payload.is_empty())
+        .collect();
+    variant
+}
+
+fn convert(docs: &[Element]) -> Result<Vec<OvdECU>> {
+    let index = Index::new(docs);
+    let mut containers = Vec::new();
+    docs.iter().for_each(|d| d.find_all("DIAG-LAYER-CONTAINER", &mut containers));
+
+    let mut ecus = Vec::new();
+    for c in containers {
+        let mut base_variants = Vec::new();
+        let mut ecu_variants = Vec::new();
+        c.find_all("BASE-VARIANT", &mut base_variants);
+        c.find_all("ECU-VARIANT", &mut ecu_variants);
+        for base in base_variants {
+            println!("Converting ECU {}", base.short_name());
+            let base_id = base.attr("ID");
+            let children: Vec<&Element> = ecu_variants.iter()
+                .filter(|v| v.path("PARENT-REFS/PARENT-REF").and_then(|r| r.attr("ID-REF")) == base_id)
+                .copied()
+                .collect();
+            // Like CBF, the base variant is only a template for the ECU variants,
+            // unless the file has no ECU variants for it
+            let variants = if children.is_empty() {
+                vec![create_variant(&index, base)]
+            } else {
+                children.iter().map(|v| create_variant(&index, v)).collect()
+            };
+            ecus.push(OvdECU {
+                name: base.short_name(),
+                description: base.long_name(),
+                variants
+            });
+        }
+    }
+    if ecus.is_empty() {
+        Err(OdxError::NoDiagLayers)
+    } else {
+        Ok(ecus)
+    }
+}
+
+/// Parses the contents of an ODX file (.odx or .odx-d), returning each ECU (Base variant) in it
+pub fn parse_odx(bytes: &[u8]) -> Result<Vec<OvdECU>> {
+    convert(&[read_xml(bytes)?])
+}
+
+/// Parses the contents of a PDX file. Every ODX file in the archive is read,
+/// as references between layers can cross files
+pub fn parse_pdx(bytes: &[u8]) -> Result<Vec<OvdECU>> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).map_err(|e| OdxError::ArchiveError(e.to_string()))?;
+    let mut docs = Vec::new();
+    for i in 0..archive.len() {
+        let mut f = archive.by_index(i).map_err(|e| OdxError::ArchiveError(e.to_string()))?;
+        let name = f.name().to_lowercase();
+        if name.ends_with(".odx") || name.ends_with(".odx-d") {
+            let mut buf = Vec::new();
+            f.read_to_end(&mut buf).map_err(|e| OdxError::ArchiveError(e.to_string()))?;
+            docs.push(read_xml(&buf)?);
+        }
+    }
+    convert(&docs)
+}
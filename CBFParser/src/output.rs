@@ -0,0 +1,88 @@
+use std::io::Write;
+use common::schema::{OvdECU, diag::{dtc::ECUDTC, service::Service}};
+use serde::Serialize;
+
+/// Formatter used to write the converted ECU to its output file
+pub trait OutputFormatter {
+    /// File extension (Without the '.') of the output file
+    fn get_extension(&self) -> &'static str;
+    /// Writes the entire ECU to the output
+    fn write_ecu(&self, ecu: &OvdECU, out: &mut dyn Write) -> std::io::Result<()>;
+}
+
+/// Human readable JSON (Default)
+pub struct PrettyJson;
+
+impl OutputFormatter for PrettyJson {
+    fn get_extension(&self) -> &'static str {
+        "json"
+    }
+
+    fn write_ecu(&self, ecu: &OvdECU, out: &mut dyn Write) -> std::io::Result<()> {
+        serde_json::to_writer_pretty(out, ecu).map_err(std::io::Error::from)
+    }
+}
+
+/// JSON with no whitespace
+pub struct CompactJson;
+
+impl OutputFormatter for CompactJson {
+    fn get_extension(&self) -> &'static str {
+        "json"
+    }
+
+    fn write_ecu(&self, ecu: &OvdECU, out: &mut dyn Write) -> std::io::Result<()> {
+        serde_json::to_writer(out, ecu).map_err(std::io::Error::from)
+    }
+}
+
+/// Newline delimited JSON. Every service and DTC of every variant
+/// is written as its own record on a single line
+pub struct NdJson;
+
+#[derive(Serialize)]
+#[serde(tag = "record")]
+enum NdJsonRecord<'a> {
+    Service {
+        ecu: &'a str,
+        variant: &'a str,
+        service: &'a Service,
+    },
+    DTC {
+        ecu: &'a str,
+        variant: &'a str,
+        dtc: &'a ECUDTC,
+    },
+}
+
+impl OutputFormatter for NdJson {
+    fn get_extension(&self) -> &'static str {
+        "ndjson"
+    }
+
+    fn write_ecu(&self, ecu: &OvdECU, out: &mut dyn Write) -> std::io::Result<()> {
+        for variant in ecu.variants.iter() {
+            for service in variant.services.iter() {
+                let record = NdJsonRecord::Service { ecu: &ecu.name, variant: &variant.name, service };
+                serde_json::to_writer(&mut *out, &record)?;
+                out.write_all(b"\n")?;
+            }
+            for dtc in variant.errors.iter() {
+                let record = NdJsonRecord::DTC { ecu: &ecu.name, variant: &variant.name, dtc };
+                serde_json::to_writer(&mut *out, &record)?;
+                out.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the formatter for the name given with `--format`
+pub fn get_formatter(name: &str) -> Option<Box<dyn OutputFormatter>> {
+    match name {
+        "pretty" => Some(Box::new(PrettyJson)),
+        "compact" => Some(Box::new(CompactJson)),
+        "ndjson" => Some(Box::new(NdJson)),
+        _ => None
+    }
+}
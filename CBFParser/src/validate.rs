@@ -0,0 +1,110 @@
+use common::schema::{OvdECU, diag::service::Service};
+
+/// Largest payload that can be sent over ISO-TP
+const MAX_PAYLOAD_LEN: usize = 4095;
+
+/// Service IDs used by KWP2000 and UDS, with the minimum length of a request
+/// for that service (Including the service ID byte)
+const KNOWN_SERVICES: &[(u8, &str, usize)] = &[
+    (0x10, "DiagnosticSessionControl", 2),
+    (0x11, "ECUReset", 2),
+    (0x14, "ClearDiagnosticInformation", 3),
+    (0x17, "ReadStatusOfDTC", 3),
+    (0x18, "ReadDTCByStatus", 2),
+    (0x19, "ReadDTCInformation", 2),
+    (0x1A, "ReadECUIdentification", 2),
+    (0x20, "StopDiagnosticSession", 1),
+    (0x21, "ReadDataByLocalIdentifier", 2),
+    (0x22, "ReadDataByIdentifier", 3),
+    (0x23, "ReadMemoryByAddress", 4),
+    (0x24, "ReadScalingDataByIdentifier", 3),
+    (0x27, "SecurityAccess", 2),
+    (0x28, "CommunicationControl", 2),
+    (0x29, "Authentication", 2),
+    (0x2A, "ReadDataByPeriodicIdentifier", 2),
+    (0x2C, "DynamicallyDefineDataIdentifier", 2),
+    (0x2E, "WriteDataByIdentifier", 4),
+    (0x2F, "InputOutputControlByIdentifier", 4),
+    (0x30, "InputOutputControlByLocalIdentifier", 3),
+    (0x31, "RoutineControl", 2),
+    (0x32, "StopRoutineByLocalIdentifier", 2),
+    (0x33, "RequestRoutineResultsByLocalIdentifier", 2),
+    (0x34, "RequestDownload", 4),
+    (0x35, "RequestUpload", 4),
+    (0x36, "TransferData", 2),
+    (0x37, "RequestTransferExit", 1),
+    (0x38, "RequestFileTransfer", 5),
+    (0x3B, "WriteDataByLocalIdentifier", 3),
+    (0x3D, "WriteMemoryByAddress", 5),
+    (0x3E, "TesterPresent", 1),
+    (0x81, "StartCommunication", 1),
+    (0x82, "StopCommunication", 1),
+    (0x83, "AccessTimingParameter", 2),
+    (0x84, "SecuredDataTransmission", 1),
+    (0x85, "ControlDTCSetting", 2),
+    (0x86, "ResponseOnEvent", 2),
+    (0x87, "LinkControl", 2),
+];
+
+/// Checks that a service's request template is a plausible KWP2000 / UDS request.
+/// Returns the name of the service ID if it is, or why it is not
+pub fn validate_service(s: &Service) -> Result<&'static str, String> {
+    let sid = match s.payload.get(0) {
+        Some(sid) => *sid,
+        None => return Err("Empty payload".into())
+    };
+    let (_, name, min_len) = KNOWN_SERVICES.iter()
+        .find(|(id, _, _)| *id == sid)
+        .ok_or(format!("Unknown service ID 0x{:02X}", sid))?;
+    if s.payload.len() < *min_len {
+        Err(format!("{} requires at least {} bytes, payload has {}", name, min_len, s.payload.len()))
+    } else if s.payload.len() > MAX_PAYLOAD_LEN {
+        Err(format!("Payload of {} bytes is too large for ISO-TP", s.payload.len()))
+    } else {
+        Ok(name)
+    }
+}
+
+/// A service whose request template did not look like a KWP2000 / UDS request
+pub struct InvalidService {
+    pub variant: String,
+    pub service: String,
+    pub payload: Vec<u8>,
+    pub reason: String,
+}
+
+#[derive(Default)]
+pub struct ValidationReport {
+    pub recognized: usize,
+    pub unrecognized: Vec<InvalidService>,
+}
+
+impl ValidationReport {
+    pub fn print_summary(&self) {
+        let total = self.recognized + self.unrecognized.len();
+        println!("Service validation: {}/{} services recognized, {} unrecognized", self.recognized, total, self.unrecognized.len());
+        for s in self.unrecognized.iter() {
+            println!("  {} -> {} ({:02X?}): {}", s.variant, s.service, s.payload, s.reason);
+        }
+    }
+}
+
+/// Validates the request templates of every service in every variant of the ECU.
+/// Services that are not recognized usually mean the parser has misread part of the CBF
+pub fn validate_ecu(ecu: &OvdECU) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    for variant in ecu.variants.iter() {
+        for service in variant.services.iter() {
+            match validate_service(service) {
+                Ok(_) => report.recognized += 1,
+                Err(reason) => report.unrecognized.push(InvalidService {
+                    variant: variant.name.clone(),
+                    service: service.name.clone(),
+                    payload: service.payload.clone(),
+                    reason
+                })
+            }
+        }
+    }
+    report
+}
@@ -42,6 +42,10 @@ pub mod draw_routine {
                 recv_id: 1268,
                 block_size: 8,
                 sep_time: 20,
+                auto_fc: false,
+                can_fd: None,
+                padding: None,
+                ext_addr: None,
             },
         )
         .expect("Error opening connection with IC ECU");
@@ -0,0 +1,266 @@
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+
+use lazy_static::lazy_static;
+
+use crate::commapi::comm_api::{ComServer, ComServerError};
+use crate::passthru::PassthruDevice;
+
+lazy_static! {
+    /// The adapter OVD is connected to
+    pub static ref ADAPTER: AdapterActor = AdapterActor::spawn();
+}
+
+/// Commands that can be ran on the adapter thread, with the channel the result is sent back on
+enum AdapterCmd {
+    Connect(
+        Box<dyn ComServer>,
+        Sender<Result<Box<dyn ComServer>, ComServerError>>,
+    ),
+    Disconnect(Sender<Result<(), ComServerError>>),
+    GetVbatt(Sender<Result<f32, ComServerError>>),
+    GetDeviceList(Sender<Vec<PassthruDevice>>),
+}
+
+/// Owns the adapter OVD is connected to on a thread of its own. Connecting to and disconnecting
+/// from the adapter, reading its battery voltage and listing the Passthru devices are sent to
+/// the thread as commands, and ran one at a time in the order they were sent.
+///
+/// This can be shared between threads freely. There is no lock to poison or to take twice,
+/// and each call only waits for the commands sent before it
+#[derive(Debug, Clone)]
+pub struct AdapterActor {
+    tx: SyncSender<AdapterCmd>,
+}
+
+impl AdapterActor {
+    /// Starts the thread the adapter is owned by. It runs until every copy of the
+    /// returned actor has been dropped
+    pub fn spawn() -> Self {
+        let (tx, rx) = mpsc::sync_channel(16);
+        std::thread::spawn(move || Self::run(rx));
+        Self { tx }
+    }
+
+    fn run(rx: Receiver<AdapterCmd>) {
+        let mut server: Option<Box<dyn ComServer>> = None;
+        for cmd in rx {
+            // Sending the result only fails if the caller stopped waiting for it
+            match cmd {
+                AdapterCmd::Connect(mut new_server, res_tx) => {
+                    // Only one adapter can be connected to at a time
+                    if let Some(old) = server.take() {
+                        if let Err(e) = Self::close(old) {
+                            eprintln!("Error disconnecting from the previous adapter: {}", e)
+                        }
+                    }
+                    let res = new_server.open_device().map(|_| new_server.clone_box());
+                    if res.is_ok() {
+                        server = Some(new_server);
+                    }
+                    let _ = res_tx.send(res);
+                }
+                AdapterCmd::Disconnect(res_tx) => {
+                    let _ = res_tx.send(server.take().map(Self::close).unwrap_or(Ok(())));
+                }
+                AdapterCmd::GetVbatt(res_tx) => {
+                    let res = match &server {
+                        Some(s) => s.read_battery_voltage(),
+                        None => Err(Self::not_connected_err()),
+                    };
+                    let _ = res_tx.send(res);
+                }
+                AdapterCmd::GetDeviceList(res_tx) => {
+                    let _ = res_tx.send(PassthruDevice::find_all().unwrap_or_default());
+                }
+            }
+        }
+    }
+
+    /// Closes any interfaces left open on an adapter, then the adapter itself
+    fn close(mut server: Box<dyn ComServer>) -> Result<(), ComServerError> {
+        if let Err(e) = server.close_iso15765_interface() {
+            eprintln!("Error closing ISO15765 interface: {}", e.err_desc)
+        }
+        if let Err(e) = server.close_can_interface() {
+            eprintln!("Error closing CAN interface: {}", e.err_desc)
+        }
+        server.close_device()
+    }
+
+    fn not_connected_err() -> ComServerError {
+        ComServerError {
+            err_code: 2,
+            err_desc: "No adapter connected".into(),
+        }
+    }
+
+    fn stopped_err() -> ComServerError {
+        ComServerError {
+            err_code: 99,
+            err_desc: "Adapter thread has stopped".into(),
+        }
+    }
+
+    /// Sends a command to the adapter thread, and waits for its result
+    fn call<T>(&self, cmd: impl FnOnce(Sender<T>) -> AdapterCmd) -> Result<T, ComServerError> {
+        let (res_tx, res_rx) = mpsc::channel();
+        self.tx.send(cmd(res_tx)).map_err(|_| Self::stopped_err())?;
+        res_rx.recv().map_err(|_| Self::stopped_err())
+    }
+
+    /// Opens the adapter, replacing the adapter that was connected to before (If any).
+    ///
+    /// ## Returns
+    /// A copy of the opened adapter to use for diagnostics, or the reason it could not be opened
+    pub fn connect_device(
+        &self,
+        server: Box<dyn ComServer>,
+    ) -> Result<Box<dyn ComServer>, ComServerError> {
+        self.call(|tx| AdapterCmd::Connect(server, tx))?
+    }
+
+    /// Closes the connected adapter, and any interfaces left open on it.
+    /// Does nothing if no adapter is connected
+    pub fn disconnect_device(&self) -> Result<(), ComServerError> {
+        self.call(AdapterCmd::Disconnect)?
+    }
+
+    /// Reads the battery voltage from the connected adapter
+    pub fn get_vbatt(&self) -> Result<f32, ComServerError> {
+        self.call(AdapterCmd::GetVbatt)?
+    }
+
+    /// Returns every Passthru device installed
+    pub fn get_device_list(&self) -> Vec<PassthruDevice> {
+        self.call(AdapterCmd::GetDeviceList).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod adapter_test {
+    use std::sync::{Arc, Barrier};
+
+    use super::AdapterActor;
+    use crate::commapi::mock_api::MockComServer;
+    use crate::passthru::PassthruDevice;
+
+    const THREADS: usize = 8;
+
+    #[test]
+    fn vbatt_fails_without_adapter() {
+        let adapter = AdapterActor::spawn();
+        assert!(adapter.get_vbatt().is_err());
+    }
+
+    #[test]
+    fn concurrent_get_vbatt() {
+        let adapter = AdapterActor::spawn();
+        adapter
+            .connect_device(Box::new(MockComServer::new()))
+            .unwrap();
+        // Every thread asks at the same time
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let adapter = adapter.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    (0..50).map(|_| adapter.get_vbatt()).collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        for h in handles {
+            for res in h.join().unwrap() {
+                assert_eq!(res.unwrap(), 12.6);
+            }
+        }
+    }
+
+    #[test]
+    fn concurrent_get_vbatt_and_device_list() {
+        let adapter = AdapterActor::spawn();
+        adapter
+            .connect_device(Box::new(MockComServer::new()))
+            .unwrap();
+        let expected: Vec<String> = PassthruDevice::find_all()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|d| d.name)
+            .collect();
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let handles: Vec<_> = (0..THREADS)
+            .map(|i| {
+                let adapter = adapter.clone();
+                let barrier = barrier.clone();
+                let expected = expected.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    for _ in 0..20 {
+                        // Half the threads poll the voltage whilst the others list devices
+                        if i % 2 == 0 {
+                            assert_eq!(adapter.get_vbatt().unwrap(), 12.6);
+                        } else {
+                            let names: Vec<String> = adapter
+                                .get_device_list()
+                                .into_iter()
+                                .map(|d| d.name)
+                                .collect();
+                            assert_eq!(names, expected);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn connect_disconnect_reconnect() {
+        let adapter = AdapterActor::spawn();
+        let mock = MockComServer::new();
+        for _ in 0..20 {
+            let mut server = adapter.connect_device(Box::new(mock.clone())).unwrap();
+            assert_eq!(mock.open_state(), (true, false, false));
+            assert_eq!(adapter.get_vbatt().unwrap(), 12.6);
+            // A diagnostic session leaves its interfaces open
+            server.open_can_interface(500_000, false).unwrap();
+            server
+                .open_iso15765_interface(500_000, false, false)
+                .unwrap();
+            assert_eq!(mock.open_state(), (true, true, true));
+            adapter.disconnect_device().unwrap();
+            assert_eq!(mock.open_state(), (false, false, false));
+            assert!(adapter.get_vbatt().is_err());
+        }
+        // Disconnecting again does nothing
+        adapter.disconnect_device().unwrap();
+    }
+
+    #[test]
+    fn connect_replaces_previous_adapter() {
+        let adapter = AdapterActor::spawn();
+        let first = MockComServer::new();
+        let second = MockComServer::new();
+        adapter.connect_device(Box::new(first.clone())).unwrap();
+        adapter.connect_device(Box::new(second.clone())).unwrap();
+        assert_eq!(first.open_state(), (false, false, false));
+        assert_eq!(second.open_state(), (true, false, false));
+        adapter.connect_device(Box::new(first.clone())).unwrap();
+        assert_eq!(first.open_state(), (true, false, false));
+        assert_eq!(second.open_state(), (false, false, false));
+    }
+
+    #[test]
+    fn stopped_thread_returns_error() {
+        let (tx, rx) = std::sync::mpsc::sync_channel(1);
+        // No thread is receiving commands
+        drop(rx);
+        let adapter = AdapterActor { tx };
+        assert!(adapter.get_vbatt().is_err());
+        assert!(adapter.disconnect_device().is_err());
+        assert!(adapter.get_device_list().is_empty());
+    }
+}
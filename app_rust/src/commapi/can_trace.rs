@@ -0,0 +1,478 @@
+// Export of captured CAN traffic to the trace formats used by Vector's tools
+// (CANalyzer / CANoe), which Wireshark and most other CAN tools can also open.
+//
+// ASC - ASCII log, one frame per line
+// BLF - Binary logging format. Frames are written in uncompressed log containers
+//
+// ASC traces can also be imported, to replay traces captured by other tools
+
+use std::io::Write;
+
+use chrono::{DateTime, Datelike, Local, Timelike};
+
+use super::comm_api::CanFrame;
+
+/// A CAN frame captured by OVD
+#[derive(Debug, Copy, Clone)]
+pub struct TraceFrame {
+    /// Time since the start of the capture in microseconds
+    pub timestamp_us: u128,
+    pub frame: CanFrame,
+}
+
+/// File format CAN traces are exported as
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TraceFormat {
+    Asc,
+    Blf,
+}
+
+impl TraceFormat {
+    /// File extension (Without the '.') of exported traces
+    pub fn get_extension(&self) -> &'static str {
+        match self {
+            TraceFormat::Asc => "asc",
+            TraceFormat::Blf => "blf",
+        }
+    }
+}
+
+impl std::fmt::Display for TraceFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceFormat::Asc => write!(f, "ASC"),
+            TraceFormat::Blf => write!(f, "BLF"),
+        }
+    }
+}
+
+/// Largest standard (11 bit) CAN ID. Anything above is written as an extended ID
+const MAX_STD_ID: u32 = 0x7FF;
+
+/// Writes a capture to `out` in the given format. `start` is when the capture started
+pub fn export_trace(
+    out: &mut dyn Write,
+    format: TraceFormat,
+    frames: &[TraceFrame],
+    start: DateTime<Local>,
+) -> std::io::Result<()> {
+    match format {
+        TraceFormat::Asc => write_asc(out, frames, start),
+        TraceFormat::Blf => write_blf(out, frames, start),
+    }
+}
+
+fn write_asc(
+    out: &mut dyn Write,
+    frames: &[TraceFrame],
+    start: DateTime<Local>,
+) -> std::io::Result<()> {
+    let date = start.format("%a %b %d %I:%M:%S%.3f %P %Y");
+    writeln!(out, "date {}", date)?;
+    writeln!(out, "base hex  timestamps absolute")?;
+    writeln!(out, "internal events logged")?;
+    writeln!(out, "Begin Triggerblock {}", date)?;
+    writeln!(out, "   0.000000 Start of measurement")?;
+    for f in frames {
+        writeln!(out, "{}", asc_line(f))?;
+    }
+    writeln!(out, "End TriggerBlock")
+}
+
+/// Formats a frame as a line of an ASC trace. All frames are logged as received on channel 1
+fn asc_line(f: &TraceFrame) -> String {
+    let id = if f.frame.id > MAX_STD_ID {
+        format!("{:X}x", f.frame.id)
+    } else {
+        format!("{:X}", f.frame.id)
+    };
+    let data: Vec<String> = f
+        .frame
+        .get_data()
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect();
+    format!(
+        "{:>4}.{:06} 1  {:<15} Rx   d {} {}",
+        f.timestamp_us / 1_000_000,
+        f.timestamp_us % 1_000_000,
+        id,
+        f.frame.dlc,
+        data.join(" ")
+    )
+    .trim_end()
+    .to_string()
+}
+
+/// Result of importing an ASC trace
+#[derive(Debug, Clone, Default)]
+pub struct AscImport {
+    /// Classic CAN frames in the trace, in the order they were logged
+    pub frames: Vec<TraceFrame>,
+    /// Number of events that are not classic CAN frames (Error frames, remote frames,
+    /// CAN FD frames, statistics), which cannot be replayed
+    pub skipped: usize,
+    /// Line numbers (Starting at 1) of lines that could not be understood
+    pub malformed: Vec<usize>,
+}
+
+impl std::fmt::Display for AscImport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} frames imported, {} other events skipped, {} malformed lines",
+            self.frames.len(),
+            self.skipped,
+            self.malformed.len()
+        )?;
+        if let Some(line) = self.malformed.first() {
+            write!(f, " (First on line {})", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of parsing an event line of an ASC trace
+enum AscEvent {
+    Frame(f64, CanFrame),
+    Skipped(f64),
+}
+
+/// Imports an ASC trace. Lines that cannot be parsed are counted rather than stopping the
+/// import, as traces written by different tools vary slightly in format
+pub fn import_asc(text: &str) -> AscImport {
+    let mut res = AscImport::default();
+    let mut hex = true;
+    let mut relative = false;
+    let mut last_time = 0.0;
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let lower = line.to_ascii_lowercase();
+        if line.is_empty() || line.starts_with("//") {
+            continue;
+        }
+        if lower.starts_with("base ") {
+            // EG: 'base hex  timestamps absolute'
+            hex = !lower.contains("base dec");
+            relative = lower.contains("timestamps relative");
+            continue;
+        }
+        if lower.starts_with("date ")
+            || lower.starts_with("begin triggerblock")
+            || lower.starts_with("end triggerblock")
+            || lower.starts_with("internal events")
+            || lower.starts_with("no internal events")
+        {
+            continue;
+        }
+        match parse_asc_event(line, hex) {
+            Some(event) => {
+                let time = match event {
+                    AscEvent::Frame(t, _) | AscEvent::Skipped(t) => t,
+                };
+                let time = if relative { last_time + time } else { time };
+                last_time = time;
+                match event {
+                    AscEvent::Frame(_, frame) => res.frames.push(TraceFrame {
+                        timestamp_us: (time * 1_000_000.0).round() as u128,
+                        frame,
+                    }),
+                    AscEvent::Skipped(_) => res.skipped += 1,
+                }
+            }
+            None => res.malformed.push(idx + 1),
+        }
+    }
+    res
+}
+
+/// Parses an event line, EG: '   0.001234 1  7E8             Rx   d 3 02 7E 00'
+fn parse_asc_event(line: &str, hex: bool) -> Option<AscEvent> {
+    let mut parts = line.split_whitespace();
+    let time = parts.next()?.parse::<f64>().ok().filter(|t| *t >= 0.0)?;
+    let parts: Vec<&str> = parts.collect();
+    // Classic CAN frames start with the channel number, anything else is another event
+    // (EG: 'Start of measurement', 'CANFD', 'ErrorFrame' after the channel)
+    if parts.first()?.parse::<u8>().is_err() {
+        return Some(AscEvent::Skipped(time));
+    }
+    let id_str = parts.get(1)?;
+    if id_str.eq_ignore_ascii_case("ErrorFrame") || id_str.eq_ignore_ascii_case("Statistic:") {
+        return Some(AscEvent::Skipped(time));
+    }
+    let radix = if hex { 16 } else { 10 };
+    let (id_str, ext) = match id_str.strip_suffix(|c| c == 'x' || c == 'X') {
+        Some(id) => (id, true),
+        None => (*id_str, false),
+    };
+    let id = u32::from_str_radix(id_str, radix).ok()?;
+    if id > 0x1FFF_FFFF || (!ext && id > MAX_STD_ID) {
+        return None;
+    }
+    // Direction, then 'd' for data frames or 'r' for remote frames
+    match parts.get(3).map(|x| x.to_ascii_lowercase()).as_deref() {
+        Some("d") => {}
+        Some("r") => return Some(AscEvent::Skipped(time)),
+        _ => return None,
+    }
+    let dlc = u8::from_str_radix(parts.get(4)?, 16).ok()?;
+    if dlc > 8 {
+        return Some(AscEvent::Skipped(time));
+    }
+    let data = parts
+        .get(5..5 + dlc as usize)?
+        .iter()
+        .map(|b| u8::from_str_radix(b, radix).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(AscEvent::Frame(time, CanFrame::new(id, &data)))
+}
+
+/// Size of the BLF file header
+const BLF_FILE_HEADER_SIZE: usize = 144;
+/// Size of the base header all BLF objects start with
+const BLF_OBJ_HEADER_BASE_SIZE: usize = 16;
+/// Size of a log container header, including the base header
+const BLF_CONTAINER_HEADER_SIZE: usize = BLF_OBJ_HEADER_BASE_SIZE + 16;
+/// Size of a CAN message object, including its header
+const BLF_CAN_MESSAGE_SIZE: usize = BLF_OBJ_HEADER_BASE_SIZE + 16 + 16;
+/// Number of frames written to each log container
+const BLF_FRAMES_PER_CONTAINER: usize = 1000;
+
+const BLF_OBJ_CAN_MESSAGE: u32 = 1;
+const BLF_OBJ_LOG_CONTAINER: u32 = 10;
+/// Object timestamps are in nanoseconds
+const BLF_TIME_ONE_NANS: u32 = 2;
+/// Bit set in the ID of CAN messages with an extended ID
+const BLF_EXT_ID_FLAG: u32 = 0x8000_0000;
+
+fn write_blf(
+    out: &mut dyn Write,
+    frames: &[TraceFrame],
+    start: DateTime<Local>,
+) -> std::io::Result<()> {
+    let containers: Vec<Vec<u8>> = frames
+        .chunks(BLF_FRAMES_PER_CONTAINER)
+        .map(blf_container)
+        .collect();
+    let file_size = BLF_FILE_HEADER_SIZE + containers.iter().map(|c| c.len()).sum::<usize>();
+    let end = frames
+        .last()
+        .map(|f| start + chrono::Duration::microseconds(f.timestamp_us as i64))
+        .unwrap_or(start);
+
+    let mut header = Vec::with_capacity(BLF_FILE_HEADER_SIZE);
+    header.extend_from_slice(b"LOGG");
+    header.extend_from_slice(&(BLF_FILE_HEADER_SIZE as u32).to_le_bytes());
+    // Application ID and version
+    header.extend_from_slice(&[0, 0, 0, 0]);
+    // BLF version
+    header.extend_from_slice(&[2, 6, 8, 1]);
+    header.extend_from_slice(&(file_size as u64).to_le_bytes());
+    // Containers are not compressed, so the uncompressed size is the file size
+    header.extend_from_slice(&(file_size as u64).to_le_bytes());
+    header.extend_from_slice(&(frames.len() as u32).to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&blf_system_time(start));
+    header.extend_from_slice(&blf_system_time(end));
+    header.resize(BLF_FILE_HEADER_SIZE, 0);
+
+    out.write_all(&header)?;
+    for c in containers {
+        out.write_all(&c)?;
+    }
+    Ok(())
+}
+
+/// Base header of a BLF object
+fn blf_obj_header(header_size: usize, version: u16, object_size: usize, obj_type: u32) -> Vec<u8> {
+    let mut res = Vec::with_capacity(BLF_OBJ_HEADER_BASE_SIZE);
+    res.extend_from_slice(b"LOBJ");
+    res.extend_from_slice(&(header_size as u16).to_le_bytes());
+    res.extend_from_slice(&version.to_le_bytes());
+    res.extend_from_slice(&(object_size as u32).to_le_bytes());
+    res.extend_from_slice(&obj_type.to_le_bytes());
+    res
+}
+
+/// Writes frames as CAN message objects, inside an uncompressed log container
+fn blf_container(frames: &[TraceFrame]) -> Vec<u8> {
+    let data_size = frames.len() * BLF_CAN_MESSAGE_SIZE;
+    let mut res = blf_obj_header(
+        BLF_OBJ_HEADER_BASE_SIZE,
+        1,
+        BLF_CONTAINER_HEADER_SIZE + data_size,
+        BLF_OBJ_LOG_CONTAINER,
+    );
+    // Compression method (None) and reserved bytes
+    res.extend_from_slice(&[0; 8]);
+    res.extend_from_slice(&(data_size as u32).to_le_bytes());
+    res.extend_from_slice(&[0; 4]);
+    for f in frames {
+        res.extend_from_slice(&blf_obj_header(
+            BLF_OBJ_HEADER_BASE_SIZE + 16,
+            1,
+            BLF_CAN_MESSAGE_SIZE,
+            BLF_OBJ_CAN_MESSAGE,
+        ));
+        res.extend_from_slice(&BLF_TIME_ONE_NANS.to_le_bytes());
+        // Client index and object version
+        res.extend_from_slice(&[0; 4]);
+        res.extend_from_slice(&((f.timestamp_us * 1000) as u64).to_le_bytes());
+        // Channel 1, received
+        res.extend_from_slice(&1u16.to_le_bytes());
+        res.push(0);
+        res.push(f.frame.dlc);
+        let id = if f.frame.id > MAX_STD_ID {
+            f.frame.id | BLF_EXT_ID_FLAG
+        } else {
+            f.frame.id
+        };
+        res.extend_from_slice(&id.to_le_bytes());
+        let mut data = [0u8; 8];
+        data[..f.frame.get_data().len()].copy_from_slice(f.frame.get_data());
+        res.extend_from_slice(&data);
+    }
+    res
+}
+
+/// Encodes a time as a Windows SYSTEMTIME, which BLF uses for the start and end of a capture
+fn blf_system_time(t: DateTime<Local>) -> [u8; 16] {
+    let fields = [
+        t.year() as u16,
+        t.month() as u16,
+        t.weekday().num_days_from_sunday() as u16,
+        t.day() as u16,
+        t.hour() as u16,
+        t.minute() as u16,
+        t.second() as u16,
+        (t.nanosecond() / 1_000_000).min(999) as u16,
+    ];
+    let mut res = [0u8; 16];
+    for (i, f) in fields.iter().enumerate() {
+        res[i * 2..i * 2 + 2].copy_from_slice(&f.to_le_bytes());
+    }
+    res
+}
+
+#[cfg(test)]
+mod can_trace_test {
+    use super::*;
+
+    fn frames() -> Vec<TraceFrame> {
+        vec![
+            TraceFrame {
+                timestamp_us: 1_234,
+                frame: CanFrame::new(0x7E8, &[0x02, 0x7E, 0x00]),
+            },
+            TraceFrame {
+                timestamp_us: 12_500_000,
+                frame: CanFrame::new(
+                    0x18DAF110,
+                    &[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08],
+                ),
+            },
+        ]
+    }
+
+    #[test]
+    fn asc_export() {
+        let f = frames();
+        assert_eq!(
+            asc_line(&f[0]),
+            "   0.001234 1  7E8             Rx   d 3 02 7E 00"
+        );
+        assert_eq!(
+            asc_line(&f[1]),
+            "  12.500000 1  18DAF110x       Rx   d 8 01 02 03 04 05 06 07 08"
+        );
+        let mut out = Vec::new();
+        export_trace(&mut out, TraceFormat::Asc, &f, Local::now()).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("date "));
+        assert!(text.trim_end().ends_with("End TriggerBlock"));
+        assert_eq!(text.lines().count(), 8);
+    }
+
+    #[test]
+    fn blf_export() {
+        let f = frames();
+        let mut out = Vec::new();
+        export_trace(&mut out, TraceFormat::Blf, &f, Local::now()).unwrap();
+        let expected_size =
+            BLF_FILE_HEADER_SIZE + BLF_CONTAINER_HEADER_SIZE + 2 * BLF_CAN_MESSAGE_SIZE;
+        assert_eq!(out.len(), expected_size);
+        assert_eq!(&out[0..4], b"LOGG");
+        assert_eq!(
+            u64::from_le_bytes([
+                out[16], out[17], out[18], out[19], out[20], out[21], out[22], out[23]
+            ]),
+            expected_size as u64
+        );
+        assert_eq!(&out[144..148], b"LOBJ");
+        // Second CAN message in the container
+        let msg = &out[BLF_FILE_HEADER_SIZE + BLF_CONTAINER_HEADER_SIZE + BLF_CAN_MESSAGE_SIZE..];
+        assert_eq!(&msg[0..4], b"LOBJ");
+        assert_eq!(
+            u64::from_le_bytes([
+                msg[24], msg[25], msg[26], msg[27], msg[28], msg[29], msg[30], msg[31]
+            ]),
+            12_500_000_000
+        );
+        assert_eq!(msg[35], 8);
+        assert_eq!(
+            u32::from_le_bytes([msg[36], msg[37], msg[38], msg[39]]),
+            0x98DAF110
+        );
+    }
+
+    #[test]
+    fn asc_import() {
+        let trace = "date Thu Oct 16 10:00:00.000 am 2026
+base hex  timestamps absolute
+internal events logged
+// version 9.0.0
+Begin Triggerblock Thu Oct 16 10:00:00.000 am 2026
+   0.000000 Start of measurement
+   0.001234 1  7E8             Rx   d 3 02 7E 00
+   0.002000 1  ErrorFrame
+   0.003000 1  100             Rx   r
+   0.004000 1  7E0             Tx   d 8 02 3E 00 00 00 00 00
+   0.005000 1  18DAF110x       Rx   d 2 50 03
+   0.006000 1  ZZZ             Rx   d 1 00
+End TriggerBlock
+";
+        let res = import_asc(trace);
+        assert_eq!(res.frames.len(), 2);
+        assert_eq!(res.frames[0].timestamp_us, 1234);
+        assert_eq!(res.frames[0].frame.get_data(), &[0x02, 0x7E, 0x00]);
+        assert_eq!(res.frames[1].frame.id, 0x18DAF110);
+        assert_eq!(res.skipped, 3);
+        // Data frame with too few bytes, and an ID that is not hex
+        assert_eq!(res.malformed, vec![10, 12]);
+    }
+
+    #[test]
+    fn asc_round_trip() {
+        let mut out = Vec::new();
+        export_trace(&mut out, TraceFormat::Asc, &frames(), Local::now()).unwrap();
+        let res = import_asc(&String::from_utf8(out).unwrap());
+        assert!(res.malformed.is_empty());
+        assert_eq!(res.frames.len(), 2);
+        assert_eq!(res.frames[1].timestamp_us, 12_500_000);
+        assert_eq!(res.frames[1].frame.id, 0x18DAF110);
+        assert_eq!(res.frames[1].frame.get_data(), frames()[1].frame.get_data());
+    }
+
+    #[test]
+    fn asc_import_decimal_relative() {
+        let trace = "base dec  timestamps relative
+   0.500000 1  2024            Rx   d 2 16 255
+   0.250000 1  2024            Rx   d 1 1
+";
+        let res = import_asc(trace);
+        assert!(res.malformed.is_empty());
+        assert_eq!(res.frames[0].frame.id, 0x7E8);
+        assert_eq!(res.frames[0].frame.get_data(), &[0x10, 0xFF]);
+        assert_eq!(res.frames[1].timestamp_us, 750_000);
+    }
+}
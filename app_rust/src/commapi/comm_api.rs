@@ -1,6 +1,8 @@
 use std::cmp::min;
+use std::collections::{BTreeMap, HashSet};
 use std::fmt;
 use std::fmt::Debug;
+use std::sync::{Arc, RwLock};
 use std::time::Instant;
 use std::{fmt::Formatter, result::Result};
 
@@ -9,6 +11,8 @@ pub struct CanFrame {
     pub id: u32,
     pub dlc: u8,
     data: [u8; 8],
+    /// Timestamp the adapter received the frame at in microseconds, if the adapter provides one
+    hw_timestamp_us: Option<u32>,
 }
 
 impl CanFrame {
@@ -23,8 +27,21 @@ impl CanFrame {
             id,
             dlc: dlc as u8,
             data: can_data,
+            hw_timestamp_us: None,
         }
     }
+
+    /// Sets the hardware timestamp (In microseconds) of the frame provided by the adapter
+    pub fn with_hw_timestamp(mut self, timestamp_us: u32) -> Self {
+        self.hw_timestamp_us = Some(timestamp_us);
+        self
+    }
+
+    /// Returns the hardware timestamp of the frame in microseconds.
+    /// None if the adapter does not timestamp frames
+    pub fn get_hw_timestamp_us(&self) -> Option<u32> {
+        self.hw_timestamp_us
+    }
 }
 
 impl From<CanFrame> for socketcan::CANFrame {
@@ -40,6 +57,7 @@ impl From<socketcan::CANFrame> for CanFrame {
             id: s.id(),
             dlc: data.len() as u8,
             data: [0, 0, 0, 0, 0, 0, 0, 0],
+            hw_timestamp_us: None,
         };
         for x in 0..data.len() {
             res.data[x] = data[x];
@@ -89,10 +107,99 @@ pub struct ISO15765Config {
     pub recv_id: u32,
     pub block_size: u32,
     pub sep_time: u32,
+    /// If true, block size and separation time are defaults rather than values
+    /// from the user, and can be adjusted if the ECU's responses get lost
+    pub auto_fc: bool,
+    /// CAN FD settings, if the ECU should be talked to using CAN FD rather than classic CAN
+    pub can_fd: Option<CanFdConfig>,
+    /// Byte to pad frames sent to the ECU with, for ECUs that ignore frames shorter than 8 bytes.
+    /// None sends frames unpadded. Passthru adapters pad with the byte their driver uses
+    pub padding: Option<u8>,
+    /// Address of the ECU, sent as the first byte of every frame (Extended ISO-TP addressing).
+    /// None for normal addressing
+    pub ext_addr: Option<u8>,
 }
 unsafe impl Send for ISO15765Config {}
 unsafe impl Sync for ISO15765Config {}
 
+/// Channel settings for CAN FD
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CanFdConfig {
+    /// Speed of the data phase in bps, used when bit rate switching is enabled
+    pub data_bitrate: u32,
+    /// Bit rate switch (BRS). Send the data phase of frames at the data bitrate
+    pub bit_rate_switch: bool,
+}
+
+impl Default for CanFdConfig {
+    fn default() -> Self {
+        Self {
+            data_bitrate: 2_000_000,
+            bit_rate_switch: true,
+        }
+    }
+}
+
+impl ISO15765Config {
+    /// Block size used when no flow control parameters are specified
+    pub const AUTO_BLOCK_SIZE: u32 = 8;
+    /// Separation time used when no flow control parameters are specified
+    pub const AUTO_SEP_TIME: u32 = 20;
+    /// Largest separation time allowed in milliseconds (ISO15765-2)
+    const MAX_SEP_TIME: u32 = 127;
+
+    /// Creates a config that automatically works out the flow control
+    /// parameters to send to the ECU. Only quirky ECUs should need their
+    /// block size and separation time to be set manually
+    pub fn new_auto_fc(send_id: u32, recv_id: u32) -> Self {
+        Self {
+            send_id,
+            recv_id,
+            block_size: Self::AUTO_BLOCK_SIZE,
+            sep_time: Self::AUTO_SEP_TIME,
+            auto_fc: true,
+            can_fd: None,
+            padding: None,
+            ext_addr: None,
+        }
+    }
+
+    /// Builds a payload to send to `id`, padded and addressed as the ECU needs
+    pub fn to_iso15765_data(&self, id: u32, data: &[u8]) -> ISO15765Data {
+        let mut payload = Vec::with_capacity(data.len() + 1);
+        payload.extend(self.ext_addr);
+        payload.extend_from_slice(data);
+        ISO15765Data {
+            id,
+            data: payload,
+            pad_frame: self.padding.is_some(),
+            ext_addressing: self.ext_addr.is_some(),
+        }
+    }
+
+    /// Removes the address byte from the start of a payload received with extended addressing
+    pub fn strip_ext_addr(&self, mut data: Vec<u8>) -> Vec<u8> {
+        if self.ext_addr.is_some() && !data.is_empty() {
+            data.remove(0);
+        }
+        data
+    }
+
+    /// Makes the flow control parameters more conservative after the ECU's multi-frame
+    /// response was lost, so the ECU sends its consecutive frames slower and in smaller blocks.
+    ///
+    /// ## Returns
+    /// False if the parameters cannot be relaxed any further, or were set manually
+    pub fn relax_flow_control(&mut self) -> bool {
+        if !self.auto_fc || (self.sep_time >= Self::MAX_SEP_TIME && self.block_size == 1) {
+            return false;
+        }
+        self.sep_time = std::cmp::min(std::cmp::max(self.sep_time * 2, 1), Self::MAX_SEP_TIME);
+        self.block_size = std::cmp::max(self.block_size / 2, 1);
+        true
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum FilterType {
     Pass,
@@ -105,6 +212,103 @@ pub struct ComServerError {
     pub err_desc: String,
 }
 
+/// Error code given when an adapter has no free message filters on a channel.
+/// Same value as SAE J2534's ERR_EXCEEDED_LIMIT
+pub const ERR_FILTER_LIMIT: u32 = 0x0C;
+
+/// Error code given when an ISO-TP message is sent to an ID that is not the flow control ID
+/// of any filter. Same value as SAE J2534's ERR_NO_FLOW_CONTROL
+pub const ERR_NO_FLOW_CONTROL: u32 = 0x17;
+
+/// CAN bitrates commonly used by vehicles, tried when looking for traffic at another bitrate
+pub const COMMON_CAN_BITRATES: [u32; 4] = [500_000, 250_000, 125_000, 1_000_000];
+
+/// Returns the CAN bitrate from the settings
+pub fn get_can_bitrate() -> u32 {
+    crate::settings::get_settings().can_bitrate
+}
+
+/// Looks for CAN traffic at the common bitrates other than the one in the settings, for when
+/// nothing responds. At each bitrate the bus is only listened to, for up to `listen_ms`, as
+/// sending at the wrong bitrate would disrupt the bus. Returns the first bitrate traffic was
+/// seen at. Buses that are silent until a request is sent are not found.
+///
+/// The adapter's CAN and ISO-TP interfaces must be closed when this is called
+pub fn find_active_bitrate(server: &mut dyn ComServer, listen_ms: u128) -> Option<u32> {
+    let current = get_can_bitrate();
+    COMMON_CAN_BITRATES
+        .iter()
+        .copied()
+        .filter(|b| *b != current)
+        .find(|b| {
+            if server.open_can_interface(*b, false).is_err() {
+                return false;
+            }
+            let mut seen = false;
+            if server.add_can_filter(FilterType::Pass, 0, 0).is_ok() {
+                let start = Instant::now();
+                while !seen && start.elapsed().as_millis() < listen_ms {
+                    seen = !server
+                        .read_can_packets(0, 100)
+                        .unwrap_or_default()
+                        .is_empty();
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+            }
+            if let Err(e) = server.close_can_interface() {
+                eprintln!("Bitrate detection - Could not close CAN interface: {}", e)
+            }
+            seen
+        })
+}
+
+/// Keeps track of the message filters active on a channel. Adapters only have a
+/// few hardware filters per channel, and most drivers give a generic failure once
+/// they run out, so this lets the limit be reported clearly instead
+#[derive(Debug, Clone, Default)]
+pub struct FilterCounter {
+    in_use: Arc<RwLock<HashSet<u32>>>,
+}
+
+impl FilterCounter {
+    /// Number of filters active on the channel
+    pub fn in_use(&self) -> usize {
+        self.in_use.read().unwrap().len()
+    }
+
+    /// Checks that another filter can be added to the channel
+    ///
+    /// ## Params
+    /// * max - Maximum number of filters the adapter supports per channel. None if unlimited
+    /// * channel - Name of the channel, for the error message
+    pub fn check_free(&self, max: Option<u32>, channel: &str) -> Result<(), ComServerError> {
+        match max {
+            Some(max) if self.in_use() >= max as usize => Err(ComServerError {
+                err_code: ERR_FILTER_LIMIT,
+                err_desc: format!(
+                    "All {} of the adapter's message filters on the {} channel are in use. \
+                    Close other channels or ECU connections to free some up",
+                    max, channel
+                ),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn added(&self, filter_idx: u32) {
+        self.in_use.write().unwrap().insert(filter_idx);
+    }
+
+    pub fn removed(&self, filter_idx: u32) {
+        self.in_use.write().unwrap().remove(&filter_idx);
+    }
+
+    /// Forgets all filters, for when the channel is closed (Which removes its filters)
+    pub fn clear(&self) {
+        self.in_use.write().unwrap().clear()
+    }
+}
+
 impl std::fmt::Display for ComServerError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Error code {} ({})", self.err_code, self.err_desc)
@@ -147,6 +351,8 @@ pub struct DeviceCapabilities {
     pub(crate) can: Capability,
     /// Supports ISO15765 (ISO-TP)
     pub(crate) iso15765: Capability,
+    /// Supports CAN FD (And ISO15765 over CAN FD)
+    pub(crate) can_fd: Capability,
     /// Supports K-Line OBD ISO9141
     pub(crate) iso9141: Capability,
     /// Supports K-Line KWP2000 ISO14230
@@ -155,6 +361,8 @@ pub struct DeviceCapabilities {
     pub(crate) ip: Capability,
     /// Supports reading the battery voltage
     pub(crate) battery_voltage: Capability,
+    /// Maximum number of message filters per channel. None if there is no limit
+    pub(crate) max_filters: Option<u32>,
 }
 
 impl DeviceCapabilities {
@@ -168,9 +376,12 @@ impl DeviceCapabilities {
         self.library_path.clone()
     }
 
-    pub fn support_can_fd(&self) -> Capability {
+    pub fn supports_can(&self) -> Capability {
         self.can
     }
+    pub fn supports_can_fd(&self) -> Capability {
+        self.can_fd
+    }
     pub fn supports_iso15765(&self) -> Capability {
         self.iso15765
     }
@@ -192,6 +403,11 @@ impl DeviceCapabilities {
         self.ip
     }
 
+    /// Maximum number of message filters that can be active on each channel. None if there is no limit
+    pub fn get_max_filters(&self) -> Option<u32> {
+        self.max_filters
+    }
+
     pub fn get_device_fw_version(&self) -> String {
         self.device_fw_version.clone()
     }
@@ -266,6 +482,9 @@ pub trait ComServer: Send + Sync + Debug {
     ///
     /// *NOTE*: You must set a filter prior to using this function, or no data will ever be read.
     ///
+    /// A message with an empty payload indicates the adapter has received the first frame of a
+    /// multi-frame message, and is receiving the rest of it. Not all adapters report this.
+    ///
     /// ## Params
     /// * timeout_ms - Timeout for waiting for data from the vehicle. A value of 0 tells the adapter
     /// to return whatever data it has in its Rx queue, and don't wait for any more
@@ -305,6 +524,40 @@ pub trait ComServer: Send + Sync + Debug {
         ext_addressing: bool,
     ) -> Result<(), ComServerError>;
 
+    /// Attempts to create an ISO-TP Interface on the adapter that runs over CAN FD.
+    /// Only adapters that report [CAN FD support](fn@DeviceCapabilities::supports_can_fd)
+    /// implement this.
+    ///
+    /// ## Params
+    /// * `bus_speed` - Speed of the arbitration phase in bps
+    /// * `fd` - Data phase settings
+    /// * `is_ext_can` - Tells the adapter to use extended CAN Addressing (29bit CAN ID)
+    /// * `ext_addressing` - Tells the adapter to use extended ISO-TP addressing
+    fn open_iso15765_fd_interface(
+        &mut self,
+        _bus_speed: u32,
+        _fd: CanFdConfig,
+        _is_ext_can: bool,
+        _ext_addressing: bool,
+    ) -> Result<(), ComServerError> {
+        Err(ComServerError {
+            err_code: 99,
+            err_desc: format!("CAN FD is not supported by {}", self.get_api()),
+        })
+    }
+
+    /// Opens the ISO-TP Interface on the adapter at the bitrate in the settings, using CAN FD
+    /// if the ISO-TP config asks for it
+    fn open_iso15765_interface_for(&mut self, cfg: &ISO15765Config) -> Result<(), ComServerError> {
+        let ext_addressing = cfg.ext_addr.is_some();
+        match cfg.can_fd {
+            Some(fd) => {
+                self.open_iso15765_fd_interface(get_can_bitrate(), fd, false, ext_addressing)
+            }
+            None => self.open_iso15765_interface(get_can_bitrate(), false, ext_addressing),
+        }
+    }
+
     /// Attempts to destroy the ISO-TP Interface on the adapter
     fn close_iso15765_interface(&mut self) -> Result<(), ComServerError>;
 
@@ -343,6 +596,17 @@ pub trait ComServer: Send + Sync + Debug {
             })
     }
 
+    /// Lets functionally addressed (broadcast) requests be sent to `functional_id` on an open
+    /// ISO15765 channel. Adapters refuse to send to an ID that is not the flow control ID of a
+    /// filter, so a filter is added with `functional_id` as both its pattern and flow control ID.
+    /// Functional requests are single frames, so no ECU sends flow control back to it
+    ///
+    /// ## Returns
+    /// The filter ID provided by the adapter. Use this when destroying the filter
+    fn add_functional_filter(&self, functional_id: u32) -> Result<u32, ComServerError> {
+        self.add_iso15765_filter(functional_id, 0xFFFF, functional_id)
+    }
+
     /// Tells the adapter to remove an active filter on an open ISO15765 channel
     /// # Params
     /// * filter_idx - Filter ID to remove, this should be the value given by [`add_iso15765_filter`](fn@add_iso15765_filter)
@@ -371,15 +635,42 @@ pub trait ComServer: Send + Sync + Debug {
         p: ISO15765Data,
         max_timeout_ms: u128,
         max_resp: usize,
+    ) -> Result<Vec<ISO15765Data>, ComServerError> {
+        self.send_receive_iso15765_mf(p, max_timeout_ms, max_timeout_ms, max_resp)
+    }
+
+    /// Sends an ISOTP payload and attempts to read the ECUs response, with a separate
+    /// timeout for multi-frame responses.
+    ///
+    /// IMPORTANT - This function assumes the ISO15765 interface is ALREADY open
+    ///
+    /// # Params
+    /// * p - Payload to send
+    /// * first_timeout_ms - Time to wait for the ECU to start responding
+    /// * mf_timeout_ms - Time to wait for the rest of a multi-frame response, once its first
+    ///                   frame has been received. Only used by adapters that report first frames
+    /// * max_resp - Number of responses to wait for. 0 waits for the full timeout
+    fn send_receive_iso15765_mf(
+        &self,
+        p: ISO15765Data,
+        first_timeout_ms: u128,
+        mf_timeout_ms: u128,
+        max_resp: usize,
     ) -> Result<Vec<ISO15765Data>, ComServerError> {
         self.clear_iso15765_rx_buffer()?; // Clear the receive buffer
         self.send_iso15765_data(&[p], 0)?; // Send data
-        let mut timeout = max_timeout_ms;
+        let mut timeout = first_timeout_ms;
         let mut payloads: Vec<ISO15765Data> = Vec::new();
-        let start = Instant::now();
+        let mut start = Instant::now();
         while start.elapsed().as_millis() < timeout {
             if let Ok(d) = self.read_iso15765_packets(0, 10) {
                 for msg in d {
+                    if msg.data.is_empty() {
+                        // First frame indication. Allow the ECU time to send the rest of it
+                        start = Instant::now();
+                        timeout = mf_timeout_ms;
+                        continue;
+                    }
                     payloads.push(msg);
                     if max_resp != 0 && payloads.len() >= max_resp {
                         timeout = 0; // Return now!
@@ -391,6 +682,48 @@ pub trait ComServer: Send + Sync + Debug {
         Ok(payloads)
     }
 
+    /// Sends an ISO15765 payload to a functional (broadcast) ID, and collects
+    /// every response received within the time window, keyed by the CAN ID of the responding ECU.
+    ///
+    /// An ECU that responds with 'Response pending' (NRC 0x78), or starts a multi-frame response,
+    /// is waited for until `window_ms` after it last did so, even if that is after the time window.
+    /// Its final response is kept. If an ECU responds more than once, only its first final
+    /// response is kept
+    ///
+    /// # Params
+    /// * p - Payload to send to the functional ID
+    /// * window_ms - Time in milliseconds to wait for responses
+    fn send_receive_iso15765_functional(
+        &self,
+        p: ISO15765Data,
+        window_ms: u128,
+    ) -> Result<BTreeMap<u32, Vec<u8>>, ComServerError> {
+        self.clear_iso15765_rx_buffer()?;
+        self.send_iso15765_data(&[p], 0)?;
+        let mut res = BTreeMap::new();
+        // ECUs still working on their response, and when they last said so
+        let mut pending: BTreeMap<u32, Instant> = BTreeMap::new();
+        let start = Instant::now();
+        while start.elapsed().as_millis() < window_ms
+            || pending
+                .values()
+                .any(|t| t.elapsed().as_millis() < window_ms)
+        {
+            for msg in self.read_iso15765_packets(0, 10).unwrap_or_default() {
+                let busy = msg.data.is_empty() // First frame indication
+                    || (msg.data.len() >= 3 && msg.data[0] == 0x7F && msg.data[2] == 0x78);
+                if busy {
+                    pending.insert(msg.id, Instant::now());
+                } else {
+                    pending.remove(&msg.id);
+                    res.entry(msg.id).or_insert(msg.data);
+                }
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        Ok(res)
+    }
+
     /// Tells the adapter to clear any data in its Rx buffer
     /// that is from CAN protocol
     fn clear_can_rx_buffer(&self) -> Result<(), ComServerError>;
@@ -429,3 +762,57 @@ impl Clone for Box<dyn ComServer> {
         self.clone_box()
     }
 }
+
+#[cfg(test)]
+mod comm_api_test {
+    use super::{ComServer, ISO15765Config, ISO15765Data};
+    use crate::commapi::mock_api::MockComServer;
+
+    fn resp(id: u32, data: &[u8]) -> ISO15765Data {
+        ISO15765Data {
+            id,
+            data: data.to_vec(),
+            pad_frame: false,
+            ext_addressing: false,
+        }
+    }
+
+    #[test]
+    fn functional_waits_past_response_pending() {
+        let mut server = MockComServer::new();
+        server.open_device().unwrap();
+        server
+            .open_iso15765_interface(500_000, false, false)
+            .unwrap();
+        server.add_functional_filter(0x07DF).unwrap();
+        // Engine ECU (0x7E8) asks for more time before answering, the gearbox answers straight away
+        server.script_iso15765_responses(&[Some(vec![0x7F, 0x01, 0x78])]);
+        server.queue_iso15765_responses(&[
+            resp(0x7E9, &[0x41, 0x00, 0x01]),
+            resp(0x7E8, &[0x7F, 0x01, 0x78]),
+            resp(0x7E8, &[0x41, 0x00, 0x02]),
+        ]);
+        let res = server
+            .send_receive_iso15765_functional(resp(0x07DF, &[0x01, 0x00]), 50)
+            .unwrap();
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[&0x7E8], vec![0x41, 0x00, 0x02]);
+        assert_eq!(res[&0x7E9], vec![0x41, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn config_pads_and_addresses_payload() {
+        let mut cfg = ISO15765Config::new_auto_fc(0x7E0, 0x7E8);
+        let data = cfg.to_iso15765_data(0x7E0, &[0x3E, 0x01]);
+        assert_eq!(data.data, vec![0x3E, 0x01]);
+        assert!(!data.pad_frame && !data.ext_addressing);
+        assert_eq!(cfg.strip_ext_addr(vec![0x7E, 0x01]), vec![0x7E, 0x01]);
+
+        cfg.padding = Some(0x55);
+        cfg.ext_addr = Some(0x40);
+        let data = cfg.to_iso15765_data(0x7E0, &[0x3E, 0x01]);
+        assert_eq!(data.data, vec![0x40, 0x3E, 0x01]);
+        assert!(data.pad_frame && data.ext_addressing);
+        assert_eq!(cfg.strip_ext_addr(vec![0xF1, 0x7E, 0x01]), vec![0x7E, 0x01]);
+    }
+}
@@ -0,0 +1,1000 @@
+// Decoding of raw ISO-TP (ISO 15765-2) CAN frames. Used when OVD has to look at
+// ISO-TP traffic itself, rather than letting the adapter handle it (EG: ECU scanning).
+//
+// Not all ECUs pad their frames to 8 bytes, so the length of a frame is always taken
+// from its PCI byte(s), and never assumed from the CAN DLC.
+
+use std::time::{Duration, Instant};
+
+use super::comm_api::{CanFrame, ComServer};
+
+/// Largest single frame payload on classic CAN (DLC 8)
+const MAX_CLASSIC_SF_LEN: usize = 7;
+
+/// Largest single frame payload on CAN FD (DLC 15, 64 bytes, minus the 2 PCI bytes)
+const MAX_FD_SF_LEN: usize = 62;
+
+/// Largest message length a first frame can hold without the escape sequence
+const MAX_FF_LEN: usize = 0xFFF;
+
+/// Byte unused bytes of a frame are padded with
+const PAD_BYTE: u8 = 0xCC;
+
+/// Frame lengths CAN FD supports. Frames with any other length must be padded
+/// up to the next one
+const CAN_FD_FRAME_LENGTHS: [usize; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 12, 16, 20, 24, 32, 48, 64];
+
+/// Returns the length of CAN FD frame needed to carry `len` bytes.
+/// None if `len` is too large for a CAN FD frame
+pub fn can_fd_frame_len(len: usize) -> Option<usize> {
+    CAN_FD_FRAME_LENGTHS.iter().copied().find(|l| *l >= len)
+}
+
+/// Encodes a payload as a single frame. Payloads too large for a single frame return None.
+///
+/// On classic CAN, the frame is padded with 0xCC to 8 bytes, as many ECUs ignore shorter
+/// frames. On CAN FD, payloads over 7 bytes use the escape sequence, and the frame is padded
+/// with 0xCC up to a valid CAN FD frame length.
+pub fn encode_single_frame(data: &[u8], fd: bool) -> Option<Vec<u8>> {
+    let mut frame = if data.is_empty() {
+        return None;
+    } else if data.len() <= MAX_CLASSIC_SF_LEN {
+        vec![data.len() as u8]
+    } else if fd && data.len() <= MAX_FD_SF_LEN {
+        vec![0x00, data.len() as u8]
+    } else {
+        return None;
+    };
+    frame.extend_from_slice(data);
+    let frame_len = if fd {
+        can_fd_frame_len(frame.len())?
+    } else {
+        8
+    };
+    frame.resize(frame_len, PAD_BYTE);
+    Some(frame)
+}
+
+/// A frame in a preview of how a message is sent on the bus
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreviewFrame {
+    pub id: u32,
+    /// Bytes of the frame. Empty for frames the ECU sends, as their contents are up to the ECU
+    pub data: Vec<u8>,
+    /// What the frame is for
+    pub desc: String,
+}
+
+impl std::fmt::Display for PreviewFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let id = if self.id > 0x7FF {
+            format!("{:08X}", self.id)
+        } else {
+            format!("{:03X}", self.id)
+        };
+        if self.data.is_empty() {
+            write!(f, "{} <- {}", id, self.desc)
+        } else {
+            write!(f, "{} -> {:02X?} {}", id, self.data, self.desc)
+        }
+    }
+}
+
+/// Works out the frames a message is sent as, without sending anything. Frames are shown
+/// padded like OVD pads the frames it sends itself.
+///
+/// A multi-frame message has to wait for the ECU's flow control after the first frame.
+/// The ECU's flow control sets the block size and separation time of the consecutive
+/// frames, so these are not known until the message is actually sent
+///
+/// ## Params
+/// * data - Message to send, starting with the service ID
+/// * send_id - CAN ID the message is sent to
+/// * recv_id - CAN ID the ECU replies with
+/// * fd - Send using CAN FD
+pub fn preview_frames(data: &[u8], send_id: u32, recv_id: u32, fd: bool) -> Vec<PreviewFrame> {
+    let frame = |data: Vec<u8>, desc: String| PreviewFrame {
+        id: send_id,
+        data,
+        desc,
+    };
+    if let Some(sf) = encode_single_frame(data, fd) {
+        return vec![frame(sf, format!("Single frame, {} bytes", data.len()))];
+    }
+    let (first, consecutive) = encode_multi_frame(data, fd);
+    let mut frames = vec![
+        frame(first, format!("First frame, {} bytes in total", data.len())),
+        PreviewFrame {
+            id: recv_id,
+            data: Vec::new(),
+            desc: "Flow control from the ECU (30 BS STmin). Its block size sets how many \
+                consecutive frames are sent before waiting for the next one"
+                .into(),
+        },
+    ];
+    for cf in consecutive {
+        let desc = format!("Consecutive frame {}", cf[0] & 0x0F);
+        frames.push(frame(cf, desc));
+    }
+    frames
+}
+
+/// Splits a message too large for a single frame into its first frame and consecutive frames
+fn encode_multi_frame(data: &[u8], fd: bool) -> (Vec<u8>, Vec<Vec<u8>>) {
+    let frame_len = if fd { 64 } else { 8 };
+    let mut first = if data.len() <= MAX_FF_LEN {
+        vec![0x10 | (data.len() >> 8) as u8, data.len() as u8]
+    } else {
+        let mut f = vec![0x10, 0x00];
+        f.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        f
+    };
+    let first_data = (frame_len - first.len()).min(data.len());
+    first.extend_from_slice(&data[..first_data]);
+    let consecutive = data[first_data..]
+        .chunks(frame_len - 1)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let seq = ((i + 1) & 0x0F) as u8;
+            let mut cf = vec![0x20 | seq];
+            cf.extend_from_slice(chunk);
+            let cf_len = if fd {
+                can_fd_frame_len(cf.len()).unwrap_or(frame_len)
+            } else {
+                frame_len
+            };
+            cf.resize(cf_len, PAD_BYTE);
+            cf
+        })
+        .collect();
+    (first, consecutive)
+}
+
+/// Converts the STmin byte of a flow control frame to microseconds. 0x00-0x7F are
+/// milliseconds, 0xF1-0xF9 are 100-900 microseconds. Reserved values must be treated
+/// as the largest separation time (127ms)
+pub fn sep_time_micros(st_min: u8) -> u32 {
+    match st_min {
+        0x00..=0x7F => st_min as u32 * 1000,
+        0xF1..=0xF9 => (st_min - 0xF0) as u32 * 100,
+        _ => 127_000,
+    }
+}
+
+/// What the sender of a multi-frame message does after receiving a flow control frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendStep {
+    /// Send these consecutive frames, `sep_time_us` apart. If `await_fc` is set, the
+    /// receiver's next flow control has to be waited for once the block is sent
+    Block {
+        frames: Vec<Vec<u8>>,
+        sep_time_us: u32,
+        await_fc: bool,
+    },
+    /// The receiver is not ready yet, and will send another flow control
+    Wait,
+    /// The receiver cannot take the message, so it must not be sent
+    Overflow,
+}
+
+/// Keeps track of which consecutive frames of a multi-frame message are still to be sent,
+/// for when OVD has to do the ISO-TP segmentation itself rather than the adapter.
+///
+/// A block size of 0 in the flow control means every remaining consecutive frame is sent
+/// without waiting for another flow control. Any other block size means that many frames
+/// are sent, then the sender waits for the next flow control
+#[derive(Debug, Clone)]
+pub struct MultiFrameSender {
+    consecutive: Vec<Vec<u8>>,
+    sent: usize,
+}
+
+impl MultiFrameSender {
+    /// Creates a sender for a message. Returns the first frame to send with it, or None if
+    /// the message fits in a single frame
+    pub fn new(data: &[u8], fd: bool) -> Option<(Vec<u8>, Self)> {
+        if data.is_empty() || encode_single_frame(data, fd).is_some() {
+            return None;
+        }
+        let (first, consecutive) = encode_multi_frame(data, fd);
+        Some((
+            first,
+            Self {
+                consecutive,
+                sent: 0,
+            },
+        ))
+    }
+
+    /// Returns true once every consecutive frame has been sent
+    pub fn is_done(&self) -> bool {
+        self.sent >= self.consecutive.len()
+    }
+
+    /// Works out what to send after receiving a flow control frame. Returns None if
+    /// `frame` is not a flow control frame, or the message was already sent
+    pub fn on_flow_control(&mut self, frame: &[u8]) -> Option<SendStep> {
+        if self.is_done() {
+            return None;
+        }
+        let (status, block_size, sep_time) = match decode_frame(frame)? {
+            IsoTpFrame::FlowControl {
+                status,
+                block_size,
+                sep_time,
+            } => (status, block_size, sep_time),
+            _ => return None,
+        };
+        match status {
+            0 => {
+                let remaining = self.consecutive.len() - self.sent;
+                let count = if block_size == 0 {
+                    remaining
+                } else {
+                    (block_size as usize).min(remaining)
+                };
+                let frames = self.consecutive[self.sent..self.sent + count].to_vec();
+                self.sent += count;
+                Some(SendStep::Block {
+                    frames,
+                    sep_time_us: sep_time_micros(sep_time),
+                    await_fc: !self.is_done(),
+                })
+            }
+            1 => Some(SendStep::Wait),
+            _ => Some(SendStep::Overflow),
+        }
+    }
+}
+
+/// Default time to wait for the receiver's flow control (N_Bs), or for the sender's
+/// next consecutive frame (N_Cr), in milliseconds
+pub const DEFAULT_TIMEOUT_MS: u128 = 1000;
+
+/// Default number of wait flow control frames in a row the sender accepts before giving up
+/// (N_WFTmax). Buffer limited ECUs can ask the sender to wait several times before they are
+/// ready for the rest of a message
+pub const DEFAULT_MAX_WAIT_FRAMES: usize = 10;
+
+/// Why a multi-frame message could not be sent or received
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IsoTpError {
+    /// No flow control or consecutive frame arrived in time
+    Timeout,
+    /// A consecutive frame arrived out of order, so the message is corrupt
+    WrongSequence { expected: u8, actual: u8 },
+    /// The receiver cannot take a message of this size
+    Overflow,
+    /// The receiver kept asking the sender to wait
+    TooManyWaits,
+    /// The message is too large to be sent
+    TooLarge,
+    /// The adapter could not send or receive frames
+    Transport(String),
+}
+
+impl std::fmt::Display for IsoTpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IsoTpError::Timeout => write!(f, "ISO-TP timeout"),
+            IsoTpError::WrongSequence { expected, actual } => write!(
+                f,
+                "Consecutive frame {:X} received, expected {:X}",
+                actual, expected
+            ),
+            IsoTpError::Overflow => write!(f, "Receiver overflow, message is too large"),
+            IsoTpError::TooManyWaits => write!(f, "Receiver sent too many wait frames"),
+            IsoTpError::TooLarge => write!(f, "Message is too large for ISO-TP"),
+            IsoTpError::Transport(e) => write!(f, "Adapter error: {}", e),
+        }
+    }
+}
+
+/// Reassembles a multi-frame message from its first frame and consecutive frames, for when
+/// OVD has to receive ISO-TP messages itself rather than the adapter
+#[derive(Debug, Clone)]
+pub struct MultiFrameReceiver {
+    total_len: usize,
+    data: Vec<u8>,
+    next_seq: u8,
+    /// Time the last frame of the message was received
+    last_frame: Instant,
+    timeout_ms: u128,
+}
+
+impl MultiFrameReceiver {
+    /// Starts receiving a message from its first frame, received at `now`. Returns None if
+    /// `frame` is not a first frame
+    pub fn new(frame: &[u8], now: Instant, timeout_ms: u128) -> Option<Self> {
+        match decode_frame(frame)? {
+            IsoTpFrame::First { total_len, data } => {
+                let total_len = total_len as usize;
+                Some(Self {
+                    total_len,
+                    data: data[..data.len().min(total_len)].to_vec(),
+                    next_seq: 1,
+                    last_frame: now,
+                    timeout_ms,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns true once the whole message has been received
+    pub fn is_done(&self) -> bool {
+        self.data.len() >= self.total_len
+    }
+
+    /// Handles a frame from the sender, received at `now`. Returns the message once its
+    /// last consecutive frame is received. Frames other than consecutive frames are ignored
+    pub fn on_frame(&mut self, frame: &[u8], now: Instant) -> Result<Option<Vec<u8>>, IsoTpError> {
+        if self.is_done() {
+            return Ok(None);
+        }
+        self.check_timeout(now)?;
+        let (seq, data) = match decode_frame(frame) {
+            Some(IsoTpFrame::Consecutive { seq, data }) => (seq, data),
+            _ => return Ok(None),
+        };
+        if seq != self.next_seq {
+            return Err(IsoTpError::WrongSequence {
+                expected: self.next_seq,
+                actual: seq,
+            });
+        }
+        self.next_seq = (self.next_seq + 1) & 0x0F;
+        self.last_frame = now;
+        // The last consecutive frame may be padded
+        let remaining = self.total_len - self.data.len();
+        self.data
+            .extend_from_slice(&data[..data.len().min(remaining)]);
+        Ok(if self.is_done() {
+            Some(self.data.clone())
+        } else {
+            None
+        })
+    }
+
+    /// Returns an error if the sender has taken too long to send the next consecutive frame
+    pub fn check_timeout(&self, now: Instant) -> Result<(), IsoTpError> {
+        if now.duration_since(self.last_frame).as_millis() > self.timeout_ms {
+            Err(IsoTpError::Timeout)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Sends a message as raw classic CAN frames, doing the ISO-TP segmentation in OVD rather than
+/// the adapter. The CAN interface must be open, and pass the receiver's flow control frames.
+///
+/// The receiver's flow control is waited for after the first frame and after each block,
+/// and consecutive frames are sent at least the receiver's separation time apart. If the
+/// receiver sends a wait flow control, nothing is sent until its next flow control, which
+/// gets the full timeout again
+///
+/// ## Params
+/// * server - Adapter with an open CAN interface
+/// * send_id - CAN ID to send the message to
+/// * recv_id - CAN ID the receiver sends flow control frames on
+/// * data - Message to send
+/// * timeout_ms - Time to wait for each flow control frame
+/// * max_waits - Wait flow control frames in a row to accept before giving up (N_WFTmax).
+/// 0 means the receiver is not allowed to ask the sender to wait at all
+pub fn send_message(
+    server: &dyn ComServer,
+    send_id: u32,
+    recv_id: u32,
+    data: &[u8],
+    timeout_ms: u128,
+    max_waits: usize,
+) -> Result<(), IsoTpError> {
+    let send = |frame: &[u8]| {
+        server
+            .send_can_packets(&[CanFrame::new(send_id, frame)], 0)
+            .map(|_| ())
+            .map_err(|e| IsoTpError::Transport(e.to_string()))
+    };
+    let (first, mut sender) = match MultiFrameSender::new(data, false) {
+        Some(x) => x,
+        None => return send(&encode_single_frame(data, false).ok_or(IsoTpError::TooLarge)?),
+    };
+    if data.len() > MAX_FF_LEN {
+        // Escaped first frames do not fit in a classic CAN frame
+        return Err(IsoTpError::TooLarge);
+    }
+    send(&first)?;
+    let mut waits = 0;
+    while !sender.is_done() {
+        match await_flow_control(server, recv_id, &mut sender, timeout_ms)? {
+            SendStep::Block {
+                frames,
+                sep_time_us,
+                ..
+            } => {
+                waits = 0;
+                for (i, frame) in frames.iter().enumerate() {
+                    if i > 0 {
+                        std::thread::sleep(Duration::from_micros(sep_time_us as u64));
+                    }
+                    send(frame)?;
+                }
+            }
+            SendStep::Wait => {
+                waits += 1;
+                if waits > max_waits {
+                    return Err(IsoTpError::TooManyWaits);
+                }
+            }
+            SendStep::Overflow => return Err(IsoTpError::Overflow),
+        }
+    }
+    Ok(())
+}
+
+/// Waits for the receiver's next flow control frame, returning what to send next
+fn await_flow_control(
+    server: &dyn ComServer,
+    recv_id: u32,
+    sender: &mut MultiFrameSender,
+    timeout_ms: u128,
+) -> Result<SendStep, IsoTpError> {
+    let start = Instant::now();
+    while start.elapsed().as_millis() <= timeout_ms {
+        // One frame at a time, so the flow control frames after this one are not lost
+        let frames = server
+            .read_can_packets(0, 1)
+            .map_err(|e| IsoTpError::Transport(e.to_string()))?;
+        for f in frames.iter().filter(|f| f.id == recv_id) {
+            if let Some(step) = sender.on_flow_control(f.get_data()) {
+                return Ok(step);
+            }
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    Err(IsoTpError::Timeout)
+}
+
+/// A decoded ISO-TP frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IsoTpFrame<'a> {
+    /// A complete message in a single frame
+    Single(&'a [u8]),
+    /// The start of a multi-frame message
+    First { total_len: u32, data: &'a [u8] },
+    /// Continuation of a multi-frame message
+    Consecutive { seq: u8, data: &'a [u8] },
+    /// Flow control frame sent by the receiver of a multi-frame message
+    FlowControl {
+        status: u8,
+        block_size: u8,
+        sep_time: u8,
+    },
+}
+
+/// Decodes a raw CAN frame payload as an ISO-TP frame. Returns None if the payload is not
+/// a valid ISO-TP frame.
+///
+/// Single frames are accepted with any DLC that fits their payload, and the CAN FD escape
+/// sequences (Single frame length byte of 0, First frame length of 0) are supported.
+pub fn decode_frame(frame: &[u8]) -> Option<IsoTpFrame> {
+    let pci = *frame.get(0)?;
+    match pci >> 4 {
+        0x0 => {
+            let len = (pci & 0x0F) as usize;
+            if len == 0 {
+                // CAN FD escape. Length is in the next byte
+                let len = *frame.get(1)? as usize;
+                if len <= MAX_CLASSIC_SF_LEN {
+                    return None;
+                }
+                frame.get(2..2 + len).map(IsoTpFrame::Single)
+            } else if len <= MAX_CLASSIC_SF_LEN {
+                frame.get(1..1 + len).map(IsoTpFrame::Single)
+            } else {
+                None
+            }
+        }
+        0x1 => {
+            let len = ((pci as u32 & 0x0F) << 8) | *frame.get(1)? as u32;
+            if len == 0 {
+                // Escape sequence for messages over 4095 bytes. Length is in the next 4 bytes
+                let b = frame.get(2..6)?;
+                Some(IsoTpFrame::First {
+                    total_len: u32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+                    data: &frame[6..],
+                })
+            } else {
+                Some(IsoTpFrame::First {
+                    total_len: len,
+                    data: &frame[2..],
+                })
+            }
+        }
+        0x2 => Some(IsoTpFrame::Consecutive {
+            seq: pci & 0x0F,
+            data: &frame[1..],
+        }),
+        0x3 => Some(IsoTpFrame::FlowControl {
+            status: pci & 0x0F,
+            block_size: *frame.get(1)?,
+            sep_time: *frame.get(2)?,
+        }),
+        _ => None,
+    }
+}
+
+/// Returns true if `id` is in a range used for diagnostics. This is the 11 bit 0x700-0x7FF range,
+/// or 29 bit normal fixed addressing (0x18DAxxxx)
+pub fn is_diag_id(id: u32) -> bool {
+    (0x700..=0x7FF).contains(&id) || id & 0x1FFF_0000 == 0x18DA_0000
+}
+
+/// Returns true if a raw CAN frame payload is the start of a response to service `sid`.
+/// Both positive and negative responses count, as either proves the ECU received the request
+pub fn is_response_to(frame: &[u8], sid: u8) -> bool {
+    let data = match decode_frame(frame) {
+        Some(IsoTpFrame::Single(d)) => d,
+        Some(IsoTpFrame::First { data, .. }) => data,
+        _ => return false,
+    };
+    match data {
+        [0x7F, s, ..] => *s == sid,
+        [b, ..] => *b == sid | 0x40,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod iso_tp_test {
+    use std::time::{Duration, Instant};
+
+    use super::{
+        can_fd_frame_len, decode_frame, encode_single_frame, is_diag_id, is_response_to,
+        preview_frames, send_message, sep_time_micros, IsoTpError, IsoTpFrame, MultiFrameReceiver,
+        MultiFrameSender, SendStep, DEFAULT_MAX_WAIT_FRAMES as WFT_MAX,
+    };
+    use crate::commapi::{
+        comm_api::{CanFrame, ComServer},
+        mock_api::MockComServer,
+    };
+
+    /// Simulated adapter with its CAN interface open
+    fn open_mock() -> MockComServer {
+        let mut server = MockComServer::new();
+        server.open_can_interface(500_000, false).unwrap();
+        server
+    }
+
+    /// Flow control frame from the ECU
+    fn fc(data: &[u8]) -> CanFrame {
+        CanFrame::new(0x7E8, data)
+    }
+
+    fn sent_data(server: &MockComServer) -> Vec<Vec<u8>> {
+        server
+            .take_sent_can_frames()
+            .iter()
+            .map(|(_, f)| f.get_data().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn short_single_frames() {
+        // Padded to 8 bytes
+        assert_eq!(
+            decode_frame(&[0x02, 0x50, 0x03, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA]),
+            Some(IsoTpFrame::Single(&[0x50, 0x03]))
+        );
+        // DLC equals the payload length
+        assert_eq!(
+            decode_frame(&[0x02, 0x50, 0x03]),
+            Some(IsoTpFrame::Single(&[0x50, 0x03]))
+        );
+        assert_eq!(
+            decode_frame(&[0x01, 0x7E]),
+            Some(IsoTpFrame::Single(&[0x7E]))
+        );
+        // DLC too short for the payload length
+        assert_eq!(decode_frame(&[0x03, 0x50, 0x03]), None);
+        assert_eq!(decode_frame(&[]), None);
+    }
+
+    #[test]
+    fn can_fd_escape() {
+        let mut frame = vec![0x00, 0x0A];
+        frame.extend_from_slice(&[0x62; 10]);
+        assert_eq!(decode_frame(&frame), Some(IsoTpFrame::Single(&[0x62; 10])));
+        // Escape must not be used for lengths that fit in a classic single frame
+        assert_eq!(decode_frame(&[0x00, 0x02, 0x50, 0x03]), None);
+        assert_eq!(
+            decode_frame(&[0x10, 0x00, 0x00, 0x00, 0x10, 0x00, 0x01, 0x02]),
+            Some(IsoTpFrame::First {
+                total_len: 0x1000,
+                data: &[0x01, 0x02]
+            })
+        );
+    }
+
+    #[test]
+    fn can_fd_framing() {
+        assert_eq!(can_fd_frame_len(8), Some(8));
+        assert_eq!(can_fd_frame_len(9), Some(12));
+        assert_eq!(can_fd_frame_len(33), Some(48));
+        assert_eq!(can_fd_frame_len(65), None);
+        // Classic CAN frames are padded to 8 bytes
+        assert_eq!(
+            encode_single_frame(&[0x3E, 0x00], false),
+            Some(vec![0x02, 0x3E, 0x00, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC])
+        );
+        assert_eq!(encode_single_frame(&[0x22; 8], false), None);
+        // CAN FD frames use the escape sequence and are padded to a valid length
+        let frame = encode_single_frame(&[0x22; 10], true).unwrap();
+        assert_eq!(frame.len(), 12);
+        assert_eq!(decode_frame(&frame), Some(IsoTpFrame::Single(&[0x22; 10])));
+        assert_eq!(encode_single_frame(&[0x22; 63], true), None);
+    }
+
+    #[test]
+    fn short_flow_control() {
+        let fc = Some(IsoTpFrame::FlowControl {
+            status: 0,
+            block_size: 8,
+            sep_time: 20,
+        });
+        assert_eq!(decode_frame(&[0x30, 0x08, 0x14]), fc);
+        assert_eq!(
+            decode_frame(&[0x30, 0x08, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00]),
+            fc
+        );
+        assert_eq!(decode_frame(&[0x30, 0x08]), None);
+    }
+
+    #[test]
+    fn response_detection() {
+        assert!(is_response_to(
+            &[0x02, 0x7E, 0x00, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA],
+            0x3E
+        ));
+        assert!(is_response_to(&[0x03, 0x7F, 0x3E, 0x12], 0x3E));
+        assert!(is_response_to(
+            &[0x10, 0x14, 0x62, 0xF1, 0x90, 0x57, 0x44, 0x42],
+            0x22
+        ));
+        // Negative response to another service
+        assert!(!is_response_to(&[0x03, 0x7F, 0x22, 0x31], 0x3E));
+        // Flow control and the request itself are not responses
+        assert!(!is_response_to(&[0x30, 0x08, 0x14], 0x3E));
+        assert!(!is_response_to(&[0x02, 0x3E, 0x00], 0x3E));
+
+        assert!(is_diag_id(0x7E8));
+        assert!(is_diag_id(0x18DAF110));
+        assert!(!is_diag_id(0x6FF));
+        assert!(!is_diag_id(0x18FEF100));
+    }
+
+    #[test]
+    fn frame_preview() {
+        let frames = preview_frames(&[0x3E, 0x00], 0x7E0, 0x7E8, false);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(
+            frames[0].data,
+            vec![0x02, 0x3E, 0x00, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC]
+        );
+
+        // 6 bytes in the first frame, then 7 in each consecutive frame
+        let msg: Vec<u8> = (0..20).collect();
+        let frames = preview_frames(&msg, 0x7E0, 0x7E8, false);
+        assert_eq!(frames.len(), 4);
+        assert_eq!(frames[0].data, vec![0x10, 0x14, 0, 1, 2, 3, 4, 5]);
+        assert_eq!(frames[1].id, 0x7E8);
+        assert!(frames[1].data.is_empty());
+        assert_eq!(frames[2].data, vec![0x21, 6, 7, 8, 9, 10, 11, 12]);
+        assert_eq!(frames[3].data, vec![0x22, 13, 14, 15, 16, 17, 18, 19]);
+
+        // Sequence number wraps back to 0 after F
+        let frames = preview_frames(&[0x36; 200], 0x7E0, 0x7E8, false);
+        assert_eq!(frames[16].data[0], 0x2F);
+        assert_eq!(frames[17].data[0], 0x20);
+
+        // CAN FD consecutive frames are padded to a valid length
+        let frames = preview_frames(&[0x36; 100], 0x7E0, 0x7E8, true);
+        assert_eq!(frames[0].data.len(), 64);
+        assert_eq!(frames[2].data.len(), 48);
+    }
+
+    #[test]
+    fn block_size_zero_sends_everything() {
+        let msg: Vec<u8> = (0..50).collect();
+        let (first, mut sender) = MultiFrameSender::new(&msg, false).unwrap();
+        assert_eq!(first[..2], [0x10, 50]);
+        // 6 bytes in the first frame, then 44 bytes in 7 consecutive frames
+        match sender.on_flow_control(&[0x30, 0x00, 0x0A]) {
+            Some(SendStep::Block {
+                frames,
+                sep_time_us,
+                await_fc,
+            }) => {
+                assert_eq!(frames.len(), 7);
+                assert_eq!(frames[6], vec![0x27, 48, 49, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC]);
+                assert_eq!(sep_time_us, 10_000);
+                assert!(!await_fc);
+            }
+            x => panic!("Unexpected step {:?}", x),
+        }
+        assert!(sender.is_done());
+        // Nothing left to send, even if the receiver sends another flow control
+        assert_eq!(sender.on_flow_control(&[0x30, 0x00, 0x00]), None);
+    }
+
+    #[test]
+    fn block_size_waits_for_flow_control() {
+        let msg: Vec<u8> = (0..50).collect();
+        let (_, mut sender) = MultiFrameSender::new(&msg, false).unwrap();
+        let block = |step: Option<SendStep>| match step {
+            Some(SendStep::Block {
+                frames, await_fc, ..
+            }) => (frames, await_fc),
+            x => panic!("Unexpected step {:?}", x),
+        };
+        let (frames, await_fc) = block(sender.on_flow_control(&[0x30, 0x03, 0xF5]));
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0][0], 0x21);
+        assert!(await_fc);
+        // Receiver is busy, so nothing is sent
+        assert_eq!(
+            sender.on_flow_control(&[0x31, 0x00, 0x00]),
+            Some(SendStep::Wait)
+        );
+        let (frames, await_fc) = block(sender.on_flow_control(&[0x30, 0x03, 0x00]));
+        assert_eq!(frames[0][0], 0x24);
+        assert!(await_fc);
+        // Last block is shorter than the block size
+        let (frames, await_fc) = block(sender.on_flow_control(&[0x30, 0x03, 0x00]));
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0][0], 0x27);
+        assert!(!await_fc);
+        assert!(sender.is_done());
+    }
+
+    #[test]
+    fn flow_control_edge_cases() {
+        assert!(MultiFrameSender::new(&[0x3E, 0x00], false).is_none());
+        let (_, mut sender) = MultiFrameSender::new(&[0x36; 20], false).unwrap();
+        // Consecutive frames are not flow control
+        assert_eq!(sender.on_flow_control(&[0x21, 0x00]), None);
+        assert_eq!(
+            sender.on_flow_control(&[0x32, 0x00, 0x00]),
+            Some(SendStep::Overflow)
+        );
+        assert!(!sender.is_done());
+
+        assert_eq!(sep_time_micros(0x7F), 127_000);
+        assert_eq!(sep_time_micros(0xF1), 100);
+        assert_eq!(sep_time_micros(0xF9), 900);
+        // Reserved values
+        assert_eq!(sep_time_micros(0x80), 127_000);
+        assert_eq!(sep_time_micros(0xFA), 127_000);
+    }
+
+    #[test]
+    fn send_single_frame() {
+        let server = open_mock();
+        send_message(&server, 0x7E0, 0x7E8, &[0x3E, 0x00], 50, WFT_MAX).unwrap();
+        let sent = server.take_sent_can_frames();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1.id, 0x7E0);
+        assert_eq!(
+            sent[0].1.get_data(),
+            &[0x02, 0x3E, 0x00, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC]
+        );
+    }
+
+    #[test]
+    fn send_pads_classic_frames() {
+        let server = open_mock();
+        server.queue_can_frames(&[fc(&[0x30, 0x00, 0x00])]);
+        // 6 bytes in the first frame, then 7 and 2 in the consecutive frames
+        let msg: Vec<u8> = (0..15).collect();
+        send_message(&server, 0x7E0, 0x7E8, &msg, 50, WFT_MAX).unwrap();
+        let sent = sent_data(&server);
+        assert_eq!(sent.len(), 3);
+        assert!(sent.iter().all(|f| f.len() == 8));
+        assert_eq!(sent[2], vec![0x22, 13, 14, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC]);
+    }
+
+    #[test]
+    fn send_with_block_size() {
+        let server = open_mock();
+        // Block size of 1, so each consecutive frame needs its own flow control
+        server.queue_can_frames(&[fc(&[0x30, 0x01, 0x00]), fc(&[0x30, 0x01, 0x00])]);
+        let msg: Vec<u8> = (0..20).collect();
+        send_message(&server, 0x7E0, 0x7E8, &msg, 50, WFT_MAX).unwrap();
+        assert_eq!(
+            sent_data(&server),
+            vec![
+                vec![0x10, 0x14, 0, 1, 2, 3, 4, 5],
+                vec![0x21, 6, 7, 8, 9, 10, 11, 12],
+                vec![0x22, 13, 14, 15, 16, 17, 18, 19],
+            ]
+        );
+    }
+
+    #[test]
+    fn send_flow_control_timeout() {
+        let server = open_mock();
+        let msg: Vec<u8> = (0..20).collect();
+        // No flow control at all. Only the simulated traffic is on the bus
+        assert_eq!(
+            send_message(&server, 0x7E0, 0x7E8, &msg, 20, WFT_MAX),
+            Err(IsoTpError::Timeout)
+        );
+        assert_eq!(sent_data(&server).len(), 1);
+        // Flow control for the first block only, and flow control from another ECU
+        server.queue_can_frames(&[
+            fc(&[0x30, 0x01, 0x00]),
+            CanFrame::new(0x7E9, &[0x30, 0x00, 0x00]),
+        ]);
+        assert_eq!(
+            send_message(&server, 0x7E0, 0x7E8, &msg, 20, WFT_MAX),
+            Err(IsoTpError::Timeout)
+        );
+        assert_eq!(sent_data(&server).len(), 2);
+    }
+
+    #[test]
+    fn send_flow_control_wait() {
+        let server = open_mock();
+        server.queue_can_frames(&[
+            fc(&[0x31, 0x00, 0x00]),
+            fc(&[0x31, 0x00, 0x00]),
+            fc(&[0x30, 0x00, 0x00]),
+        ]);
+        send_message(&server, 0x7E0, 0x7E8, &[0x36; 20], 50, WFT_MAX).unwrap();
+        assert_eq!(sent_data(&server).len(), 3);
+
+        // Receiver never stops asking the sender to wait
+        server.queue_can_frames(&[fc(&[0x31, 0x00, 0x00]); 11]);
+        assert_eq!(
+            send_message(&server, 0x7E0, 0x7E8, &[0x36; 20], 50, WFT_MAX),
+            Err(IsoTpError::TooManyWaits)
+        );
+        assert_eq!(sent_data(&server).len(), 1);
+
+        server.queue_can_frames(&[fc(&[0x32, 0x00, 0x00])]);
+        assert_eq!(
+            send_message(&server, 0x7E0, 0x7E8, &[0x36; 20], 50, WFT_MAX),
+            Err(IsoTpError::Overflow)
+        );
+        assert_eq!(sent_data(&server).len(), 1);
+    }
+
+    #[test]
+    fn send_waits_for_buffer_limited_ecu() {
+        let server = open_mock();
+        let msg: Vec<u8> = (0..20).collect();
+        let waits = [fc(&[0x31, 0x00, 0x00]); 4];
+        // ECU asks the sender to wait 4 times before it is ready, then takes the whole
+        // message. Nothing but the first frame is sent until then
+        server.queue_can_frames(&waits);
+        server.queue_can_frames(&[fc(&[0x30, 0x00, 0x00])]);
+        send_message(&server, 0x7E0, 0x7E8, &msg, 50, 4).unwrap();
+        assert_eq!(
+            sent_data(&server),
+            vec![
+                vec![0x10, 0x14, 0, 1, 2, 3, 4, 5],
+                vec![0x21, 6, 7, 8, 9, 10, 11, 12],
+                vec![0x22, 13, 14, 15, 16, 17, 18, 19],
+            ]
+        );
+
+        // Waits between blocks count from 0 again
+        server.queue_can_frames(&waits);
+        server.queue_can_frames(&[fc(&[0x30, 0x01, 0x00])]);
+        server.queue_can_frames(&waits);
+        server.queue_can_frames(&[fc(&[0x30, 0x01, 0x00])]);
+        send_message(&server, 0x7E0, 0x7E8, &msg, 50, 4).unwrap();
+        assert_eq!(sent_data(&server).len(), 3);
+
+        // One wait too many for the configured WFTmax
+        server.queue_can_frames(&waits);
+        assert_eq!(
+            send_message(&server, 0x7E0, 0x7E8, &msg, 50, 3),
+            Err(IsoTpError::TooManyWaits)
+        );
+        assert_eq!(sent_data(&server).len(), 1);
+
+        // WFTmax of 0 does not allow any wait frames
+        server.queue_can_frames(&[fc(&[0x31, 0x00, 0x00])]);
+        assert_eq!(
+            send_message(&server, 0x7E0, 0x7E8, &msg, 50, 0),
+            Err(IsoTpError::TooManyWaits)
+        );
+    }
+
+    #[test]
+    fn send_enforces_sep_time() {
+        let server = open_mock();
+        // Send everything, 10ms apart
+        server.queue_can_frames(&[fc(&[0x30, 0x00, 0x0A])]);
+        send_message(&server, 0x7E0, 0x7E8, &[0x36; 40], 50, WFT_MAX).unwrap();
+        let sent = server.take_sent_can_frames();
+        // First frame, then 5 consecutive frames
+        assert_eq!(sent.len(), 6);
+        for pair in sent[1..].windows(2) {
+            assert!(pair[1].0.duration_since(pair[0].0) >= Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn receive_multi_frame() {
+        let start = Instant::now();
+        // 6 bytes in the first frame, then 7 and 5 in the consecutive frames
+        let msg: Vec<u8> = (0..18).collect();
+        let frames = preview_frames(&msg, 0x7E8, 0x7E0, false);
+        let mut receiver = MultiFrameReceiver::new(&frames[0].data, start, 1000).unwrap();
+        assert_eq!(receiver.on_frame(&frames[2].data, start), Ok(None));
+        assert!(!receiver.is_done());
+        // Padding on the last frame is not part of the message
+        assert_eq!(receiver.on_frame(&frames[3].data, start), Ok(Some(msg)));
+        assert!(receiver.is_done());
+        assert!(MultiFrameReceiver::new(&[0x02, 0x3E, 0x00], start, 1000).is_none());
+
+        // Sequence number wraps back to 0 after F
+        let msg: Vec<u8> = (0..200).collect();
+        let frames = preview_frames(&msg, 0x7E8, 0x7E0, false);
+        let mut receiver = MultiFrameReceiver::new(&frames[0].data, start, 1000).unwrap();
+        let mut res = None;
+        for f in &frames[2..] {
+            res = receiver.on_frame(&f.data, start).unwrap();
+        }
+        assert_eq!(res, Some(msg));
+    }
+
+    #[test]
+    fn receive_wrong_sequence() {
+        let start = Instant::now();
+        let mut receiver =
+            MultiFrameReceiver::new(&[0x10, 0x14, 0, 1, 2, 3, 4, 5], start, 1000).unwrap();
+        // Other frames are ignored
+        assert_eq!(receiver.on_frame(&[0x30, 0x00, 0x00], start), Ok(None));
+        assert_eq!(
+            receiver.on_frame(&[0x22, 6, 7, 8, 9, 10, 11, 12], start),
+            Err(IsoTpError::WrongSequence {
+                expected: 1,
+                actual: 2
+            })
+        );
+        assert_eq!(
+            receiver.on_frame(&[0x21, 6, 7, 8, 9, 10, 11, 12], start),
+            Ok(None)
+        );
+        // Repeated frame
+        assert_eq!(
+            receiver.on_frame(&[0x21, 6, 7, 8, 9, 10, 11, 12], start),
+            Err(IsoTpError::WrongSequence {
+                expected: 2,
+                actual: 1
+            })
+        );
+    }
+
+    #[test]
+    fn receive_consecutive_timeout() {
+        let start = Instant::now();
+        let after = |ms: u64| start + Duration::from_millis(ms);
+        let mut receiver =
+            MultiFrameReceiver::new(&[0x10, 0x14, 0, 1, 2, 3, 4, 5], start, 1000).unwrap();
+        assert_eq!(
+            receiver.on_frame(&[0x21, 6, 7, 8, 9, 10, 11, 12], after(900)),
+            Ok(None)
+        );
+        // Timeout is from the last frame received, not the first frame
+        assert_eq!(receiver.check_timeout(after(1800)), Ok(()));
+        assert_eq!(
+            receiver.on_frame(&[0x22, 13, 14, 15, 16, 17, 18, 19], after(1901)),
+            Err(IsoTpError::Timeout)
+        );
+    }
+}
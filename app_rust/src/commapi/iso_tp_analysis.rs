@@ -0,0 +1,336 @@
+// Breakdown of how captured diagnostic exchanges were split into ISO-TP (ISO 15765-2) frames.
+// Useful for seeing which frame was the first frame, which were consecutive frames, what
+// the flow control asked for, and how long each side took between frames.
+//
+// Frames are taken from a capture of the CAN tracer, so their timestamps are whatever the
+// capture used (Adapter timestamps if it provided them, otherwise when OVD read the frame).
+
+use super::{
+    can_trace::TraceFrame,
+    iso_tp::{decode_frame, sep_time_micros, IsoTpFrame},
+};
+
+/// Which side of the exchange sent a frame
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent by the tester to the ECU
+    Request,
+    /// Sent by the ECU to the tester
+    Response,
+}
+
+impl Direction {
+    fn index(&self) -> usize {
+        match self {
+            Direction::Request => 0,
+            Direction::Response => 1,
+        }
+    }
+
+    fn other(&self) -> Self {
+        match self {
+            Direction::Request => Direction::Response,
+            Direction::Response => Direction::Request,
+        }
+    }
+}
+
+/// A single CAN frame of an exchange
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameInfo {
+    /// Time since the start of the capture in microseconds
+    pub timestamp_us: u128,
+    /// Time since the previous frame of the exchange in microseconds. 0 for the first frame
+    pub delta_us: u128,
+    pub direction: Direction,
+    pub id: u32,
+    pub data: Vec<u8>,
+    /// What the frame is, EG: 'Consecutive frame 3'
+    pub desc: String,
+    /// Something wrong with the frame, EG: A consecutive frame out of order
+    pub problem: Option<String>,
+}
+
+/// A request from the tester, and every frame sent by either side until the next request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exchange {
+    /// Reassembled request. None if it could not be fully reassembled
+    pub request: Option<Vec<u8>>,
+    /// Reassembled responses from the ECU, in the order they completed.
+    /// There can be more than one if the ECU asked the tester to wait (Response pending)
+    pub responses: Vec<Vec<u8>>,
+    pub frames: Vec<FrameInfo>,
+}
+
+impl Exchange {
+    /// Time from the first frame of the request, to the last frame of the exchange in microseconds
+    pub fn get_duration_us(&self) -> u128 {
+        match (self.frames.first(), self.frames.last()) {
+            (Some(first), Some(last)) => last.timestamp_us - first.timestamp_us,
+            _ => 0,
+        }
+    }
+
+    /// Returns true if any frame of the exchange has a problem
+    pub fn has_problems(&self) -> bool {
+        self.frames.iter().any(|f| f.problem.is_some())
+    }
+}
+
+/// A multi-frame message still being reassembled
+#[derive(Debug, Clone)]
+struct PartialMessage {
+    total_len: usize,
+    data: Vec<u8>,
+    next_seq: u8,
+}
+
+/// Splits a capture into exchanges between a tester and an ECU, and works out what each of
+/// their frames was. Frames with other IDs are ignored. Every single or first frame from the
+/// tester starts a new exchange. Frames from the ECU before the first request are ignored
+///
+/// ## Params
+/// * frames - Captured frames, oldest first
+/// * send_id - CAN ID the tester sends requests with
+/// * recv_id - CAN ID the ECU responds with
+pub fn analyze_exchanges(frames: &[TraceFrame], send_id: u32, recv_id: u32) -> Vec<Exchange> {
+    let mut exchanges: Vec<Exchange> = Vec::new();
+    // Message being reassembled in each direction
+    let mut partial: [Option<PartialMessage>; 2] = [None, None];
+    // Separation time in microseconds each direction's consecutive frames were asked to keep
+    let mut sep_time: [Option<u32>; 2] = [None, None];
+
+    for f in frames {
+        let direction = if f.frame.id == send_id {
+            Direction::Request
+        } else if f.frame.id == recv_id {
+            Direction::Response
+        } else {
+            continue;
+        };
+        let data = f.frame.get_data();
+        let decoded = decode_frame(data);
+        let starts_request = matches!(
+            decoded,
+            Some(IsoTpFrame::Single(_)) | Some(IsoTpFrame::First { .. })
+        );
+        if direction == Direction::Request && starts_request {
+            exchanges.push(Exchange {
+                request: None,
+                responses: Vec::new(),
+                frames: Vec::new(),
+            });
+            partial = [None, None];
+            sep_time = [None, None];
+        }
+        let exchange = match exchanges.last_mut() {
+            Some(e) => e,
+            None => continue,
+        };
+        let prev = exchange.frames.last();
+        let delta_us = prev.map(|p| f.timestamp_us - p.timestamp_us).unwrap_or(0);
+        // STmin is the gap between consecutive frames, so only applies if the frame
+        // before this one was a consecutive frame from the same side
+        let after_consecutive = prev
+            .map(|p| p.direction == direction && data_is_consecutive(&p.data))
+            .unwrap_or(false);
+
+        let idx = direction.index();
+        let mut problem = None;
+        let mut complete = None;
+        let desc = match decoded {
+            Some(IsoTpFrame::Single(msg)) => {
+                complete = Some(msg.to_vec());
+                format!("Single frame, {} bytes", msg.len())
+            }
+            Some(IsoTpFrame::First { total_len, data }) => {
+                let total_len = total_len as usize;
+                partial[idx] = Some(PartialMessage {
+                    total_len,
+                    data: data[..data.len().min(total_len)].to_vec(),
+                    next_seq: 1,
+                });
+                format!("First frame, {} bytes in total", total_len)
+            }
+            Some(IsoTpFrame::Consecutive { seq, data }) => {
+                match partial[idx].as_mut() {
+                    None => problem = Some("No first frame before this consecutive frame".into()),
+                    Some(p) if p.next_seq != seq => {
+                        problem = Some(format!(
+                            "Out of order, expected consecutive frame {:X}",
+                            p.next_seq
+                        ));
+                        partial[idx] = None;
+                    }
+                    Some(p) => {
+                        let remaining = p.total_len - p.data.len();
+                        p.data.extend_from_slice(&data[..data.len().min(remaining)]);
+                        p.next_seq = (p.next_seq + 1) & 0x0F;
+                        if p.data.len() >= p.total_len {
+                            complete = partial[idx].take().map(|p| p.data);
+                        }
+                    }
+                }
+                if let (true, Some(min), None) = (after_consecutive, sep_time[idx], &problem) {
+                    if delta_us < min as u128 {
+                        problem = Some(format!(
+                            "Sent {}us after the previous frame, less than the STmin of {}us",
+                            delta_us, min
+                        ));
+                    }
+                }
+                format!("Consecutive frame {:X}", seq)
+            }
+            Some(IsoTpFrame::FlowControl {
+                status,
+                block_size,
+                sep_time: st_min,
+            }) => {
+                // Flow control sets the rules for the other side's consecutive frames
+                sep_time[direction.other().index()] = Some(sep_time_micros(st_min));
+                if partial[direction.other().index()].is_none() {
+                    problem = Some("No multi-frame message is being sent to this side".into());
+                }
+                match status {
+                    0 => format!(
+                        "Flow control, continue to send. Block size {}, STmin {}us",
+                        block_size,
+                        sep_time_micros(st_min)
+                    ),
+                    1 => "Flow control, wait".into(),
+                    2 => "Flow control, overflow. Message is too large for the receiver".into(),
+                    s => format!("Flow control, invalid status {}", s),
+                }
+            }
+            None => {
+                problem = Some("Not a valid ISO-TP frame".into());
+                "Unknown frame".into()
+            }
+        };
+
+        if let Some(msg) = complete {
+            match direction {
+                Direction::Request => exchange.request = Some(msg),
+                Direction::Response => exchange.responses.push(msg),
+            }
+        }
+        exchange.frames.push(FrameInfo {
+            timestamp_us: f.timestamp_us,
+            delta_us,
+            direction,
+            id: f.frame.id,
+            data: data.to_vec(),
+            desc,
+            problem,
+        });
+    }
+    exchanges
+}
+
+fn data_is_consecutive(data: &[u8]) -> bool {
+    matches!(decode_frame(data), Some(IsoTpFrame::Consecutive { .. }))
+}
+
+#[cfg(test)]
+mod iso_tp_analysis_test {
+    use super::{analyze_exchanges, Direction};
+    use crate::commapi::{can_trace::TraceFrame, comm_api::CanFrame};
+
+    fn frame(timestamp_us: u128, id: u32, data: &[u8]) -> TraceFrame {
+        TraceFrame {
+            timestamp_us,
+            frame: CanFrame::new(id, data),
+        }
+    }
+
+    #[test]
+    fn multi_frame_response() {
+        let frames = vec![
+            frame(0, 0x7E0, &[0x03, 0x22, 0xF1, 0x90]),
+            // Unrelated traffic is ignored
+            frame(500, 0x123, &[0x00; 8]),
+            frame(
+                10_000,
+                0x7E8,
+                &[0x10, 0x0A, 0x62, 0xF1, 0x90, 0x57, 0x44, 0x42],
+            ),
+            frame(11_000, 0x7E0, &[0x30, 0x00, 0x05]),
+            frame(17_000, 0x7E8, &[0x21, 0x31, 0x32, 0x33, 0x34]),
+        ];
+        let exchanges = analyze_exchanges(&frames, 0x7E0, 0x7E8);
+        assert_eq!(exchanges.len(), 1);
+        let e = &exchanges[0];
+        assert_eq!(e.request, Some(vec![0x22, 0xF1, 0x90]));
+        assert_eq!(
+            e.responses,
+            vec![vec![
+                0x62, 0xF1, 0x90, 0x57, 0x44, 0x42, 0x31, 0x32, 0x33, 0x34
+            ]]
+        );
+        assert_eq!(e.frames.len(), 4);
+        assert_eq!(e.frames[1].desc, "First frame, 10 bytes in total");
+        assert_eq!(e.frames[2].direction, Direction::Request);
+        assert_eq!(e.frames[2].delta_us, 1_000);
+        assert_eq!(e.frames[3].desc, "Consecutive frame 1");
+        assert_eq!(e.get_duration_us(), 17_000);
+        assert!(!e.has_problems());
+    }
+
+    #[test]
+    fn new_request_starts_exchange() {
+        let frames = vec![
+            // Response without a request is ignored
+            frame(0, 0x7E8, &[0x02, 0x7E, 0x00]),
+            frame(1_000, 0x7E0, &[0x02, 0x10, 0x03]),
+            frame(2_000, 0x7E8, &[0x03, 0x7F, 0x10, 0x78]),
+            frame(5_000, 0x7E8, &[0x02, 0x50, 0x03]),
+            frame(9_000, 0x7E0, &[0x02, 0x3E, 0x00]),
+        ];
+        let exchanges = analyze_exchanges(&frames, 0x7E0, 0x7E8);
+        assert_eq!(exchanges.len(), 2);
+        assert_eq!(exchanges[0].responses.len(), 2);
+        assert_eq!(exchanges[0].frames.len(), 3);
+        assert_eq!(exchanges[1].request, Some(vec![0x3E, 0x00]));
+        assert!(exchanges[1].responses.is_empty());
+    }
+
+    #[test]
+    fn sequence_and_timing_problems() {
+        let frames = vec![
+            frame(0, 0x7E0, &[0x10, 0x1B, 0x2E, 0xF1, 0x90, 0x00, 0x00, 0x00]),
+            frame(1_000, 0x7E8, &[0x30, 0x00, 0x0A]),
+            frame(
+                12_000,
+                0x7E0,
+                &[0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            ),
+            // Only 2ms after the last consecutive frame, STmin is 10ms
+            frame(
+                14_000,
+                0x7E0,
+                &[0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            ),
+            // Skips sequence number 3
+            frame(
+                30_000,
+                0x7E0,
+                &[0x24, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            ),
+        ];
+        let exchanges = analyze_exchanges(&frames, 0x7E0, 0x7E8);
+        assert_eq!(exchanges.len(), 1);
+        let e = &exchanges[0];
+        assert!(e.has_problems());
+        assert_eq!(e.request, None);
+        assert_eq!(
+            e.frames[1].desc,
+            "Flow control, continue to send. Block size 0, STmin 10000us"
+        );
+        assert!(e.frames[2].problem.is_none());
+        assert!(e.frames[3].problem.as_ref().unwrap().contains("STmin"));
+        assert_eq!(
+            e.frames[4].problem.as_deref(),
+            Some("Out of order, expected consecutive frame 3")
+        );
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use crate::commapi::comm_api::{
+    CanFdConfig, CanFrame, Capability, ComServer, ComServerError, DeviceCapabilities, FilterType,
+    ISO15765Data, ERR_NO_FLOW_CONTROL,
+};
+
+/// Name returned by [get_api](fn@ComServer::get_api) for the simulated adapter.
+/// Diagnostic sessions check for this to label their logs as simulated
+pub const SIMULATION_API_NAME: &str = "Simulation";
+
+/// VIN reported by the simulated vehicle
+const MOCK_VIN: &[u8] = b"WDD2030462A123456";
+
+/// Number of sent CAN frames kept for [take_sent_can_frames](fn@MockComServer::take_sent_can_frames)
+const MAX_SENT_CAN_FRAMES: usize = 1000;
+
+/// Number of sent ISO-TP payloads kept for [take_sent_iso15765_data](fn@MockComServer::take_sent_iso15765_data)
+const MAX_SENT_ISO15765_MSGS: usize = 1000;
+
+/// Seed the simulated ECU sends for every security access level. Any key is accepted
+const MOCK_SEED: [u8; 2] = [0x12, 0x34];
+
+/// Simulated vehicle for trying out OVD without a vehicle or adapter.
+///
+/// Every ISO-TP request sent is answered straight away with a plausible response
+/// for the common diagnostic services (Session control, tester present, DTCs, VIN)
+/// and a negative response (Service not supported) for anything else
+#[derive(Debug, Clone)]
+pub struct MockComServer {
+    is_open: Arc<RwLock<bool>>,
+    can_open: Arc<RwLock<bool>>,
+    iso15765_open: Arc<RwLock<bool>>,
+    /// Filter ID -> (Response ID, Flow control (Request) ID)
+    iso15765_filters: Arc<RwLock<HashMap<u32, (u32, u32)>>>,
+    next_filter_id: Arc<RwLock<u32>>,
+    iso15765_rx: Arc<RwLock<VecDeque<ISO15765Data>>>,
+    can_counter: Arc<RwLock<u8>>,
+    /// CAN frames queued to be read before the simulated traffic
+    can_rx: Arc<RwLock<VecDeque<CanFrame>>>,
+    /// CAN frames sent, and the time they were sent at
+    can_tx: Arc<RwLock<VecDeque<(Instant, CanFrame)>>>,
+    /// Responses to the next ISO-TP requests, used instead of the simulated ones.
+    /// None leaves a request unanswered
+    iso15765_script: Arc<RwLock<VecDeque<Option<Vec<u8>>>>>,
+    /// ISO-TP payloads sent
+    iso15765_tx: Arc<RwLock<VecDeque<ISO15765Data>>>,
+    /// ISO-TP payloads other ECUs send after the response to the next request
+    iso15765_queued: Arc<RwLock<VecDeque<ISO15765Data>>>,
+}
+
+impl MockComServer {
+    pub fn new() -> Self {
+        Self {
+            is_open: Arc::new(RwLock::new(false)),
+            can_open: Arc::new(RwLock::new(false)),
+            iso15765_open: Arc::new(RwLock::new(false)),
+            iso15765_filters: Arc::new(RwLock::new(HashMap::new())),
+            next_filter_id: Arc::new(RwLock::new(0)),
+            iso15765_rx: Arc::new(RwLock::new(VecDeque::new())),
+            can_counter: Arc::new(RwLock::new(0)),
+            can_rx: Arc::new(RwLock::new(VecDeque::new())),
+            can_tx: Arc::new(RwLock::new(VecDeque::new())),
+            iso15765_script: Arc::new(RwLock::new(VecDeque::new())),
+            iso15765_tx: Arc::new(RwLock::new(VecDeque::new())),
+            iso15765_queued: Arc::new(RwLock::new(VecDeque::new())),
+        }
+    }
+
+    /// Queues CAN frames to be read from the bus, in order, before any simulated traffic.
+    /// Lets a test script what an ECU sends
+    pub fn queue_can_frames(&self, frames: &[CanFrame]) {
+        self.can_rx.write().unwrap().extend(frames.iter().copied());
+    }
+
+    /// Returns the CAN frames sent since this was last called, and the time each was sent at
+    pub fn take_sent_can_frames(&self) -> Vec<(Instant, CanFrame)> {
+        self.can_tx.write().unwrap().drain(..).collect()
+    }
+
+    /// Queues the responses to the next ISO-TP requests, in order, instead of the simulated
+    /// ones. None leaves a request unanswered. Lets a test script an ECU going silent
+    pub fn script_iso15765_responses(&self, responses: &[Option<Vec<u8>>]) {
+        self.iso15765_script
+            .write()
+            .unwrap()
+            .extend(responses.iter().cloned());
+    }
+
+    /// Queues ISO-TP payloads to be received after the response to the next request, in order.
+    /// Lets a test script several ECUs answering a functional request
+    pub fn queue_iso15765_responses(&self, msgs: &[ISO15765Data]) {
+        self.iso15765_queued
+            .write()
+            .unwrap()
+            .extend(msgs.iter().cloned());
+    }
+
+    /// Returns the ISO-TP payloads sent since this was last called
+    pub fn take_sent_iso15765_data(&self) -> Vec<ISO15765Data> {
+        self.iso15765_tx.write().unwrap().drain(..).collect()
+    }
+
+    /// Returns if the device, the CAN interface and the ISO-TP interface are open
+    pub fn open_state(&self) -> (bool, bool, bool) {
+        (
+            *self.is_open.read().unwrap(),
+            *self.can_open.read().unwrap(),
+            *self.iso15765_open.read().unwrap(),
+        )
+    }
+
+    fn not_open_err(iface: &str) -> ComServerError {
+        ComServerError {
+            err_code: 2,
+            err_desc: format!("{} interface not open", iface),
+        }
+    }
+
+    /// Generates the simulated ECU's response to a request
+    fn respond(req: &[u8]) -> Option<Vec<u8>> {
+        let sid = *req.get(0)?;
+        let arg = req.get(1).copied().unwrap_or(0x00);
+        let mut resp = vec![sid + 0x40];
+        match sid {
+            // OBD-II Service 01 - Supported PIDs
+            0x01 if arg == 0x00 => resp.extend_from_slice(&[0x00, 0xBE, 0x1F, 0xA8, 0x13]),
+            // OBD-II Service 01 - Engine RPM
+            0x01 if arg == 0x0C => resp.extend_from_slice(&[0x0C, 0x0B, 0xB8]),
+            // OBD-II Service 09 - Supported PIDs
+            0x09 if arg == 0x00 => resp.extend_from_slice(&[0x00, 0x55, 0x40, 0x00, 0x00]),
+            // OBD-II Service 09 - VIN
+            0x09 if arg == 0x02 => {
+                resp.extend_from_slice(&[0x02, 0x01]);
+                resp.extend_from_slice(MOCK_VIN);
+            }
+            // OBD-II Service 03, 07 and 0A - Stored, pending and permanent DTCs
+            0x03 => resp.extend_from_slice(&[0x01, 0x01, 0x71]),
+            0x07 => resp.extend_from_slice(&[0x01, 0x03, 0x00]),
+            0x0A => resp.extend_from_slice(&[0x01, 0x01, 0x71]),
+            // Diagnostic session control (KWP2000 sessions start at 0x81)
+            0x10 => {
+                resp.push(arg);
+                if arg < 0x81 {
+                    resp.extend_from_slice(&[0x00, 0x32, 0x01, 0xF4]) // UDS P2 timings
+                }
+            }
+            0x11 => resp.push(arg),
+            // Clear DTCs
+            0x14 => {}
+            // KWP2000 Read DTCs by status (2 stored DTCs)
+            0x18 => resp.extend_from_slice(&[0x02, 0x03, 0x00, 0xE0, 0x01, 0x71, 0x60]),
+            // UDS Read DTC information with severity (1 stored DTC, check at next halt)
+            0x19 if arg == 0x42 => {
+                resp.extend_from_slice(&[arg, 0x33, 0xFF, 0xE0, 0x04, 0x40, 0x03, 0x00, 0x00, 0x2F])
+            }
+            // UDS Read DTC information with permanent status (None)
+            0x19 if arg == 0x15 => resp.extend_from_slice(&[arg, 0xFF]),
+            // UDS Read DTC information (1 stored DTC)
+            0x19 => resp.extend_from_slice(&[arg, 0xFF, 0x03, 0x00, 0x00, 0x2F]),
+            // KWP2000 Read ECU identification
+            0x1A => {
+                resp.push(arg);
+                match arg {
+                    0x90 => resp.extend_from_slice(MOCK_VIN),
+                    _ => resp.extend_from_slice(&[0x00, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04]),
+                }
+            }
+            // KWP2000 Read data by local ID
+            0x21 => resp.extend_from_slice(&[arg, 0x00, 0x00, 0x01, 0x18, 0x02, 0x6D]),
+            // UDS Read data by ID
+            0x22 => {
+                resp.extend_from_slice(&req[1..std::cmp::min(3, req.len())]);
+                match (arg, req.get(2)) {
+                    (0xF1, Some(0x90)) => resp.extend_from_slice(MOCK_VIN),
+                    _ => resp.extend_from_slice(&[0x00, 0x00]),
+                }
+            }
+            // Tester present. Bit 7 of the sub function suppresses the response
+            0x3E => {
+                if arg & 0x80 != 0 {
+                    return None;
+                }
+                resp.push(arg)
+            }
+            0x31 => resp.extend_from_slice(&req[1..]),
+            // KWP2000 Request routine results by local ID
+            0x33 => resp.extend_from_slice(&[arg, 0x0C, 0x0D]),
+            // Security access. Odd sub functions request the seed, even ones send the key
+            0x27 => {
+                resp.push(arg);
+                if arg % 2 == 1 {
+                    resp.extend_from_slice(&MOCK_SEED)
+                }
+            }
+            _ => return Some(vec![0x7F, sid, 0x11]), // Service not supported
+        }
+        Some(resp)
+    }
+}
+
+impl ComServer for MockComServer {
+    fn open_device(&mut self) -> Result<(), ComServerError> {
+        *self.is_open.write().unwrap() = true;
+        Ok(())
+    }
+
+    fn close_device(&mut self) -> Result<(), ComServerError> {
+        *self.is_open.write().unwrap() = false;
+        Ok(())
+    }
+
+    fn send_can_packets(
+        &self,
+        data: &[CanFrame],
+        _timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        if !*self.can_open.read().unwrap() {
+            return Err(Self::not_open_err("CAN"));
+        }
+        let mut tx = self.can_tx.write().unwrap();
+        let now = Instant::now();
+        for f in data {
+            if tx.len() >= MAX_SENT_CAN_FRAMES {
+                tx.pop_front();
+            }
+            tx.push_back((now, *f));
+        }
+        Ok(data.len())
+    }
+
+    fn is_connected(&self) -> bool {
+        *self.can_open.read().unwrap() || *self.iso15765_open.read().unwrap()
+    }
+
+    fn read_can_packets(
+        &self,
+        _timeout_ms: u32,
+        max_msgs: usize,
+    ) -> Result<Vec<CanFrame>, ComServerError> {
+        if !*self.can_open.read().unwrap() {
+            return Err(Self::not_open_err("CAN"));
+        }
+        let mut rx = self.can_rx.write().unwrap();
+        if !rx.is_empty() {
+            let count = std::cmp::min(max_msgs, rx.len());
+            return Ok(rx.drain(0..count).collect());
+        }
+        // Simulate some traffic on the bus with a changing counter
+        let mut counter = self.can_counter.write().unwrap();
+        *counter = counter.wrapping_add(1);
+        Ok(vec![
+            CanFrame::new(
+                0x0100,
+                &[*counter, 0x00, 0x0B, 0xB8, 0x00, 0x00, 0x00, 0x00],
+            ),
+            CanFrame::new(0x0200, &[0x12, 0x34, 0x56, 0x78]),
+        ])
+    }
+
+    fn send_iso15765_data(
+        &self,
+        data: &[ISO15765Data],
+        _timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        if !*self.iso15765_open.read().unwrap() {
+            return Err(Self::not_open_err("ISO15765"));
+        }
+        let filters = self.iso15765_filters.read().unwrap();
+        let mut rx = self.iso15765_rx.write().unwrap();
+        let mut tx = self.iso15765_tx.write().unwrap();
+        for msg in data {
+            // Like a real adapter, only IDs that are the flow control ID of a filter can be sent to
+            let resp_id = match filters.values().find(|(_, fc_id)| *fc_id == msg.id) {
+                // Functional filter, answer as the engine ECU
+                Some((resp_id, fc_id)) if resp_id == fc_id => 0x07E8,
+                Some((resp_id, _)) => *resp_id,
+                None => {
+                    return Err(ComServerError {
+                        err_code: ERR_NO_FLOW_CONTROL,
+                        err_desc: format!("No flow control filter for 0x{:04X}", msg.id),
+                    })
+                }
+            };
+            if tx.len() >= MAX_SENT_ISO15765_MSGS {
+                tx.pop_front();
+            }
+            tx.push_back(msg.clone());
+            let resp = match self.iso15765_script.write().unwrap().pop_front() {
+                Some(scripted) => scripted,
+                None => Self::respond(&msg.data),
+            };
+            if let Some(resp) = resp {
+                rx.push_back(ISO15765Data {
+                    id: resp_id,
+                    data: resp,
+                    pad_frame: msg.pad_frame,
+                    ext_addressing: msg.ext_addressing,
+                })
+            }
+            rx.extend(self.iso15765_queued.write().unwrap().drain(..));
+        }
+        Ok(data.len())
+    }
+
+    fn read_iso15765_packets(
+        &self,
+        _timeout_ms: u32,
+        max_msgs: usize,
+    ) -> Result<Vec<ISO15765Data>, ComServerError> {
+        if !*self.iso15765_open.read().unwrap() {
+            return Err(Self::not_open_err("ISO15765"));
+        }
+        let mut rx = self.iso15765_rx.write().unwrap();
+        let count = std::cmp::min(max_msgs, rx.len());
+        Ok(rx.drain(0..count).collect())
+    }
+
+    fn open_can_interface(
+        &mut self,
+        _bus_speed: u32,
+        _is_ext_can: bool,
+    ) -> Result<(), ComServerError> {
+        *self.can_open.write().unwrap() = true;
+        Ok(())
+    }
+
+    fn close_can_interface(&mut self) -> Result<(), ComServerError> {
+        *self.can_open.write().unwrap() = false;
+        Ok(())
+    }
+
+    fn open_iso15765_interface(
+        &mut self,
+        _bus_speed: u32,
+        _is_ext_can: bool,
+        _ext_addressing: bool,
+    ) -> Result<(), ComServerError> {
+        *self.iso15765_open.write().unwrap() = true;
+        Ok(())
+    }
+
+    fn open_iso15765_fd_interface(
+        &mut self,
+        bus_speed: u32,
+        _fd: CanFdConfig,
+        is_ext_can: bool,
+        ext_addressing: bool,
+    ) -> Result<(), ComServerError> {
+        // Simulated ECUs respond the same way over CAN FD
+        self.open_iso15765_interface(bus_speed, is_ext_can, ext_addressing)
+    }
+
+    fn close_iso15765_interface(&mut self) -> Result<(), ComServerError> {
+        *self.iso15765_open.write().unwrap() = false;
+        self.iso15765_filters.write().unwrap().clear();
+        self.iso15765_rx.write().unwrap().clear();
+        Ok(())
+    }
+
+    fn add_can_filter(
+        &self,
+        _filter: FilterType,
+        _id: u32,
+        _mask: u32,
+    ) -> Result<u32, ComServerError> {
+        Ok(0)
+    }
+
+    fn rem_can_filter(&self, _filter_idx: u32) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn add_iso15765_filter(&self, id: u32, _mask: u32, fc_id: u32) -> Result<u32, ComServerError> {
+        let mut next = self.next_filter_id.write().unwrap();
+        let filter_id = *next;
+        *next += 1;
+        self.iso15765_filters
+            .write()
+            .unwrap()
+            .insert(filter_id, (id, fc_id));
+        Ok(filter_id)
+    }
+
+    fn rem_iso15765_filter(&self, filter_idx: u32) -> Result<(), ComServerError> {
+        self.iso15765_filters.write().unwrap().remove(&filter_idx);
+        Ok(())
+    }
+
+    fn set_iso15765_params(
+        &self,
+        _separation_time_min: u32,
+        _block_size: u32,
+    ) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn clear_can_rx_buffer(&self) -> Result<(), ComServerError> {
+        self.can_rx.write().unwrap().clear();
+        Ok(())
+    }
+
+    fn clear_can_tx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn clear_iso15765_rx_buffer(&self) -> Result<(), ComServerError> {
+        self.iso15765_rx.write().unwrap().clear();
+        Ok(())
+    }
+
+    fn clear_iso15765_tx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn read_battery_voltage(&self) -> Result<f32, ComServerError> {
+        Ok(12.6)
+    }
+
+    fn clone_box(&self) -> Box<dyn ComServer> {
+        Box::new(self.clone())
+    }
+
+    fn get_capabilities(&self) -> DeviceCapabilities {
+        DeviceCapabilities {
+            name: "Simulated vehicle".into(),
+            vendor: "OpenVehicleDiag".into(),
+            library_path: "N/A".into(),
+            device_fw_version: "N/A".into(),
+            library_version: env!("CARGO_PKG_VERSION").into(),
+            j1850vpw: Capability::No,
+            j1850pwm: Capability::No,
+            can: Capability::Yes,
+            iso15765: Capability::Yes,
+            can_fd: Capability::Yes,
+            iso9141: Capability::No,
+            iso14230: Capability::No,
+            ip: Capability::No,
+            battery_voltage: Capability::Yes,
+            max_filters: None,
+        }
+    }
+
+    fn get_api(&self) -> &str {
+        SIMULATION_API_NAME
+    }
+}
@@ -1,7 +1,13 @@
+pub mod adapter;
+pub mod can_trace;
 pub mod comm_api;
+pub mod iso_tp;
+pub mod iso_tp_analysis;
+pub mod mock_api;
 pub mod passthru_api;
 pub mod pdu_api;
 pub mod protocols;
+pub mod replay_api;
 
 #[cfg(target_os = "linux")]
 pub mod socket_can_api;
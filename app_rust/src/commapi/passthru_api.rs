@@ -1,5 +1,6 @@
 use crate::commapi::comm_api::{
-    CanFrame, Capability, ComServer, ComServerError, DeviceCapabilities, FilterType, ISO15765Data,
+    CanFrame, Capability, ComServer, ComServerError, DeviceCapabilities, FilterCounter, FilterType,
+    ISO15765Data,
 };
 use crate::passthru::{self, DrvVersion, PassthruDevice, PassthruDrv};
 use std::sync::{Arc, Mutex, RwLock};
@@ -12,6 +13,13 @@ use J2534Common::{
     TxFlag, PASSTHRU_MSG,
 };
 
+/// RxStatus bit set on an indication that the first frame of a multi-frame
+/// ISO15765 message has been received
+const ISO15765_FIRST_FRAME: u32 = 0x00000002;
+
+/// Number of message filters per channel an SAE J2534 adapter has to support
+const J2534_MAX_FILTERS: u32 = 10;
+
 #[derive(Debug, Clone)]
 pub struct PassthruApi {
     device: Arc<PassthruDevice>,
@@ -21,6 +29,8 @@ pub struct PassthruApi {
     can_channel_idx: Arc<RwLock<Option<u32>>>,
     iso15765_channel_idx: Arc<RwLock<Option<u32>>>,
     iso9141_channel_idx: Arc<RwLock<Option<u32>>>,
+    can_filters: FilterCounter,
+    iso15765_filters: FilterCounter,
 }
 
 impl ComServer for PassthruApi {
@@ -126,10 +136,13 @@ impl ComServer for PassthruApi {
                 .read_messages(channel_id, 1, timeout_ms)
                 .map(|read| {
                     read.iter()
+                        // Keep first frame indications, but not other indications (EG: Tx done)
+                        .filter(|msg| {
+                            msg.data_size > 4 || msg.rx_status & ISO15765_FIRST_FRAME != 0
+                        })
                         .map(|msg| PassthruApi::pt_msg_to_iso15765(msg))
                         .filter_map(Option::Some)
                         .map(|x| x.unwrap())
-                        .filter(|x| !x.data.is_empty())
                         .collect()
                 });
             match t {
@@ -175,6 +188,8 @@ impl ComServer for PassthruApi {
             .map_err(|e| self.convert_error(e))?;
         *self.can_channel_idx.write().unwrap() = Some(channel_id);
         *self.iso15765_channel_idx.write().unwrap() = None; // Physically impossible to have both CAN and ISOTP enabled at the same time
+        self.can_filters.clear();
+        self.iso15765_filters.clear();
         Ok(())
     }
 
@@ -189,6 +204,7 @@ impl ComServer for PassthruApi {
                 .disconnect(lock.unwrap())
                 .map_err(|e| self.convert_error(e))?;
             *lock = None;
+            self.can_filters.clear();
         }
         Ok(())
     }
@@ -223,6 +239,8 @@ impl ComServer for PassthruApi {
             .map_err(|e| self.convert_error(e))?;
         *self.iso15765_channel_idx.write().unwrap() = Some(channel_id);
         *self.can_channel_idx.write().unwrap() = None; // Physically impossible to have both CAN and ISOTP enabled at the same time
+        self.can_filters.clear();
+        self.iso15765_filters.clear();
         Ok(())
     }
 
@@ -237,6 +255,7 @@ impl ComServer for PassthruApi {
                 .disconnect(lock.unwrap())
                 .map_err(|e| self.convert_error(e))?;
             *lock = None;
+            self.iso15765_filters.clear();
         }
         Ok(())
     }
@@ -250,6 +269,8 @@ impl ComServer for PassthruApi {
         match *self.can_channel_idx.read().unwrap() {
             None => Err(self.convert_error(ERR_INVALID_CHANNEL_ID)),
             Some(idx) => {
+                self.can_filters
+                    .check_free(self.get_capabilities().get_max_filters(), "CAN")?;
                 let f_type = match filter {
                     FilterType::Pass => PASS_FILTER,
                     FilterType::Block => BLOCK_FILTER,
@@ -272,6 +293,10 @@ impl ComServer for PassthruApi {
                     .lock()
                     .unwrap()
                     .start_msg_filter(idx, f_type, &mask_msg, &ptn_msg, None)
+                    .map(|filter_idx| {
+                        self.can_filters.added(filter_idx);
+                        filter_idx
+                    })
                     .map_err(|e| self.convert_error(e))
             }
         }
@@ -280,12 +305,14 @@ impl ComServer for PassthruApi {
     fn rem_can_filter(&self, filter_idx: u32) -> Result<(), ComServerError> {
         match *self.can_channel_idx.read().unwrap() {
             None => Ok(()), // OK as filter has already been deleted when channel was destroyed
-            Some(id) => self
-                .driver
-                .lock()
-                .unwrap()
-                .stop_msg_filter(id, filter_idx)
-                .map_err(|e| self.convert_error(e)),
+            Some(id) => {
+                self.can_filters.removed(filter_idx);
+                self.driver
+                    .lock()
+                    .unwrap()
+                    .stop_msg_filter(id, filter_idx)
+                    .map_err(|e| self.convert_error(e))
+            }
         }
     }
 
@@ -298,6 +325,8 @@ impl ComServer for PassthruApi {
         match *self.iso15765_channel_idx.read().unwrap() {
             None => Err(self.convert_error(ERR_INVALID_CHANNEL_ID)),
             Some(idx) => {
+                self.iso15765_filters
+                    .check_free(self.get_capabilities().get_max_filters(), "ISO15765")?;
                 let mut mask_msg = PASSTHRU_MSG {
                     protocol_id: Protocol::ISO15765 as u32,
                     data_size: 4,
@@ -322,6 +351,10 @@ impl ComServer for PassthruApi {
                     .lock()
                     .unwrap()
                     .start_msg_filter(idx, FLOW_CONTROL_FILTER, &mask_msg, &ptn_msg, Some(fc_msg))
+                    .map(|filter_idx| {
+                        self.iso15765_filters.added(filter_idx);
+                        filter_idx
+                    })
                     .map_err(|e| self.convert_error(e))
             }
         }
@@ -330,12 +363,14 @@ impl ComServer for PassthruApi {
     fn rem_iso15765_filter(&self, filter_idx: u32) -> Result<(), ComServerError> {
         match *self.iso15765_channel_idx.read().unwrap() {
             None => Ok(()), // Return OK if the channel no longer exists since the filter has already been removed
-            Some(idx) => self
-                .driver
-                .lock()
-                .unwrap()
-                .stop_msg_filter(idx, filter_idx)
-                .map_err(|e| self.convert_error(e)),
+            Some(idx) => {
+                self.iso15765_filters.removed(filter_idx);
+                self.driver
+                    .lock()
+                    .unwrap()
+                    .stop_msg_filter(idx, filter_idx)
+                    .map_err(|e| self.convert_error(e))
+            }
         }
     }
 
@@ -451,6 +486,8 @@ impl ComServer for PassthruApi {
             can_channel_idx: self.can_channel_idx.clone(),
             iso15765_channel_idx: self.iso15765_channel_idx.clone(),
             iso9141_channel_idx: self.iso9141_channel_idx.clone(),
+            can_filters: self.can_filters.clone(),
+            iso15765_filters: self.iso15765_filters.clone(),
         })
     }
 
@@ -478,10 +515,15 @@ impl ComServer for PassthruApi {
             j1850pwm: Capability::from_bool(self.device.j1850pwm),
             can: Capability::from_bool(self.device.can),
             iso15765: Capability::from_bool(self.device.iso15765),
+            // SAE J2534-1 v04.04 does not define any CAN FD protocols
+            can_fd: Capability::NA,
             iso9141: Capability::from_bool(self.device.iso9141),
             iso14230: Capability::from_bool(self.device.iso14230),
             ip: Capability::NA,
             battery_voltage: Capability::Yes,
+            // SAE J2534-1 requires at least 10 filters per channel, which is also
+            // all that many adapters support
+            max_filters: Some(J2534_MAX_FILTERS),
         };
         *self.caps.write().unwrap() = Some(caps.clone());
         caps
@@ -508,6 +550,8 @@ impl PassthruApi {
             can_channel_idx: Arc::from(RwLock::new(None)),
             iso15765_channel_idx: Arc::from(RwLock::new(None)),
             iso9141_channel_idx: Arc::from(RwLock::new(None)),
+            can_filters: FilterCounter::default(),
+            iso15765_filters: FilterCounter::default(),
         }
     }
 
@@ -529,7 +573,12 @@ impl PassthruApi {
             return None;
         }
         let data = &msg.data[4..msg.data_size as usize];
-        Some(CanFrame::new(PassthruApi::msg_id_to_u32(msg), data))
+        let frame = CanFrame::new(PassthruApi::msg_id_to_u32(msg), data);
+        // Adapters that do not support timestamping leave the timestamp as 0
+        match msg.timestamp {
+            0 => Some(frame),
+            ts => Some(frame.with_hw_timestamp(ts)),
+        }
     }
 
     fn pt_msg_to_iso15765(msg: &PASSTHRU_MSG) -> Option<ISO15765Data> {
@@ -0,0 +1,502 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+use super::{obd2::Service01, DiagProtocol, DiagServer};
+use crate::commapi::comm_api::{ComServer, ISO15765Config};
+
+// "Known good" baselines of a vehicle. A snapshot records which ECUs respond, the DTCs each
+// of them has stored, the OBD-II status (MIL and readiness monitors) and key live values
+// (EG: Coolant temperature and fuel trims). A snapshot of a
+// healthy vehicle is saved as its baseline. Later snapshots are compared against it, to find
+// anything that has changed for the worse (EG: After a service, or between fleet checks).
+
+/// Service 01 PIDs recorded in a snapshot, and how far each can drift from the
+/// baseline before it is a deviation (In the PID's unit)
+const LIVE_PIDS: &[(u8, f32)] = &[
+    (0x05, 10.0),  // Engine coolant temperature
+    (0x06, 10.0),  // Short term fuel trim (Bank 1)
+    (0x07, 10.0),  // Long term fuel trim (Bank 1)
+    (0x08, 10.0),  // Short term fuel trim (Bank 2)
+    (0x09, 10.0),  // Long term fuel trim (Bank 2)
+    (0x0C, 200.0), // Engine speed
+    (0x42, 1.0),   // Control module voltage
+];
+
+/// An ECU to include in a snapshot
+#[derive(Debug, Clone)]
+pub struct SnapshotTarget {
+    pub name: String,
+    pub cfg: ISO15765Config,
+    pub protocol: DiagProtocol,
+}
+
+/// State of a single ECU when a snapshot was taken
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ECUSnapshot {
+    pub name: String,
+    pub send_id: u32,
+    /// A diagnostic session could be started with the ECU
+    pub responded: bool,
+    /// DTCs stored in the ECU. None if they could not be read
+    pub dtcs: Option<Vec<String>>,
+    /// Why the ECU did not respond, or its DTCs could not be read
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// Value of a Service 01 PID when a snapshot was taken
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LiveValue {
+    pub pid: u8,
+    pub name: String,
+    pub unit: String,
+    pub value: f32,
+}
+
+/// OBD-II status of the vehicle when a snapshot was taken
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct OBDSnapshot {
+    pub mil_on: bool,
+    /// Number of emissions related DTCs stored
+    pub dtc_count: u8,
+    /// Readiness monitors the ECU supports that had not completed
+    pub incomplete_monitors: Vec<String>,
+}
+
+/// State of a vehicle at a point in time
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct VehicleSnapshot {
+    pub vehicle: String,
+    /// Local time the snapshot was taken
+    pub time: String,
+    pub ecus: Vec<ECUSnapshot>,
+    /// None if the vehicle did not respond to OBD-II
+    pub obd: Option<OBDSnapshot>,
+    /// Key live values the vehicle supports. Empty if it did not respond to OBD-II
+    #[serde(default)]
+    pub live_values: Vec<LiveValue>,
+}
+
+impl VehicleSnapshot {
+    fn find_ecu(&self, ecu: &ECUSnapshot) -> Option<&ECUSnapshot> {
+        self.ecus
+            .iter()
+            .find(|e| e.send_id == ecu.send_id && e.name == ecu.name)
+    }
+}
+
+/// Something that differs between a baseline and a later snapshot of the vehicle
+#[derive(Debug, Clone, PartialEq)]
+pub enum Deviation {
+    /// ECU responded when the baseline was captured, but does not now
+    ECUMissing { name: String, send_id: u32 },
+    /// ECU responds now, but did not when the baseline was captured
+    ECUAdded { name: String, send_id: u32 },
+    /// DTC stored in an ECU that was not there in the baseline
+    NewDTC { ecu: String, dtc: String },
+    /// MIL is on, but was off in the baseline
+    MILOn,
+    /// Readiness monitor that was complete in the baseline has not completed
+    MonitorIncomplete(String),
+    /// Vehicle responded to OBD-II in the baseline, but does not now
+    OBDMissing,
+    /// Live value has drifted further from the baseline than it should
+    LiveValueChanged {
+        name: String,
+        unit: String,
+        before: f32,
+        now: f32,
+    },
+}
+
+impl Display for Deviation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Deviation::ECUMissing { name, send_id } => {
+                write!(f, "{} (0x{:04X}) no longer responds", name, send_id)
+            }
+            Deviation::ECUAdded { name, send_id } => {
+                write!(
+                    f,
+                    "{} (0x{:04X}) responds, but did not before",
+                    name, send_id
+                )
+            }
+            Deviation::NewDTC { ecu, dtc } => write!(f, "{} has a new error {}", ecu, dtc),
+            Deviation::MILOn => write!(f, "Check engine light is on"),
+            Deviation::MonitorIncomplete(m) => {
+                write!(f, "Readiness monitor '{}' has not completed", m)
+            }
+            Deviation::OBDMissing => write!(f, "Vehicle no longer responds to OBD-II"),
+            Deviation::LiveValueChanged {
+                name,
+                unit,
+                before,
+                now,
+            } => {
+                // Round to 2 decimal places, but don't show trailing zeros
+                let round = |v: &f32| (v * 100.0).round() / 100.0;
+                write!(
+                    f,
+                    "{} is {} {}, but was {} {}",
+                    name,
+                    round(now),
+                    unit,
+                    round(before),
+                    unit
+                )
+            }
+        }
+    }
+}
+
+/// Takes a snapshot of the vehicle, by starting a diagnostic session with each ECU in turn
+/// and reading its DTCs, then reading the OBD-II readiness status and live values
+pub fn take_snapshot(
+    server: Box<dyn ComServer>,
+    vehicle: &str,
+    targets: &[SnapshotTarget],
+) -> VehicleSnapshot {
+    let ecus = targets
+        .iter()
+        .map(|t| snapshot_ecu(server.clone(), t))
+        .collect();
+    let mut obd_server = server.clone();
+    let service01 = Service01::init(&mut obd_server, true).ok();
+    let obd = service01
+        .and_then(|s| s.get_readiness(&mut obd_server, true).ok())
+        .map(|r| OBDSnapshot {
+            mil_on: r.mil_on,
+            dtc_count: r.dtc_count,
+            incomplete_monitors: r
+                .get_incomplete()
+                .iter()
+                .map(|m| m.name.to_string())
+                .collect(),
+        });
+    // PIDs the vehicle does not support are left out
+    let live_values = service01
+        .map(|s| {
+            LIVE_PIDS
+                .iter()
+                .filter_map(|(pid, _)| s.read_pid(&mut obd_server, true, *pid).ok())
+                .map(|v| LiveValue {
+                    pid: v.pid,
+                    name: v.name.to_string(),
+                    unit: v.unit.to_string(),
+                    value: v.value,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    VehicleSnapshot {
+        vehicle: vehicle.to_string(),
+        time: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        ecus,
+        obd,
+        live_values,
+    }
+}
+
+/// Starts a diagnostic session with an ECU and reads its DTCs
+fn snapshot_ecu(server: Box<dyn ComServer>, target: &SnapshotTarget) -> ECUSnapshot {
+    let mut snapshot = ECUSnapshot {
+        name: target.name.clone(),
+        send_id: target.cfg.send_id,
+        responded: false,
+        dtcs: None,
+        error: None,
+    };
+    match DiagServer::new(server, &target.cfg, target.protocol) {
+        Ok(s) => {
+            snapshot.responded = true;
+            match s.read_errors() {
+                Ok(dtcs) => snapshot.dtcs = Some(dtcs.into_iter().map(|d| d.error).collect()),
+                Err(e) => snapshot.error = Some(format!("Could not read DTCs: {}", e.get_text())),
+            }
+        }
+        Err(e) => snapshot.error = Some(e.get_text()),
+    }
+    snapshot
+}
+
+/// Compares a snapshot of the vehicle against its baseline. DTCs that have gone since the
+/// baseline are not deviations, as the vehicle is no worse off
+pub fn compare_to_baseline(
+    baseline: &VehicleSnapshot,
+    current: &VehicleSnapshot,
+) -> Vec<Deviation> {
+    let mut res = Vec::new();
+    for ecu in baseline.ecus.iter().filter(|e| e.responded) {
+        if !current.find_ecu(ecu).map(|e| e.responded).unwrap_or(false) {
+            res.push(Deviation::ECUMissing {
+                name: ecu.name.clone(),
+                send_id: ecu.send_id,
+            })
+        }
+    }
+    for ecu in current.ecus.iter().filter(|e| e.responded) {
+        let before = baseline.find_ecu(ecu).filter(|e| e.responded);
+        if before.is_none() {
+            res.push(Deviation::ECUAdded {
+                name: ecu.name.clone(),
+                send_id: ecu.send_id,
+            })
+        }
+        // DTCs can only be compared if they were read both times
+        let before_dtcs = before.and_then(|e| e.dtcs.as_ref());
+        if let (Some(before_dtcs), Some(dtcs)) = (before_dtcs, &ecu.dtcs) {
+            res.extend(dtcs.iter().filter(|d| !before_dtcs.contains(d)).map(|d| {
+                Deviation::NewDTC {
+                    ecu: ecu.name.clone(),
+                    dtc: d.clone(),
+                }
+            }));
+        }
+    }
+    match (&baseline.obd, &current.obd) {
+        (Some(_), None) => res.push(Deviation::OBDMissing),
+        (Some(before), Some(now)) => {
+            if now.mil_on && !before.mil_on {
+                res.push(Deviation::MILOn)
+            }
+            res.extend(
+                now.incomplete_monitors
+                    .iter()
+                    .filter(|m| !before.incomplete_monitors.contains(m))
+                    .map(|m| Deviation::MonitorIncomplete(m.clone())),
+            );
+        }
+        _ => {}
+    }
+    // Live values can only be compared if they were read both times
+    for now in &current.live_values {
+        let before = baseline.live_values.iter().find(|v| v.pid == now.pid);
+        let tolerance = LIVE_PIDS.iter().find(|(pid, _)| *pid == now.pid);
+        if let (Some(before), Some((_, tolerance))) = (before, tolerance) {
+            if (now.value - before.value).abs() > *tolerance {
+                res.push(Deviation::LiveValueChanged {
+                    name: now.name.clone(),
+                    unit: now.unit.clone(),
+                    before: before.value,
+                    now: now.value,
+                })
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod baseline_test {
+    use super::{
+        compare_to_baseline, take_snapshot, Deviation, ECUSnapshot, LiveValue, OBDSnapshot,
+        SnapshotTarget, VehicleSnapshot,
+    };
+    use crate::commapi::{
+        comm_api::ISO15765Config, mock_api::MockComServer, protocols::DiagProtocol,
+    };
+
+    fn ecu(name: &str, send_id: u32, dtcs: Option<&[&str]>) -> ECUSnapshot {
+        ECUSnapshot {
+            name: name.into(),
+            send_id,
+            responded: true,
+            dtcs: dtcs.map(|d| d.iter().map(|s| s.to_string()).collect()),
+            error: None,
+        }
+    }
+
+    fn snapshot(ecus: Vec<ECUSnapshot>, obd: Option<OBDSnapshot>) -> VehicleSnapshot {
+        VehicleSnapshot {
+            vehicle: "Test vehicle".into(),
+            time: "2021-01-01 00:00:00".into(),
+            ecus,
+            obd,
+            live_values: Vec::new(),
+        }
+    }
+
+    fn live(pid: u8, value: f32) -> LiveValue {
+        LiveValue {
+            pid,
+            name: format!("PID {:02X}", pid),
+            unit: "unit".into(),
+            value,
+        }
+    }
+
+    fn target(protocol: DiagProtocol) -> SnapshotTarget {
+        SnapshotTarget {
+            name: "ECM".into(),
+            cfg: ISO15765Config {
+                send_id: 0x7E0,
+                recv_id: 0x7E8,
+                block_size: 8,
+                sep_time: 20,
+                auto_fc: false,
+                can_fd: None,
+                padding: None,
+                ext_addr: None,
+            },
+            protocol,
+        }
+    }
+
+    #[test]
+    fn snapshot_uses_target_protocol() {
+        // The simulated ECU reports 1 DTC over UDS, and 2 over KWP2000
+        let uds = take_snapshot(
+            Box::new(MockComServer::new()),
+            "Test vehicle",
+            &[target(DiagProtocol::UDS)],
+        );
+        assert_eq!(uds.vehicle, "Test vehicle");
+        assert_eq!(uds.ecus.len(), 1);
+        assert!(uds.ecus[0].responded);
+        assert_eq!(uds.ecus[0].send_id, 0x7E0);
+        assert_eq!(uds.ecus[0].dtcs.as_ref().map(|d| d.len()), Some(1));
+        assert_eq!(uds.ecus[0].error, None);
+        // The simulated ECU only answers the engine speed PID
+        assert_eq!(
+            uds.live_values,
+            vec![LiveValue {
+                pid: 0x0C,
+                name: "Engine speed".into(),
+                unit: "rpm".into(),
+                value: 750.0
+            }]
+        );
+
+        let kwp = take_snapshot(
+            Box::new(MockComServer::new()),
+            "Test vehicle",
+            &[target(DiagProtocol::KWP2000)],
+        );
+        assert!(kwp.ecus[0].responded);
+        assert_eq!(
+            kwp.ecus[0].dtcs,
+            Some(vec!["0300".to_string(), "0171".to_string()])
+        );
+    }
+
+    #[test]
+    fn healthy_vehicle() {
+        let baseline = snapshot(
+            vec![ecu("ECM", 0x7E0, Some(&["P0420"])), ecu("TCM", 0x7E1, None)],
+            None,
+        );
+        // A DTC that has gone since the baseline is not a deviation
+        let current = snapshot(
+            vec![
+                ecu("ECM", 0x7E0, Some(&[])),
+                ecu("TCM", 0x7E1, Some(&["P0700"])),
+            ],
+            None,
+        );
+        assert!(compare_to_baseline(&baseline, &current).is_empty());
+    }
+
+    #[test]
+    fn ecus_and_dtcs() {
+        let mut missing = ecu("ABS", 0x7B0, None);
+        missing.responded = false;
+        missing.error = Some("ISO-TP timeout".into());
+        let baseline = snapshot(
+            vec![
+                ecu("ECM", 0x7E0, Some(&["P0420"])),
+                ecu("ABS", 0x7B0, Some(&[])),
+            ],
+            None,
+        );
+        let current = snapshot(
+            vec![
+                ecu("ECM", 0x7E0, Some(&["P0420", "P0301"])),
+                missing,
+                ecu("SRS", 0x7D0, Some(&["B0001"])),
+            ],
+            None,
+        );
+        assert_eq!(
+            compare_to_baseline(&baseline, &current),
+            vec![
+                Deviation::ECUMissing {
+                    name: "ABS".into(),
+                    send_id: 0x7B0
+                },
+                Deviation::NewDTC {
+                    ecu: "ECM".into(),
+                    dtc: "P0301".into()
+                },
+                Deviation::ECUAdded {
+                    name: "SRS".into(),
+                    send_id: 0x7D0
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn obd_status() {
+        let obd = |mil_on: bool, incomplete: &[&str]| {
+            Some(OBDSnapshot {
+                mil_on,
+                dtc_count: 0,
+                incomplete_monitors: incomplete.iter().map(|s| s.to_string()).collect(),
+            })
+        };
+        let baseline = snapshot(Vec::new(), obd(false, &["Evaporative system"]));
+        let current = snapshot(Vec::new(), obd(true, &["Evaporative system", "Catalyst"]));
+        assert_eq!(
+            compare_to_baseline(&baseline, &current),
+            vec![
+                Deviation::MILOn,
+                Deviation::MonitorIncomplete("Catalyst".into())
+            ]
+        );
+        assert_eq!(
+            compare_to_baseline(&baseline, &snapshot(Vec::new(), None)),
+            vec![Deviation::OBDMissing]
+        );
+    }
+
+    #[test]
+    fn live_values() {
+        let mut baseline = snapshot(Vec::new(), None);
+        baseline.live_values = vec![live(0x05, 90.0), live(0x06, -2.0), live(0x0C, 750.0)];
+        let mut current = snapshot(Vec::new(), None);
+        // Coolant temperature and engine speed are within tolerance, fuel trim is not.
+        // Control module voltage was not read in the baseline, so cannot be compared
+        current.live_values = vec![
+            live(0x05, 95.0),
+            live(0x06, 12.5),
+            live(0x0C, 900.0),
+            live(0x42, 11.0),
+        ];
+        let deviations = compare_to_baseline(&baseline, &current);
+        assert_eq!(
+            deviations,
+            vec![Deviation::LiveValueChanged {
+                name: "PID 06".into(),
+                unit: "unit".into(),
+                before: -2.0,
+                now: 12.5
+            }]
+        );
+        assert_eq!(
+            deviations[0].to_string(),
+            "PID 06 is 12.5 unit, but was -2 unit"
+        );
+        // Values the vehicle stopped reporting are not deviations
+        assert!(compare_to_baseline(&baseline, &snapshot(Vec::new(), None)).is_empty());
+    }
+
+    #[test]
+    fn old_baseline_without_live_values() {
+        let json =
+            r#"{"vehicle":"Test vehicle","time":"2021-01-01 00:00:00","ecus":[],"obd":null}"#;
+        let baseline: VehicleSnapshot = serde_json::from_str(json).unwrap();
+        assert!(baseline.live_values.is_empty());
+    }
+}
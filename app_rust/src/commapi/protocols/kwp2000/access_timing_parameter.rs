@@ -0,0 +1,132 @@
+use crate::commapi::protocols::{ProtocolError, ProtocolResult, ProtocolServer};
+
+use super::KWP2000ECU;
+
+/*
+The service, Access timing parameter ($83), is used to read and change the timing
+parameters of the current diagnostic session. Some ECUs need longer timings than
+the defaults in order to communicate reliably.
+
+P2 - Time between the end of the request and the start of the ECU's response
+P3 - Time between the end of the ECU's response and the next request. Once P3max
+     runs out, the ECU leaves its diagnostic session
+P4 - Time between bytes of a request (Only used on K-Line)
+*/
+
+#[derive(Debug, Copy, Clone)]
+enum TimingParameterID {
+    ReadLimits = 0x00,
+    SetDefaults = 0x01,
+    ReadCurrent = 0x02,
+    SetValues = 0x03,
+}
+
+/// Resolution of P2min, P3min and P4min in milliseconds
+const MIN_RESOLUTION_MS: f32 = 0.5;
+/// Resolution of P2max in milliseconds
+const P2_MAX_RESOLUTION_MS: f32 = 25.0;
+/// Resolution of P3max in milliseconds
+const P3_MAX_RESOLUTION_MS: f32 = 250.0;
+
+/// Timing parameters of a KWP2000 session, in milliseconds
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TimingParameters {
+    pub p2_min: f32,
+    pub p2_max: f32,
+    pub p3_min: f32,
+    pub p3_max: f32,
+    pub p4_min: f32,
+}
+
+impl TimingParameters {
+    fn decode(resp: &[u8]) -> ProtocolResult<Self> {
+        // Response is C3, the timing parameter ID, then the 5 timing parameters
+        if resp.len() < 7 {
+            return Err(ProtocolError::InvalidResponseSize {
+                expect: 7,
+                actual: resp.len(),
+            });
+        }
+        Ok(Self {
+            p2_min: resp[2] as f32 * MIN_RESOLUTION_MS,
+            p2_max: resp[3] as f32 * P2_MAX_RESOLUTION_MS,
+            p3_min: resp[4] as f32 * MIN_RESOLUTION_MS,
+            p3_max: resp[5] as f32 * P3_MAX_RESOLUTION_MS,
+            p4_min: resp[6] as f32 * MIN_RESOLUTION_MS,
+        })
+    }
+
+    fn encode(&self) -> [u8; 5] {
+        let byte = |ms: f32, res: f32| (ms / res).round().max(0.0).min(255.0) as u8;
+        [
+            byte(self.p2_min, MIN_RESOLUTION_MS),
+            byte(self.p2_max, P2_MAX_RESOLUTION_MS),
+            byte(self.p3_min, MIN_RESOLUTION_MS),
+            byte(self.p3_max, P3_MAX_RESOLUTION_MS),
+            byte(self.p4_min, MIN_RESOLUTION_MS),
+        ]
+    }
+
+    /// Parses timing parameters written as 'P2min,P2max,P3min,P3max,P4min' in milliseconds
+    pub fn parse(s: &str) -> Option<Self> {
+        let v = s
+            .split(',')
+            .map(|x| x.trim().parse::<f32>().ok().filter(|ms| *ms >= 0.0))
+            .collect::<Option<Vec<f32>>>()?;
+        match v.as_slice() {
+            [p2_min, p2_max, p3_min, p3_max, p4_min] => Some(Self {
+                p2_min: *p2_min,
+                p2_max: *p2_max,
+                p3_min: *p3_min,
+                p3_max: *p3_max,
+                p4_min: *p4_min,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for TimingParameters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{},{},{},{},{}",
+            self.p2_min, self.p2_max, self.p3_min, self.p3_max, self.p4_min
+        )
+    }
+}
+
+/// Reads the timing parameters the ECU is currently using
+pub fn read_timing_parameters(ecu: &KWP2000ECU) -> ProtocolResult<TimingParameters> {
+    let res = ecu.run_command(
+        super::Service::AccessTimingParameters.into(),
+        &[TimingParameterID::ReadCurrent as u8],
+    )?;
+    TimingParameters::decode(&res)
+}
+
+/// Reads the limits of the timing parameters the ECU accepts
+pub fn read_timing_limits(ecu: &KWP2000ECU) -> ProtocolResult<TimingParameters> {
+    let res = ecu.run_command(
+        super::Service::AccessTimingParameters.into(),
+        &[TimingParameterID::ReadLimits as u8],
+    )?;
+    TimingParameters::decode(&res)
+}
+
+/// Sets the timing parameters of the ECU. Values are rounded to the resolution KWP2000 uses
+pub fn set_timing_parameters(ecu: &KWP2000ECU, timing: &TimingParameters) -> ProtocolResult<()> {
+    let mut args = vec![TimingParameterID::SetValues as u8];
+    args.extend_from_slice(&timing.encode());
+    ecu.run_command(super::Service::AccessTimingParameters.into(), &args)?;
+    Ok(())
+}
+
+/// Returns the ECU to its default timing parameters
+pub fn set_default_timing(ecu: &KWP2000ECU) -> ProtocolResult<()> {
+    ecu.run_command(
+        super::Service::AccessTimingParameters.into(),
+        &[TimingParameterID::SetDefaults as u8],
+    )?;
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use commapi::comm_api::{ComServer, ISO15765Config, ISO15765Data};
+use commapi::comm_api::{ComServer, ISO15765Config};
 use read_ecu_identification::read_code_fingerprint;
 use std::sync::atomic::Ordering::Relaxed;
 use std::{
@@ -10,21 +10,30 @@ use std::{
     time::Instant,
 };
 
-use self::start_diag_session::DiagSession;
+use self::{
+    access_timing_parameter::TimingParameters,
+    reset_detect::{ResetCause, ResetDetector},
+    security_access::{KeyAlgorithm, RestoreResult, SecurityState},
+    start_diag_session::DiagSession,
+};
 use crate::{
     commapi::{self, comm_api::ComServerError},
     windows::diag_session::kwp2000_session::{self, KWP2000DiagSession},
 };
 
 use super::{
-    CautionLevel, CommandError, ECUCommand, ProtocolError, ProtocolResult, ProtocolServer,
-    Selectable, DTC,
+    get_cmd_attempts, Addressing, CautionLevel, CommandError, DTCCategory, ECUCommand,
+    ProtocolError, ProtocolResult, ProtocolServer, Selectable, DTC,
 };
 
+pub mod access_timing_parameter;
 pub mod clear_diag_information;
 pub mod ecu_reset;
 pub mod read_ecu_identification;
 pub mod read_status_dtc;
+pub mod reset_detect;
+pub mod routine_control;
+pub mod security_access;
 pub mod start_diag_session;
 
 // Developed using Daimler's KWP2000 documentation
@@ -59,6 +68,7 @@ pub enum Service {
     TesterPresent,
     ControlDTCSettings,
     ResponseOnEvent,
+    AccessTimingParameters,
     SupplierCustom(u8),
 }
 
@@ -96,6 +106,7 @@ impl Selectable for Service {
             Service::TesterPresent => "Tester present",
             Service::ControlDTCSettings => "Control DTC Settings",
             Service::ResponseOnEvent => "Response on event",
+            Service::AccessTimingParameters => "Access timing parameters",
             Service::SupplierCustom(x) => return format!("Custom({:02X})", x),
         }
         .into()
@@ -108,6 +119,33 @@ impl ToString for Service {
     }
 }
 
+/// What an ECU typically has to be set up for before it accepts a service. This
+/// differs between ECUs, so it is only used to warn the user before sending a request
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct ServiceRequirement {
+    /// Needs a diagnostic session other than the default session
+    pub extended_session: bool,
+    /// Needs security access to be granted first
+    pub security_access: bool,
+}
+
+/// Returns the typical requirements of a service from its service ID
+pub fn get_service_requirement(sid: u8) -> ServiceRequirement {
+    let (extended_session, security_access) = match sid {
+        // Reading memory, security access, communication control and routines
+        0x23 | 0x27 | 0x28 | 0x29 | 0x2C | 0x31 | 0x32 | 0x33 | 0x85 => (true, false),
+        // Actuator tests
+        0x30 => (true, false),
+        // Writing data, coding and flashing
+        0x2E | 0x34 | 0x35 | 0x36 | 0x37 | 0x3B | 0x3D => (true, true),
+        _ => (false, false),
+    };
+    ServiceRequirement {
+        extended_session,
+        security_access,
+    }
+}
+
 impl Into<u8> for Service {
     fn into(self) -> u8 {
         match self {
@@ -138,6 +176,7 @@ impl Into<u8> for Service {
             Service::TesterPresent => 0x3E,
             Service::ControlDTCSettings => 0x85,
             Service::ResponseOnEvent => 0x86,
+            Service::AccessTimingParameters => 0x83,
             Service::SupplierCustom(sid) => sid,
         }
     }
@@ -173,6 +212,7 @@ impl ECUCommand for Service {
             Service::TesterPresent => CautionLevel::None,
             Service::ControlDTCSettings => CautionLevel::Warn,
             Service::ResponseOnEvent => CautionLevel::Warn,
+            Service::AccessTimingParameters => CautionLevel::Warn,
             Service::SupplierCustom(_) => CautionLevel::Warn,
         }
     }
@@ -206,6 +246,7 @@ impl ECUCommand for Service {
             //Self::TesterPresent,
             Self::ControlDTCSettings,
             Self::ResponseOnEvent,
+            Self::AccessTimingParameters,
         ]
     }
 }
@@ -342,16 +383,38 @@ impl CommandError for KwpNegativeCode {
     }
 }
 
+/// Something the diagnostic server thread noticed about the connection to the ECU
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionEvent {
+    /// Information about the connection, EG: Reconnecting after a timeout
+    Message(String),
+    /// The ECU appears to have reset by itself
+    EcuReset(ResetCause),
+}
+
 #[derive(Debug, Clone)]
 pub struct KWP2000ECU {
     iso_tp_settings: ISO15765Config,
     should_run: Arc<AtomicBool>,
     last_error: Arc<RwLock<Option<ProtocolError>>>,
-    cmd_tx: Sender<(u8, Vec<u8>, bool)>,
-    cmd_rx: Arc<Receiver<ProtocolResult<Vec<u8>>>>,
+    cmd_tx: Sender<(u8, Vec<u8>, bool, Addressing)>,
+    /// Responses from the diagnostic server thread. Locked whilst a command is sent and its
+    /// response received, so commands from other threads cannot take each other's responses
+    cmd_rx: Arc<Mutex<Receiver<ProtocolResult<Vec<u8>>>>>,
     curr_session_type: Arc<RwLock<DiagSession>>,
+    /// Security access was granted by the ECU, and has not been reset by a session change
+    security_granted: Arc<AtomicBool>,
+    /// Timing parameters of the session. None if the ECU could not report them
+    timing: Arc<RwLock<Option<TimingParameters>>>,
+    /// Security access sequences accepted by the ECU, so they can be run again after a reconnect
+    security: Arc<RwLock<SecurityState>>,
+    /// Events from the diagnostic server thread about the connection, waiting to be shown
+    events: Arc<RwLock<Vec<ConnectionEvent>>>,
+    /// Addressing used for requests that do not specify one
+    default_addressing: Arc<RwLock<Addressing>>,
+    /// Addressing used for the tester present messages that keep the session alive
+    tester_present_addressing: Arc<RwLock<Addressing>>,
     send_id: u32,
-    cmd_mutex: Arc<Mutex<()>>,
 }
 
 #[derive(Debug, Clone)]
@@ -393,7 +456,7 @@ impl KWP2000ECU {
         Ok(())
     }
 
-    fn set_diag_session_mode(
+    pub(crate) fn set_diag_session_mode(
         &mut self,
         mode: DiagSession,
     ) -> std::result::Result<(), ProtocolError> {
@@ -412,6 +475,247 @@ impl KWP2000ECU {
     pub fn get_session_type(&self) -> DiagSession {
         *self.curr_session_type.read().unwrap()
     }
+
+    /// Reads the ECU's timing parameters, and uses them for the rest of the session
+    pub fn read_timing_parameters(&self) -> ProtocolResult<TimingParameters> {
+        let timing = access_timing_parameter::read_timing_parameters(self)?;
+        *self.timing.write().unwrap() = Some(timing);
+        Ok(timing)
+    }
+
+    /// Changes the ECU's timing parameters, and uses them for the rest of the session
+    pub fn set_timing_parameters(&self, timing: TimingParameters) -> ProtocolResult<()> {
+        access_timing_parameter::set_timing_parameters(self, &timing)?;
+        *self.timing.write().unwrap() = Some(timing);
+        Ok(())
+    }
+
+    /// Timing parameters of the session. None if they are not known
+    pub fn get_timing_parameters(&self) -> Option<TimingParameters> {
+        *self.timing.read().unwrap()
+    }
+
+    /// Time between tester present messages in milliseconds. Has to be shorter
+    /// than P3max, or the ECU will leave its diagnostic session
+    fn get_tester_present_interval(timing: &RwLock<Option<TimingParameters>>) -> u128 {
+        match *timing.read().unwrap() {
+            // P3max of 0 means there is no limit
+            Some(t) if t.p3_max > 0.0 => (t.p3_max as u128 / 2).min(2000),
+            _ => 2000,
+        }
+    }
+
+    /// Returns true if the ECU has granted security access in the current session
+    pub fn is_security_granted(&self) -> bool {
+        self.security_granted.load(Relaxed)
+    }
+
+    /// Sets the algorithm used to compute security access keys. Without one, security
+    /// access can only be restored after a reconnect if the ECU sends the same seed again
+    pub fn set_key_algorithm(&self, algo: Option<KeyAlgorithm>) {
+        self.security.write().unwrap().algorithm = algo;
+    }
+
+    /// Returns the events the diagnostic server has logged about the connection since
+    /// this was last called
+    pub fn take_connection_events(&self) -> Vec<ConnectionEvent> {
+        std::mem::take(&mut *self.events.write().unwrap())
+    }
+
+    /// Sets the addressing used for requests that do not specify one
+    pub fn set_default_addressing(&self, addressing: Addressing) {
+        *self.default_addressing.write().unwrap() = addressing;
+    }
+
+    pub fn get_default_addressing(&self) -> Addressing {
+        *self.default_addressing.read().unwrap()
+    }
+
+    /// Sets the addressing used for tester present messages. Some vehicles expect tester
+    /// present to be sent functionally, to keep every ECU in its session
+    pub fn set_tester_present_addressing(&self, addressing: Addressing) {
+        *self.tester_present_addressing.write().unwrap() = addressing;
+    }
+
+    pub fn get_tester_present_addressing(&self) -> Addressing {
+        *self.tester_present_addressing.read().unwrap()
+    }
+
+    /// Runs a command like [run_command](fn@ProtocolServer::run_command), sent with the given addressing
+    /// rather than the session's default
+    pub fn run_command_addressed(
+        &self,
+        cmd: u8,
+        args: &[u8],
+        addressing: Addressing,
+    ) -> ProtocolResult<Vec<u8>> {
+        addressing.check_request_len(args.len() + 1)?;
+        let cmd_rx = self.cmd_rx.lock().unwrap(); // We are allowed to send / receive!
+        if self
+            .cmd_tx
+            .send((cmd, Vec::from(args), true, addressing))
+            .is_err()
+        {
+            return Err(ProtocolError::CustomError("Channel Tx failed".into()));
+        }
+        let resp = cmd_rx.recv().unwrap()?;
+        if resp[0] == 0x7F {
+            Err(ProtocolError::negative_response::<KwpNegativeCode>(resp[2]))
+        } else {
+            self.track_state(cmd, args, &resp);
+            Ok(resp)
+        }
+    }
+
+    /// Sends a command like [send_command](fn@ProtocolServer::send_command), sent with the given addressing
+    /// rather than the session's default
+    pub fn send_command_addressed(
+        &self,
+        cmd: u8,
+        args: &[u8],
+        addressing: Addressing,
+    ) -> ProtocolResult<()> {
+        addressing.check_request_len(args.len() + 1)?;
+        let cmd_rx = self.cmd_rx.lock().unwrap(); // We are allowed to send / receive!
+        if self
+            .cmd_tx
+            .send((cmd, Vec::from(args), false, addressing))
+            .is_err()
+        {
+            return Err(ProtocolError::CustomError("Channel Tx failed".into()));
+        }
+        cmd_rx.recv().unwrap().map(|_| ())
+    }
+
+    /// Runs a command like [run_command_suppress_response](fn@ProtocolServer::run_command_suppress_response),
+    /// sent with the given addressing rather than the session's default
+    pub fn run_command_suppress_response_addressed(
+        &self,
+        cmd: u8,
+        args: &[u8],
+        addressing: Addressing,
+    ) -> ProtocolResult<()> {
+        let args = Self::suppress_response_args(cmd, args)?;
+        self.send_command_addressed(cmd, &args, addressing)
+    }
+
+    /// Re-runs the last accepted security access sequence after the connection was
+    /// re-established, as the ECU resets security access when it restarts its session
+    fn restore_security(
+        server: &dyn ComServer,
+        cfg: &ISO15765Config,
+        security: &RwLock<SecurityState>,
+        granted: &AtomicBool,
+        events: &RwLock<Vec<ConnectionEvent>>,
+    ) {
+        let state = security.read().unwrap().clone();
+        let unlock = match state.unlock {
+            Some(u) => u,
+            None => return,
+        };
+        let msg = match security_access::restore_security_access(
+            server,
+            cfg,
+            &unlock,
+            state.algorithm.as_ref(),
+        ) {
+            Ok(RestoreResult::AlreadyUnlocked) | Ok(RestoreResult::Restored) => {
+                granted.store(true, Relaxed);
+                format!(
+                    "Security access level {:02X} restored after reconnecting",
+                    unlock.level
+                )
+            }
+            Ok(RestoreResult::NoKey) => format!(
+                "Security access level {:02X} was lost after reconnecting, the ECU sent a new seed. Unlock the ECU again",
+                unlock.level
+            ),
+            Err(e) => format!(
+                "Security access level {:02X} could not be restored after reconnecting - {}",
+                unlock.level,
+                e.get_text()
+            ),
+        };
+        println!("KWP2000 - {}", msg);
+        events.write().unwrap().push(ConnectionEvent::Message(msg));
+    }
+
+    /// Called by the diagnostic server thread when the ECU appears to have reset by itself.
+    /// The ECU is back in its default session, so the session state is reset to match
+    fn on_ecu_reset(
+        cause: ResetCause,
+        session_type: &RwLock<DiagSession>,
+        granted: &AtomicBool,
+        timing: &RwLock<Option<TimingParameters>>,
+        events: &RwLock<Vec<ConnectionEvent>>,
+    ) {
+        println!("KWP2000 - Possible ECU reset! {}", cause);
+        *session_type.write().unwrap() = DiagSession::Default;
+        granted.store(false, Relaxed);
+        *timing.write().unwrap() = None;
+        events
+            .write()
+            .unwrap()
+            .push(ConnectionEvent::EcuReset(cause));
+    }
+
+    /// Keeps track of the ECU's session and security access state after a positive
+    /// response, as they can also be changed by payloads the user sends
+    fn track_state(&self, cmd: u8, args: &[u8], resp: &[u8]) {
+        match (cmd, args.first()) {
+            (0x10, Some(mode)) => {
+                // Changing session resets security access and the timing parameters
+                self.security_granted.store(false, Relaxed);
+                *self.timing.write().unwrap() = None;
+                if let Some(session) = DiagSession::from_id(*mode) {
+                    *self.curr_session_type.write().unwrap() = session;
+                }
+            }
+            // Even sub-functions send the key, so a positive response unlocks the ECU
+            (0x27, Some(level)) => {
+                self.security.write().unwrap().track_response(args, resp);
+                if level % 2 == 0 {
+                    self.security_granted.store(true, Relaxed)
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Decodes the DTCs in a positive response to ReadDTCByStatus, when the DTCs
+    /// were requested in hex format (2 bytes per DTC)
+    pub fn decode_dtcs(resp: &[u8]) -> ProtocolResult<Vec<DTC>> {
+        let count = *resp.get(1).ok_or(ProtocolError::InvalidResponseSize {
+            expect: 2,
+            actual: resp.len(),
+        })? as usize;
+        // DTC is 3 bytes (1 for status, 2 for the ID)
+        let bytes = &resp[2..];
+        if bytes.len() < count * 3 {
+            return Err(ProtocolError::InvalidResponseSize {
+                expect: 2 + count * 3,
+                actual: resp.len(),
+            });
+        }
+        Ok(bytes
+            .chunks_exact(3)
+            .take(count)
+            .map(|dtc| {
+                let status = dtc[2];
+                let flag = (status >> 4 & 0b00000001) > 0;
+                let storage_state = (status >> 6) & 0b0000011;
+                let mil = (status >> 7 & 0b00000001) > 0;
+                DTC {
+                    error: format!("{:02X}{:02X}", dtc[0], dtc[1]),
+                    present: flag,
+                    stored: storage_state > 0,
+                    check_engine_on: mil,
+                    severity: None,
+                    category: DTCCategory::Stored,
+                }
+            })
+            .collect())
+    }
 }
 
 impl ProtocolServer for KWP2000ECU {
@@ -422,11 +726,21 @@ impl ProtocolServer for KWP2000ECU {
         cfg: &ISO15765Config,
     ) -> ProtocolResult<Self> {
         comm_server
-            .open_iso15765_interface(500_000, false, false)
+            .open_iso15765_interface_for(cfg)
             .map_err(ProtocolError::CommError)?;
         comm_server
             .configure_iso15765(cfg)
             .map_err(ProtocolError::CommError)?;
+        // The adapter will not send functional requests without a filter for them
+        let functional_id = Addressing::Functional.get_send_id(cfg);
+        if functional_id != cfg.send_id {
+            if let Err(e) = comm_server.add_functional_filter(functional_id) {
+                println!(
+                    "KWP2000 - Functional requests cannot be sent to {:04X}: {}",
+                    functional_id, e
+                );
+            }
+        }
 
         let should_run = Arc::new(AtomicBool::new(true));
         let should_run_t = should_run.clone();
@@ -435,8 +749,8 @@ impl ProtocolServer for KWP2000ECU {
         let last_error_t = last_error.clone();
 
         let (channel_tx_sender, channel_tx_receiver): (
-            Sender<(u8, Vec<u8>, bool)>,
-            Receiver<(u8, Vec<u8>, bool)>,
+            Sender<(u8, Vec<u8>, bool, Addressing)>,
+            Receiver<(u8, Vec<u8>, bool, Addressing)>,
         ) = mpsc::channel();
         let (channel_rx_sender, channel_rx_receiver): (
             Sender<ProtocolResult<Vec<u8>>>,
@@ -446,44 +760,117 @@ impl ProtocolServer for KWP2000ECU {
         let session_type = Arc::new(RwLock::new(DiagSession::Default));
         let session_type_t = session_type.clone();
 
+        let timing: Arc<RwLock<Option<TimingParameters>>> = Arc::new(RwLock::new(None));
+        let timing_t = timing.clone();
+
+        let security_granted = Arc::new(AtomicBool::new(false));
+        let security_granted_t = security_granted.clone();
+
+        let security = Arc::new(RwLock::new(SecurityState::default()));
+        let security_t = security.clone();
+
+        let events = Arc::new(RwLock::new(Vec::new()));
+        let events_t = events.clone();
+
+        let tester_present_addressing = Arc::new(RwLock::new(Addressing::Physical));
+        let tester_present_addressing_t = tester_present_addressing.clone();
+
         // Enter extended diagnostic session (Full features)
-        let s_id = cfg.send_id;
+        let mut fc_cfg = *cfg;
+        let mut reset_detector =
+            ResetDetector::new(crate::settings::get_settings().reset_detect_gap_ms as u128);
+        let ecu_reset_sid: u8 = Service::ECUReset.into();
         std::thread::spawn(move || {
             println!("Diag server start!");
             let mut timer = Instant::now();
             while should_run_t.load(Relaxed) {
                 if let Ok(data) = channel_tx_receiver.try_recv() {
-                    let res = Self::run_command_iso_tp(
-                        comm_server.as_ref(),
-                        s_id,
-                        data.0,
-                        &data.1,
-                        data.2,
-                    );
+                    let sent = Instant::now();
+                    let res = match data.3 {
+                        Addressing::Physical => Self::run_command_iso_tp_auto_fc(
+                            comm_server.as_ref(),
+                            &mut fc_cfg,
+                            data.0,
+                            &data.1,
+                            data.2,
+                        ),
+                        // Functional requests are single frame, so flow control does not apply
+                        Addressing::Functional => Self::run_command_iso_tp(
+                            comm_server.as_ref(),
+                            &data.3.get_cfg(&fc_cfg),
+                            data.0,
+                            &data.1,
+                            data.2,
+                        ),
+                    };
+                    let in_session = *session_type_t.read().unwrap() != DiagSession::Default;
+                    let reset = match &res {
+                        Ok(_) if data.2 => {
+                            if data.0 == ecu_reset_sid {
+                                reset_detector.on_reset_requested()
+                            }
+                            reset_detector.on_response(Instant::now())
+                        }
+                        Err(ProtocolError::NegativeResponse { nrc, .. }) => {
+                            reset_detector.on_negative_response(*nrc, in_session, Instant::now())
+                        }
+                        Err(e) if e.is_timeout() => {
+                            reset_detector.on_timeout(sent);
+                            None
+                        }
+                        _ => None,
+                    };
+                    if let Some(cause) = reset {
+                        Self::on_ecu_reset(
+                            cause,
+                            &session_type_t,
+                            &security_granted_t,
+                            &timing_t,
+                            &events_t,
+                        );
+                    }
                     if channel_rx_sender.send(res).is_err() {
                         *last_error_t.write().unwrap() =
                             Some(ProtocolError::CustomError("Sender channel died".into()));
                         break;
                     }
                 }
-                if timer.elapsed().as_millis() >= 2000
+                if timer.elapsed().as_millis() >= Self::get_tester_present_interval(&timing_t)
                     && *session_type_t.read().unwrap() != DiagSession::Default
                 {
                     timer = Instant::now();
+                    let sent = timer;
                     //if let Err(e) = Self::run_command_iso_tp(comm_server.as_ref(), 0x001C, Service::TesterPresent.into(), &[0x02], false) {
-                    if let Err(e) = Self::run_command_iso_tp(
+                    let tp_cfg = tester_present_addressing_t.read().unwrap().get_cfg(&fc_cfg);
+                    // The response is waited for, as a tester present the ECU does not answer
+                    // is how a lost connection is noticed, and one it rejects can show a reset
+                    let res = Self::run_command_iso_tp(
                         comm_server.as_ref(),
-                        s_id,
+                        &tp_cfg,
                         Service::TesterPresent.into(),
                         &[0x01],
-                        false,
-                    ) {
-                        if e.is_timeout() {
+                        true,
+                    );
+                    let reset = match res {
+                        Ok(_) => reset_detector.on_response(Instant::now()),
+                        Err(ProtocolError::NegativeResponse { nrc, .. }) => {
+                            let reset =
+                                reset_detector.on_negative_response(nrc, true, Instant::now());
+                            if reset.is_none() {
+                                println!(
+                                    "Warning. ECU did not approve of tester present - NRC {:02X}",
+                                    nrc
+                                );
+                            }
+                            reset
+                        }
+                        Err(e) if e.is_timeout() => {
                             println!("Lost connection with ECU! - {:?}", e);
+                            reset_detector.on_timeout(sent);
                             // Try to regain connection
                             if Self::run_command_iso_tp(
                                 comm_server.as_ref(),
-                                s_id,
+                                &fc_cfg,
                                 Service::StartDiagSession.into(),
                                 &[0x92],
                                 true,
@@ -494,10 +881,39 @@ impl ProtocolServer for KWP2000ECU {
                                 should_run_t.store(false, Relaxed);
                             } else {
                                 println!("Regained connection to the ECU!");
+                                // The ECU restarted its session, so it is locked again
+                                security_granted_t.store(false, Relaxed);
+                                *timing_t.write().unwrap() = None;
+                                let event = match reset_detector.on_response(Instant::now()) {
+                                    Some(cause) => ConnectionEvent::EcuReset(cause),
+                                    None => ConnectionEvent::Message(
+                                        "Lost connection with the ECU, reconnected".into(),
+                                    ),
+                                };
+                                events_t.write().unwrap().push(event);
+                                Self::restore_security(
+                                    comm_server.as_ref(),
+                                    &fc_cfg,
+                                    &security_t,
+                                    &security_granted_t,
+                                    &events_t,
+                                );
                             }
-                        } else {
-                            println!("Warning. ECU did not approve of tester present - {:?}", e);
+                            None
+                        }
+                        Err(e) => {
+                            println!("Warning. Could not send tester present - {:?}", e);
+                            None
                         }
+                    };
+                    if let Some(cause) = reset {
+                        Self::on_ecu_reset(
+                            cause,
+                            &session_type_t,
+                            &security_granted_t,
+                            &timing_t,
+                            &events_t,
+                        );
                     }
                 }
                 std::thread::sleep(std::time::Duration::from_micros(100))
@@ -511,10 +927,15 @@ impl ProtocolServer for KWP2000ECU {
             should_run,
             last_error,
             cmd_tx: channel_tx_sender,
-            cmd_rx: Arc::new(channel_rx_receiver),
+            cmd_rx: Arc::new(Mutex::new(channel_rx_receiver)),
             send_id: cfg.send_id,
             curr_session_type: session_type, // Assumed,
-            cmd_mutex: Arc::new(Mutex::new(())),
+            security_granted,
+            timing,
+            security,
+            events,
+            default_addressing: Arc::new(RwLock::new(Addressing::Physical)),
+            tester_present_addressing,
         };
 
         if let Err(e) = ecu.set_diag_session_mode(DiagSession::Extended) {
@@ -522,6 +943,14 @@ impl ProtocolServer for KWP2000ECU {
             ecu.should_run.store(false, Relaxed);
             return Err(e);
         }
+        // Not all ECUs support reading their timing, in which case the defaults are used
+        match ecu.read_timing_parameters() {
+            Ok(t) => println!("KWP2000 - ECU timing parameters: {:?}", t),
+            Err(e) => println!(
+                "KWP2000 - Could not read timing parameters: {}",
+                e.get_text()
+            ),
+        }
         Ok(ecu)
     }
 
@@ -530,44 +959,31 @@ impl ProtocolServer for KWP2000ECU {
     }
 
     fn run_command(&self, cmd: u8, args: &[u8]) -> ProtocolResult<Vec<u8>> {
-        let _guard = self.cmd_mutex.lock().unwrap(); // We are allowed to send / receive!
-        if self.cmd_tx.send((cmd, Vec::from(args), true)).is_err() {
-            return Err(ProtocolError::CustomError("Channel Tx failed".into()));
-        }
-        let resp = self.cmd_rx.recv().unwrap()?;
-        if resp[0] == 0x7F {
-            let neg_code = KwpNegativeCode::from_byte(resp[2]);
-            Err(ProtocolError::ProtocolError(Box::new(neg_code)))
-        } else {
-            Ok(resp)
-        }
+        self.run_command_addressed(cmd, args, self.get_default_addressing())
+    }
+
+    fn send_command(&self, cmd: u8, args: &[u8]) -> ProtocolResult<()> {
+        self.send_command_addressed(cmd, args, self.get_default_addressing())
+    }
+
+    fn has_sub_function(cmd: u8) -> bool {
+        // 0x31, 0x33 and 0x3B start with a local identifier, not a sub-function
+        matches!(cmd, 0x10 | 0x11 | 0x27 | 0x28 | 0x3E | 0x85)
+    }
+
+    fn run_command_suppress_response(&self, cmd: u8, args: &[u8]) -> ProtocolResult<()> {
+        self.run_command_suppress_response_addressed(cmd, args, self.get_default_addressing())
     }
 
     fn read_errors(&self) -> ProtocolResult<Vec<DTC>> {
         // 0x02 - Request Hex DTCs as 2 bytes
         // 0xFF00 - Request all DTCs (Mandatory per KWP2000)
-        let mut bytes = self.run_command(Service::ReadDTCByStatus.into(), &[0x02, 0xFF, 0x00])?;
-        bytes.drain(..1);
-        let count = bytes[0] as usize;
-        bytes.drain(0..1);
-
-        let mut res: Vec<DTC> = Vec::new();
-        for _ in 0..count {
-            let name = format!("{:02X}{:02X}", bytes[0], bytes[1]);
-            let status = bytes[2];
-            let flag = (status >> 4 & 0b00000001) > 0;
-            let storage_state = (status >> 6) & 0b0000011;
-            let mil = (status >> 7 & 0b00000001) > 0;
-
-            res.push(DTC {
-                error: name,
-                present: flag,
-                stored: storage_state > 0,
-                check_engine_on: mil,
-            });
-            bytes.drain(0..3); // DTC is 3 bytes (1 for status, 2 for the ID)
-        }
-        Ok(res)
+        let bytes = self.run_command_retry(
+            Service::ReadDTCByStatus.into(),
+            &[0x02, 0xFF, 0x00],
+            get_cmd_attempts(),
+        )?;
+        KWP2000ECU::decode_dtcs(&bytes)
     }
 
     fn is_in_diag_session(&self) -> bool {
@@ -581,3 +997,113 @@ impl ProtocolServer for KWP2000ECU {
         }
     }
 }
+
+#[cfg(test)]
+mod kwp2000_test {
+    use std::time::{Duration, Instant};
+
+    use super::{
+        reset_detect::ResetCause, start_diag_session::DiagSession, ConnectionEvent, KWP2000ECU,
+    };
+    use crate::commapi::{
+        comm_api::ISO15765Config,
+        mock_api::MockComServer,
+        protocols::{Addressing, ProtocolServer},
+    };
+
+    fn start_session(server: &MockComServer) -> KWP2000ECU {
+        KWP2000ECU::start_diag_session(
+            Box::new(server.clone()),
+            &ISO15765Config {
+                send_id: 0x7E0,
+                recv_id: 0x7E8,
+                block_size: 8,
+                sep_time: 20,
+                auto_fc: false,
+                can_fd: None,
+                padding: None,
+                ext_addr: None,
+            },
+        )
+        .unwrap()
+    }
+
+    /// Waits until the diagnostic server has logged `count` connection events
+    fn wait_for_events(ecu: &KWP2000ECU, count: usize) -> Vec<ConnectionEvent> {
+        let start = Instant::now();
+        let mut events = Vec::new();
+        while events.len() < count && start.elapsed() < Duration::from_secs(10) {
+            events.extend(ecu.take_connection_events());
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        events
+    }
+
+    #[test]
+    fn restores_security_after_reconnect() {
+        let server = MockComServer::new();
+        let mut ecu = start_session(&server);
+        // The simulated ECU always sends the same seed
+        ecu.run_command(0x27, &[0x01]).unwrap();
+        ecu.run_command(0x27, &[0x02, 0xAB, 0xCD]).unwrap();
+        assert!(ecu.is_security_granted());
+        server.take_sent_iso15765_data();
+
+        // ECU misses the next tester present, then answers again
+        server.script_iso15765_responses(&[None]);
+        let events = wait_for_events(&ecu, 2);
+        assert_eq!(
+            events,
+            vec![
+                ConnectionEvent::Message("Lost connection with the ECU, reconnected".into()),
+                ConnectionEvent::Message(
+                    "Security access level 01 restored after reconnecting".into()
+                ),
+            ]
+        );
+        assert!(ecu.is_security_granted());
+        let sent: Vec<Vec<u8>> = server
+            .take_sent_iso15765_data()
+            .into_iter()
+            .map(|d| d.data)
+            .collect();
+        assert!(sent.starts_with(&[
+            vec![0x3E, 0x01],
+            vec![0x10, 0x92],
+            vec![0x27, 0x01],
+            vec![0x27, 0x02, 0xAB, 0xCD],
+        ]));
+        ecu.exit_diag_session();
+    }
+
+    #[test]
+    fn detects_reset_from_tester_present() {
+        let server = MockComServer::new();
+        let mut ecu = start_session(&server);
+        // ECU reset by itself, so rejects tester present as it is back in its default session
+        server.script_iso15765_responses(&[Some(vec![0x7F, 0x3E, 0x80])]);
+        assert_eq!(
+            wait_for_events(&ecu, 1),
+            vec![ConnectionEvent::EcuReset(ResetCause::SessionLost)]
+        );
+        assert_eq!(ecu.get_session_type(), DiagSession::Default);
+        assert!(!ecu.is_security_granted());
+        ecu.exit_diag_session();
+    }
+
+    #[test]
+    fn sends_functional_requests() {
+        let server = MockComServer::new();
+        let mut ecu = start_session(&server);
+        server.take_sent_iso15765_data();
+        // The mock, like a real adapter, rejects sends to IDs without a flow control filter
+        let resp = ecu.run_command_addressed(0x3E, &[0x01], Addressing::Functional);
+        assert_eq!(resp.unwrap(), vec![0x7E, 0x01]);
+        let functional_id = crate::settings::get_settings().functional_send_id;
+        assert!(server
+            .take_sent_iso15765_data()
+            .iter()
+            .any(|d| d.id == functional_id && d.data == [0x3E, 0x01]));
+        ecu.exit_diag_session();
+    }
+}
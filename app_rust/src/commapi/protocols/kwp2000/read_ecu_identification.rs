@@ -1,4 +1,4 @@
-use crate::commapi::protocols::{ProtocolError, ProtocolResult, ProtocolServer};
+use crate::commapi::protocols::{get_cmd_attempts, ProtocolError, ProtocolResult, ProtocolServer};
 
 use super::{bcd_decode, bcd_decode_slice, KWP2000ECU};
 
@@ -53,7 +53,11 @@ pub struct CodeFingerprint {
 }
 
 pub fn read_dcs_id(ecu: &KWP2000ECU) -> ProtocolResult<DcsEcuId> {
-    let res = ecu.run_command(super::Service::ReadECUID.into(), &[0x86])?;
+    let res = ecu.run_command_retry(
+        super::Service::ReadECUID.into(),
+        &[0x86],
+        get_cmd_attempts(),
+    )?;
     if res.len() != 18 {
         return Err(ProtocolError::InvalidResponseSize {
             expect: 18,
@@ -79,7 +83,11 @@ pub fn read_dcs_id(ecu: &KWP2000ECU) -> ProtocolResult<DcsEcuId> {
 }
 
 pub fn read_dcx_mmc_id(ecu: &KWP2000ECU) -> ProtocolResult<DcxMmcECUId> {
-    let res = ecu.run_command(super::Service::ReadECUID.into(), &[0x87])?;
+    let res = ecu.run_command_retry(
+        super::Service::ReadECUID.into(),
+        &[0x87],
+        get_cmd_attempts(),
+    )?;
     if res.len() != 22 {
         return Err(ProtocolError::InvalidResponseSize {
             expect: 22,
@@ -97,7 +105,11 @@ pub fn read_dcx_mmc_id(ecu: &KWP2000ECU) -> ProtocolResult<DcxMmcECUId> {
 }
 
 pub fn read_original_vin(ecu: &KWP2000ECU) -> ProtocolResult<String> {
-    let res = ecu.run_command(super::Service::ReadECUID.into(), &[0x88])?;
+    let res = ecu.run_command_retry(
+        super::Service::ReadECUID.into(),
+        &[0x88],
+        get_cmd_attempts(),
+    )?;
     if res.len() != 19 {
         return Err(ProtocolError::InvalidResponseSize {
             expect: 22,
@@ -108,7 +120,11 @@ pub fn read_original_vin(ecu: &KWP2000ECU) -> ProtocolResult<String> {
 }
 
 pub fn read_variant_code(ecu: &KWP2000ECU) -> ProtocolResult<u32> {
-    let res = ecu.run_command(super::Service::ReadECUID.into(), &[0x90])?;
+    let res = ecu.run_command_retry(
+        super::Service::ReadECUID.into(),
+        &[0x90],
+        get_cmd_attempts(),
+    )?;
     if res.len() != 6 {
         return Err(ProtocolError::InvalidResponseSize {
             expect: 22,
@@ -119,7 +135,11 @@ pub fn read_variant_code(ecu: &KWP2000ECU) -> ProtocolResult<u32> {
 }
 
 pub fn read_current_vin(ecu: &KWP2000ECU) -> ProtocolResult<String> {
-    let res = ecu.run_command(super::Service::ReadECUID.into(), &[0x90])?;
+    let res = ecu.run_command_retry(
+        super::Service::ReadECUID.into(),
+        &[0x90],
+        get_cmd_attempts(),
+    )?;
     if res.len() != 19 {
         return Err(ProtocolError::InvalidResponseSize {
             expect: 22,
@@ -130,7 +150,11 @@ pub fn read_current_vin(ecu: &KWP2000ECU) -> ProtocolResult<String> {
 }
 
 pub fn read_calibration_id(ecu: &KWP2000ECU) -> ProtocolResult<String> {
-    let res = ecu.run_command(super::Service::ReadECUID.into(), &[0x96])?;
+    let res = ecu.run_command_retry(
+        super::Service::ReadECUID.into(),
+        &[0x96],
+        get_cmd_attempts(),
+    )?;
     if res.len() != 18 {
         return Err(ProtocolError::InvalidResponseSize {
             expect: 22,
@@ -141,7 +165,11 @@ pub fn read_calibration_id(ecu: &KWP2000ECU) -> ProtocolResult<String> {
 }
 
 pub fn read_calibration_verification_number(ecu: &KWP2000ECU) -> ProtocolResult<[u8; 4]> {
-    let res = ecu.run_command(super::Service::ReadECUID.into(), &[0x96])?;
+    let res = ecu.run_command_retry(
+        super::Service::ReadECUID.into(),
+        &[0x96],
+        get_cmd_attempts(),
+    )?;
     if res.len() != 6 {
         return Err(ProtocolError::InvalidResponseSize {
             expect: 22,
@@ -160,7 +188,8 @@ pub fn read_data_fingerprint(ecu: &KWP2000ECU) -> ProtocolResult<CodeFingerprint
 }
 
 fn read_fingerprint(ecu: &KWP2000ECU, cmd: u8) -> ProtocolResult<CodeFingerprint> {
-    let mut res = ecu.run_command(super::Service::ReadECUID.into(), &[cmd])?;
+    let mut res =
+        ecu.run_command_retry(super::Service::ReadECUID.into(), &[cmd], get_cmd_attempts())?;
     if res.len() < 4 {
         return Err(ProtocolError::InvalidResponseSize {
             expect: 4,
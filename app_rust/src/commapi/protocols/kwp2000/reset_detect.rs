@@ -0,0 +1,171 @@
+use std::time::Instant;
+
+/*
+An ECU which resets by itself (EG: From a brownout) drops out of its diagnostic session and
+loses security access. From the tester's side, this looks like the ECU going silent for a
+while, then responding again as if it had just started.
+
+Resets are dangerous whilst the ECU is being programmed, so they are reported separately
+from normal timeouts.
+*/
+
+/// Negative response code for a service not supported in the active diagnostic session
+const NRC_WRONG_SESSION: u8 = 0x80;
+
+/// Why the ECU is thought to have reset
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResetCause {
+    /// The ECU rejected a request as not supported in its active session, whilst it
+    /// should still be in a non default session
+    SessionLost,
+    /// The ECU stopped responding for `gap_ms`, then responded again
+    ResponseGap { gap_ms: u128 },
+}
+
+impl std::fmt::Display for ResetCause {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResetCause::SessionLost => {
+                write!(f, "ECU unexpectedly returned to its default session")
+            }
+            ResetCause::ResponseGap { gap_ms } => {
+                write!(
+                    f,
+                    "ECU stopped responding for {}ms before responding again",
+                    gap_ms
+                )
+            }
+        }
+    }
+}
+
+/// Watches the ECU's responses for signs that it reset by itself
+#[derive(Debug, Clone)]
+pub struct ResetDetector {
+    /// Gaps in responses at least this long are treated as a reset. 0 disables this check
+    gap_ms: u128,
+    /// Time the first request the ECU did not respond to was sent
+    silent_since: Option<Instant>,
+    /// The ECU was asked to reset, so the next reset is expected
+    reset_requested: bool,
+}
+
+impl ResetDetector {
+    pub fn new(gap_ms: u128) -> Self {
+        Self {
+            gap_ms,
+            silent_since: None,
+            reset_requested: false,
+        }
+    }
+
+    /// Records that the ECU did not respond to a request sent at `sent`
+    pub fn on_timeout(&mut self, sent: Instant) {
+        self.silent_since.get_or_insert(sent);
+    }
+
+    /// Records that the ECU accepted a request to reset, so the reset that follows is not reported
+    pub fn on_reset_requested(&mut self) {
+        self.reset_requested = true;
+    }
+
+    /// Records a response from the ECU at `now`, checking how long it was silent for beforehand
+    pub fn on_response(&mut self, now: Instant) -> Option<ResetCause> {
+        let gap_ms = now.duration_since(self.silent_since.take()?).as_millis();
+        if self.gap_ms == 0 || gap_ms < self.gap_ms || self.take_reset_requested() {
+            return None;
+        }
+        Some(ResetCause::ResponseGap { gap_ms })
+    }
+
+    /// Records a negative response from the ECU at `now`. `in_session` is true if the ECU
+    /// should be in a non default session
+    pub fn on_negative_response(
+        &mut self,
+        nrc: u8,
+        in_session: bool,
+        now: Instant,
+    ) -> Option<ResetCause> {
+        if let Some(cause) = self.on_response(now) {
+            return Some(cause);
+        }
+        if nrc != NRC_WRONG_SESSION || !in_session || self.take_reset_requested() {
+            return None;
+        }
+        Some(ResetCause::SessionLost)
+    }
+
+    fn take_reset_requested(&mut self) -> bool {
+        std::mem::replace(&mut self.reset_requested, false)
+    }
+}
+
+#[cfg(test)]
+mod reset_detect_test {
+    use super::*;
+    use std::time::Duration;
+
+    fn after(start: Instant, ms: u64) -> Instant {
+        start + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_response_gap() {
+        let start = Instant::now();
+        let mut detector = ResetDetector::new(2500);
+        // Responding normally
+        assert_eq!(detector.on_response(start), None);
+        detector.on_timeout(start);
+        // Later timeouts do not move the start of the gap
+        detector.on_timeout(after(start, 1000));
+        assert_eq!(
+            detector.on_response(after(start, 3000)),
+            Some(ResetCause::ResponseGap { gap_ms: 3000 })
+        );
+        // Gap is cleared once the ECU responds
+        assert_eq!(detector.on_response(after(start, 4000)), None);
+
+        // Short gaps are normal timeouts
+        detector.on_timeout(start);
+        assert_eq!(detector.on_response(after(start, 1200)), None);
+
+        // Disabled
+        let mut detector = ResetDetector::new(0);
+        detector.on_timeout(start);
+        assert_eq!(detector.on_response(after(start, 10000)), None);
+    }
+
+    #[test]
+    fn test_session_lost() {
+        let start = Instant::now();
+        let mut detector = ResetDetector::new(2500);
+        assert_eq!(
+            detector.on_negative_response(0x80, true, start),
+            Some(ResetCause::SessionLost)
+        );
+        assert_eq!(detector.on_negative_response(0x80, false, start), None);
+        assert_eq!(detector.on_negative_response(0x11, true, start), None);
+        // A long gap is reported first
+        detector.on_timeout(start);
+        assert_eq!(
+            detector.on_negative_response(0x80, true, after(start, 2500)),
+            Some(ResetCause::ResponseGap { gap_ms: 2500 })
+        );
+    }
+
+    #[test]
+    fn test_requested_reset() {
+        let start = Instant::now();
+        let mut detector = ResetDetector::new(2500);
+        detector.on_reset_requested();
+        detector.on_timeout(start);
+        assert_eq!(detector.on_response(after(start, 5000)), None);
+        // Only the first reset after the request is expected
+        detector.on_timeout(start);
+        assert!(detector.on_response(after(start, 5000)).is_some());
+
+        detector.on_reset_requested();
+        assert_eq!(detector.on_negative_response(0x80, true, start), None);
+        assert!(detector.on_negative_response(0x80, true, start).is_some());
+    }
+}
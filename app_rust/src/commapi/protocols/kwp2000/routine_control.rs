@@ -0,0 +1,93 @@
+use common::schema::diag::service::Parameter;
+
+use crate::commapi::protocols::{ProtocolError, ProtocolResult, ProtocolServer};
+
+use super::KWP2000ECU;
+
+/*
+The services, Start routine by local ID ($31) and Request routine results
+by local ID ($33), are used to start a routine (EG: Compression test) in the ECU
+and to read back the results of the routine once it has completed.
+
+The layout of the result bytes is specific to the ECU and routine, so it has
+to come from the ECU's definition (CBF) or a user defined schema
+*/
+
+/// Single named value from a routine's results
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoutineResultField {
+    pub name: String,
+    pub value: String,
+}
+
+/// Results returned by a routine
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoutineResult {
+    /// Full positive response from the ECU
+    pub raw: Vec<u8>,
+    /// Decoded values. Empty if no layout was known for the routine
+    pub fields: Vec<RoutineResultField>,
+}
+
+impl RoutineResult {
+    /// Decodes a routine response using the provided layout. The start bits of the layout's
+    /// parameters are relative to the start of the response (Including the service ID).
+    ///
+    /// If the layout is empty, or any parameter fails to decode, no fields are returned
+    /// and only the raw bytes are available
+    pub fn decode(raw: Vec<u8>, layout: &[Parameter]) -> Self {
+        let fields = layout
+            .iter()
+            .map(|p| {
+                p.decode_value_to_string(&raw)
+                    .map(|value| RoutineResultField {
+                        name: p.name.clone(),
+                        value,
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap_or_default();
+        Self { raw, fields }
+    }
+
+    /// Returns a human readable representation of the results.
+    /// EG: 'cyl1=12.1 bar, cyl2=12.4 bar'. Falls back to hex if the results could not be decoded
+    pub fn to_display_string(&self) -> String {
+        if self.fields.is_empty() {
+            format!("{:02X?}", self.raw)
+        } else {
+            self.fields
+                .iter()
+                .map(|f| format!("{}={}", f.name, f.value))
+                .collect::<Vec<String>>()
+                .join(", ")
+        }
+    }
+}
+
+/// Starts a routine in the ECU, returning the routine entry status from the ECU
+pub fn start_routine(ecu: &KWP2000ECU, local_id: u8, args: &[u8]) -> ProtocolResult<Vec<u8>> {
+    let mut payload = vec![local_id];
+    payload.extend_from_slice(args);
+    let res = ecu.run_command(super::Service::StartRoutineByLocalID.into(), &payload)?;
+    if res.len() < 2 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: 2,
+            actual: res.len(),
+        });
+    }
+    Ok(res[2..].to_vec())
+}
+
+/// Requests the results of a previously started routine, decoding them with the provided layout
+pub fn request_routine_results(
+    ecu: &KWP2000ECU,
+    local_id: u8,
+    layout: &[Parameter],
+) -> ProtocolResult<RoutineResult> {
+    let res = ecu.run_command(
+        super::Service::RequestRoutineResultsByLocalID.into(),
+        &[local_id],
+    )?;
+    Ok(RoutineResult::decode(res, layout))
+}
@@ -0,0 +1,314 @@
+use std::sync::Arc;
+
+use crate::commapi::comm_api::{ComServer, ISO15765Config};
+use crate::commapi::protocols::{ProtocolResult, ProtocolServer};
+
+use super::KWP2000ECU;
+
+/*
+The service, Security access ($27), unlocks protected functions of the ECU.
+An odd sub-function requests a seed for a security level, and the following even
+sub-function sends the key computed from that seed.
+
+Security access is reset by the ECU whenever it leaves its diagnostic session, so
+if the connection drops, the unlock sequence has to be run again once reconnected.
+*/
+
+/// Computes the key for a security level from the seed the ECU sent.
+/// Returns None if the algorithm does not support the level
+pub type KeyAlgorithm = Arc<dyn Fn(u8, &[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+/// A step of a [KeyAlgorithmParams] algorithm
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyStep {
+    Xor(u32),
+    Add(u32),
+    Sub(u32),
+    Mul(u32),
+    RotateLeft(u32),
+    RotateRight(u32),
+    Not,
+}
+
+/// A key algorithm made of simple steps, applied in order to the seed read as a big endian
+/// number. Results wrap around at the seed's size, and the key is the same size as the seed.
+///
+/// Written as steps separated by ',', EG: 'xor 5A5A, rol 3, add 1234'. Values of xor, add,
+/// sub and mul are hex, rol and ror take a number of bits. 'not' inverts every bit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyAlgorithmParams {
+    pub steps: Vec<KeyStep>,
+}
+
+impl KeyAlgorithmParams {
+    /// Longest seed (In bytes) the steps can be applied to
+    pub const MAX_SEED_LEN: usize = 4;
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let steps = s
+            .split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(Self::parse_step)
+            .collect::<Result<Vec<KeyStep>, String>>()?;
+        if steps.is_empty() {
+            return Err("No algorithm steps entered".into());
+        }
+        Ok(Self { steps })
+    }
+
+    fn parse_step(step: &str) -> Result<KeyStep, String> {
+        let mut parts = step.split_whitespace();
+        let op = parts.next().unwrap_or_default().to_lowercase();
+        let arg = parts.next();
+        if parts.next().is_some() {
+            return Err(format!("'{}' has too many values", step));
+        }
+        let hex = || {
+            arg.and_then(|a| u32::from_str_radix(a.trim_start_matches("0x"), 16).ok())
+                .ok_or_else(|| format!("'{}' needs a hex value", step))
+        };
+        let bits = || {
+            arg.and_then(|a| a.parse::<u32>().ok())
+                .ok_or_else(|| format!("'{}' needs a number of bits", step))
+        };
+        Ok(match op.as_str() {
+            "xor" => KeyStep::Xor(hex()?),
+            "add" => KeyStep::Add(hex()?),
+            "sub" => KeyStep::Sub(hex()?),
+            "mul" => KeyStep::Mul(hex()?),
+            "rol" => KeyStep::RotateLeft(bits()?),
+            "ror" => KeyStep::RotateRight(bits()?),
+            "not" if arg.is_none() => KeyStep::Not,
+            _ => return Err(format!("Unknown algorithm step '{}'", step)),
+        })
+    }
+
+    /// Computes the key for a seed. None if the seed is empty or longer than [MAX_SEED_LEN](Self::MAX_SEED_LEN)
+    pub fn compute(&self, seed: &[u8]) -> Option<Vec<u8>> {
+        if seed.is_empty() || seed.len() > Self::MAX_SEED_LEN {
+            return None;
+        }
+        let bits = seed.len() as u32 * 8;
+        let mask = u32::MAX >> (32 - bits);
+        let rotate_left = |v: u32, n: u32| match n % bits {
+            0 => v,
+            n => (v << n) | (v >> (bits - n)),
+        };
+        let mut v = seed.iter().fold(0u32, |acc, x| acc << 8 | *x as u32);
+        for step in self.steps.iter() {
+            v = match *step {
+                KeyStep::Xor(x) => v ^ x,
+                KeyStep::Add(x) => v.wrapping_add(x),
+                KeyStep::Sub(x) => v.wrapping_sub(x),
+                KeyStep::Mul(x) => v.wrapping_mul(x),
+                KeyStep::RotateLeft(n) => rotate_left(v, n),
+                KeyStep::RotateRight(n) => rotate_left(v, bits - n % bits),
+                KeyStep::Not => !v,
+            } & mask;
+        }
+        Some(v.to_be_bytes()[Self::MAX_SEED_LEN - seed.len()..].to_vec())
+    }
+
+    /// Returns the steps as a [KeyAlgorithm]. The same steps are used for every security level
+    pub fn to_algorithm(&self) -> KeyAlgorithm {
+        let params = self.clone();
+        Arc::new(move |_, seed| params.compute(seed))
+    }
+}
+
+/// A security access sequence which the ECU accepted
+#[derive(Debug, Clone, PartialEq)]
+pub struct SecurityUnlock {
+    /// Sub-function used to request the seed (Always odd)
+    pub level: u8,
+    /// Seed the ECU sent
+    pub seed: Vec<u8>,
+    /// Key that unlocked the ECU
+    pub key: Vec<u8>,
+}
+
+impl SecurityUnlock {
+    /// Picks the key to send for a new seed. The key algorithm is used if there is one,
+    /// otherwise the key that was accepted before can only be reused if the ECU sent the
+    /// same seed again
+    pub fn key_for_seed(&self, seed: &[u8], algo: Option<&KeyAlgorithm>) -> Option<Vec<u8>> {
+        match algo {
+            Some(f) => f(self.level, seed),
+            None if seed == self.seed.as_slice() => Some(self.key.clone()),
+            None => None,
+        }
+    }
+}
+
+/// Security access sequences seen during the session, and how to run them again
+#[derive(Default, Clone)]
+pub struct SecurityState {
+    /// Level and seed of the last seed request, waiting for its key to be accepted
+    pending_seed: Option<(u8, Vec<u8>)>,
+    /// Last sequence the ECU accepted
+    pub unlock: Option<SecurityUnlock>,
+    /// Key algorithm provided by the user
+    pub algorithm: Option<KeyAlgorithm>,
+}
+
+impl std::fmt::Debug for SecurityState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecurityState")
+            .field("pending_seed", &self.pending_seed)
+            .field("unlock", &self.unlock)
+            .field("algorithm", &self.algorithm.is_some())
+            .finish()
+    }
+}
+
+impl SecurityState {
+    /// Records a positive response to Security access, so that a sequence the user sent
+    /// by hand can also be run again after a reconnect
+    pub fn track_response(&mut self, args: &[u8], resp: &[u8]) {
+        match args.first() {
+            Some(level) if level % 2 == 1 => {
+                self.pending_seed = Some((*level, resp.get(2..).unwrap_or(&[]).to_vec()))
+            }
+            Some(level) => {
+                if let Some((seed_level, seed)) = self.pending_seed.take() {
+                    if seed_level + 1 == *level {
+                        self.unlock = Some(SecurityUnlock {
+                            level: seed_level,
+                            seed,
+                            key: args[1..].to_vec(),
+                        })
+                    }
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+/// Result of trying to restore security access after a reconnect
+#[derive(Debug, Clone, PartialEq)]
+pub enum RestoreResult {
+    /// The ECU reported it was still unlocked
+    AlreadyUnlocked,
+    /// The unlock sequence was run again and the ECU accepted the key
+    Restored,
+    /// There is no way to compute a key for the new seed
+    NoKey,
+}
+
+/// Runs a stored security access sequence again directly on the ISO-TP interface.
+/// This is used by the diagnostic server thread, so it must not go through the ECU's
+/// command channel
+pub(crate) fn restore_security_access(
+    server: &dyn ComServer,
+    cfg: &ISO15765Config,
+    unlock: &SecurityUnlock,
+    algo: Option<&KeyAlgorithm>,
+) -> ProtocolResult<RestoreResult> {
+    let res = KWP2000ECU::run_command_iso_tp(
+        server,
+        cfg,
+        super::Service::SecurityAccess.into(),
+        &[unlock.level],
+        true,
+    )?;
+    let seed = res.get(2..).unwrap_or(&[]);
+    // A seed of all zeros means the ECU is already unlocked at this level
+    if !seed.is_empty() && seed.iter().all(|x| *x == 0) {
+        return Ok(RestoreResult::AlreadyUnlocked);
+    }
+    let key = match unlock.key_for_seed(seed, algo) {
+        Some(k) => k,
+        None => return Ok(RestoreResult::NoKey),
+    };
+    let mut args = vec![unlock.level + 1];
+    args.extend_from_slice(&key);
+    KWP2000ECU::run_command_iso_tp(
+        server,
+        cfg,
+        super::Service::SecurityAccess.into(),
+        &args,
+        true,
+    )
+    .map(|_| RestoreResult::Restored)
+}
+
+#[cfg(test)]
+mod security_access_test {
+    use super::*;
+
+    fn unlock() -> SecurityUnlock {
+        SecurityUnlock {
+            level: 0x01,
+            seed: vec![0x12, 0x34],
+            key: vec![0xAB, 0xCD],
+        }
+    }
+
+    #[test]
+    fn test_reuse_key_for_static_seed() {
+        assert_eq!(
+            unlock().key_for_seed(&[0x12, 0x34], None),
+            Some(vec![0xAB, 0xCD])
+        );
+        assert_eq!(unlock().key_for_seed(&[0x56, 0x78], None), None);
+    }
+
+    #[test]
+    fn test_track_response() {
+        let mut state = SecurityState::default();
+        state.track_response(&[0x01], &[0x67, 0x01, 0x12, 0x34]);
+        assert_eq!(state.unlock, None);
+        state.track_response(&[0x02, 0xAB, 0xCD], &[0x67, 0x02]);
+        assert_eq!(state.unlock, Some(unlock()));
+        // Key without a matching seed request is not recorded
+        state.track_response(&[0x04, 0x00], &[0x67, 0x04]);
+        assert_eq!(state.unlock, Some(unlock()));
+    }
+
+    #[test]
+    fn test_key_algorithm() {
+        let algo: KeyAlgorithm = Arc::new(|level, seed| {
+            if level != 0x01 {
+                return None;
+            }
+            Some(seed.iter().map(|x| x ^ 0xFF).collect())
+        });
+        assert_eq!(
+            unlock().key_for_seed(&[0x56, 0x78], Some(&algo)),
+            Some(vec![0xA9, 0x87])
+        );
+    }
+
+    #[test]
+    fn test_key_algorithm_params() {
+        let params = KeyAlgorithmParams::parse("xor 00FF, rol 4, add 1").unwrap();
+        // 0x1234 ^ 0x00FF = 0x12CB, rotated left by 4 = 0x2CB1, + 1 = 0x2CB2
+        assert_eq!(params.compute(&[0x12, 0x34]), Some(vec![0x2C, 0xB2]));
+        // Results wrap around at the size of the seed
+        let params = KeyAlgorithmParams::parse("add 0x10, mul 2, not").unwrap();
+        assert_eq!(params.compute(&[0xFF]), Some(vec![0xE1]));
+        let params = KeyAlgorithmParams::parse("ror 8, sub 1").unwrap();
+        assert_eq!(
+            params.compute(&[0x00, 0x00, 0x00, 0x01]),
+            Some(vec![0x00, 0xFF, 0xFF, 0xFF])
+        );
+        assert_eq!(params.compute(&[]), None);
+        assert_eq!(params.compute(&[0x00; 5]), None);
+        assert_eq!(
+            unlock().key_for_seed(&[0x12, 0x34], Some(&params.to_algorithm())),
+            Some(vec![0x34, 0x11])
+        );
+    }
+
+    #[test]
+    fn test_key_algorithm_params_invalid() {
+        assert!(KeyAlgorithmParams::parse("").is_err());
+        assert!(KeyAlgorithmParams::parse("xor").is_err());
+        assert!(KeyAlgorithmParams::parse("xor ZZ").is_err());
+        assert!(KeyAlgorithmParams::parse("rol 3 4").is_err());
+        assert!(KeyAlgorithmParams::parse("not 1").is_err());
+        assert!(KeyAlgorithmParams::parse("swap").is_err());
+    }
+}
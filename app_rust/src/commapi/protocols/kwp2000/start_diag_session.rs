@@ -1,4 +1,4 @@
-use crate::commapi::protocols::{ProtocolResult, ProtocolServer};
+use crate::commapi::protocols::{ProtocolResult, ProtocolServer, SessionSupport};
 
 use super::KWP2000ECU;
 
@@ -16,8 +16,54 @@ pub enum DiagSession {
     Extended = 0x92,
 }
 
+impl DiagSession {
+    /// Returns the session with the ID, None if it is not a known session
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0x81 => Some(Self::Default),
+            0x85 => Some(Self::Flash),
+            0x89 => Some(Self::Standby),
+            0x90 => Some(Self::Passive),
+            0x92 => Some(Self::Extended),
+            _ => None,
+        }
+    }
+}
+
 /// Attempts to set the diagnostic session type of the ECU
 pub fn set_diag_session(ecu: &KWP2000ECU, mode: DiagSession) -> ProtocolResult<()> {
     ecu.run_command(super::Service::StartDiagSession.into(), &[mode as u8])?;
     Ok(())
 }
+
+/// Sessions tried when probing which sessions the ECU supports
+const PROBE_SESSIONS: [DiagSession; 5] = [
+    DiagSession::Default,
+    DiagSession::Flash,
+    DiagSession::Standby,
+    DiagSession::Passive,
+    DiagSession::Extended,
+];
+
+/// Works out which diagnostic sessions the ECU supports, by asking it to enter each one.
+/// After each session the ECU enters, it is returned to the default session. Once done, the
+/// ECU is put back in the session it was in before probing
+pub fn probe_sessions(ecu: &mut KWP2000ECU) -> Vec<SessionSupport> {
+    let prev = ecu.get_session_type();
+    let res: Vec<SessionSupport> = PROBE_SESSIONS
+        .iter()
+        .map(|mode| {
+            let res = set_diag_session(ecu, *mode);
+            if res.is_ok() && *mode != DiagSession::Default {
+                let _ = set_diag_session(ecu, DiagSession::Default);
+            }
+            SessionSupport::new(format!("{:?}", mode), &res)
+        })
+        .collect();
+    if prev != DiagSession::Default {
+        if let Err(e) = ecu.set_diag_session_mode(prev) {
+            eprintln!("Could not return ECU to {:?} session: {}", prev, e);
+        }
+    }
+    res
+}
@@ -1,33 +1,97 @@
 use std::{fmt::Display, time::Instant};
 
 use comm_api::{ComServerError, ISO15765Config};
+use common::schema::diag::service::RequiredSession;
 use kwp2000::KWP2000ECU;
+use serde::{Deserialize, Serialize};
 use uds::UDSECU;
 
-use super::comm_api::{self, ComServer, ISO15765Data};
+use super::{
+    comm_api::{self, CanFrame, ComServer},
+    iso_tp,
+};
 
+pub mod baseline;
 pub mod kwp2000;
 pub mod obd2;
+pub mod registry;
 pub mod uds;
 pub mod vin;
+pub mod wake_up;
 
 #[derive(Debug)]
 pub enum ProtocolError {
     CommError(comm_api::ComServerError),
-    ProtocolError(Box<dyn CommandError>),
+    /// ECU gave a negative response. Contains the negative response code, and its description
+    NegativeResponse {
+        nrc: u8,
+        error: Box<dyn CommandError>,
+    },
     CustomError(String),
     InvalidResponseSize { expect: usize, actual: usize },
+    /// Response did not match the expected response. Contains the full response from the ECU
+    ResponseMismatch { expect: Vec<u8>, actual: Vec<u8> },
     Timeout,
 }
 
+/// What caused a [ProtocolError]. Lets callers handle errors differently depending on their cause
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ProtocolErrorKind {
+    /// ECU did not respond in time
+    Timeout,
+    /// ECU rejected the request with a negative response code not covered by the other kinds
+    NegativeResponse { nrc: u8 },
+    /// Error with the adapter or the vehicle network
+    TransportError,
+    /// ECU does not support the service or sub-function (In its current session)
+    NotSupported,
+    /// ECU requires security access, or rejected the security key
+    SecurityDenied,
+    /// ECU is busy and the request should be repeated later
+    Busy,
+    /// Any other error
+    Other,
+}
+
 impl ProtocolError {
+    /// Creates a negative response error from the negative response code returned by the ECU
+    pub fn negative_response<E: CommandError + 'static>(nrc: u8) -> Self {
+        ProtocolError::NegativeResponse {
+            nrc,
+            error: Box::new(E::from_byte(nrc)),
+        }
+    }
+
     pub fn is_timeout(&self) -> bool {
-        match &self {
-            ProtocolError::CommError(_) => false,
-            ProtocolError::ProtocolError(_) => false,
-            ProtocolError::CustomError(_) => false,
-            ProtocolError::InvalidResponseSize { expect, actual } => false,
-            ProtocolError::Timeout => true,
+        self.kind() == ProtocolErrorKind::Timeout
+    }
+
+    /// Returns true if repeating the request that caused this error may succeed. Negative
+    /// responses other than 'busy, repeat request' are the ECU's final answer, so are not transient
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self.kind(),
+            ProtocolErrorKind::Timeout
+                | ProtocolErrorKind::TransportError
+                | ProtocolErrorKind::Busy
+        )
+    }
+
+    /// Returns what caused the error. Negative response codes shared by KWP2000 and UDS
+    /// are grouped into their matching kind
+    pub fn kind(&self) -> ProtocolErrorKind {
+        match self {
+            ProtocolError::CommError(_) => ProtocolErrorKind::TransportError,
+            ProtocolError::NegativeResponse { nrc, .. } => match nrc {
+                0x11 | 0x12 | 0x7E | 0x7F | 0x80 => ProtocolErrorKind::NotSupported,
+                0x33 | 0x35 | 0x36 | 0x37 => ProtocolErrorKind::SecurityDenied,
+                0x21 | 0x78 => ProtocolErrorKind::Busy,
+                nrc => ProtocolErrorKind::NegativeResponse { nrc: *nrc },
+            },
+            ProtocolError::Timeout => ProtocolErrorKind::Timeout,
+            ProtocolError::CustomError(_)
+            | ProtocolError::InvalidResponseSize { .. }
+            | ProtocolError::ResponseMismatch { .. } => ProtocolErrorKind::Other,
         }
     }
 }
@@ -45,18 +109,58 @@ impl ProtocolError {
     pub fn get_text(&self) -> String {
         match self {
             ProtocolError::CommError(e) => e.to_string(),
-            ProtocolError::ProtocolError(e) => e.get_desc(),
+            ProtocolError::NegativeResponse { error, .. } => error.get_desc(),
             ProtocolError::Timeout => "Communication timeout".into(),
             ProtocolError::CustomError(s) => s.clone(),
             ProtocolError::InvalidResponseSize { expect, actual } => {
                 format!("Expected {} bytes, got {} bytes", expect, actual)
             }
+            ProtocolError::ResponseMismatch { expect, actual } => {
+                format!("Expected response {:02X?}, got {:02X?}", expect, actual)
+            }
         }
     }
 }
 
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.get_text())
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
 pub type ProtocolResult<T> = std::result::Result<T, ProtocolError>;
 
+/// Checks if a response matches an expected response.
+///
+/// ## Params
+/// * resp - Response from the ECU
+/// * expected - Expected response
+/// * mask - Optional bit mask to apply to each byte before comparing. Bits that are 0 in the mask
+/// are not validated (Useful for counters or timestamps in the response). Any bytes beyond the end of the mask
+/// are compared in full
+///
+/// ## Returns
+/// True if every masked in bit of the response matches the expected response
+pub fn response_matches(resp: &[u8], expected: &[u8], mask: Option<&[u8]>) -> bool {
+    if resp.len() < expected.len() {
+        return false;
+    }
+    expected.iter().enumerate().all(|(i, e)| {
+        let m = mask.and_then(|m| m.get(i)).copied().unwrap_or(0xFF);
+        resp[i] & m == e & m
+    })
+}
+
+/// Number of times a read-only command is tried before giving up, from the settings.
+/// See [run_command_retry](fn@ProtocolServer::run_command_retry)
+pub fn get_cmd_attempts() -> u32 {
+    crate::settings::get_settings()
+        .cmd_retries
+        .saturating_add(1)
+}
+
 pub trait Selectable: Into<u8> {
     fn get_desc(&self) -> String;
     fn get_name(&self) -> String;
@@ -91,29 +195,324 @@ impl std::fmt::Debug for Box<dyn CommandError> {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct DTC {
     pub(crate) error: String,
     pub(crate) present: bool,
     pub(crate) stored: bool,
     pub(crate) check_engine_on: bool,
+    /// Severity of the DTC. None if the ECU does not report severity
+    pub(crate) severity: Option<DTCSeverity>,
+    pub(crate) category: DTCCategory,
+}
+
+/// Where a DTC was read from
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DTCCategory {
+    /// Confirmed DTC stored in the ECU's memory
+    Stored,
+    /// DTC that has been detected, but not yet confirmed
+    Pending,
+    /// DTC that cannot be cleared by a command. The ECU only clears
+    /// it once it has verified the fault is no longer present
+    Permanent,
+}
+
+impl Display for DTCCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DTCCategory::Stored => write!(f, "Stored"),
+            DTCCategory::Pending => write!(f, "Pending"),
+            DTCCategory::Permanent => write!(f, "Permanent"),
+        }
+    }
+}
+
+impl DTC {
+    /// Returns readable flags for the DTC, showing if it is illuminating
+    /// the warning lamp or is just stored in the ECU's memory
+    pub fn get_status_text(&self) -> String {
+        let mut flags: Vec<&str> = Vec::new();
+        match self.category {
+            DTCCategory::Stored => {}
+            DTCCategory::Pending => flags.push("Pending"),
+            DTCCategory::Permanent => flags.push("Permanent - Cannot be cleared by command"),
+        }
+        if self.check_engine_on {
+            flags.push("Warning lamp on");
+        } else if self.stored {
+            flags.push("Stored only");
+        }
+        if self.present {
+            flags.push("Present");
+        }
+        if let Some(severity) = self.severity {
+            flags.extend(severity.get_flags());
+        }
+        flags.join(", ")
+    }
+
+    /// Returns true if the DTC is expected to be removed by clearing the ECU's DTCs
+    pub fn can_clear(&self) -> bool {
+        self.category != DTCCategory::Permanent
+    }
 }
 
 impl Display for DTC {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{} - Present?: {}, In memory?: {}, Check engine light on?: {}",
-            self.error, self.present, self.stored, self.check_engine_on
-        )
+            "{} ({}) - Present?: {}, In memory?: {}, Check engine light on?: {}",
+            self.error, self.category, self.present, self.stored, self.check_engine_on
+        )?;
+        if let Some(severity) = self.severity {
+            write!(f, ", Severity: {}", severity.get_flags().join(", "))?;
+        }
+        Ok(())
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+/// DTC severity byte as reported by UDS ECUs (ISO14229 DTCSeverityMask).
+/// Bits 7-5 are the severity, bits 4-0 are the DTC class
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DTCSeverity(pub u8);
+
+impl DTCSeverity {
+    /// Fault only needs attention at the next service
+    pub fn maintenance_only(&self) -> bool {
+        self.0 & 0b0010_0000 != 0
+    }
+
+    /// Fault should be checked at the next stop
+    pub fn check_at_next_halt(&self) -> bool {
+        self.0 & 0b0100_0000 != 0
+    }
+
+    /// Fault should be checked immediately
+    pub fn check_immediately(&self) -> bool {
+        self.0 & 0b1000_0000 != 0
+    }
+
+    /// Returns the WWH-OBD class of the DTC, if reported
+    pub fn get_class(&self) -> Option<&'static str> {
+        match self.0 & 0b0001_1111 {
+            x if x & 0b0000_0010 != 0 => Some("Class A"),
+            x if x & 0b0000_0100 != 0 => Some("Class B1"),
+            x if x & 0b0000_1000 != 0 => Some("Class B2"),
+            x if x & 0b0001_0000 != 0 => Some("Class C"),
+            _ => None,
+        }
+    }
+
+    pub fn get_flags(&self) -> Vec<&'static str> {
+        let mut res = Vec::new();
+        if self.check_immediately() {
+            res.push("Check immediately");
+        }
+        if self.check_at_next_halt() {
+            res.push("Check at next halt");
+        }
+        if self.maintenance_only() {
+            res.push("Maintenance only");
+        }
+        if let Some(class) = self.get_class() {
+            res.push(class);
+        }
+        res
+    }
+}
+
+/// Result of asking an ECU to enter a diagnostic session, when probing which sessions it supports
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionSupport {
+    /// Name of the session. EG: 'Extended'
+    pub session: String,
+    /// Why the ECU would not enter the session. None if it is supported
+    pub error: Option<String>,
+}
+
+impl SessionSupport {
+    pub fn new(session: String, res: &ProtocolResult<()>) -> Self {
+        Self {
+            session,
+            error: res.as_ref().err().map(|e| e.get_text()),
+        }
+    }
+
+    pub fn is_supported(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+impl Display for SessionSupport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.error {
+            None => write!(f, "{}: Supported", self.session),
+            Some(e) => write!(f, "{}: Not supported ({})", self.session, e),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum DiagProtocol {
     KWP2000,
     UDS,
 }
 
+impl Display for DiagProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiagProtocol::KWP2000 => write!(f, "KWP2000"),
+            DiagProtocol::UDS => write!(f, "UDS"),
+        }
+    }
+}
+
+/// How a request is addressed on the CAN bus
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Addressing {
+    /// Sent to the ECU's own send ID, so only that ECU responds
+    Physical,
+    /// Sent to the functional (broadcast) ID from the settings. Every ECU listening on it may
+    /// respond, but only the response from this ECU's response ID is returned
+    Functional,
+}
+
+impl Default for Addressing {
+    fn default() -> Self {
+        Addressing::Physical
+    }
+}
+
+impl Display for Addressing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Addressing::Physical => write!(f, "Physical"),
+            Addressing::Functional => write!(f, "Functional"),
+        }
+    }
+}
+
+impl Addressing {
+    pub const ALL: [Addressing; 2] = [Addressing::Physical, Addressing::Functional];
+
+    /// Max length of a functionally addressed request. ISO-TP does not allow functional
+    /// requests to be split into multiple frames, as every ECU would send flow control
+    pub const MAX_FUNCTIONAL_LEN: usize = 7;
+
+    /// Returns the CAN ID to send a request to the ECU with
+    pub fn get_send_id(&self, cfg: &ISO15765Config) -> u32 {
+        match self {
+            Addressing::Physical => cfg.send_id,
+            Addressing::Functional => crate::settings::get_settings().functional_send_id,
+        }
+    }
+
+    /// Returns the ISO-TP config to send a request to the ECU with
+    pub fn get_cfg(&self, cfg: &ISO15765Config) -> ISO15765Config {
+        ISO15765Config {
+            send_id: self.get_send_id(cfg),
+            ..*cfg
+        }
+    }
+
+    /// Checks a request of `len` bytes (Including the service ID) can be sent with this addressing
+    pub fn check_request_len(&self, len: usize) -> ProtocolResult<()> {
+        if *self == Addressing::Functional && len > Self::MAX_FUNCTIONAL_LEN {
+            return Err(ProtocolError::CustomError(format!(
+                "Functional requests must fit in a single frame ({} bytes), request is {} bytes",
+                Self::MAX_FUNCTIONAL_LEN,
+                len
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl DiagProtocol {
+    /// Works out which diagnostic protocol an ECU speaks. A UDS DiagnosticSessionControl request
+    /// is tried first, followed by a KWP2000 StartDiagnosticSession request. The first protocol
+    /// the ECU gives a positive response to is returned. Once detected, the ECU is asked to
+    /// return to its default session
+    pub fn detect(
+        mut comm_server: Box<dyn ComServer>,
+        cfg: &ISO15765Config,
+    ) -> ProtocolResult<Self> {
+        comm_server
+            .open_iso15765_interface_for(cfg)
+            .map_err(ProtocolError::CommError)?;
+        let res = Self::detect_iso_tp(comm_server.as_ref(), cfg);
+        if let Err(e) = comm_server.close_iso15765_interface() {
+            eprintln!(
+                "Protocol detection - Could not close ISO-TP interface: {}",
+                e
+            )
+        }
+        res
+    }
+
+    fn detect_iso_tp(server: &dyn ComServer, cfg: &ISO15765Config) -> ProtocolResult<Self> {
+        let mut cfg = *cfg;
+        server
+            .configure_iso15765(&cfg)
+            .map_err(ProtocolError::CommError)?;
+        // UDS - Extended diagnostic session
+        if UDSECU::run_command_iso_tp_auto_fc(server, &mut cfg, 0x10, &[0x03], true).is_ok() {
+            let _ = UDSECU::run_command_iso_tp(server, &cfg, 0x10, &[0x01], true);
+            return Ok(DiagProtocol::UDS);
+        }
+        // KWP2000 - Extended diagnostic session
+        if KWP2000ECU::run_command_iso_tp_auto_fc(server, &mut cfg, 0x10, &[0x92], true).is_ok() {
+            let _ = KWP2000ECU::run_command_iso_tp(server, &cfg, 0x10, &[0x81], true);
+            return Ok(DiagProtocol::KWP2000);
+        }
+        Err(ProtocolError::CustomError(
+            "ECU did not accept a UDS or KWP2000 diagnostic session request".into(),
+        ))
+    }
+}
+
+/// Works out which CAN ID an ECU responds on, for ECUs whose response ID is unknown or
+/// was guessed wrong. A TesterPresent request is sent to `send_id` as a raw CAN frame, and
+/// the first ID in the diagnostic range to respond to it within the command timeout is returned.
+pub fn learn_response_id(mut comm_server: Box<dyn ComServer>, send_id: u32) -> ProtocolResult<u32> {
+    let ext_can = send_id > 0x7FF;
+    comm_server
+        .open_can_interface(comm_api::get_can_bitrate(), ext_can)
+        .map_err(ProtocolError::CommError)?;
+    let res = learn_response_id_can(comm_server.as_ref(), send_id);
+    if let Err(e) = comm_server.close_can_interface() {
+        eprintln!("Learn response ID - Could not close CAN interface: {}", e)
+    }
+    res
+}
+
+fn learn_response_id_can(server: &dyn ComServer, send_id: u32) -> ProtocolResult<u32> {
+    server
+        .add_can_filter(comm_api::FilterType::Pass, 0x00000000, 0x00000000)
+        .map_err(ProtocolError::CommError)?;
+    let _ = server.clear_can_rx_buffer();
+    // TesterPresent is understood by both UDS and KWP2000, and has no side effects
+    let frame = iso_tp::encode_single_frame(&[0x3E, 0x00], false).unwrap_or_default();
+    server
+        .send_can_packets(&[CanFrame::new(send_id, &frame)], 0)
+        .map_err(ProtocolError::CommError)?;
+    let timeout = crate::settings::get_settings().cmd_timeout_ms as u128;
+    let start = Instant::now();
+    while start.elapsed().as_millis() <= timeout {
+        for f in server.read_can_packets(0, 100).unwrap_or_default() {
+            if f.id != send_id
+                && iso_tp::is_diag_id(f.id)
+                && iso_tp::is_response_to(f.get_data(), 0x3E)
+            {
+                return Ok(f.id);
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    Err(ProtocolError::Timeout)
+}
+
 #[derive(Debug, Clone)]
 pub enum DiagServer {
     KWP2000(KWP2000ECU),
@@ -127,8 +526,10 @@ impl DiagServer {
         protocol: DiagProtocol,
     ) -> ProtocolResult<Self> {
         Ok(match protocol {
-            KWP2000 => Self::KWP2000(KWP2000ECU::start_diag_session(comm_server, cfg)?),
-            UDS => Self::UDS(UDSECU::start_diag_session(comm_server, cfg)?),
+            DiagProtocol::KWP2000 => {
+                Self::KWP2000(KWP2000ECU::start_diag_session(comm_server, cfg)?)
+            }
+            DiagProtocol::UDS => Self::UDS(UDSECU::start_diag_session(comm_server, cfg)?),
         })
     }
 
@@ -153,6 +554,74 @@ impl DiagServer {
         }
     }
 
+    pub fn run_cmd_suppress_response(&mut self, cmd: u8, args: &[u8]) -> ProtocolResult<()> {
+        match self {
+            Self::KWP2000(s) => s.run_command_suppress_response(cmd, args),
+            Self::UDS(s) => s.run_command_suppress_response(cmd, args),
+        }
+    }
+
+    pub fn run_cmd_expect(
+        &mut self,
+        cmd: u8,
+        args: &[u8],
+        expected: &[u8],
+        mask: Option<&[u8]>,
+    ) -> ProtocolResult<Vec<u8>> {
+        match self {
+            Self::KWP2000(s) => s.run_command_expect(cmd, args, expected, mask),
+            Self::UDS(s) => s.run_command_expect(cmd, args, expected, mask),
+        }
+    }
+
+    /// Switches the ECU to the session a service requires to run.
+    /// Does nothing if the ECU is already in that session
+    pub fn enter_session(&mut self, session: RequiredSession) -> ProtocolResult<()> {
+        match self {
+            Self::KWP2000(s) => {
+                let mode = match session {
+                    RequiredSession::Extended => kwp2000::start_diag_session::DiagSession::Extended,
+                    RequiredSession::Programming => kwp2000::start_diag_session::DiagSession::Flash,
+                };
+                if s.get_session_type() == mode {
+                    return Ok(());
+                }
+                s.set_diag_session_mode(mode)
+            }
+            Self::UDS(s) => {
+                let mode = match session {
+                    RequiredSession::Extended => uds::diag_session_control::DiagSession::Extended,
+                    RequiredSession::Programming => {
+                        uds::diag_session_control::DiagSession::Programming
+                    }
+                };
+                if s.get_session_type() == mode {
+                    return Ok(());
+                }
+                s.set_diag_session_mode(mode)
+            }
+        }
+    }
+
+    /// Asks the ECU to enter each standard diagnostic session, to find which it supports.
+    /// The ECU is left in the session it was in before probing
+    pub fn probe_sessions(&mut self) -> Vec<SessionSupport> {
+        match self {
+            Self::KWP2000(s) => kwp2000::start_diag_session::probe_sessions(s),
+            Self::UDS(s) => uds::diag_session_control::probe_sessions(s),
+        }
+    }
+
+    /// Reads the VIN, trying the protocol's own method first, then falling back to the others
+    pub fn read_vin(&self) -> ProtocolResult<vin::Vin> {
+        match self {
+            Self::KWP2000(s) => {
+                vin::read_vin(|c, a| s.run_command(c, a), &vin::KWP2000_VIN_METHODS)
+            }
+            Self::UDS(s) => vin::read_vin(|c, a| s.run_command(c, a), &vin::UDS_VIN_METHODS),
+        }
+    }
+
     pub fn read_errors(&self) -> ProtocolResult<Vec<DTC>> {
         match self {
             Self::KWP2000(s) => s.read_errors(),
@@ -184,52 +653,163 @@ pub trait ProtocolServer: Sized {
     ) -> ProtocolResult<Self>;
     fn exit_diag_session(&mut self);
     fn run_command(&self, cmd: u8, args: &[u8]) -> ProtocolResult<Vec<u8>>;
+    /// Sends a command to the ECU without waiting for a response
+    fn send_command(&self, cmd: u8, args: &[u8]) -> ProtocolResult<()>;
     fn read_errors(&self) -> ProtocolResult<Vec<DTC>>;
     fn is_in_diag_session(&self) -> bool;
     fn get_last_error(&self) -> Option<String>;
 
+    /// Runs a command, and validates the ECU's response against an expected response.
+    /// See [response_matches] for how the mask is applied.
+    ///
+    /// ## Returns
+    /// The full response from the ECU. If the response does not match, [ProtocolError::ResponseMismatch]
+    /// is returned, which also contains the full response
+    fn run_command_expect(
+        &self,
+        cmd: u8,
+        args: &[u8],
+        expected: &[u8],
+        mask: Option<&[u8]>,
+    ) -> ProtocolResult<Vec<u8>> {
+        let resp = self.run_command(cmd, args)?;
+        if response_matches(&resp, expected, mask) {
+            Ok(resp)
+        } else {
+            Err(ProtocolError::ResponseMismatch {
+                expect: expected.to_vec(),
+                actual: resp,
+            })
+        }
+    }
+
+    /// Runs a command, trying it up to `attempts` times in total whilst it fails with a
+    /// [transient](fn@ProtocolError::is_transient) error.
+    ///
+    /// Only use this for commands that are safe to repeat, such as reading data. A command that
+    /// timed out may still have been run by the ECU
+    fn run_command_retry(&self, cmd: u8, args: &[u8], attempts: u32) -> ProtocolResult<Vec<u8>> {
+        let mut attempt = 1;
+        loop {
+            match self.run_command(cmd, args) {
+                Err(e) if e.is_transient() && attempt < attempts => {
+                    attempt += 1;
+                    println!(
+                        "Command {:02X} failed ({}), retrying. Attempt {} of {}",
+                        cmd,
+                        e.get_text(),
+                        attempt,
+                        attempts
+                    );
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Returns true if the first argument of `cmd` is a sub-function, whose Bit 7 is the
+    /// suppressPosRspMsgIndicationBit. For other commands the first argument is data such as a
+    /// local identifier, which setting the bit would change
+    fn has_sub_function(cmd: u8) -> bool;
+
+    /// Returns the arguments of `cmd` with the suppressPosRspMsgIndicationBit set, or an error
+    /// if the command has no sub-function to set it in
+    fn suppress_response_args(cmd: u8, args: &[u8]) -> ProtocolResult<Vec<u8>> {
+        if !Self::has_sub_function(cmd) || args.is_empty() {
+            return Err(ProtocolError::CustomError(format!(
+                "Command {:02X} has no sub-function to suppress the response with",
+                cmd
+            )));
+        }
+        let mut args = args.to_vec();
+        args[0] |= 0x80;
+        Ok(args)
+    }
+
+    /// Runs a command with the suppressPosRspMsgIndicationBit (Bit 7 of the sub-function) set.
+    /// The ECU will not reply if the command succeeds, so no response is waited for.
+    ///
+    /// Only commands with a sub-function (See [has_sub_function](fn@ProtocolServer::has_sub_function))
+    /// can suppress their response
+    fn run_command_suppress_response(&self, cmd: u8, args: &[u8]) -> ProtocolResult<()> {
+        let args = Self::suppress_response_args(cmd, args)?;
+        self.send_command(cmd, &args)
+    }
+
+    /// Runs a command like [run_command_iso_tp](fn@ProtocolServer::run_command_iso_tp), but if the
+    /// flow control parameters of `cfg` were automatically chosen and the ECU's response times out,
+    /// the flow control parameters are relaxed and the command is retried until it succeeds or the
+    /// parameters cannot be relaxed any further. `cfg` is updated with the working parameters
+    fn run_command_iso_tp_auto_fc(
+        server: &dyn ComServer,
+        cfg: &mut ISO15765Config,
+        cmd: u8,
+        args: &[u8],
+        receive_require: bool,
+    ) -> std::result::Result<Vec<u8>, ProtocolError> {
+        let mut res = Self::run_command_iso_tp(server, cfg, cmd, args, receive_require);
+        while receive_require
+            && res.as_ref().err().map(|e| e.is_timeout()).unwrap_or(false)
+            && cfg.relax_flow_control()
+        {
+            println!(
+                "ISO-TP - No response, retrying with block size {} and separation time {}ms",
+                cfg.block_size, cfg.sep_time
+            );
+            server
+                .set_iso15765_params(cfg.sep_time, cfg.block_size)
+                .map_err(ProtocolError::CommError)?;
+            res = Self::run_command_iso_tp(server, cfg, cmd, args, receive_require);
+        }
+        res
+    }
+
+    /// Sends a command to `cfg.send_id`, padded and addressed as `cfg` asks for
     fn run_command_iso_tp(
         server: &dyn ComServer,
-        send_id: u32,
+        cfg: &ISO15765Config,
         cmd: u8,
         args: &[u8],
         receive_require: bool,
     ) -> std::result::Result<Vec<u8>, ProtocolError> {
-        let mut data = ISO15765Data {
-            id: send_id,
-            data: vec![cmd],
-            pad_frame: false,
-            ext_addressing: true,
-        };
-        data.data.extend_from_slice(args);
+        let data = cfg.to_iso15765_data(cfg.send_id, &[&[cmd], args].concat());
         if !receive_require {
             server
                 .send_iso15765_data(&[data], 0)
                 .map(|_| vec![])
                 .map_err(ProtocolError::CommError)
         } else {
-            // Await max 1 second for response
-            let res = server.send_receive_iso15765(data, 1000, 1)?;
+            // Await max timeout (From settings) for response
+            let settings = crate::settings::get_settings();
+            let timeout = settings.cmd_timeout_ms as u128;
+            let res = server.send_receive_iso15765_mf(
+                data,
+                timeout,
+                settings.multi_frame_timeout_ms as u128,
+                1,
+            )?;
             if res.is_empty() {
                 return Err(ProtocolError::Timeout);
             }
-            let mut tmp_res = res[0].data.clone();
+            let mut tmp_res = cfg.strip_ext_addr(res[0].data.clone());
             if tmp_res[0] == 0x7F && tmp_res[2] == 0x78 {
                 // ResponsePending
                 println!("KWP2000 - ECU is processing request - Waiting!");
                 let start = Instant::now();
-                while start.elapsed().as_millis() < 1000 {
+                while start.elapsed().as_millis() < timeout {
                     // ECU is sending a response, but its busy right now. just gotta wait for the ECU to give us its response!
-                    if let Some(msg) = server.read_iso15765_packets(0, 1)?.get(0) {
-                        tmp_res = msg.data.clone();
+                    if let Some(msg) = server
+                        .read_iso15765_packets(0, 1)?
+                        .into_iter()
+                        .find(|m| !m.data.is_empty())
+                    {
+                        tmp_res = cfg.strip_ext_addr(msg.data);
                     }
                 }
             }
             if tmp_res[0] == 0x7F {
                 // Still error :(
-                Err(ProtocolError::ProtocolError(Box::new(
-                    Self::Error::from_byte(tmp_res[2]),
-                )))
+                Err(ProtocolError::negative_response::<Self::Error>(tmp_res[2]))
             } else if tmp_res[0] == (cmd + 0x40) {
                 Ok(tmp_res)
             } else {
@@ -242,3 +822,107 @@ pub trait ProtocolServer: Sized {
         }
     }
 }
+
+#[cfg(test)]
+mod protocols_test {
+    use super::{
+        kwp2000::KWP2000ECU, learn_response_id, response_matches, uds::UDSECU, ProtocolError,
+        ProtocolServer,
+    };
+    use crate::commapi::{
+        comm_api::{CanFrame, ISO15765Config},
+        mock_api::MockComServer,
+    };
+
+    #[test]
+    fn response_matching() {
+        assert!(response_matches(&[0x50, 0x03, 0x00], &[0x50, 0x03], None));
+        // Positive response to another service
+        assert!(!response_matches(&[0x51, 0x03], &[0x50, 0x03], None));
+        assert!(!response_matches(&[0x50], &[0x50, 0x03], None));
+
+        // Negative response echoing another service
+        let nrc: &[u8] = &[0x7F, 0x10, 0x12];
+        assert!(response_matches(nrc, nrc, None));
+        assert!(!response_matches(&[0x7F, 0x11, 0x12], nrc, None));
+
+        // Counter in the second byte is not checked, but the bytes beyond the mask are
+        let expected: &[u8] = &[0x62, 0x00, 0x01];
+        let mask: &[u8] = &[0xFF, 0x00];
+        assert!(response_matches(&[0x62, 0x42, 0x01], expected, Some(mask)));
+        assert!(!response_matches(&[0x62, 0x42, 0x02], expected, Some(mask)));
+    }
+
+    #[test]
+    fn expect_ignores_masked_bytes() {
+        let server = MockComServer::new();
+        let mut ecu = KWP2000ECU::start_diag_session(
+            Box::new(server.clone()),
+            &ISO15765Config::new_auto_fc(0x7E0, 0x7E8),
+        )
+        .unwrap();
+        // The third byte is a counter, which changes every time the ECU is asked
+        server.script_iso15765_responses(&[
+            Some(vec![0x61, 0x05, 0x42, 0x01]),
+            Some(vec![0x61, 0x05, 0x43, 0x02]),
+        ]);
+        let expected: &[u8] = &[0x61, 0x05, 0x00, 0x01];
+        let mask: &[u8] = &[0xFF, 0xFF, 0x00];
+        assert_eq!(
+            ecu.run_command_expect(0x21, &[0x05], expected, Some(mask))
+                .unwrap(),
+            vec![0x61, 0x05, 0x42, 0x01]
+        );
+        // A byte beyond the mask differs, so the full response is returned with the mismatch
+        match ecu.run_command_expect(0x21, &[0x05], expected, Some(mask)) {
+            Err(ProtocolError::ResponseMismatch { expect, actual }) => {
+                assert_eq!(expect, expected);
+                assert_eq!(actual, vec![0x61, 0x05, 0x43, 0x02]);
+            }
+            res => panic!("Expected a response mismatch, got {:?}", res),
+        }
+        ecu.exit_diag_session();
+    }
+
+    #[test]
+    fn learns_id_other_than_recv_id() {
+        let server = MockComServer::new();
+        // The save file says 0x7E8, but the ECU answers on 0x7E9
+        server.queue_can_frames(&[CanFrame::new(0x7E9, &[0x02, 0x7E, 0x00])]);
+        assert_eq!(learn_response_id(Box::new(server), 0x7E0).unwrap(), 0x7E9);
+    }
+
+    #[test]
+    fn learn_ignores_other_responses() {
+        let server = MockComServer::new();
+        server.queue_can_frames(&[
+            // Echo of the request
+            CanFrame::new(0x7E0, &[0x02, 0x3E, 0x00]),
+            // Response to another service
+            CanFrame::new(0x7E8, &[0x02, 0x50, 0x03]),
+            // Negative response to another service
+            CanFrame::new(0x7EA, &[0x03, 0x7F, 0x10, 0x11]),
+            // Not a diagnostic ID
+            CanFrame::new(0x123, &[0x02, 0x7E, 0x00]),
+            CanFrame::new(0x7EB, &[0x03, 0x7F, 0x3E, 0x12]),
+        ]);
+        assert_eq!(learn_response_id(Box::new(server), 0x7E0).unwrap(), 0x7EB);
+    }
+
+    #[test]
+    fn suppress_response_only_sets_sub_functions() {
+        assert_eq!(
+            KWP2000ECU::suppress_response_args(0x3E, &[0x01]).unwrap(),
+            vec![0x81]
+        );
+        assert_eq!(
+            UDSECU::suppress_response_args(0x31, &[0x01, 0xFF, 0x00]).unwrap(),
+            vec![0x81, 0xFF, 0x00]
+        );
+        // Local identifiers and DIDs must not be changed
+        assert!(KWP2000ECU::suppress_response_args(0x3B, &[0x01, 0xAA]).is_err());
+        assert!(KWP2000ECU::suppress_response_args(0x31, &[0x01]).is_err());
+        assert!(UDSECU::suppress_response_args(0x22, &[0xF1, 0x90]).is_err());
+        assert!(UDSECU::suppress_response_args(0x10, &[]).is_err());
+    }
+}
@@ -1,7 +1,17 @@
+use std::collections::BTreeMap;
 use std::env::set_current_dir;
 
-use crate::commapi::comm_api::{ComServer, ComServerError, ISO15765Config, ISO15765Data};
+use crate::commapi::comm_api::{
+    get_can_bitrate, ComServer, ComServerError, ISO15765Config, ISO15765Data,
+};
 use crate::commapi::protocols::vin::Vin;
+use crate::commapi::protocols::{DTCCategory, DTC};
+use pids::PidValue;
+use readiness::Readiness;
+
+pub mod pids;
+pub mod readiness;
+
 pub type Result<T> = std::result::Result<T, OBDProcessError>;
 
 fn read_write_payload_isotp(
@@ -9,7 +19,7 @@ fn read_write_payload_isotp(
     payload: &OBDRequest,
 ) -> Result<Vec<u8>> {
     server
-        .open_iso15765_interface(500_000, false, false)
+        .open_iso15765_interface(get_can_bitrate(), false, false)
         .map_err(|e| OBDProcessError::CommError(e))?;
     // Guess to use something appropriate for block size and sep time
     let send_data = ISO15765Data {
@@ -24,7 +34,15 @@ fn read_write_payload_isotp(
         recv_id: 0x07E8,
         block_size: 8, // Sensible decision
         sep_time: 20,  // Sensible decision
+        auto_fc: false,
+        can_fd: None,
+        padding: None,
+        ext_addr: None,
     };
+    if let Err(e) = server.configure_iso15765(&cfg) {
+        server.close_iso15765_interface();
+        return Err(OBDProcessError::CommError(e));
+    }
     let res = server.send_receive_iso15765(send_data, 500, 1);
 
     server.close_iso15765_interface();
@@ -41,6 +59,48 @@ fn read_write_payload_isotp(
     }
 }
 
+/// Sends a payload to the OBD-II functional ID (0x7DF) and collects the responses of all ECUs
+/// that reply within the response window, keyed by the CAN ID of the responding ECU.
+fn read_write_payload_isotp_functional(
+    server: &mut Box<dyn ComServer>,
+    payload: &OBDRequest,
+) -> Result<BTreeMap<u32, Vec<u8>>> {
+    server
+        .open_iso15765_interface(get_can_bitrate(), false, false)
+        .map_err(|e| OBDProcessError::CommError(e))?;
+    // OBD-II ECUs respond on 0x7E8-0x7EF, and expect flow control on 0x7E0-0x7E7
+    for i in 0..8 {
+        if let Err(e) = server.add_iso15765_filter(0x07E8 + i, 0xFFFF, 0x07E0 + i) {
+            server.close_iso15765_interface();
+            return Err(OBDProcessError::CommError(e));
+        }
+    }
+    if let Err(e) = server.add_functional_filter(0x07DF) {
+        server.close_iso15765_interface();
+        return Err(OBDProcessError::CommError(e));
+    }
+    let send_data = ISO15765Data {
+        id: 0x07DF, // Global request ID for OBD-II over CAN
+        data: payload.to_vec(),
+        pad_frame: false,
+        ext_addressing: false,
+    };
+    let res = server.send_receive_iso15765_functional(send_data, 500);
+
+    server.close_iso15765_interface();
+
+    match res {
+        Ok(resps) => {
+            if resps.is_empty() {
+                Err(OBDProcessError::NoResponse)
+            } else {
+                Ok(resps)
+            }
+        }
+        Err(e) => Err(OBDProcessError::CommError(e)),
+    }
+}
+
 fn read_write_payload(
     server: &mut Box<dyn ComServer>,
     use_can: bool,
@@ -50,43 +110,65 @@ fn read_write_payload(
         true => read_write_payload_isotp(server, payload),
         false => unimplemented!(),
     };
+    parse_response(payload, &resp?)
+}
 
-    return if let Ok(p) = resp {
-        if p.len() > 1 {
-            if p[0] == payload.service | 0x40 {
-                match payload.pid {
-                    None => Ok(OBDResponse {
-                        service: payload.service,
-                        pid: None,
-                        data: Vec::from(&p[1..]),
-                    }),
-                    Some(pid) => {
-                        if p[1] == pid {
-                            Ok(OBDResponse {
-                                service: payload.service,
-                                pid: Some(pid),
-                                data: Vec::from(&p[2..]),
-                            })
-                        } else {
-                            Err(OBDProcessError::InvalidResponse(
-                                "Response pid did not match request pid".into(),
-                            ))
-                        }
+/// Sends an OBD-II request to every ECU on the bus, returning each ECU's response
+/// keyed by the CAN ID it responded on.
+///
+/// ECUs that return an invalid response are not included
+pub fn read_write_payload_all(
+    server: &mut Box<dyn ComServer>,
+    use_can: bool,
+    payload: &OBDRequest,
+) -> Result<BTreeMap<u32, OBDResponse>> {
+    let resps = match use_can {
+        true => read_write_payload_isotp_functional(server, payload)?,
+        false => {
+            return Err(OBDProcessError::Unsupported(
+                "Functional addressing is only supported over ISO15765".into(),
+            ))
+        }
+    };
+    Ok(resps
+        .iter()
+        .filter_map(|(id, p)| parse_response(payload, p).ok().map(|r| (*id, r)))
+        .collect())
+}
+
+fn parse_response(payload: &OBDRequest, p: &[u8]) -> Result<OBDResponse> {
+    if p.len() > 1 {
+        if p[0] == payload.service | 0x40 {
+            match payload.pid {
+                None => Ok(OBDResponse {
+                    service: payload.service,
+                    pid: None,
+                    data: Vec::from(&p[1..]),
+                }),
+                Some(pid) => {
+                    if p[1] == pid {
+                        Ok(OBDResponse {
+                            service: payload.service,
+                            pid: Some(pid),
+                            data: Vec::from(&p[2..]),
+                        })
+                    } else {
+                        Err(OBDProcessError::InvalidResponse(
+                            "Response pid did not match request pid".into(),
+                        ))
                     }
                 }
-            } else {
-                Err(OBDProcessError::InvalidResponse(
-                    "Response service did not match request service".into(),
-                ))
             }
         } else {
             Err(OBDProcessError::InvalidResponse(
-                "ECU Did not reply with enough data".into(),
+                "Response service did not match request service".into(),
             ))
         }
     } else {
-        Err(resp.err().unwrap())
-    };
+        Err(OBDProcessError::InvalidResponse(
+            "ECU Did not reply with enough data".into(),
+        ))
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -97,7 +179,7 @@ pub struct OBDRequest {
 }
 
 impl OBDRequest {
-    fn new(service: u8, pid: u8) -> Self {
+    pub fn new(service: u8, pid: u8) -> Self {
         Self {
             service,
             pid: Some(pid),
@@ -105,7 +187,7 @@ impl OBDRequest {
         }
     }
 
-    fn new_nopid(service: u8) -> Self {
+    pub fn new_nopid(service: u8) -> Self {
         Self {
             service,
             pid: None,
@@ -132,6 +214,20 @@ pub struct OBDResponse {
     data: Vec<u8>,
 }
 
+impl OBDResponse {
+    pub fn get_service(&self) -> u8 {
+        self.service
+    }
+
+    pub fn get_pid(&self) -> Option<u8> {
+        self.pid
+    }
+
+    pub fn get_data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum OBDProcessError {
     NoResponse,
@@ -139,6 +235,8 @@ pub enum OBDProcessError {
     ServiceNotSupported,
     PIDNotSupported,
     InvalidResponse(String),
+    /// The request cannot be sent over the interface in use
+    Unsupported(String),
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -238,6 +336,31 @@ impl Service01 {
         Ok(s01)
     }
 
+    /// Reads a PID, and converts it to engineering units using its standard scaling
+    pub fn read_pid(
+        &self,
+        server: &mut Box<dyn ComServer>,
+        use_can: bool,
+        pid: u8,
+    ) -> Result<PidValue> {
+        let def = pids::get_pid_definition(pid).ok_or(OBDProcessError::PIDNotSupported)?;
+        let data = self.read_pid_supported(server, use_can, pid as usize)?;
+        def.decode(&data).ok_or_else(|| {
+            OBDProcessError::InvalidResponse(format!("Not enough data for PID {:02X}", pid))
+        })
+    }
+
+    /// Reads the MIL status, DTC count and readiness monitors (PID 01)
+    pub fn get_readiness(
+        &self,
+        server: &mut Box<dyn ComServer>,
+        use_can: bool,
+    ) -> Result<Readiness> {
+        let data = self.read_pid_supported(server, use_can, 0x01)?;
+        Readiness::decode(&data)
+            .ok_or_else(|| OBDProcessError::InvalidResponse("Not enough data for PID 01".into()))
+    }
+
     fn a_b(src: Vec<u8>) -> (f32, f32) {
         (src[0] as f32, src[1] as f32)
     }
@@ -536,13 +659,76 @@ pub enum FuelType {
     BifuelDiesel,
 }
 
+/// Decodes a 2 byte OBD-II DTC. EG: [0x01, 0x71] -> 'P0171'
+fn decode_obd_dtc(a: u8, b: u8) -> String {
+    let system = match a >> 6 {
+        0 => 'P',
+        1 => 'C',
+        2 => 'B',
+        _ => 'U',
+    };
+    format!("{}{:01X}{:01X}{:02X}", system, (a >> 4) & 0x03, a & 0x0F, b)
+}
+
+/// Reads DTCs using Service 03, 07 or 0A. They all share the same response format
+fn read_dtcs(
+    server: &mut Box<dyn ComServer>,
+    use_can: bool,
+    service: u8,
+    category: DTCCategory,
+) -> Result<Vec<DTC>> {
+    let res = read_write_payload(server, use_can, &OBDRequest::new_nopid(service))?;
+    Ok(decode_dtcs(&res.data, use_can, category))
+}
+
+/// Decodes the DTCs in a response to Service 03, 07 or 0A (Without the service byte)
+pub(crate) fn decode_dtcs(data: &[u8], use_can: bool, category: DTCCategory) -> Vec<DTC> {
+    // Over CAN, the first byte is the number of DTCs
+    let data = match use_can {
+        true => data.get(1..).unwrap_or_default(),
+        false => data,
+    };
+    data.chunks_exact(2)
+        .filter(|dtc| dtc[0] != 0x00 || dtc[1] != 0x00) // Padding
+        .map(|dtc| DTC {
+            error: decode_obd_dtc(dtc[0], dtc[1]),
+            present: false,
+            stored: category == DTCCategory::Stored,
+            check_engine_on: false,
+            severity: None,
+            category,
+        })
+        .collect()
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct Service03;
 
 impl Service03 {
-    pub fn get_error_codes(server: &mut Box<dyn ComServer>, use_can: bool) -> Result<()> {
-        read_write_payload(server, use_can, &OBDRequest::new_nopid(0x03))
-            .map(|mut res| println!("{:02X?}", res))
+    /// Reads stored (Confirmed) DTCs
+    pub fn get_error_codes(server: &mut Box<dyn ComServer>, use_can: bool) -> Result<Vec<DTC>> {
+        read_dtcs(server, use_can, 0x03, DTCCategory::Stored)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Service07;
+
+impl Service07 {
+    /// Reads pending DTCs, detected during the current or last drive cycle
+    pub fn get_pending_codes(server: &mut Box<dyn ComServer>, use_can: bool) -> Result<Vec<DTC>> {
+        read_dtcs(server, use_can, 0x07, DTCCategory::Pending)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Service0A;
+
+impl Service0A {
+    /// Reads permanent DTCs. These cannot be cleared with Service 04, the ECU
+    /// clears them itself once it has verified the fault is no longer present
+    pub fn get_permanent_codes(server: &mut Box<dyn ComServer>, use_can: bool) -> Result<Vec<DTC>> {
+        read_dtcs(server, use_can, 0x0A, DTCCategory::Permanent)
     }
 }
 
@@ -567,13 +753,9 @@ impl Service09 {
         }
         let mut data = read_write_payload(server, use_can, &OBDRequest::new(0x09, 0x02))?.data;
         data.drain(0..1);
-        if let Some(x) = Vin::new(String::from_utf8(data).unwrap()) {
-            Ok(x)
-        } else {
-            Err(OBDProcessError::InvalidResponse(
-                "VIN is not correct length".into(),
-            ))
-        }
+        Vin::from_bytes(&data).ok_or(OBDProcessError::InvalidResponse(
+            "VIN is not a valid 17 character VIN".into(),
+        ))
     }
 
     pub fn init(server: &mut Box<dyn ComServer>, use_can: bool) -> Result<Self> {
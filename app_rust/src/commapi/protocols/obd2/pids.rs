@@ -0,0 +1,144 @@
+// Scaling of Service 01 (Show current data) PIDs, as defined by SAE J1979.
+// A, B, C... refer to the data bytes of the response, after the PID byte.
+
+/// How to convert a Service 01 PID's data bytes to a value in engineering units
+#[derive(Copy, Clone)]
+pub struct PidDefinition {
+    pub pid: u8,
+    pub name: &'static str,
+    pub unit: &'static str,
+    /// Number of data bytes the PID's value uses
+    pub len: usize,
+    scale: fn(&[u8]) -> f32,
+}
+
+impl std::fmt::Debug for PidDefinition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PID {:02X} ({})", self.pid, self.name)
+    }
+}
+
+impl PidDefinition {
+    /// Converts the data bytes of a response to this PID. None if there are not enough bytes
+    pub fn decode(&self, data: &[u8]) -> Option<PidValue> {
+        data.get(..self.len).map(|d| PidValue {
+            pid: self.pid,
+            name: self.name,
+            unit: self.unit,
+            value: (self.scale)(d),
+        })
+    }
+}
+
+/// A decoded Service 01 PID value
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PidValue {
+    pub pid: u8,
+    pub name: &'static str,
+    pub unit: &'static str,
+    pub value: f32,
+}
+
+impl std::fmt::Display for PidValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Round to 2 decimal places, but don't show trailing zeros
+        let value = (self.value * 100.0).round() / 100.0;
+        write!(f, "{}: {} {}", self.name, value, self.unit)
+    }
+}
+
+fn a(d: &[u8]) -> f32 {
+    d[0] as f32
+}
+
+fn ab(d: &[u8]) -> f32 {
+    d[0] as f32 * 256.0 + d[1] as f32
+}
+
+fn percent(d: &[u8]) -> f32 {
+    a(d) * 100.0 / 255.0
+}
+
+fn temperature(d: &[u8]) -> f32 {
+    a(d) - 40.0
+}
+
+fn fuel_trim(d: &[u8]) -> f32 {
+    a(d) * 100.0 / 128.0 - 100.0
+}
+
+/// Common Service 01 PIDs
+#[rustfmt::skip]
+pub const SERVICE01_PIDS: &[PidDefinition] = &[
+    PidDefinition { pid: 0x04, name: "Calculated engine load", unit: "%", len: 1, scale: percent },
+    PidDefinition { pid: 0x05, name: "Engine coolant temperature", unit: "°C", len: 1, scale: temperature },
+    PidDefinition { pid: 0x06, name: "Short term fuel trim (Bank 1)", unit: "%", len: 1, scale: fuel_trim },
+    PidDefinition { pid: 0x07, name: "Long term fuel trim (Bank 1)", unit: "%", len: 1, scale: fuel_trim },
+    PidDefinition { pid: 0x08, name: "Short term fuel trim (Bank 2)", unit: "%", len: 1, scale: fuel_trim },
+    PidDefinition { pid: 0x09, name: "Long term fuel trim (Bank 2)", unit: "%", len: 1, scale: fuel_trim },
+    PidDefinition { pid: 0x0A, name: "Fuel pressure", unit: "kPa", len: 1, scale: |d| a(d) * 3.0 },
+    PidDefinition { pid: 0x0B, name: "Intake manifold absolute pressure", unit: "kPa", len: 1, scale: a },
+    PidDefinition { pid: 0x0C, name: "Engine speed", unit: "rpm", len: 2, scale: |d| ab(d) / 4.0 },
+    PidDefinition { pid: 0x0D, name: "Vehicle speed", unit: "km/h", len: 1, scale: a },
+    PidDefinition { pid: 0x0E, name: "Timing advance", unit: "° before TDC", len: 1, scale: |d| a(d) / 2.0 - 64.0 },
+    PidDefinition { pid: 0x0F, name: "Intake air temperature", unit: "°C", len: 1, scale: temperature },
+    PidDefinition { pid: 0x10, name: "Mass air flow rate", unit: "g/s", len: 2, scale: |d| ab(d) / 100.0 },
+    PidDefinition { pid: 0x11, name: "Throttle position", unit: "%", len: 1, scale: percent },
+    PidDefinition { pid: 0x1F, name: "Run time since engine start", unit: "s", len: 2, scale: ab },
+    PidDefinition { pid: 0x21, name: "Distance traveled with MIL on", unit: "km", len: 2, scale: ab },
+    PidDefinition { pid: 0x22, name: "Fuel rail pressure (Relative to vacuum)", unit: "kPa", len: 2, scale: |d| ab(d) * 0.079 },
+    PidDefinition { pid: 0x23, name: "Fuel rail gauge pressure", unit: "kPa", len: 2, scale: |d| ab(d) * 10.0 },
+    PidDefinition { pid: 0x2C, name: "Commanded EGR", unit: "%", len: 1, scale: percent },
+    PidDefinition { pid: 0x2F, name: "Fuel tank level", unit: "%", len: 1, scale: percent },
+    PidDefinition { pid: 0x31, name: "Distance traveled since codes cleared", unit: "km", len: 2, scale: ab },
+    PidDefinition { pid: 0x33, name: "Barometric pressure", unit: "kPa", len: 1, scale: a },
+    PidDefinition { pid: 0x42, name: "Control module voltage", unit: "V", len: 2, scale: |d| ab(d) / 1000.0 },
+    PidDefinition { pid: 0x43, name: "Absolute load", unit: "%", len: 2, scale: |d| ab(d) * 100.0 / 255.0 },
+    PidDefinition { pid: 0x44, name: "Commanded air-fuel equivalence ratio", unit: "lambda", len: 2, scale: |d| ab(d) * 2.0 / 65536.0 },
+    PidDefinition { pid: 0x45, name: "Relative throttle position", unit: "%", len: 1, scale: percent },
+    PidDefinition { pid: 0x46, name: "Ambient air temperature", unit: "°C", len: 1, scale: temperature },
+    PidDefinition { pid: 0x49, name: "Accelerator pedal position D", unit: "%", len: 1, scale: percent },
+    PidDefinition { pid: 0x4C, name: "Commanded throttle actuator", unit: "%", len: 1, scale: percent },
+    PidDefinition { pid: 0x5C, name: "Engine oil temperature", unit: "°C", len: 1, scale: temperature },
+    PidDefinition { pid: 0x5E, name: "Engine fuel rate", unit: "L/h", len: 2, scale: |d| ab(d) / 20.0 },
+];
+
+/// Returns the scaling of a Service 01 PID, if it is a known PID
+pub fn get_pid_definition(pid: u8) -> Option<&'static PidDefinition> {
+    SERVICE01_PIDS.iter().find(|p| p.pid == pid)
+}
+
+#[cfg(test)]
+mod pids_test {
+    use super::get_pid_definition;
+
+    fn decode(pid: u8, data: &[u8]) -> f32 {
+        get_pid_definition(pid).unwrap().decode(data).unwrap().value
+    }
+
+    #[test]
+    fn scaling() {
+        assert_eq!(decode(0x0C, &[0x0C, 0x8A]), 802.5);
+        assert_eq!(decode(0x05, &[0x7B]), 83.0);
+        // Below zero temperatures
+        assert_eq!(decode(0x0F, &[0x1E]), -10.0);
+        assert_eq!(decode(0x06, &[0x80]), 0.0);
+        assert_eq!(decode(0x42, &[0x37, 0xDC]), 14.3);
+        assert_eq!(decode(0x11, &[0xFF]), 100.0);
+    }
+
+    #[test]
+    fn short_responses() {
+        assert!(get_pid_definition(0x0C).unwrap().decode(&[0x0C]).is_none());
+        assert!(get_pid_definition(0x02).is_none());
+    }
+
+    #[test]
+    fn display() {
+        let v = get_pid_definition(0x10)
+            .unwrap()
+            .decode(&[0x01, 0x23])
+            .unwrap();
+        assert_eq!(v.to_string(), "Mass air flow rate: 2.91 g/s");
+    }
+}
@@ -0,0 +1,167 @@
+// Decoding of Service 01 PID 01 (Monitor status since DTCs cleared), as defined by SAE J1979.
+// A, B, C and D refer to the data bytes of the response, after the PID byte.
+
+/// Monitors tested by every engine, in bit order of byte B
+const COMMON_MONITORS: [&str; 3] = ["Misfire", "Fuel system", "Comprehensive components"];
+
+/// Monitors of spark ignition (Petrol) engines, in bit order of bytes C and D
+const SPARK_MONITORS: [Option<&str>; 8] = [
+    Some("Catalyst"),
+    Some("Heated catalyst"),
+    Some("Evaporative system"),
+    Some("Secondary air system"),
+    Some("A/C refrigerant"),
+    Some("Oxygen sensor"),
+    Some("Oxygen sensor heater"),
+    Some("EGR / VVT system"),
+];
+
+/// Monitors of compression ignition (Diesel) engines, in bit order of bytes C and D
+const COMPRESSION_MONITORS: [Option<&str>; 8] = [
+    Some("NMHC catalyst"),
+    Some("NOx / SCR aftertreatment"),
+    None, // Reserved
+    Some("Boost pressure"),
+    None, // Reserved
+    Some("Exhaust gas sensor"),
+    Some("Particulate filter"),
+    Some("EGR / VVT system"),
+];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IgnitionType {
+    Spark,
+    Compression,
+}
+
+impl std::fmt::Display for IgnitionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IgnitionType::Spark => write!(f, "Spark ignition"),
+            IgnitionType::Compression => write!(f, "Compression ignition"),
+        }
+    }
+}
+
+/// A readiness monitor the ECU supports
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ReadinessMonitor {
+    pub name: &'static str,
+    /// The monitor has run to completion since DTCs were last cleared
+    pub complete: bool,
+}
+
+/// MIL status, DTC count and readiness monitors decoded from PID 01
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Readiness {
+    /// Malfunction indicator lamp (Check engine light) is on
+    pub mil_on: bool,
+    /// Number of emissions related DTCs stored
+    pub dtc_count: u8,
+    pub ignition: IgnitionType,
+    /// Monitors the ECU supports. Unsupported monitors are left out
+    pub monitors: Vec<ReadinessMonitor>,
+}
+
+impl Readiness {
+    /// Decodes the data bytes of a response to PID 01. None if there are not enough bytes
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let (a, b, c, d) = match data {
+            [a, b, c, d, ..] => (*a, *b, *c, *d),
+            _ => return None,
+        };
+        let ignition = if b & 0x08 != 0 {
+            IgnitionType::Compression
+        } else {
+            IgnitionType::Spark
+        };
+        // Support bits are set for supported monitors, completion bits are set for incomplete ones
+        let mut monitors: Vec<ReadinessMonitor> = COMMON_MONITORS
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| b >> bit & 0x01 != 0)
+            .map(|(bit, name)| ReadinessMonitor {
+                name: *name,
+                complete: b >> (bit + 4) & 0x01 == 0,
+            })
+            .collect();
+        let specific = match ignition {
+            IgnitionType::Spark => &SPARK_MONITORS,
+            IgnitionType::Compression => &COMPRESSION_MONITORS,
+        };
+        monitors.extend(
+            specific
+                .iter()
+                .enumerate()
+                .filter(|(bit, _)| c >> bit & 0x01 != 0)
+                .filter_map(|(bit, name)| {
+                    name.map(|name| ReadinessMonitor {
+                        name,
+                        complete: d >> bit & 0x01 == 0,
+                    })
+                }),
+        );
+        Some(Self {
+            mil_on: a & 0x80 != 0,
+            dtc_count: a & 0x7F,
+            ignition,
+            monitors,
+        })
+    }
+
+    /// Returns the supported monitors that have not completed yet
+    pub fn get_incomplete(&self) -> Vec<&ReadinessMonitor> {
+        self.monitors.iter().filter(|m| !m.complete).collect()
+    }
+}
+
+#[cfg(test)]
+mod readiness_test {
+    use super::{IgnitionType, Readiness};
+
+    fn status(r: &Readiness, name: &str) -> Option<bool> {
+        r.monitors
+            .iter()
+            .find(|m| m.name == name)
+            .map(|m| m.complete)
+    }
+
+    #[test]
+    fn spark_ignition() {
+        // MIL on with 2 DTCs. Misfire and fuel system supported, fuel system incomplete.
+        // Catalyst, EVAP and O2 sensor supported, EVAP incomplete
+        let r = Readiness::decode(&[0x82, 0x23, 0x25, 0x04]).unwrap();
+        assert!(r.mil_on);
+        assert_eq!(r.dtc_count, 2);
+        assert_eq!(r.ignition, IgnitionType::Spark);
+        assert_eq!(r.monitors.len(), 5);
+        assert_eq!(status(&r, "Misfire"), Some(true));
+        assert_eq!(status(&r, "Fuel system"), Some(false));
+        assert_eq!(status(&r, "Comprehensive components"), None);
+        assert_eq!(status(&r, "Catalyst"), Some(true));
+        assert_eq!(status(&r, "Evaporative system"), Some(false));
+        assert_eq!(status(&r, "Oxygen sensor"), Some(true));
+        assert_eq!(r.get_incomplete().len(), 2);
+    }
+
+    #[test]
+    fn compression_ignition() {
+        // MIL off, no DTCs. All common monitors supported and complete.
+        // NMHC catalyst, reserved bit 2 and particulate filter supported, particulate filter incomplete
+        let r = Readiness::decode(&[0x00, 0x0F, 0x45, 0x40]).unwrap();
+        assert!(!r.mil_on);
+        assert_eq!(r.dtc_count, 0);
+        assert_eq!(r.ignition, IgnitionType::Compression);
+        // Reserved bits are not monitors
+        assert_eq!(r.monitors.len(), 5);
+        assert_eq!(status(&r, "NMHC catalyst"), Some(true));
+        assert_eq!(status(&r, "Particulate filter"), Some(false));
+        assert_eq!(status(&r, "Catalyst"), None);
+        assert_eq!(r.get_incomplete().len(), 1);
+    }
+
+    #[test]
+    fn short_response() {
+        assert!(Readiness::decode(&[0x00, 0x0F, 0x45]).is_none());
+    }
+}
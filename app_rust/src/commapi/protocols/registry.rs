@@ -0,0 +1,297 @@
+use std::sync::{Mutex, RwLock};
+
+use lazy_static::lazy_static;
+
+use crate::commapi::comm_api::{ComServer, ISO15765Config};
+
+use super::{
+    kwp2000::KWP2000ECU,
+    obd2::decode_dtcs,
+    uds::{UDSNegativeCode, UDSECU},
+    DTCCategory, ProtocolError, ProtocolResult, ProtocolServer, DTC,
+};
+
+// Registry of the diagnostic protocols that can be picked for a custom diagnostic session.
+//
+// [ProtocolServer] cannot be used as a trait object, so protocols are registered through
+// [DynProtocolServer] instead, along with a factory that connects to the ECU. KWP2000, UDS and
+// OBD-II are registered from the start. To add a proprietary protocol without forking OVD,
+// implement [DynProtocolServer] for it, then register it in `main` before the UI starts:
+//
+// ```
+// register_protocol(ProtocolRegistration {
+//     name: "MyProtocol",
+//     description: "Proprietary protocol used by ...",
+//     factory: |server, cfg| Ok(Box::new(MyProtocolServer::start(server, cfg)?)),
+// })
+// ```
+
+/// A connection to an ECU using a registered protocol
+pub trait DynProtocolServer: Send + std::fmt::Debug {
+    /// Sends a request to the ECU, returning its positive response.
+    /// Negative responses are returned as [ProtocolError::NegativeResponse]
+    fn run_command(&self, cmd: u8, args: &[u8]) -> ProtocolResult<Vec<u8>>;
+    /// Reads the error codes stored in the ECU
+    fn read_errors(&self) -> ProtocolResult<Vec<DTC>>;
+    /// Clears the error codes stored in the ECU
+    fn clear_errors(&self) -> ProtocolResult<()>;
+    /// Returns false once the connection to the ECU has been lost
+    fn is_in_diag_session(&self) -> bool;
+    /// Description of the error that ended the connection, if any
+    fn get_last_error(&self) -> Option<String>;
+    /// Disconnects from the ECU
+    fn exit_diag_session(&mut self);
+    /// Returns true if the protocol can turn storing of DTCs in the ECU on and off
+    fn can_control_dtc_setting(&self) -> bool {
+        false
+    }
+    /// Turns storing of DTCs in the ECU on or off. Protocols which can do this must
+    /// turn DTC setting back on when the diagnostic session ends
+    fn control_dtc_setting(&self, _on: bool) -> ProtocolResult<()> {
+        Err(ProtocolError::CustomError(
+            "Protocol cannot control DTC setting".into(),
+        ))
+    }
+}
+
+/// Connects to the ECU described by the ISO-TP settings
+pub type ProtocolFactory =
+    fn(Box<dyn ComServer>, &ISO15765Config) -> ProtocolResult<Box<dyn DynProtocolServer>>;
+
+/// A protocol which can be picked for a custom diagnostic session
+#[derive(Debug, Copy, Clone)]
+pub struct ProtocolRegistration {
+    /// Unique name, shown to the user
+    pub name: &'static str,
+    /// One line summary of the protocol, shown to the user
+    pub description: &'static str,
+    pub factory: ProtocolFactory,
+}
+
+lazy_static! {
+    static ref PROTOCOLS: RwLock<Vec<ProtocolRegistration>> = RwLock::new(builtin_protocols());
+}
+
+fn builtin_protocols() -> Vec<ProtocolRegistration> {
+    vec![
+        ProtocolRegistration {
+            name: "KWP2000",
+            description: "Keyword protocol 2000 (ISO 14230) over ISO-TP",
+            factory: |server, cfg| Ok(Box::new(KWP2000ECU::start_diag_session(server, cfg)?)),
+        },
+        ProtocolRegistration {
+            name: "UDS",
+            description: "Unified diagnostic services (ISO 14229) over ISO-TP",
+            factory: |server, cfg| Ok(Box::new(UDSECU::start_diag_session(server, cfg)?)),
+        },
+        ProtocolRegistration {
+            name: "OBD-II",
+            description: "OBD-II (SAE J1979) services, sent to the ECU's ISO-TP IDs",
+            factory: |server, cfg| Ok(Box::new(OBD2Server::new(server, cfg))),
+        },
+    ]
+}
+
+/// Registers a protocol, so it can be picked for custom diagnostic sessions.
+/// Fails if a protocol with the same name is already registered
+pub fn register_protocol(protocol: ProtocolRegistration) -> Result<(), String> {
+    let mut protocols = PROTOCOLS.write().unwrap();
+    if protocols.iter().any(|p| p.name == protocol.name) {
+        return Err(format!("Protocol {} is already registered", protocol.name));
+    }
+    protocols.push(protocol);
+    Ok(())
+}
+
+/// Returns every registered protocol, built in protocols first
+pub fn get_protocols() -> Vec<ProtocolRegistration> {
+    PROTOCOLS.read().unwrap().clone()
+}
+
+/// Connects to an ECU with the registered protocol called `name`
+pub fn start_protocol(
+    name: &str,
+    server: Box<dyn ComServer>,
+    cfg: &ISO15765Config,
+) -> ProtocolResult<Box<dyn DynProtocolServer>> {
+    let protocol = get_protocols()
+        .into_iter()
+        .find(|p| p.name == name)
+        .ok_or_else(|| ProtocolError::CustomError(format!("Unknown protocol {}", name)))?;
+    (protocol.factory)(server, cfg)
+}
+
+impl DynProtocolServer for KWP2000ECU {
+    fn run_command(&self, cmd: u8, args: &[u8]) -> ProtocolResult<Vec<u8>> {
+        ProtocolServer::run_command(self, cmd, args)
+    }
+
+    fn read_errors(&self) -> ProtocolResult<Vec<DTC>> {
+        ProtocolServer::read_errors(self)
+    }
+
+    fn clear_errors(&self) -> ProtocolResult<()> {
+        KWP2000ECU::clear_errors(self)
+    }
+
+    fn is_in_diag_session(&self) -> bool {
+        ProtocolServer::is_in_diag_session(self)
+    }
+
+    fn get_last_error(&self) -> Option<String> {
+        ProtocolServer::get_last_error(self)
+    }
+
+    fn exit_diag_session(&mut self) {
+        ProtocolServer::exit_diag_session(self)
+    }
+}
+
+impl DynProtocolServer for UDSECU {
+    fn run_command(&self, cmd: u8, args: &[u8]) -> ProtocolResult<Vec<u8>> {
+        ProtocolServer::run_command(self, cmd, args)
+    }
+
+    fn read_errors(&self) -> ProtocolResult<Vec<DTC>> {
+        ProtocolServer::read_errors(self)
+    }
+
+    fn clear_errors(&self) -> ProtocolResult<()> {
+        UDSECU::clear_errors(self)
+    }
+
+    fn can_control_dtc_setting(&self) -> bool {
+        true
+    }
+
+    fn control_dtc_setting(&self, on: bool) -> ProtocolResult<()> {
+        UDSECU::control_dtc_setting(self, on)
+    }
+
+    fn is_in_diag_session(&self) -> bool {
+        ProtocolServer::is_in_diag_session(self)
+    }
+
+    fn get_last_error(&self) -> Option<String> {
+        ProtocolServer::get_last_error(self)
+    }
+
+    fn exit_diag_session(&mut self) {
+        ProtocolServer::exit_diag_session(self)
+    }
+}
+
+/// OBD-II has no diagnostic session to keep alive, so each request opens the ISO-TP
+/// interface, sends the request to the ECU, then closes the interface again
+#[derive(Debug)]
+struct OBD2Server {
+    server: Mutex<Box<dyn ComServer>>,
+    cfg: ISO15765Config,
+}
+
+impl OBD2Server {
+    fn new(server: Box<dyn ComServer>, cfg: &ISO15765Config) -> Self {
+        Self {
+            server: Mutex::new(server),
+            cfg: *cfg,
+        }
+    }
+}
+
+impl DynProtocolServer for OBD2Server {
+    fn run_command(&self, cmd: u8, args: &[u8]) -> ProtocolResult<Vec<u8>> {
+        let mut server = self.server.lock().unwrap();
+        server
+            .open_iso15765_interface_for(&self.cfg)
+            .map_err(ProtocolError::CommError)?;
+        let mut data = vec![cmd];
+        data.extend_from_slice(args);
+        let res = server
+            .configure_iso15765(&self.cfg)
+            .and_then(|_| {
+                server.send_receive_iso15765(
+                    self.cfg.to_iso15765_data(self.cfg.send_id, &data),
+                    crate::settings::get_settings().cmd_timeout_ms as u128,
+                    1,
+                )
+            })
+            .map_err(ProtocolError::CommError);
+        if let Err(e) = server.close_iso15765_interface() {
+            eprintln!("OBD-II - Could not close ISO-TP interface: {}", e)
+        }
+        match res?
+            .into_iter()
+            .next()
+            .map(|m| self.cfg.strip_ext_addr(m.data))
+        {
+            None => Err(ProtocolError::Timeout),
+            // OBD-II on CAN uses the same negative response codes as UDS
+            Some(resp) if resp.get(0) == Some(&0x7F) => {
+                Err(ProtocolError::negative_response::<UDSNegativeCode>(
+                    resp.get(2).copied().unwrap_or_default(),
+                ))
+            }
+            Some(resp) => Ok(resp),
+        }
+    }
+
+    fn read_errors(&self) -> ProtocolResult<Vec<DTC>> {
+        // Service 03 - Show stored DTCs
+        let resp = self.run_command(0x03, &[])?;
+        if resp.get(0) != Some(&0x43) {
+            return Err(ProtocolError::ResponseMismatch {
+                expect: vec![0x43],
+                actual: resp,
+            });
+        }
+        Ok(decode_dtcs(&resp[1..], true, DTCCategory::Stored))
+    }
+
+    fn clear_errors(&self) -> ProtocolResult<()> {
+        // Service 04 - Clear diagnostic information
+        self.run_command(0x04, &[]).map(|_| ())
+    }
+
+    fn is_in_diag_session(&self) -> bool {
+        true
+    }
+
+    fn get_last_error(&self) -> Option<String> {
+        None
+    }
+
+    fn exit_diag_session(&mut self) {}
+}
+
+#[cfg(test)]
+mod registry_test {
+    use super::start_protocol;
+    use crate::commapi::{comm_api::ISO15765Config, mock_api::MockComServer};
+
+    #[test]
+    fn obd_reads_errors_from_configured_ecu() {
+        let mock = MockComServer::new();
+        let cfg = ISO15765Config {
+            send_id: 0x7E1,
+            recv_id: 0x7E9,
+            block_size: 8,
+            sep_time: 20,
+            auto_fc: false,
+            can_fd: None,
+            padding: Some(0xAA),
+            ext_addr: None,
+        };
+        let server = start_protocol("OBD-II", Box::new(mock.clone()), &cfg).unwrap();
+        let dtcs = server.read_errors().unwrap();
+        assert_eq!(
+            dtcs.iter().map(|d| d.error.as_str()).collect::<Vec<_>>(),
+            vec!["P0171"]
+        );
+        // Sent to the ECU's own ID, not the functional OBD-II ID (0x7DF)
+        let sent = mock.take_sent_iso15765_data();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].id, 0x7E1);
+        assert_eq!(sent[0].data, vec![0x03]);
+        assert!(sent[0].pad_frame);
+    }
+}
@@ -0,0 +1,37 @@
+use crate::commapi::protocols::{ProtocolResult, ProtocolServer};
+
+use super::UDSECU;
+
+// The service, Control DTC Setting ($85), is used by the diagnostic tool to stop or resume
+// the setting of diagnostic trouble codes in an ECU. Whilst DTC setting is off, the ECU keeps
+// running its fault detection, but does not store any new DTCs. This is useful during
+// procedures such as actuator tests, which would otherwise leave spurious faults behind.
+//
+// Request format:
+// Byte 0 - DTC setting type (0x01 - On, 0x02 - Off)
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DTCSettingType {
+    /// ECU resumes storing DTCs
+    On,
+    /// ECU stops storing DTCs
+    Off,
+}
+
+impl DTCSettingType {
+    fn to_byte(&self) -> u8 {
+        match &self {
+            DTCSettingType::On => 0x01,
+            DTCSettingType::Off => 0x02,
+        }
+    }
+}
+
+/// Asks the ECU to start or stop storing DTCs
+pub fn control_dtc_setting(ecu: &UDSECU, setting: DTCSettingType) -> ProtocolResult<()> {
+    ecu.run_command(
+        super::UDSCommand::ControlDTCSetting.into(),
+        &[setting.to_byte()],
+    )?;
+    Ok(())
+}
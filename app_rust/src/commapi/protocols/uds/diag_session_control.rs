@@ -1,4 +1,4 @@
-use crate::commapi::protocols::{ProtocolResult, ProtocolServer};
+use crate::commapi::protocols::{ProtocolResult, ProtocolServer, SessionSupport};
 
 use super::UDSECU;
 
@@ -38,3 +38,34 @@ pub fn set_diag_session(ecu: &UDSECU, mode: DiagSession) -> ProtocolResult<()> {
     )?;
     Ok(())
 }
+
+/// Sessions tried when probing which sessions the ECU supports
+const PROBE_SESSIONS: [DiagSession; 4] = [
+    DiagSession::Default,
+    DiagSession::Programming,
+    DiagSession::Extended,
+    DiagSession::SafetySystem,
+];
+
+/// Works out which diagnostic sessions the ECU supports, by asking it to enter each one.
+/// After each session the ECU enters, it is returned to the default session. Once done, the
+/// ECU is put back in the session it was in before probing
+pub fn probe_sessions(ecu: &mut UDSECU) -> Vec<SessionSupport> {
+    let prev = ecu.get_session_type();
+    let res: Vec<SessionSupport> = PROBE_SESSIONS
+        .iter()
+        .map(|mode| {
+            let res = set_diag_session(ecu, *mode);
+            if res.is_ok() && *mode != DiagSession::Default {
+                let _ = set_diag_session(ecu, DiagSession::Default);
+            }
+            SessionSupport::new(format!("{:?}", mode), &res)
+        })
+        .collect();
+    if prev != DiagSession::Default {
+        if let Err(e) = ecu.set_diag_session_mode(prev) {
+            eprintln!("Could not return ECU to {:?} session: {}", prev, e);
+        }
+    }
+    res
+}
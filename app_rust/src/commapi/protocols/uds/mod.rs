@@ -1,3 +1,4 @@
+use self::control_dtc_setting::DTCSettingType;
 use self::diag_session_control::DiagSession;
 use super::{
     CautionLevel, CommandError, ECUCommand, ProtocolError, ProtocolResult, ProtocolServer,
@@ -14,7 +15,10 @@ use std::{
     time::Instant,
 };
 
+pub mod control_dtc_setting;
 pub mod diag_session_control;
+pub mod read_dtc_information;
+pub mod read_memory_by_address;
 
 #[derive(Copy, Clone, Debug, Eq, PartialOrd, PartialEq)]
 /// UDS Commands AKA SID (Service identifiers)
@@ -398,10 +402,13 @@ pub struct UDSECU {
     should_run: Arc<AtomicBool>,
     last_error: Arc<RwLock<Option<ProtocolError>>>,
     cmd_tx: Sender<(u8, Vec<u8>, bool)>,
-    cmd_rx: Arc<Receiver<ProtocolResult<Vec<u8>>>>,
+    /// Responses from the diagnostic server thread. Locked whilst a command is sent and its
+    /// response received, so commands from other threads cannot take each other's responses
+    cmd_rx: Arc<Mutex<Receiver<ProtocolResult<Vec<u8>>>>>,
     curr_session_type: Arc<RwLock<DiagSession>>,
     send_id: u32,
-    cmd_mutex: Arc<Mutex<()>>,
+    /// ECU has been told to stop storing DTCs
+    dtc_setting_off: Arc<AtomicBool>,
 }
 
 impl UDSECU {
@@ -410,7 +417,7 @@ impl UDSECU {
         Ok(())
     }
 
-    fn set_diag_session_mode(
+    pub(crate) fn set_diag_session_mode(
         &mut self,
         mode: DiagSession,
     ) -> std::result::Result<(), ProtocolError> {
@@ -429,6 +436,24 @@ impl UDSECU {
     pub fn get_session_type(&self) -> DiagSession {
         *self.curr_session_type.read().unwrap()
     }
+
+    /// Turns storing of DTCs in the ECU on or off. DTC setting is turned back on
+    /// automatically when the diagnostic session ends
+    pub fn control_dtc_setting(&self, on: bool) -> ProtocolResult<()> {
+        let setting = if on {
+            DTCSettingType::On
+        } else {
+            DTCSettingType::Off
+        };
+        control_dtc_setting::control_dtc_setting(self, setting)?;
+        self.dtc_setting_off.store(!on, Relaxed);
+        Ok(())
+    }
+
+    /// Returns true if the ECU has been told to stop storing DTCs
+    pub fn is_dtc_setting_off(&self) -> bool {
+        self.dtc_setting_off.load(Relaxed)
+    }
 }
 
 impl ProtocolServer for UDSECU {
@@ -439,7 +464,7 @@ impl ProtocolServer for UDSECU {
         cfg: &ISO15765Config,
     ) -> ProtocolResult<Self> {
         comm_server
-            .open_iso15765_interface(500_000, false, false)
+            .open_iso15765_interface_for(cfg)
             .map_err(ProtocolError::CommError)?;
         comm_server
             .configure_iso15765(cfg)
@@ -464,15 +489,15 @@ impl ProtocolServer for UDSECU {
         let session_type_t = session_type.clone();
 
         // Enter extended diagnostic session (Full features)
-        let s_id = cfg.send_id;
+        let mut fc_cfg = *cfg;
         std::thread::spawn(move || {
             println!("Diag server start!");
             let mut timer = Instant::now();
             while should_run_t.load(Relaxed) {
                 if let Ok(data) = channel_tx_receiver.try_recv() {
-                    let res = Self::run_command_iso_tp(
+                    let res = Self::run_command_iso_tp_auto_fc(
                         comm_server.as_ref(),
-                        s_id,
+                        &mut fc_cfg,
                         data.0,
                         &data.1,
                         data.2,
@@ -488,7 +513,7 @@ impl ProtocolServer for UDSECU {
                 {
                     if Self::run_command_iso_tp(
                         comm_server.as_ref(),
-                        s_id,
+                        &fc_cfg,
                         UDSCommand::TesterPresent.into(),
                         &[0x01],
                         true,
@@ -510,10 +535,10 @@ impl ProtocolServer for UDSECU {
             should_run,
             last_error,
             cmd_tx: channel_tx_sender,
-            cmd_rx: Arc::new(channel_rx_receiver),
+            cmd_rx: Arc::new(Mutex::new(channel_rx_receiver)),
             send_id: cfg.send_id,
             curr_session_type: session_type, // Assumed,
-            cmd_mutex: Arc::new(Mutex::new(())),
+            dtc_setting_off: Arc::new(AtomicBool::new(false)),
         };
 
         if let Err(e) = ecu.set_diag_session_mode(DiagSession::Extended) {
@@ -524,25 +549,47 @@ impl ProtocolServer for UDSECU {
     }
 
     fn exit_diag_session(&mut self) {
+        // Don't leave the ECU unable to store DTCs once we are gone
+        if self.is_dtc_setting_off() && self.is_in_diag_session() {
+            if let Err(e) = self.control_dtc_setting(true) {
+                eprintln!("UDS - Could not turn DTC setting back on: {}", e);
+            }
+        }
         self.should_run.store(false, Relaxed);
     }
 
     fn run_command(&self, cmd: u8, args: &[u8]) -> ProtocolResult<Vec<u8>> {
-        let _guard = self.cmd_mutex.lock().unwrap(); // We are allowed to send / receive!
+        let cmd_rx = self.cmd_rx.lock().unwrap(); // We are allowed to send / receive!
         if self.cmd_tx.send((cmd, Vec::from(args), true)).is_err() {
             return Err(ProtocolError::CustomError("Channel Tx failed".into()));
         }
-        let resp = self.cmd_rx.recv().unwrap()?;
+        let resp = cmd_rx.recv().unwrap()?;
         if resp[0] == 0x7F {
-            let neg_code = UDSNegativeCode::from_byte(resp[2]);
-            Err(ProtocolError::ProtocolError(Box::new(neg_code)))
+            Err(ProtocolError::negative_response::<UDSNegativeCode>(resp[2]))
         } else {
             Ok(resp)
         }
     }
 
+    fn send_command(&self, cmd: u8, args: &[u8]) -> ProtocolResult<()> {
+        let cmd_rx = self.cmd_rx.lock().unwrap(); // We are allowed to send / receive!
+        if self.cmd_tx.send((cmd, Vec::from(args), false)).is_err() {
+            return Err(ProtocolError::CustomError("Channel Tx failed".into()));
+        }
+        cmd_rx.recv().unwrap().map(|_| ())
+    }
+
+    fn has_sub_function(cmd: u8) -> bool {
+        // Services whose sub-function supports the suppressPosRspMsgIndicationBit.
+        // 0x19 has a sub-function, but its response cannot be suppressed
+        matches!(
+            cmd,
+            0x10 | 0x11 | 0x27 | 0x28 | 0x2C | 0x31 | 0x3E | 0x83 | 0x85 | 0x86 | 0x87
+        )
+    }
+
     fn read_errors(&self) -> ProtocolResult<Vec<DTC>> {
-        todo!()
+        read_dtc_information::read_dtcs(self)
     }
 
     fn is_in_diag_session(&self) -> bool {
@@ -558,46 +605,49 @@ impl ProtocolServer for UDSECU {
 
     fn run_command_iso_tp(
         server: &dyn ComServer,
-        send_id: u32,
+        cfg: &ISO15765Config,
         cmd: u8,
         args: &[u8],
         receive_require: bool,
     ) -> Result<Vec<u8>, ProtocolError> {
-        let mut data = crate::commapi::comm_api::ISO15765Data {
-            id: send_id,
-            data: vec![cmd],
-            pad_frame: false,
-            ext_addressing: false,
-        };
-        data.data.extend_from_slice(args);
+        let data = cfg.to_iso15765_data(cfg.send_id, &[&[cmd], args].concat());
         if !receive_require {
             server
                 .send_iso15765_data(&[data], 0)
                 .map(|_| vec![])
                 .map_err(ProtocolError::CommError)
         } else {
-            // Await max 1 second for response
-            let res = server.send_receive_iso15765(data, 1000, 1)?;
+            // Await max timeout (From settings) for response
+            let settings = crate::settings::get_settings();
+            let timeout = settings.cmd_timeout_ms as u128;
+            let res = server.send_receive_iso15765_mf(
+                data,
+                timeout,
+                settings.multi_frame_timeout_ms as u128,
+                1,
+            )?;
             if res.is_empty() {
                 return Err(ProtocolError::Timeout);
             }
-            let mut tmp_res = res[0].data.clone();
+            let mut tmp_res = cfg.strip_ext_addr(res[0].data.clone());
             if tmp_res[0] == 0x7F && tmp_res[2] == 0x78 {
                 // ResponsePending
                 println!("UDS - ECU is processing request - Waiting!");
                 let start = Instant::now();
-                while start.elapsed().as_millis() < 1000 {
+                while start.elapsed().as_millis() < timeout {
                     // ECU is sending a response, but its busy right now. just gotta wait for the ECU to give us its response!
-                    if let Some(msg) = server.read_iso15765_packets(0, 1)?.get(0) {
-                        tmp_res = msg.data.clone();
+                    if let Some(msg) = server
+                        .read_iso15765_packets(0, 1)?
+                        .into_iter()
+                        .find(|m| !m.data.is_empty())
+                    {
+                        tmp_res = cfg.strip_ext_addr(msg.data);
                     }
                 }
             }
             if tmp_res[0] == 0x7F {
                 // Still error :(
-                Err(ProtocolError::ProtocolError(Box::new(
-                    Self::Error::from_byte(tmp_res[2]),
-                )))
+                Err(ProtocolError::negative_response::<Self::Error>(tmp_res[2]))
             } else if tmp_res[0] == (cmd + 0x40) {
                 Ok(tmp_res)
             } else {
@@ -0,0 +1,113 @@
+use crate::commapi::protocols::{
+    get_cmd_attempts, DTCCategory, DTCSeverity, ProtocolError, ProtocolResult, ProtocolServer, DTC,
+};
+
+use super::UDSECU;
+
+// The service, Read DTC Information ($19), allows a client to read the status of
+// DTCs stored in the ECU. Sub function 0x02 reports DTCs matching a status mask,
+// sub function 0x42 (Optional) also reports the severity of each DTC, and sub
+// function 0x15 (Optional) reports DTCs with permanent status.
+//
+// DTC status byte:
+// Bit 0 - Test failed (Present)
+// Bit 2 - Pending DTC
+// Bit 3 - Confirmed DTC (Stored)
+// Bit 7 - Warning indicator requested (MIL)
+
+/// Functional group identifier for emissions related systems (Sub function 0x42)
+const FGID_EMISSIONS: u8 = 0x33;
+
+fn dtc_name(bytes: &[u8]) -> String {
+    format!("{:02X}{:02X}{:02X}", bytes[0], bytes[1], bytes[2])
+}
+
+/// Reads a list of 4 byte DTC records (3 byte DTC ID, 1 byte status) using the sub function
+fn read_dtc_records(ecu: &UDSECU, args: &[u8], category: DTCCategory) -> ProtocolResult<Vec<DTC>> {
+    let bytes = ecu.run_command_retry(
+        super::UDSCommand::ReadDTCInformation.into(),
+        args,
+        get_cmd_attempts(),
+    )?;
+    if bytes.len() < 3 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: 3,
+            actual: bytes.len(),
+        });
+    }
+    // 3 byte DTC ID, 1 byte status
+    Ok(bytes[3..]
+        .chunks_exact(4)
+        .map(|record| {
+            let status = record[3];
+            let stored = status & 0b0000_1000 != 0;
+            let pending = status & 0b0000_0100 != 0;
+            DTC {
+                error: dtc_name(record),
+                present: status & 0b0000_0001 != 0,
+                stored,
+                check_engine_on: status & 0b1000_0000 != 0,
+                severity: None,
+                category: match category {
+                    DTCCategory::Stored if pending && !stored => DTCCategory::Pending,
+                    c => c,
+                },
+            }
+        })
+        .collect())
+}
+
+/// Reads DTCs matching the status mask from the ECU
+pub fn read_dtc_by_status_mask(ecu: &UDSECU, status_mask: u8) -> ProtocolResult<Vec<DTC>> {
+    read_dtc_records(ecu, &[0x02, status_mask], DTCCategory::Stored)
+}
+
+/// Reads DTCs with permanent status from the ECU
+pub fn read_permanent_dtcs(ecu: &UDSECU) -> ProtocolResult<Vec<DTC>> {
+    read_dtc_records(ecu, &[0x15], DTCCategory::Permanent)
+}
+
+/// Reads the severity of DTCs from the ECU. Returns a list of (DTC name, Severity)
+pub fn read_dtc_severity(ecu: &UDSECU) -> ProtocolResult<Vec<(String, DTCSeverity)>> {
+    let bytes = ecu.run_command_retry(
+        super::UDSCommand::ReadDTCInformation.into(),
+        &[0x42, FGID_EMISSIONS, 0xFF, 0xFF],
+        get_cmd_attempts(),
+    )?;
+    if bytes.len() < 6 {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: 6,
+            actual: bytes.len(),
+        });
+    }
+    // 1 byte severity, 3 byte DTC ID, 1 byte status
+    Ok(bytes[6..]
+        .chunks_exact(5)
+        .map(|record| (dtc_name(&record[1..4]), DTCSeverity(record[0])))
+        .collect())
+}
+
+/// Reads all stored, pending and permanent DTCs in the ECU, including their
+/// severity if the ECU reports it
+pub fn read_dtcs(ecu: &UDSECU) -> ProtocolResult<Vec<DTC>> {
+    let mut dtcs = read_dtc_by_status_mask(ecu, 0xFF)?;
+    // Not all ECUs support reporting permanent DTCs
+    if let Ok(permanent) = read_permanent_dtcs(ecu) {
+        for p in permanent {
+            match dtcs.iter_mut().find(|d| d.error == p.error) {
+                Some(d) => d.category = DTCCategory::Permanent,
+                None => dtcs.push(p),
+            }
+        }
+    }
+    // Not all ECUs support reporting severity, so DTCs are still valid without it
+    if let Ok(severities) = read_dtc_severity(ecu) {
+        for dtc in dtcs.iter_mut() {
+            dtc.severity = severities
+                .iter()
+                .find(|(name, _)| name == &dtc.error)
+                .map(|(_, s)| *s);
+        }
+    }
+    Ok(dtcs)
+}
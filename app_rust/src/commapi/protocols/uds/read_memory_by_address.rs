@@ -0,0 +1,224 @@
+use crate::commapi::protocols::{get_cmd_attempts, ProtocolError, ProtocolResult, ProtocolServer};
+
+use super::UDSECU;
+
+// The service, Read Memory By Address ($23), allows a client to read a block of memory
+// from the ECU, starting at a given address. The number of bytes the ECU returns in a single
+// response is limited by the ECU's max block length, so large regions have to be read
+// as a series of requests.
+//
+// Request format:
+// Byte 0 - Address and length format identifier (High nibble - Size bytes, Low nibble - Address bytes)
+// Byte 1..n - Memory address (MSB first)
+// Byte n..m - Memory size (MSB first)
+
+/// Largest block that fits in a single ISO-TP response (4095 bytes, minus the response SID)
+pub const MAX_BLOCK_LEN: usize = 4094;
+
+/// Number of bytes used to encode the memory size of each request
+const SIZE_BYTES: u8 = 2;
+
+/// Reads a single block of memory from the ECU
+///
+/// ## Params
+/// * address - Start address of the block
+/// * addr_bytes - Number of bytes the ECU expects the address to be encoded in (1-4)
+/// * size - Number of bytes to read
+pub fn read_memory_block(
+    ecu: &UDSECU,
+    address: u32,
+    addr_bytes: u8,
+    size: u16,
+) -> ProtocolResult<Vec<u8>> {
+    if addr_bytes == 0 || addr_bytes > 4 {
+        return Err(ProtocolError::CustomError(format!(
+            "Invalid address length of {} bytes",
+            addr_bytes
+        )));
+    }
+    let mut args = vec![(SIZE_BYTES << 4) | addr_bytes];
+    args.extend_from_slice(&address.to_be_bytes()[4 - addr_bytes as usize..]);
+    args.extend_from_slice(&size.to_be_bytes());
+    let mut res = ecu.run_command_retry(
+        super::UDSCommand::ReadMemoryByAddress.into(),
+        &args,
+        get_cmd_attempts(),
+    )?;
+    res.drain(..1);
+    if res.len() != size as usize {
+        return Err(ProtocolError::InvalidResponseSize {
+            expect: size as usize,
+            actual: res.len(),
+        });
+    }
+    Ok(res)
+}
+
+/// Reads a region of memory from the ECU that may be larger than the ECU's max block length,
+/// by reading it as a series of blocks at incrementing addresses
+///
+/// ## Params
+/// * address - Start address of the region
+/// * addr_bytes - Number of bytes the ECU expects the address to be encoded in (1-4)
+/// * len - Number of bytes to read
+/// * max_block_len - Max number of bytes the ECU returns per request, as advertised by the ECU.
+/// Clamped to [MAX_BLOCK_LEN]
+/// * on_progress - Called after each block is read with the number of bytes read so far, and the
+/// total number of bytes to read
+pub fn read_memory_region<F: FnMut(usize, usize)>(
+    ecu: &UDSECU,
+    address: u32,
+    addr_bytes: u8,
+    len: usize,
+    max_block_len: usize,
+    mut on_progress: F,
+) -> ProtocolResult<Vec<u8>> {
+    let block_len = max_block_len.min(MAX_BLOCK_LEN);
+    if block_len == 0 {
+        return Err(ProtocolError::CustomError(
+            "ECU max block length cannot be 0".into(),
+        ));
+    }
+    if address as u64 + len as u64 > 1u64 << (addr_bytes.min(4) as u64 * 8) {
+        return Err(ProtocolError::CustomError(format!(
+            "Region of {} bytes at 0x{:08X} does not fit in a {} byte address",
+            len, address, addr_bytes
+        )));
+    }
+    let mut buffer = Vec::with_capacity(len);
+    while buffer.len() < len {
+        let size = block_len.min(len - buffer.len());
+        let block = read_memory_block(ecu, address + buffer.len() as u32, addr_bytes, size as u16)?;
+        buffer.extend_from_slice(&block);
+        on_progress(buffer.len(), len);
+    }
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod read_memory_by_address_test {
+    use super::{read_memory_block, read_memory_region};
+    use crate::commapi::{
+        comm_api::ISO15765Config,
+        mock_api::MockComServer,
+        protocols::{uds::UDSECU, ProtocolError, ProtocolServer},
+    };
+
+    fn start_session(server: &MockComServer) -> UDSECU {
+        let ecu = UDSECU::start_diag_session(
+            Box::new(server.clone()),
+            &ISO15765Config::new_auto_fc(0x7E0, 0x7E8),
+        )
+        .unwrap();
+        // Forget the session control request
+        server.take_sent_iso15765_data();
+        ecu
+    }
+
+    /// Returns the payloads sent to the ECU
+    fn sent(server: &MockComServer) -> Vec<Vec<u8>> {
+        server
+            .take_sent_iso15765_data()
+            .into_iter()
+            .map(|d| d.data)
+            .collect()
+    }
+
+    #[test]
+    fn address_and_length_format() {
+        let server = MockComServer::new();
+        let mut ecu = start_session(&server);
+        server.script_iso15765_responses(&[Some(vec![0x63, 0xAA, 0xBB]), Some(vec![0x63, 0xCC])]);
+        assert_eq!(
+            read_memory_block(&ecu, 0x00123456, 3, 2).unwrap(),
+            vec![0xAA, 0xBB]
+        );
+        assert_eq!(
+            read_memory_block(&ecu, 0x80001000, 4, 1).unwrap(),
+            vec![0xCC]
+        );
+        // High nibble is the number of size bytes, low nibble the number of address bytes
+        assert_eq!(
+            sent(&server),
+            vec![
+                vec![0x23, 0x23, 0x12, 0x34, 0x56, 0x00, 0x02],
+                vec![0x23, 0x24, 0x80, 0x00, 0x10, 0x00, 0x00, 0x01],
+            ]
+        );
+        assert!(read_memory_block(&ecu, 0x00, 5, 1).is_err());
+        assert!(sent(&server).is_empty());
+        ecu.exit_diag_session();
+    }
+
+    #[test]
+    fn block_of_wrong_size_is_rejected() {
+        let server = MockComServer::new();
+        let mut ecu = start_session(&server);
+        server.script_iso15765_responses(&[Some(vec![0x63, 0xAA])]);
+        match read_memory_block(&ecu, 0x1000, 2, 2) {
+            Err(ProtocolError::InvalidResponseSize { expect, actual }) => {
+                assert_eq!((expect, actual), (2, 1))
+            }
+            res => panic!("Expected an invalid response size, got {:?}", res),
+        }
+        ecu.exit_diag_session();
+    }
+
+    #[test]
+    fn region_is_read_in_blocks() {
+        let server = MockComServer::new();
+        let mut ecu = start_session(&server);
+        server.script_iso15765_responses(&[
+            Some(vec![0x63, 0x00, 0x01, 0x02, 0x03]),
+            Some(vec![0x63, 0x04, 0x05, 0x06, 0x07]),
+            Some(vec![0x63, 0x08, 0x09]),
+        ]);
+        let mut progress = Vec::new();
+        let data = read_memory_region(&ecu, 0x2000, 2, 10, 4, |read, total| {
+            progress.push((read, total))
+        })
+        .unwrap();
+        assert_eq!(data, (0..10).collect::<Vec<u8>>());
+        assert_eq!(progress, vec![(4, 10), (8, 10), (10, 10)]);
+        assert_eq!(
+            sent(&server),
+            vec![
+                vec![0x23, 0x22, 0x20, 0x00, 0x00, 0x04],
+                vec![0x23, 0x22, 0x20, 0x04, 0x00, 0x04],
+                vec![0x23, 0x22, 0x20, 0x08, 0x00, 0x02],
+            ]
+        );
+        ecu.exit_diag_session();
+    }
+
+    #[test]
+    fn region_spans_block_boundary() {
+        let server = MockComServer::new();
+        let mut ecu = start_session(&server);
+        // Region starts 2 bytes before the end of one ECU block, and ends 2 bytes into the next
+        server.script_iso15765_responses(&[
+            Some(vec![0x63, 0xA0, 0xA1, 0xA2, 0xA3]),
+            Some(vec![0x63, 0xB0]),
+        ]);
+        let data = read_memory_region(&ecu, 0x00FE, 2, 5, 4, |_, _| {}).unwrap();
+        assert_eq!(data, vec![0xA0, 0xA1, 0xA2, 0xA3, 0xB0]);
+        assert_eq!(
+            sent(&server),
+            vec![
+                vec![0x23, 0x22, 0x00, 0xFE, 0x00, 0x04],
+                vec![0x23, 0x22, 0x01, 0x02, 0x00, 0x01],
+            ]
+        );
+        ecu.exit_diag_session();
+    }
+
+    #[test]
+    fn region_must_fit_in_address() {
+        let server = MockComServer::new();
+        let mut ecu = start_session(&server);
+        assert!(read_memory_region(&ecu, 0xFFFE, 2, 4, 4, |_, _| {}).is_err());
+        assert!(read_memory_region(&ecu, 0x1000, 2, 4, 0, |_, _| {}).is_err());
+        assert!(sent(&server).is_empty());
+        ecu.exit_diag_session();
+    }
+}
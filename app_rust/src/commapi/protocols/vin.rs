@@ -1,3 +1,5 @@
+use super::{ProtocolError, ProtocolResult};
+
 #[derive(Debug, Clone)]
 pub struct Vin {
     pub raw: String,
@@ -72,6 +74,28 @@ impl Vin {
         (res.0.into(), res.1.into())
     }
 
+    /// Creates a VIN from the bytes an ECU replied with. Padding some ECUs add
+    /// (Spaces, 0x00 or 0xFF) is removed, and the VIN is upper-cased. None if the
+    /// result is not a valid 17 character VIN
+    pub fn from_bytes(raw: &[u8]) -> Option<Self> {
+        let is_padding = |b: &u8| *b == 0x00 || *b == 0xFF || *b == b' ';
+        let start = raw.iter().position(|b| !is_padding(b))?;
+        let end = raw.iter().rposition(|b| !is_padding(b))?;
+        let vin = String::from_utf8(raw[start..=end].to_vec())
+            .ok()?
+            .to_uppercase();
+        // I, O and Q are never used, as they look like 1 and 0
+        if vin.len() == 17
+            && vin
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() && !matches!(c, 'I' | 'O' | 'Q'))
+        {
+            Self::new(vin)
+        } else {
+            None
+        }
+    }
+
     pub fn new(str: String) -> Option<Self> {
         return if str.len() != 17 {
             None
@@ -87,3 +111,117 @@ impl Vin {
         };
     }
 }
+
+/// A request that reads the VIN, and the number of bytes at the start of the
+/// positive response before the VIN
+#[derive(Debug, Copy, Clone)]
+pub struct VinMethod {
+    pub name: &'static str,
+    pub cmd: u8,
+    pub args: &'static [u8],
+    pub header_len: usize,
+}
+
+/// OBD-II Service 09 PID 02. Response has the number of data items before the VIN
+pub const OBD_VIN: VinMethod = VinMethod {
+    name: "OBD-II Service 09 PID 02",
+    cmd: 0x09,
+    args: &[0x02],
+    header_len: 3,
+};
+
+/// UDS ReadDataByIdentifier F190
+pub const UDS_VIN: VinMethod = VinMethod {
+    name: "UDS DID F190",
+    cmd: 0x22,
+    args: &[0xF1, 0x90],
+    header_len: 3,
+};
+
+/// KWP2000 ReadECUIdentification 90 (Current VIN)
+pub const KWP_VIN: VinMethod = VinMethod {
+    name: "KWP2000 identification 90 (Current VIN)",
+    cmd: 0x1A,
+    args: &[0x90],
+    header_len: 2,
+};
+
+/// KWP2000 ReadECUIdentification 88 (VIN the ECU was originally coded with)
+pub const KWP_ORIGINAL_VIN: VinMethod = VinMethod {
+    name: "KWP2000 identification 88 (Original VIN)",
+    cmd: 0x1A,
+    args: &[0x88],
+    header_len: 2,
+};
+
+/// Methods tried to read the VIN from a KWP2000 ECU, in order
+pub const KWP2000_VIN_METHODS: [VinMethod; 4] = [KWP_VIN, KWP_ORIGINAL_VIN, UDS_VIN, OBD_VIN];
+
+/// Methods tried to read the VIN from a UDS ECU, in order
+pub const UDS_VIN_METHODS: [VinMethod; 3] = [UDS_VIN, OBD_VIN, KWP_VIN];
+
+/// Reads the VIN, trying each method in order until one of them returns a valid VIN.
+/// If every method fails, the error of the last method is returned
+///
+/// ## Params
+/// * run_cmd - Runs a command on the ECU, returning its positive response
+/// * methods - Methods to try, in order
+pub fn read_vin<F>(mut run_cmd: F, methods: &[VinMethod]) -> ProtocolResult<Vin>
+where
+    F: FnMut(u8, &[u8]) -> ProtocolResult<Vec<u8>>,
+{
+    let mut err = ProtocolError::CustomError("No methods to read the VIN with".into());
+    for m in methods {
+        match run_cmd(m.cmd, m.args) {
+            Ok(res) => match res.get(m.header_len..).and_then(Vin::from_bytes) {
+                Some(vin) => {
+                    println!("Read VIN using {}", m.name);
+                    return Ok(vin);
+                }
+                None => {
+                    err = ProtocolError::CustomError(format!(
+                        "ECU replied to {} with an invalid VIN",
+                        m.name
+                    ))
+                }
+            },
+            Err(e) => {
+                println!("Could not read VIN using {} - {}", m.name, e.get_text());
+                err = e
+            }
+        }
+    }
+    Err(err)
+}
+
+#[cfg(test)]
+mod vin_test {
+    use super::{read_vin, Vin, KWP2000_VIN_METHODS};
+    use crate::commapi::protocols::ProtocolError;
+
+    #[test]
+    fn normalize() {
+        let vin = Vin::from_bytes(b"\x00wdd2030461a123456  ").unwrap();
+        assert_eq!(vin.raw, "WDD2030461A123456");
+        assert_eq!(vin.manufacture_name, "Daimler AG");
+        assert_eq!(vin.year, 2001);
+        // Too short, and containing letters VINs never use
+        assert!(Vin::from_bytes(b"WDD2030461A12345").is_none());
+        assert!(Vin::from_bytes(b"WDD2030461AI23456").is_none());
+        assert!(Vin::from_bytes(&[0xFF; 17]).is_none());
+    }
+
+    #[test]
+    fn fallback() {
+        let vin = read_vin(
+            |cmd, args| match (cmd, args) {
+                (0x1A, [0x90]) => Err(ProtocolError::Timeout),
+                (0x1A, [0x88]) => Ok(b"\x5A\x88WDD2030461A123456".to_vec()),
+                _ => panic!("Fallback went past the first working method"),
+            },
+            &KWP2000_VIN_METHODS,
+        )
+        .unwrap();
+        assert_eq!(vin.raw, "WDD2030461A123456");
+    }
+}
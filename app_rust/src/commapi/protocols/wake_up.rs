@@ -0,0 +1,244 @@
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::commapi::{
+    comm_api::{get_can_bitrate, CanFrame, ComServer, FilterType, ISO15765Config},
+    iso_tp,
+};
+
+use super::{ProtocolError, ProtocolResult};
+
+// Some ECUs ignore the first diagnostic request they see, or only respond once they have
+// been woken up by a specific message. The wake up step is sent as raw CAN frames just before
+// the diagnostic session is started, so the ECU is awake when the session's first request
+// is sent.
+
+/// Time to listen for responses after the last wake up frame, in milliseconds
+const LISTEN_TIME_MS: u128 = 100;
+
+/// Step run before starting a diagnostic session with an ECU
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WakeUpInit {
+    /// Frames in hex, sent in order. Frames are sent to the ECU's send ID, unless
+    /// an ID is given before a '#'. EG: '02 3E 01' or '7DF#02 01 00'
+    Raw { frames: Vec<String>, delay_ms: u64 },
+    /// A wake up routine built into OVD, by name. See [WAKE_UP_ROUTINES]
+    Routine(String),
+}
+
+impl std::fmt::Display for WakeUpInit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WakeUpInit::Raw { frames, delay_ms } => write!(
+                f,
+                "Raw frames [{}], {}ms apart",
+                frames.join(", "),
+                delay_ms
+            ),
+            WakeUpInit::Routine(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// A wake up routine built into OVD
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WakeUpRoutine {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Frames sent, in the same format as [WakeUpInit::Raw]
+    frames: &'static [&'static str],
+    delay_ms: u64,
+}
+
+impl std::fmt::Display for WakeUpRoutine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+pub const WAKE_UP_ROUTINES: &[WakeUpRoutine] = &[
+    WakeUpRoutine {
+        name: "OBD-II broadcast",
+        description: "Requests the supported PIDs from every ECU, which wakes ECUs that only \
+            start listening once the OBD-II port is active",
+        frames: &["7DF#02 01 00 00 00 00 00 00"],
+        delay_ms: 0,
+    },
+    WakeUpRoutine {
+        name: "Tester present (KWP2000)",
+        description: "Sends tester present to the ECU, for ECUs that ignore the first request",
+        frames: &["02 3E 01 00 00 00 00 00"],
+        delay_ms: 0,
+    },
+    WakeUpRoutine {
+        name: "Tester present (UDS)",
+        description: "Sends tester present to the ECU, for ECUs that ignore the first request",
+        frames: &["02 3E 00 00 00 00 00 00"],
+        delay_ms: 0,
+    },
+    WakeUpRoutine {
+        name: "Bus activity burst",
+        description: "Sends 5 empty frames to the ECU, 20ms apart, for ECUs that \
+            sleep until they see traffic for a while",
+        frames: &[
+            "00 00 00 00 00 00 00 00",
+            "00 00 00 00 00 00 00 00",
+            "00 00 00 00 00 00 00 00",
+            "00 00 00 00 00 00 00 00",
+            "00 00 00 00 00 00 00 00",
+        ],
+        delay_ms: 20,
+    },
+];
+
+/// Returns the built in wake up routine called `name`
+pub fn get_wake_up_routine(name: &str) -> Option<&'static WakeUpRoutine> {
+    WAKE_UP_ROUTINES.iter().find(|r| r.name == name)
+}
+
+/// Parses a wake up frame. EG: '02 3E 01' (Sent to `send_id`) or '7DF#02 01 00'
+pub fn parse_wake_up_frame(s: &str, send_id: u32) -> Option<CanFrame> {
+    let (id, data) = match s.find('#') {
+        Some(idx) => (
+            u32::from_str_radix(s[..idx].trim(), 16).ok()?,
+            &s[idx + 1..],
+        ),
+        None => (send_id, s),
+    };
+    let bytes = hex::decode(data.replace(' ', "")).ok()?;
+    if bytes.len() > 8 {
+        return None;
+    }
+    Some(CanFrame::new(id, &bytes))
+}
+
+impl WakeUpInit {
+    /// Returns the frames to send and the delay between them, or an error describing
+    /// which part of the wake up step is invalid
+    pub fn get_frames(&self, send_id: u32) -> ProtocolResult<(Vec<CanFrame>, u64)> {
+        let (frames, delay_ms): (Vec<&str>, u64) = match self {
+            WakeUpInit::Raw { frames, delay_ms } => {
+                (frames.iter().map(|f| f.as_str()).collect(), *delay_ms)
+            }
+            WakeUpInit::Routine(name) => {
+                let routine = get_wake_up_routine(name).ok_or_else(|| {
+                    ProtocolError::CustomError(format!("Unknown wake up routine {}", name))
+                })?;
+                (routine.frames.to_vec(), routine.delay_ms)
+            }
+        };
+        let frames = frames
+            .iter()
+            .map(|f| {
+                parse_wake_up_frame(f, send_id).ok_or_else(|| {
+                    ProtocolError::CustomError(format!("Invalid wake up frame '{}'", f))
+                })
+            })
+            .collect::<ProtocolResult<Vec<CanFrame>>>()?;
+        Ok((frames, delay_ms))
+    }
+}
+
+/// Runs the wake up step for an ECU, before its diagnostic session is started.
+/// Returns the frames sent and received, so the exchange can be logged
+pub fn run_wake_up(
+    mut comm_server: Box<dyn ComServer>,
+    cfg: &ISO15765Config,
+    init: &WakeUpInit,
+) -> ProtocolResult<Vec<String>> {
+    let (frames, delay_ms) = init.get_frames(cfg.send_id)?;
+    let ext_can = frames.iter().any(|f| f.id > 0x7FF) || cfg.recv_id > 0x7FF;
+    comm_server
+        .open_can_interface(get_can_bitrate(), ext_can)
+        .map_err(ProtocolError::CommError)?;
+    let res = run_wake_up_can(comm_server.as_ref(), cfg, &frames, delay_ms);
+    if let Err(e) = comm_server.close_can_interface() {
+        eprintln!("Wake up - Could not close CAN interface: {}", e)
+    }
+    res
+}
+
+fn run_wake_up_can(
+    server: &dyn ComServer,
+    cfg: &ISO15765Config,
+    frames: &[CanFrame],
+    delay_ms: u64,
+) -> ProtocolResult<Vec<String>> {
+    server
+        .add_can_filter(FilterType::Pass, 0x00000000, 0x00000000)
+        .map_err(ProtocolError::CommError)?;
+    let _ = server.clear_can_rx_buffer();
+    let mut log = Vec::new();
+    for (i, frame) in frames.iter().enumerate() {
+        if i > 0 && delay_ms > 0 {
+            std::thread::sleep(std::time::Duration::from_millis(delay_ms))
+        }
+        server
+            .send_can_packets(&[*frame], 0)
+            .map_err(ProtocolError::CommError)?;
+        log.push(format!(
+            "Wake up Tx 0x{:04X}: {:02X?}",
+            frame.id,
+            frame.get_data()
+        ));
+    }
+    // Only responses from the ECU (Or diagnostic responses to a broadcast) are logged,
+    // not the rest of the bus traffic
+    let start = Instant::now();
+    while start.elapsed().as_millis() <= LISTEN_TIME_MS {
+        for f in server.read_can_packets(0, 100).unwrap_or_default() {
+            if f.id == cfg.recv_id || (iso_tp::is_diag_id(f.id) && f.id != cfg.send_id) {
+                log.push(format!("Wake up Rx 0x{:04X}: {:02X?}", f.id, f.get_data()));
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    Ok(log)
+}
+
+#[cfg(test)]
+mod wake_up_test {
+    use super::*;
+
+    #[test]
+    fn test_parse_frame() {
+        let f = parse_wake_up_frame("02 3E 01", 0x7E0).unwrap();
+        assert_eq!(f.id, 0x7E0);
+        assert_eq!(f.get_data(), &[0x02, 0x3E, 0x01]);
+        let f = parse_wake_up_frame("7DF#020100", 0x7E0).unwrap();
+        assert_eq!(f.id, 0x7DF);
+        assert_eq!(f.get_data(), &[0x02, 0x01, 0x00]);
+        assert!(parse_wake_up_frame("zz", 0x7E0).is_none());
+        assert!(parse_wake_up_frame("00 00 00 00 00 00 00 00 00", 0x7E0).is_none());
+    }
+
+    #[test]
+    fn test_routines_are_valid() {
+        for r in WAKE_UP_ROUTINES {
+            let init = WakeUpInit::Routine(r.name.into());
+            let (frames, _) = init.get_frames(0x7E0).unwrap();
+            assert_eq!(frames.len(), r.frames.len());
+        }
+        assert!(WakeUpInit::Routine("Nope".into())
+            .get_frames(0x7E0)
+            .is_err());
+    }
+
+    #[test]
+    fn test_raw_frames() {
+        let init = WakeUpInit::Raw {
+            frames: vec!["02 3E 01".into(), "7DF#01".into()],
+            delay_ms: 10,
+        };
+        let (frames, delay) = init.get_frames(0x7E0).unwrap();
+        assert_eq!(delay, 10);
+        assert_eq!(frames[0].id, 0x7E0);
+        assert_eq!(frames[1].id, 0x7DF);
+        let bad = WakeUpInit::Raw {
+            frames: vec!["0G".into()],
+            delay_ms: 0,
+        };
+        assert!(bad.get_frames(0x7E0).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use crate::commapi::can_trace::TraceFrame;
+use crate::commapi::comm_api::{
+    CanFrame, Capability, ComServer, ComServerError, DeviceCapabilities, FilterType, ISO15765Data,
+};
+
+/// Name returned by [get_api](fn@ComServer::get_api) for replayed traces
+pub const REPLAY_API_NAME: &str = "Replay";
+
+/// Replays a CAN trace imported from a file, for looking at traffic offline.
+///
+/// Frames are read back at the rate they were captured, starting when the CAN interface
+/// is opened. Every frame in the trace is replayed, regardless of the filters set, and
+/// nothing can be sent, so ISO-TP is not supported
+#[derive(Debug, Clone)]
+pub struct ReplayComServer {
+    frames: Arc<Vec<TraceFrame>>,
+    /// Time the CAN interface was opened, None if it is closed
+    can_opened: Arc<RwLock<Option<Instant>>>,
+    /// Index of the next frame to replay
+    next_frame: Arc<RwLock<usize>>,
+}
+
+impl ReplayComServer {
+    pub fn new(frames: Vec<TraceFrame>) -> Self {
+        Self {
+            frames: Arc::new(frames),
+            can_opened: Arc::new(RwLock::new(None)),
+            next_frame: Arc::new(RwLock::new(0)),
+        }
+    }
+
+    fn not_supported_err() -> ComServerError {
+        ComServerError {
+            err_code: 1,
+            err_desc: "Not supported when replaying a trace".into(),
+        }
+    }
+
+    /// Returns true once every frame of the trace has been replayed
+    pub fn is_finished(&self) -> bool {
+        *self.next_frame.read().unwrap() >= self.frames.len()
+    }
+}
+
+impl ComServer for ReplayComServer {
+    fn open_device(&mut self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn close_device(&mut self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn send_can_packets(
+        &self,
+        _data: &[CanFrame],
+        _timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        Err(Self::not_supported_err())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.can_opened.read().unwrap().is_some()
+    }
+
+    fn read_can_packets(
+        &self,
+        _timeout_ms: u32,
+        max_msgs: usize,
+    ) -> Result<Vec<CanFrame>, ComServerError> {
+        let elapsed = match *self.can_opened.read().unwrap() {
+            Some(t) => t.elapsed().as_micros(),
+            None => {
+                return Err(ComServerError {
+                    err_code: 2,
+                    err_desc: "CAN interface not open".into(),
+                })
+            }
+        };
+        let mut next = self.next_frame.write().unwrap();
+        let res: Vec<CanFrame> = self.frames[*next..]
+            .iter()
+            .take_while(|f| f.timestamp_us <= elapsed)
+            .take(max_msgs)
+            .map(|f| f.frame.with_hw_timestamp(f.timestamp_us as u32))
+            .collect();
+        *next += res.len();
+        Ok(res)
+    }
+
+    fn send_iso15765_data(
+        &self,
+        _data: &[ISO15765Data],
+        _timeout_ms: u32,
+    ) -> Result<usize, ComServerError> {
+        Err(Self::not_supported_err())
+    }
+
+    fn read_iso15765_packets(
+        &self,
+        _timeout_ms: u32,
+        _max_msgs: usize,
+    ) -> Result<Vec<ISO15765Data>, ComServerError> {
+        Err(Self::not_supported_err())
+    }
+
+    fn open_can_interface(
+        &mut self,
+        _bus_speed: u32,
+        _is_ext_can: bool,
+    ) -> Result<(), ComServerError> {
+        // Every time the interface is opened, the trace is replayed from the start
+        *self.can_opened.write().unwrap() = Some(Instant::now());
+        *self.next_frame.write().unwrap() = 0;
+        Ok(())
+    }
+
+    fn close_can_interface(&mut self) -> Result<(), ComServerError> {
+        *self.can_opened.write().unwrap() = None;
+        Ok(())
+    }
+
+    fn open_iso15765_interface(
+        &mut self,
+        _bus_speed: u32,
+        _is_ext_can: bool,
+        _ext_addressing: bool,
+    ) -> Result<(), ComServerError> {
+        Err(Self::not_supported_err())
+    }
+
+    fn close_iso15765_interface(&mut self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn add_can_filter(
+        &self,
+        _filter: FilterType,
+        _id: u32,
+        _mask: u32,
+    ) -> Result<u32, ComServerError> {
+        Ok(0)
+    }
+
+    fn rem_can_filter(&self, _filter_idx: u32) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn add_iso15765_filter(
+        &self,
+        _id: u32,
+        _mask: u32,
+        _fc_id: u32,
+    ) -> Result<u32, ComServerError> {
+        Err(Self::not_supported_err())
+    }
+
+    fn rem_iso15765_filter(&self, _filter_idx: u32) -> Result<(), ComServerError> {
+        Err(Self::not_supported_err())
+    }
+
+    fn set_iso15765_params(
+        &self,
+        _separation_time_min: u32,
+        _block_size: u32,
+    ) -> Result<(), ComServerError> {
+        Err(Self::not_supported_err())
+    }
+
+    fn clear_can_rx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn clear_can_tx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn clear_iso15765_rx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn clear_iso15765_tx_buffer(&self) -> Result<(), ComServerError> {
+        Ok(())
+    }
+
+    fn read_battery_voltage(&self) -> Result<f32, ComServerError> {
+        // No battery to measure, the same as adapters that cannot measure it
+        Ok(-1.0)
+    }
+
+    fn clone_box(&self) -> Box<dyn ComServer> {
+        Box::new(self.clone())
+    }
+
+    fn get_capabilities(&self) -> DeviceCapabilities {
+        DeviceCapabilities {
+            name: format!("Trace replay ({} frames)", self.frames.len()),
+            vendor: "OpenVehicleDiag".into(),
+            library_path: "N/A".into(),
+            device_fw_version: "N/A".into(),
+            library_version: env!("CARGO_PKG_VERSION").into(),
+            j1850vpw: Capability::No,
+            j1850pwm: Capability::No,
+            can: Capability::Yes,
+            iso15765: Capability::No,
+            can_fd: Capability::No,
+            iso9141: Capability::No,
+            iso14230: Capability::No,
+            ip: Capability::No,
+            battery_voltage: Capability::No,
+            max_filters: None,
+        }
+    }
+
+    fn get_api(&self) -> &str {
+        REPLAY_API_NAME
+    }
+}
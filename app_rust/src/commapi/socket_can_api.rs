@@ -200,10 +200,12 @@ impl ComServer for SocketCanAPI {
             j1850pwm: Capability::NA,
             can: Capability::Yes,
             iso15765: Capability::Yes,
+            can_fd: Capability::NA,
             iso9141: Capability::NA,
             iso14230: Capability::NA,
             ip: Capability::NA,
             battery_voltage: Capability::NA,
+            max_filters: None,
         }
     }
 
@@ -0,0 +1,70 @@
+use lazy_static::lazy_static;
+use std::fs::OpenOptions;
+use std::io::{LineWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::settings::Settings;
+
+// Low level trace of every call made to the adapter's driver, for debugging sessions that
+// misbehave. Unlike the log shown to the user in a diagnostic session, this records the raw
+// parameters and return code of each call, along with how long the driver took to return.
+
+const HW_LOG_FILE_NAME: &str = "ovd_hardware.log";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref HW_LOG: Mutex<Option<LineWriter<std::fs::File>>> = Mutex::new(None);
+}
+
+/// Opens or closes the hardware log, depending on the verbose hardware logging setting.
+/// The log is appended to in the log directory
+pub fn configure(s: &Settings) {
+    let mut log = HW_LOG.lock().unwrap();
+    if !s.verbose_hw_logging {
+        ENABLED.store(false, Ordering::Relaxed);
+        *log = None;
+        return;
+    }
+    let mut path = PathBuf::from(&s.log_dir);
+    path.push(HW_LOG_FILE_NAME);
+    match OpenOptions::new().create(true).append(true).open(&path) {
+        Ok(f) => {
+            *log = Some(LineWriter::new(f));
+            ENABLED.store(true, Ordering::Relaxed);
+        }
+        Err(e) => {
+            eprintln!("Could not open hardware log {}: {}", path.display(), e);
+            ENABLED.store(false, Ordering::Relaxed);
+            *log = None;
+        }
+    }
+}
+
+/// Records a driver call in the hardware log, if it is enabled.
+///
+/// ## Params
+/// * func - Name of the driver function called
+/// * start - When the call was made
+/// * res - Return code of the call
+/// * params - Returns the parameters of the call. Only ran if the log is enabled
+pub fn log_call<F: FnOnce() -> String>(func: &str, start: Instant, res: i32, params: F) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let elapsed = start.elapsed();
+    if let Some(log) = HW_LOG.lock().unwrap().as_mut() {
+        let _ = writeln!(
+            log,
+            "{} {}({}) -> 0x{:02X} [{}us]",
+            chrono::Local::now().format("%H:%M:%S%.3f"),
+            func,
+            params(),
+            res,
+            elapsed.as_micros()
+        );
+    }
+}
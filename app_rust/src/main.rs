@@ -2,7 +2,9 @@ use iced::{Application, Settings};
 mod cli_tests;
 mod commapi;
 mod graphs;
+mod hw_log;
 mod passthru;
+mod settings;
 mod themes;
 mod windows;
 
@@ -19,6 +21,8 @@ fn main() -> iced::Result {
     launcher_settings.window.resizable = false;
     launcher_settings.window.size = (WIN_WIDTH, WIN_HEIGHT);
     launcher_settings.window.min_size = Some((WIN_WIDTH, WIN_HEIGHT));
+    // Closing the window is handled by MainWindow so it can shut down cleanly
+    launcher_settings.exit_on_close_request = false;
 
     if let Ok(img) = image::load_from_memory_with_format(TRAY_ICON, ImageFormat::Png) {
         launcher_settings.window.icon =
@@ -31,5 +35,10 @@ fn main() -> iced::Result {
             themes::setDebug(true)
         }
     }
+    let user_settings = settings::get_settings();
+    if user_settings.dark_theme {
+        themes::set_dark_theme()
+    }
+    hw_log::configure(&user_settings);
     MainWindow::run(launcher_settings)
 }
@@ -1,13 +1,68 @@
 use lazy_static::lazy_static;
 use libloading::Library;
 use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
+use std::time::Instant;
 use std::{ffi::*, fmt};
+
+use crate::commapi::adapter::ADAPTER;
+use crate::hw_log;
+use crate::settings::Settings;
 use J2534Common::FilterType::FLOW_CONTROL_FILTER;
 use J2534Common::*;
 
 lazy_static! {
-    pub static ref DRIVER: Arc<RwLock<Option<PassthruDrv>>> = Arc::new(RwLock::new(None));
+    /// Devices and channels OVD has opened with a driver, and not closed yet. Starts with the
+    /// handles a previous run of OVD left open, if it crashed
+    static ref OPEN_HANDLES: RwLock<OpenHandles> = RwLock::new(OpenHandles::load());
+}
+
+const OPEN_HANDLES_FILE_NAME: &str = "passthru_handles.json";
+
+/// Handles opened with a driver. Saved to the config directory whenever they change, so
+/// the handles can still be closed if OVD crashes before closing them
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+struct OpenHandles {
+    devices: Vec<u32>,
+    channels: Vec<u32>,
+}
+
+impl OpenHandles {
+    fn get_path() -> PathBuf {
+        let mut p = Settings::get_config_dir();
+        p.push(OPEN_HANDLES_FILE_NAME);
+        p
+    }
+
+    fn load() -> Self {
+        Self::load_from(&Self::get_path())
+    }
+
+    fn load_from(path: &Path) -> Self {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default()
+    }
+
+    fn save_to(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let json = serde_json::to_string(self)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        std::fs::write(path, json)
+    }
+}
+
+/// Changes the open handles, and saves them
+fn update_open_handles<F: FnOnce(&mut OpenHandles)>(f: F) {
+    let mut handles = OPEN_HANDLES.write().unwrap();
+    f(&mut handles);
+    if let Err(e) = handles.save_to(&OpenHandles::get_path()) {
+        eprintln!("Could not save open Passthru handles: {}", e)
+    }
 }
 
 #[cfg(windows)]
@@ -209,19 +264,25 @@ impl PassthruDrv {
     pub fn open(&mut self) -> Result<u32> {
         let mut id: u32 = 0;
         let name = CString::new("test").unwrap();
+        let start = Instant::now();
         let res =
             unsafe { (&self.open_fn)(name.as_ptr() as *const libc::c_void, &mut id as *mut u32) };
+        hw_log::log_call("PassThruOpen", start, res, || format!("dev={}", id));
         if res == 0x00 {
             self.is_connected = true;
+            update_open_handles(|h| h.devices.push(id));
         }
         ret_res(res, id)
     }
 
     //type PassThruCloseFn = unsafe extern "stdcall" fn(device_id: u32) -> i32;
     pub fn close(&mut self, dev_id: u32) -> Result<()> {
+        let start = Instant::now();
         let res = unsafe { (&self.close_fn)(dev_id) };
+        hw_log::log_call("PassThruClose", start, res, || format!("dev={}", dev_id));
         if res == 0x00 {
             self.is_connected = false;
+            update_open_handles(|h| h.devices.retain(|d| *d != dev_id));
         }
         ret_res(res, ())
     }
@@ -238,6 +299,7 @@ impl PassthruDrv {
             return Ok(0);
         }
         let mut msg_count: u32 = msgs.len() as u32;
+        let start = Instant::now();
         let res = unsafe {
             (&self.write_msg_fn)(
                 channel_id,
@@ -246,6 +308,15 @@ impl PassthruDrv {
                 timeout,
             )
         };
+        hw_log::log_call("PassThruWriteMsgs", start, res, || {
+            format!(
+                "chan={}, msgs={}/{}, timeout={}",
+                channel_id,
+                msg_count,
+                msgs.len(),
+                timeout
+            )
+        });
         ret_res(res, msg_count as usize)
     }
 
@@ -271,6 +342,7 @@ impl PassthruDrv {
             max_msgs as usize
         ];
 
+        let start = Instant::now();
         let res = unsafe {
             (&self.read_msg_fn)(
                 channel_id,
@@ -279,6 +351,12 @@ impl PassthruDrv {
                 timeout,
             )
         };
+        hw_log::log_call("PassThruReadMsgs", start, res, || {
+            format!(
+                "chan={}, msgs={}/{}, timeout={}",
+                channel_id, msg_count, max_msgs, timeout
+            )
+        });
         if res == PassthruError::ERR_BUFFER_EMPTY as i32 && msg_count != 0 {
             write_array.truncate(msg_count as usize);
             return ret_res(0x00, write_array);
@@ -295,6 +373,7 @@ impl PassthruDrv {
         let mut firmware_version: [u8; 80] = [0; 80];
         let mut dll_version: [u8; 80] = [0; 80];
         let mut api_version: [u8; 80] = [0; 80];
+        let start = Instant::now();
         let res = unsafe {
             (&self.read_version_fn)(
                 dev_id,
@@ -303,6 +382,9 @@ impl PassthruDrv {
                 api_version.as_mut_ptr() as *mut libc::c_char,
             )
         };
+        hw_log::log_call("PassThruReadVersion", start, res, || {
+            format!("dev={}", dev_id)
+        });
         unsafe {
             ret_res(
                 res,
@@ -327,7 +409,9 @@ impl PassthruDrv {
     //type PassThruGetLastErrorFn = unsafe extern "stdcall" fn(error_description: *mut libc::c_char) -> i32;
     pub fn get_last_error(&self) -> Result<String> {
         let mut err: [u8; 80] = [0; 80];
+        let start = Instant::now();
         let res = unsafe { (&self.get_last_err_fn)(err.as_mut_ptr() as *mut libc::c_char) };
+        hw_log::log_call("PassThruGetLastError", start, res, String::new);
         ret_res(res, String::from_utf8(err.to_vec()).unwrap())
     }
 
@@ -339,7 +423,12 @@ impl PassthruDrv {
         input: *mut c_void,
         output: *mut c_void,
     ) -> Result<()> {
-        let res = unsafe { (&self.ioctl_fn)(handle_id, ioctl_id as u32, input, output) };
+        let id = ioctl_id as u32;
+        let start = Instant::now();
+        let res = unsafe { (&self.ioctl_fn)(handle_id, id, input, output) };
+        hw_log::log_call("PassThruIoctl", start, res, || {
+            format!("handle={}, ioctl=0x{:02X}", handle_id, id)
+        });
         ret_res(res, ())
     }
 
@@ -347,21 +436,40 @@ impl PassthruDrv {
     /// Returns channel ID
     pub fn connect(&self, dev_id: u32, protocol: Protocol, flags: u32, baud: u32) -> Result<u32> {
         let mut channel_id: u32 = 0;
+        let protocol_id = protocol as u32;
+        let start = Instant::now();
         let res = unsafe {
             (&self.connect_fn)(
                 dev_id,
-                protocol as u32,
+                protocol_id,
                 flags as u32,
                 baud,
                 &mut channel_id as *mut u32,
             )
         };
+        hw_log::log_call("PassThruConnect", start, res, || {
+            format!(
+                "dev={}, protocol=0x{:02X}, flags=0x{:08X}, baud={}, chan={}",
+                dev_id, protocol_id, flags, baud, channel_id
+            )
+        });
+        if res == 0x00 {
+            update_open_handles(|h| h.channels.push(channel_id));
+        }
         ret_res(res, channel_id)
     }
 
     //type PassThruDisconnectFn = unsafe extern "stdcall" fn(channel_id: u32) -> i32;
     pub fn disconnect(&self, channel_id: u32) -> Result<()> {
-        ret_res(unsafe { (&self.disconnect_fn)(channel_id) }, ())
+        let start = Instant::now();
+        let res = unsafe { (&self.disconnect_fn)(channel_id) };
+        hw_log::log_call("PassThruDisconnect", start, res, || {
+            format!("chan={}", channel_id)
+        });
+        if res == 0x00 {
+            update_open_handles(|h| h.channels.retain(|c| *c != channel_id));
+        }
+        ret_res(res, ())
     }
 
     //type PassThruStartPeriodicMsgFn = unsafe extern "stdcall" fn(channel_id: u32, msg: *const PASSTHRU_MSG, msg_id: *mut u32, time_interval: u32) -> i32;
@@ -373,6 +481,7 @@ impl PassthruDrv {
         time_interval: u32,
     ) -> Result<u32> {
         let mut msg_id: u32 = 0;
+        let start = Instant::now();
         let res = unsafe {
             (&self.start_periodic_fn)(
                 channel_id,
@@ -381,12 +490,23 @@ impl PassthruDrv {
                 time_interval,
             )
         };
+        hw_log::log_call("PassThruStartPeriodicMsg", start, res, || {
+            format!(
+                "chan={}, interval={}, msg_id={}",
+                channel_id, time_interval, msg_id
+            )
+        });
         ret_res(res, msg_id)
     }
 
     //type PassThruStopPeriodicMsgFn = unsafe extern "stdcall" fn(channel_id: u32, msg_id: u32) -> i32;
     pub fn stop_periodic_msg(&self, channel_id: u32, msg_id: u32) -> Result<()> {
-        ret_res(unsafe { (&self.stop_periodic_fn)(channel_id, msg_id) }, ())
+        let start = Instant::now();
+        let res = unsafe { (&self.stop_periodic_fn)(channel_id, msg_id) };
+        hw_log::log_call("PassThruStopPeriodicMsg", start, res, || {
+            format!("chan={}, msg_id={}", channel_id, msg_id)
+        });
+        ret_res(res, ())
     }
 
     //type PassThruStartMsgFilterFn = unsafe extern "stdcall" fn(channel_id: u32, filter_type: u32, m_msg: *const PASSTHRU_MSG, p_msg: *const PASSTHRU_MSG, fc_msg: *const PASSTHRU_MSG, filter_id: *mut u32) -> i32;
@@ -410,6 +530,7 @@ impl PassthruDrv {
         };
 
         let mut filter_id: u32 = 0;
+        let start = Instant::now();
         let res = unsafe {
             (&self.start_filter_fn)(
                 channel_id,
@@ -420,12 +541,22 @@ impl PassthruDrv {
                 &mut filter_id as *mut u32,
             )
         };
+        hw_log::log_call("PassThruStartMsgFilter", start, res, || {
+            format!(
+                "chan={}, type=0x{:02X}, filter_id={}",
+                channel_id, tmp, filter_id
+            )
+        });
         ret_res(res, filter_id)
     }
 
     //type PassThruStopMsgFilterFn = unsafe extern "stdcall" fn(channel_id: u32, filter_id: u32) -> i32;
     pub fn stop_msg_filter(&self, channel_id: u32, filter_id: u32) -> Result<()> {
+        let start = Instant::now();
         let res = unsafe { (&self.stop_filter_fn)(channel_id, filter_id) };
+        hw_log::log_call("PassThruStopMsgFilter", start, res, || {
+            format!("chan={}, filter_id={}", channel_id, filter_id)
+        });
         match res {
             0 => Ok(()),
             _ => Err(PassthruError::from_raw(res as u32).unwrap()),
@@ -434,8 +565,55 @@ impl PassthruDrv {
 
     //type PassThruSetProgrammingVoltageFn = unsafe extern "stdcall" fn(device_id: u32, pin_number: u32, voltage: u32) -> i32;
     pub fn set_programming_voltage(&self, dev_id: u32, pin: u32, voltage: u32) -> Result<()> {
-        ret_res(unsafe { (&self.set_prog_v_fn)(dev_id, pin, voltage) }, ())
+        let start = Instant::now();
+        let res = unsafe { (&self.set_prog_v_fn)(dev_id, pin, voltage) };
+        hw_log::log_call("PassThruSetProgrammingVoltage", start, res, || {
+            format!("dev={}, pin={}, voltage={}", dev_id, pin, voltage)
+        });
+        ret_res(res, ())
+    }
+}
+
+/// Attempts to recover a driver that was left in a bad state (For example
+/// if OVD crashed without closing the device).
+///
+/// Every channel and device OVD opened and did not close is closed, including ones left
+/// open by a previous run of OVD. Handles opened by other programs are left alone.
+/// Returns the driver that was used, so it can be used to launch OVD without loading
+/// the library again, and the number of handles that were closed
+pub fn force_reset_driver(dev: &PassthruDevice) -> DeviceError<(PassthruDrv, usize)> {
+    // The adapter cannot be in use whilst its driver is reset
+    if let Err(e) = ADAPTER.disconnect_device() {
+        eprintln!(
+            "Driver reset - Could not disconnect adapter: {}",
+            e.err_desc
+        )
+    }
+    let mut drv = PassthruDrv::load_lib(dev.drv_path.clone())
+        .map_err(|e| LoadDeviceError::LibLoadError(e.to_string()))?;
+    let handles = OPEN_HANDLES.read().unwrap().clone();
+    let mut closed = 0;
+    for channel_id in handles.channels {
+        match drv.disconnect(channel_id) {
+            Ok(_) => closed += 1,
+            Err(e) => eprintln!(
+                "Driver reset - Could not disconnect channel {}: Error 0x{:02X}",
+                channel_id, e as u32
+            ),
+        }
+    }
+    for dev_id in handles.devices {
+        match drv.close(dev_id) {
+            Ok(_) => closed += 1,
+            Err(e) => eprintln!(
+                "Driver reset - Could not close device {}: Error 0x{:02X}",
+                dev_id, e as u32
+            ),
+        }
     }
+    // The driver no longer knows the handles it refused to close
+    update_open_handles(|h| *h = OpenHandles::default());
+    Ok((drv, closed))
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -655,3 +833,25 @@ impl PassthruDevice {
         })
     }
 }
+
+#[cfg(test)]
+mod passthru_test {
+    use super::OpenHandles;
+
+    #[test]
+    fn open_handles_survive_a_restart() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("ovd_handles_test_{}", std::process::id()));
+        path.push(super::OPEN_HANDLES_FILE_NAME);
+        // Nothing saved yet
+        assert_eq!(OpenHandles::load_from(&path), OpenHandles::default());
+
+        let handles = OpenHandles {
+            devices: vec![1],
+            channels: vec![2, 3],
+        };
+        handles.save_to(&path).unwrap();
+        assert_eq!(OpenHandles::load_from(&path), handles);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,380 @@
+use crate::commapi::protocols::{wake_up::WakeUpInit, Addressing};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+/// Current version of the settings file. Bump this when
+/// a field is changed in a way that needs migrating
+const SETTINGS_VERSION: u32 = 1;
+
+const SETTINGS_FILE_NAME: &str = "settings.json";
+
+lazy_static! {
+    static ref SETTINGS: RwLock<Settings> = RwLock::new(Settings::load());
+}
+
+/// User preferences for OVD. Any fields missing from the settings file
+/// (For example, ones added in a newer version of OVD) are filled in
+/// with their default values
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Settings {
+    /// Version of the settings file
+    pub version: u32,
+    /// Use the dark theme?
+    pub dark_theme: bool,
+    /// Max time to wait for an ECU to respond to a command in milliseconds
+    pub cmd_timeout_ms: u64,
+    /// Max time to wait for the rest of a multi-frame response once its first
+    /// frame has been received in milliseconds
+    pub multi_frame_timeout_ms: u64,
+    /// Directory to save logs and reports to
+    pub log_dir: String,
+    /// Interval to poll the adapter's battery voltage in milliseconds
+    pub poll_interval_ms: u64,
+    /// Payloads saved by the user for use in diagnostic sessions
+    pub payload_presets: Vec<PayloadPreset>,
+    /// Record every call made to the adapter's driver in a separate log in the log directory
+    pub verbose_hw_logging: bool,
+    /// Interval to re-read error codes when monitoring an ECU in milliseconds
+    pub dtc_monitor_interval_ms: u64,
+    /// Show a banner when monitoring finds a new error code
+    pub dtc_alert_banner: bool,
+    /// Beep when monitoring finds a new error code
+    pub dtc_alert_beep: bool,
+    /// Export a session's log to the log directory when the session ends
+    pub auto_export_logs: bool,
+    /// Format of automatically exported logs
+    pub log_export_format: LogExportFormat,
+    /// ECUs pinned to the top of the ECU list in diagnostic mode
+    pub favorite_ecus: Vec<FavoriteECU>,
+    /// Time in milliseconds an ECU can stop responding for before it is treated as having
+    /// reset when it responds again. 0 disables detecting resets this way
+    pub reset_detect_gap_ms: u64,
+    /// CAN ID functionally addressed (Broadcast) diagnostic requests are sent to
+    pub functional_send_id: u32,
+    /// Bitrate of the vehicle's CAN bus in bits per second
+    pub can_bitrate: u32,
+    /// Times to retry a read-only command that timed out or failed with a transient error
+    pub cmd_retries: u32,
+    /// Connection settings last used successfully with each ECU, restored when it is picked again
+    pub ecu_profiles: Vec<ECUProfile>,
+    /// Directory file dialogs start in for types of file not opened or saved before.
+    /// Empty leaves it up to the OS
+    pub file_dialog_dir: String,
+    /// Directory each type of file was last opened from or saved to
+    pub last_file_dirs: BTreeMap<String, String>,
+}
+
+/// File format session logs are exported as
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum LogExportFormat {
+    Csv,
+    Json,
+}
+
+impl LogExportFormat {
+    pub const ALL: [LogExportFormat; 2] = [LogExportFormat::Csv, LogExportFormat::Json];
+
+    /// File extension (Without the '.') of exported logs
+    pub fn get_extension(&self) -> &'static str {
+        match self {
+            LogExportFormat::Csv => "csv",
+            LogExportFormat::Json => "json",
+        }
+    }
+}
+
+impl Display for LogExportFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogExportFormat::Csv => write!(f, "CSV"),
+            LogExportFormat::Json => write!(f, "JSON"),
+        }
+    }
+}
+
+/// A named payload (Or sequence of payloads) that can be recalled in a diagnostic session
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PayloadPreset {
+    /// Name shown to the user. EG: 'Enter extended session'
+    pub name: String,
+    /// Hex payloads, sent to the ECU in order. EG: '1003'. Expected negative response
+    /// codes can follow a payload, each after a '/'. EG: '1A86/11'
+    pub payloads: Vec<String>,
+}
+
+/// An ECU the user has marked as a favorite
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FavoriteECU {
+    /// Vehicle the ECU is in. EG: 'Mercedes-Benz W203 (2005)'
+    pub vehicle: String,
+    /// Name of the ECU in the vehicle's save file
+    pub name: String,
+    pub send_id: u32,
+}
+
+/// Transport and addressing settings of an ECU, saved after a session with it works
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ECUProfile {
+    /// Vehicle the ECU is in. EG: 'Mercedes-Benz W203 (2005)'
+    pub vehicle: String,
+    /// Name of the ECU in the vehicle's save file
+    pub name: String,
+    pub send_id: u32,
+    pub recv_id: u32,
+    pub block_size: u32,
+    pub sep_time: u32,
+    #[serde(default)]
+    pub can_fd: bool,
+    /// Byte frames sent to the ECU are padded with. None sends frames unpadded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub padding: Option<u8>,
+    /// Address of the ECU, for ECUs that use extended ISO-TP addressing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ext_addr: Option<u8>,
+    /// Addressing of requests sent in KWP2000 sessions
+    #[serde(default)]
+    pub addressing: Addressing,
+    /// Addressing of tester present messages sent in KWP2000 sessions
+    #[serde(default)]
+    pub tester_present_addressing: Addressing,
+    /// KWP2000 timing parameters, as 'P2min,P2max,P3min,P3max,P4min'. None keeps the ECU's defaults
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timing: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wake_up: Option<WakeUpInit>,
+}
+
+impl ECUProfile {
+    /// Returns true if this is the profile of the ECU called `name` with send ID `send_id` in `vehicle`
+    pub fn is_for(&self, vehicle: &str, name: &str, send_id: u32) -> bool {
+        self.vehicle == vehicle && self.name == name && self.send_id == send_id
+    }
+}
+
+impl Display for PayloadPreset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            version: SETTINGS_VERSION,
+            dark_theme: false,
+            cmd_timeout_ms: 1000,
+            multi_frame_timeout_ms: 2000,
+            log_dir: ".".into(),
+            poll_interval_ms: 2000,
+            payload_presets: Vec::new(),
+            verbose_hw_logging: false,
+            dtc_monitor_interval_ms: 5000,
+            dtc_alert_banner: true,
+            dtc_alert_beep: false,
+            auto_export_logs: false,
+            log_export_format: LogExportFormat::Csv,
+            favorite_ecus: Vec::new(),
+            reset_detect_gap_ms: 2500,
+            functional_send_id: 0x07DF,
+            cmd_retries: 2,
+            can_bitrate: 500_000,
+            ecu_profiles: Vec::new(),
+            file_dialog_dir: String::new(),
+            last_file_dirs: BTreeMap::new(),
+        }
+    }
+}
+
+impl Settings {
+    #[cfg(all(unix, not(test)))]
+    /// Returns the directory that OVD's configuration is stored in
+    pub fn get_config_dir() -> PathBuf {
+        PathBuf::from(shellexpand::tilde("~/.config/OpenVehicleDiag").to_string())
+    }
+
+    #[cfg(all(windows, not(test)))]
+    /// Returns the directory that OVD's configuration is stored in
+    pub fn get_config_dir() -> PathBuf {
+        let mut p = PathBuf::from(std::env::var("APPDATA").unwrap_or_else(|_| ".".into()));
+        p.push("OpenVehicleDiag");
+        p
+    }
+
+    #[cfg(test)]
+    /// Tests are given a configuration directory of their own, so they never
+    /// read or change the user's configuration
+    pub fn get_config_dir() -> PathBuf {
+        let mut p = std::env::temp_dir();
+        p.push(format!("ovd_test_config_{}", std::process::id()));
+        p
+    }
+
+    pub fn get_config_path() -> PathBuf {
+        let mut p = Self::get_config_dir();
+        p.push(SETTINGS_FILE_NAME);
+        p
+    }
+
+    /// Loads settings from the config file. If no config file exists,
+    /// or it cannot be read, default settings are returned
+    pub fn load() -> Self {
+        Self::load_from(&Self::get_config_path())
+    }
+
+    /// Loads settings from the settings file at `path`. If the file does not exist,
+    /// or it cannot be read, default settings are returned
+    pub fn load_from(path: &Path) -> Self {
+        let mut settings = match std::fs::read_to_string(path) {
+            Ok(s) => match serde_json::from_str::<Settings>(&s) {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("Settings file is invalid ({}), using defaults", e);
+                    return Self::default();
+                }
+            },
+            Err(_) => return Self::default(), // No settings file yet
+        };
+        if settings.version != SETTINGS_VERSION {
+            settings.migrate();
+            if let Err(e) = settings.save_to(path) {
+                eprintln!("Could not save migrated settings: {}", e)
+            }
+        }
+        settings
+    }
+
+    /// Saves the settings to the config file
+    pub fn save(&self) -> std::io::Result<()> {
+        self.save_to(&Self::get_config_path())
+    }
+
+    /// Saves the settings to the settings file at `path`, creating its directory if needed
+    pub fn save_to(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        std::fs::write(path, json)
+    }
+
+    /// Upgrades settings from an older version of the settings file.
+    /// New fields are already filled in with defaults by serde
+    fn migrate(&mut self) {
+        println!(
+            "Migrating settings from version {} to {}",
+            self.version, SETTINGS_VERSION
+        );
+        self.version = SETTINGS_VERSION;
+    }
+}
+
+/// Returns a copy of the current settings
+pub fn get_settings() -> Settings {
+    SETTINGS.read().unwrap().clone()
+}
+
+/// Replaces the current settings and saves them to the config file
+pub fn set_settings(s: Settings) -> std::io::Result<()> {
+    let res = s.save();
+    *SETTINGS.write().unwrap() = s;
+    res
+}
+
+/// Returns the saved profile of the ECU called `name` with send ID `send_id` in `vehicle`
+pub fn get_ecu_profile(vehicle: &str, name: &str, send_id: u32) -> Option<ECUProfile> {
+    SETTINGS
+        .read()
+        .unwrap()
+        .ecu_profiles
+        .iter()
+        .find(|p| p.is_for(vehicle, name, send_id))
+        .cloned()
+}
+
+/// Saves the profile of an ECU, replacing any profile previously saved for it
+pub fn save_ecu_profile(profile: ECUProfile) -> std::io::Result<()> {
+    let mut settings = get_settings();
+    settings
+        .ecu_profiles
+        .retain(|p| !p.is_for(&profile.vehicle, &profile.name, profile.send_id));
+    settings.ecu_profiles.push(profile);
+    set_settings(settings)
+}
+
+/// Saves the directory a type of file was last opened from or saved to
+pub fn set_last_file_dir(file_type: &str, dir: &str) -> std::io::Result<()> {
+    let mut settings = get_settings();
+    settings
+        .last_file_dirs
+        .insert(file_type.to_string(), dir.to_string());
+    set_settings(settings)
+}
+
+#[cfg(test)]
+mod settings_test {
+    use super::{Settings, SETTINGS_FILE_NAME, SETTINGS_VERSION};
+    use std::path::PathBuf;
+
+    /// Returns the path of a settings file in a directory only used by one test
+    fn test_path(test: &str) -> PathBuf {
+        let mut p = std::env::temp_dir();
+        p.push(format!("ovd_settings_test_{}_{}", test, std::process::id()));
+        p.push(SETTINGS_FILE_NAME);
+        p
+    }
+
+    #[test]
+    fn missing_file_loads_defaults() {
+        let path = test_path("missing");
+        assert_eq!(Settings::load_from(&path), Settings::default());
+    }
+
+    #[test]
+    fn settings_survive_a_restart() {
+        let path = test_path("save");
+        let settings = Settings {
+            dark_theme: true,
+            cmd_timeout_ms: 3000,
+            functional_send_id: 0x18DB33F1,
+            ..Settings::default()
+        };
+        settings.save_to(&path).unwrap();
+        assert_eq!(Settings::load_from(&path), settings);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn invalid_file_loads_defaults() {
+        let path = test_path("invalid");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "{ not json").unwrap();
+        assert_eq!(Settings::load_from(&path), Settings::default());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn old_file_is_migrated() {
+        let path = test_path("migrate");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        // Fields missing from an old file are filled in with their defaults
+        std::fs::write(&path, r#"{ "version": 0, "dark_theme": true }"#).unwrap();
+        let settings = Settings::load_from(&path);
+        assert_eq!(settings.version, SETTINGS_VERSION);
+        assert!(settings.dark_theme);
+        assert_eq!(settings.cmd_timeout_ms, Settings::default().cmd_timeout_ms);
+        // The migrated settings are saved back to the file
+        assert_eq!(Settings::load_from(&path), settings);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn tests_do_not_use_user_config() {
+        assert!(Settings::get_config_dir().starts_with(std::env::temp_dir()));
+    }
+}
@@ -168,6 +168,40 @@ impl iced::container::StyleSheet for Container {
     }
 }
 
+/// Box that help text is shown in. The border is coloured by how risky the control is
+pub struct HelpBox {
+    accent: Color,
+}
+
+impl HelpBox {
+    pub(crate) fn new(accent: ButtonType) -> Self {
+        Self {
+            accent: accent.get_colour(),
+        }
+    }
+}
+
+impl iced::container::StyleSheet for HelpBox {
+    fn style(&self) -> iced::container::Style {
+        match super::get_theme() {
+            super::Style::Light => iced::container::Style {
+                text_color: DARK_BG.into(),
+                background: WHITE.into(),
+                border_radius: BUTTON_RADIUS,
+                border_width: BUTTON_BORDER_WIDTH,
+                border_color: self.accent,
+            },
+            super::Style::Dark => iced::container::Style {
+                text_color: WHITE.into(),
+                background: DARK_BG.into(),
+                border_radius: BUTTON_RADIUS,
+                border_width: BUTTON_BORDER_WIDTH,
+                border_color: self.accent,
+            },
+        }
+    }
+}
+
 pub struct RadioBtn {
     c: Color,
 }
@@ -1,6 +1,6 @@
 pub mod elements;
 pub mod images;
-use crate::themes::elements::{ButtonStyle, DropDown, PBar};
+use crate::themes::elements::{ButtonStyle, DropDown, HelpBox, PBar};
 use iced::{
     button, pick_list, Button, Color, Container, Element, PickList, ProgressBar, Radio, Text,
 };
@@ -167,6 +167,15 @@ where
     Container::new(contents).style(elements::Container)
 }
 
+/// Box of help text explaining what a control does. Controls that can change the
+/// state of an ECU should use a [Warning](ButtonType::Warning) or [Danger](ButtonType::Danger)
+/// accent, so their risks stand out
+pub fn help_box<'a, Msg: 'a>(text: &str, accent: ButtonType) -> Container<'a, Msg> {
+    Container::new(Text::new(text).size(16))
+        .padding(6)
+        .style(HelpBox::new(accent))
+}
+
 pub fn radio_btn<Msg: Clone, V, F>(
     value: V,
     label: impl Into<String>,
@@ -1,16 +1,40 @@
-use crate::commapi::comm_api::{CanFrame, ComServer, FilterType};
-use crate::themes::{button_coloured, ButtonType};
+use crate::commapi::can_trace::{export_trace, TraceFormat, TraceFrame};
+use crate::commapi::comm_api::{get_can_bitrate, CanFrame, ComServer, FilterType};
+use crate::commapi::iso_tp_analysis::{analyze_exchanges, Direction, Exchange};
+use crate::themes::{button_coloured, text_input, ButtonType};
+use crate::windows::file_dialog::{save_file, FileType};
 use crate::windows::window::WindowMessage;
+use chrono::{DateTime, Local};
 use iced::time;
 use iced::{button, Checkbox, Color, Column, Element, Length, Row, Scrollable, Subscription, Text};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::time::Instant;
 
+/// Most frames kept in a capture. Recording stops once this is reached, so a forgotten
+/// capture does not use up all the memory
+const MAX_CAPTURE_FRAMES: usize = 1_000_000;
+
+/// Most frames shown in the scrolling trace view. Older frames are dropped
+const MAX_TRACE_ROWS: usize = 500;
+
+/// Window the update rate of each CAN ID is counted over, in microseconds
+const RATE_WINDOW_US: u128 = 1_000_000;
+
 #[derive(Debug, Clone)]
 pub enum TracerMessage {
     NewData(Instant),
     ToggleCan,
     ToggleBinaryMode(bool),
+    ToggleHwTimestamp(bool),
+    /// Show one row per CAN ID, rather than every frame as it arrives
+    ToggleGroupedView(bool),
+    ToggleRecording,
+    ExportCapture(TraceFormat),
+    EnterAnalysisSendId(String),
+    EnterAnalysisRecvId(String),
+    /// Break the capture down into the ISO-TP frames of each exchange
+    AnalyzeCapture,
+    CloseAnalysis,
 }
 
 #[derive(Debug, Clone)]
@@ -23,6 +47,37 @@ pub struct CanTracer {
     is_binary_fmt: bool,
     status_text: String,
     scroll_state: iced::scrollable::State,
+    /// Show the adapter's hardware timestamps rather than when OVD received the frame
+    use_hw_timestamp: bool,
+    /// Time the CAN interface was opened, host timestamps are relative to this
+    start_time: Instant,
+    /// Host receive time of the latest frame of each CAN ID in microseconds
+    host_timestamps: HashMap<u32, u128>,
+    /// Host receive times of each CAN ID's frames within the last [RATE_WINDOW_US]
+    rx_times: HashMap<u32, VecDeque<u128>>,
+    /// Show one row per CAN ID, updated in place. Otherwise every frame is listed as it arrives
+    grouped_view: bool,
+    /// Latest frames and their host receive time, newest first, for the trace view
+    trace: VecDeque<(u128, CanFrame)>,
+    rec_btn_state: button::State,
+    asc_btn_state: button::State,
+    blf_btn_state: button::State,
+    /// Every frame is being captured, so it can be exported as a trace
+    recording: bool,
+    capture: Vec<TraceFrame>,
+    /// Time the capture started at
+    capture_start: DateTime<Local>,
+    /// Host timestamp the capture started at in microseconds
+    capture_start_us: u128,
+    /// Hardware timestamp of the first captured frame, captured hardware timestamps are relative to this
+    capture_first_hw: Option<u32>,
+    analysis_send_string: String,
+    analysis_send_input: iced::text_input::State,
+    analysis_recv_string: String,
+    analysis_recv_input: iced::text_input::State,
+    analyze_btn_state: button::State,
+    /// Exchanges found in the capture. Shown instead of the live trace when set
+    analysis: Option<Vec<Exchange>>,
 }
 
 impl<'a> CanTracer {
@@ -36,13 +91,133 @@ impl<'a> CanTracer {
             is_binary_fmt: false,
             status_text: "".into(),
             scroll_state: Default::default(),
+            use_hw_timestamp: true,
+            start_time: Instant::now(),
+            host_timestamps: HashMap::new(),
+            rx_times: HashMap::new(),
+            grouped_view: true,
+            trace: VecDeque::new(),
+            rec_btn_state: Default::default(),
+            asc_btn_state: Default::default(),
+            blf_btn_state: Default::default(),
+            recording: false,
+            capture: Vec::new(),
+            capture_start: Local::now(),
+            capture_start_us: 0,
+            capture_first_hw: None,
+            analysis_send_string: String::new(),
+            analysis_send_input: Default::default(),
+            analysis_recv_string: String::new(),
+            analysis_recv_input: Default::default(),
+            analyze_btn_state: Default::default(),
+            analysis: None,
         }
     }
 
     pub fn insert_frames_to_map(&mut self, frames: Vec<CanFrame>) {
+        let rx_time = self.start_time.elapsed().as_micros();
         for f in frames {
+            if self.recording {
+                self.capture_frame(f, rx_time);
+            }
+            self.host_timestamps.insert(f.id, rx_time);
+            self.rx_times.entry(f.id).or_default().push_back(rx_time);
+            self.trace.push_front((rx_time, f));
             self.can_queue.insert(f.id, f);
         }
+        self.trace.truncate(MAX_TRACE_ROWS);
+        for times in self.rx_times.values_mut() {
+            while times
+                .front()
+                .map(|t| rx_time - t > RATE_WINDOW_US)
+                .unwrap_or(false)
+            {
+                times.pop_front();
+            }
+        }
+    }
+
+    /// Adds a frame to the capture. The adapter's timestamps are used if requested and the
+    /// adapter provides them, as they are more accurate than when OVD read the frame
+    fn capture_frame(&mut self, frame: CanFrame, rx_time: u128) {
+        let timestamp_us = match (self.use_hw_timestamp, frame.get_hw_timestamp_us()) {
+            (true, Some(hw)) => {
+                let first = *self.capture_first_hw.get_or_insert(hw);
+                hw.wrapping_sub(first) as u128
+            }
+            _ => rx_time.saturating_sub(self.capture_start_us),
+        };
+        self.capture.push(TraceFrame {
+            timestamp_us,
+            frame,
+        });
+        if self.capture.len() >= MAX_CAPTURE_FRAMES {
+            self.recording = false;
+            self.status_text = format!(
+                "Capture stopped, reached the limit of {} frames",
+                MAX_CAPTURE_FRAMES
+            );
+        }
+    }
+
+    /// Asks the user where to save the capture, then saves it in the given format
+    fn export_capture(&mut self, format: TraceFormat) {
+        let path = match save_file(FileType::CanTrace, format.get_extension()) {
+            Some(p) => p,
+            None => return,
+        };
+        self.status_text = match std::fs::File::create(&path)
+            .and_then(|mut f| export_trace(&mut f, format, &self.capture, self.capture_start))
+        {
+            Ok(_) => format!("Saved {} frames to {}", self.capture.len(), path.display()),
+            Err(e) => format!("Error saving {} trace: {}", format, e),
+        };
+    }
+
+    /// Parses a CAN ID entered in hex
+    fn parse_id(s: &str) -> Option<u32> {
+        let s = s.trim();
+        let s = s
+            .strip_prefix("0x")
+            .or_else(|| s.strip_prefix("0X"))
+            .unwrap_or(s);
+        u32::from_str_radix(s, 16)
+            .ok()
+            .filter(|id| *id <= 0x1FFF_FFFF)
+    }
+
+    /// Breaks the capture down into the exchanges between the entered CAN IDs
+    fn analyze_capture(&mut self) {
+        let (send_id, recv_id) = match (
+            Self::parse_id(&self.analysis_send_string),
+            Self::parse_id(&self.analysis_recv_string),
+        ) {
+            (Some(s), Some(r)) if s != r => (s, r),
+            _ => {
+                self.status_text = "Enter the request and response CAN IDs in hex".into();
+                return;
+            }
+        };
+        let exchanges = analyze_exchanges(&self.capture, send_id, recv_id);
+        self.status_text = format!(
+            "Found {} exchanges between {:X} and {:X}",
+            exchanges.len(),
+            send_id,
+            recv_id
+        );
+        self.analysis = Some(exchanges);
+    }
+
+    /// Formats the timestamp of a frame. If hardware timestamps are requested
+    /// but the adapter did not provide one, the host timestamp is used instead
+    fn format_timestamp(use_hw: bool, frame: &CanFrame, host_time_us: Option<u128>) -> String {
+        match (use_hw, frame.get_hw_timestamp_us(), host_time_us) {
+            (true, Some(hw), _) => format!("{}.{:06}s (HW)", hw / 1_000_000, hw % 1_000_000),
+            (_, _, Some(host)) => {
+                format!("{}.{:06}s (Host)", host / 1_000_000, host % 1_000_000)
+            }
+            _ => "-".into(),
+        }
     }
 
     pub fn update(&mut self, msg: &TracerMessage) -> Option<WindowMessage> {
@@ -58,12 +233,21 @@ impl<'a> CanTracer {
                         self.status_text = format!("Error closing CAN Interface {}", e)
                     } else {
                         self.is_connected = false;
+                        self.recording = false;
                         self.can_queue.clear();
+                        self.host_timestamps.clear();
+                        self.rx_times.clear();
+                        self.trace.clear();
                     }
-                } else if let Err(e) = self.server.as_mut().open_can_interface(500_000, false) {
+                } else if let Err(e) = self
+                    .server
+                    .as_mut()
+                    .open_can_interface(get_can_bitrate(), false)
+                {
                     self.status_text = format!("Error opening CAN Interface {}", e)
                 } else {
                     self.is_connected = true;
+                    self.start_time = Instant::now();
                     if let Err(e) =
                         self.server
                             .as_mut()
@@ -82,6 +266,25 @@ impl<'a> CanTracer {
                 }
             }
             TracerMessage::ToggleBinaryMode(b) => self.is_binary_fmt = *b,
+            TracerMessage::ToggleHwTimestamp(b) => self.use_hw_timestamp = *b,
+            TracerMessage::ToggleGroupedView(b) => self.grouped_view = *b,
+            TracerMessage::ToggleRecording => {
+                if !self.recording {
+                    // A new recording replaces the last capture
+                    self.capture.clear();
+                    self.capture_start = Local::now();
+                    self.capture_start_us = self.start_time.elapsed().as_micros();
+                    self.capture_first_hw = None;
+                    self.analysis = None;
+                    self.status_text = String::new();
+                }
+                self.recording = !self.recording;
+            }
+            TracerMessage::ExportCapture(format) => self.export_capture(*format),
+            TracerMessage::EnterAnalysisSendId(s) => self.analysis_send_string = s.clone(),
+            TracerMessage::EnterAnalysisRecvId(s) => self.analysis_recv_string = s.clone(),
+            TracerMessage::AnalyzeCapture => self.analyze_capture(),
+            TracerMessage::CloseAnalysis => self.analysis = None,
         }
         None
     }
@@ -101,6 +304,65 @@ impl<'a> CanTracer {
         .on_press(TracerMessage::ToggleCan);
         let check = self.is_binary_fmt;
 
+        let mut rec_btn = match self.recording {
+            false => button_coloured(&mut self.rec_btn_state, "Start capture", ButtonType::Info),
+            true => button_coloured(&mut self.rec_btn_state, "Stop capture", ButtonType::Warning),
+        };
+        if self.is_connected {
+            rec_btn = rec_btn.on_press(TracerMessage::ToggleRecording);
+        }
+        let mut asc_btn = button_coloured(&mut self.asc_btn_state, "Save as ASC", ButtonType::Info);
+        let mut blf_btn = button_coloured(&mut self.blf_btn_state, "Save as BLF", ButtonType::Info);
+        if !self.recording && !self.capture.is_empty() {
+            asc_btn = asc_btn.on_press(TracerMessage::ExportCapture(TraceFormat::Asc));
+            blf_btn = blf_btn.on_press(TracerMessage::ExportCapture(TraceFormat::Blf));
+        }
+        let capture_row = Row::new()
+            .spacing(10)
+            .push(rec_btn)
+            .push(asc_btn)
+            .push(blf_btn)
+            .push(Text::new(format!("{} frames captured", self.capture.len())));
+
+        let mut analyze_btn = match self.analysis {
+            None => button_coloured(
+                &mut self.analyze_btn_state,
+                "Analyze ISO-TP",
+                ButtonType::Info,
+            ),
+            Some(_) => button_coloured(
+                &mut self.analyze_btn_state,
+                "Back to trace",
+                ButtonType::Info,
+            ),
+        };
+        if self.analysis.is_some() {
+            analyze_btn = analyze_btn.on_press(TracerMessage::CloseAnalysis);
+        } else if !self.recording && !self.capture.is_empty() {
+            analyze_btn = analyze_btn.on_press(TracerMessage::AnalyzeCapture);
+        }
+        let analysis_row = Row::new()
+            .spacing(10)
+            .push(
+                text_input(
+                    &mut self.analysis_send_input,
+                    "Request ID (EG: 7E0)",
+                    &self.analysis_send_string,
+                    TracerMessage::EnterAnalysisSendId,
+                )
+                .width(Length::Units(200)),
+            )
+            .push(
+                text_input(
+                    &mut self.analysis_recv_input,
+                    "Response ID (EG: 7E8)",
+                    &self.analysis_recv_string,
+                    TracerMessage::EnterAnalysisRecvId,
+                )
+                .width(Length::Units(200)),
+            )
+            .push(analyze_btn);
+
         Column::new()
             .padding(10)
             .spacing(10)
@@ -111,22 +373,158 @@ impl<'a> CanTracer {
                 "View CAN in Binary",
                 TracerMessage::ToggleBinaryMode,
             ))
+            .push(Checkbox::new(
+                self.use_hw_timestamp,
+                "Use adapter timestamps (If supported)",
+                TracerMessage::ToggleHwTimestamp,
+            ))
+            .push(Checkbox::new(
+                self.grouped_view,
+                "Group by CAN ID (Otherwise list every frame as it arrives)",
+                TracerMessage::ToggleGroupedView,
+            ))
+            .push(capture_row)
+            .push(analysis_row)
+            .push(Text::new(&self.status_text))
             .push(
                 Scrollable::new(&mut self.scroll_state)
                     .height(Length::Fill)
-                    .push(Self::build_can_list(
-                        &self.is_binary_fmt,
-                        &self.can_queue,
-                        &mut self.can_prev,
-                    )),
+                    .push(match (&self.analysis, self.grouped_view) {
+                        (Some(exchanges), _) => {
+                            Self::build_analysis_list(self.is_binary_fmt, exchanges)
+                        }
+                        (None, true) => Self::build_can_list(
+                            &self.is_binary_fmt,
+                            self.use_hw_timestamp,
+                            &self.can_queue,
+                            &mut self.can_prev,
+                            &self.host_timestamps,
+                            &self.rx_times,
+                        ),
+                        (None, false) => Self::build_trace_list(
+                            self.is_binary_fmt,
+                            self.use_hw_timestamp,
+                            &self.trace,
+                        ),
+                    }),
             )
             .into()
     }
 
+    /// Formats the bytes of a frame in hex or binary
+    fn format_data(binary: bool, data: &[u8]) -> String {
+        data.iter()
+            .map(|b| match binary {
+                true => format!("{:08b}", b),
+                false => format!("{:02X}", b),
+            })
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    /// Lists the latest frames in the order they arrived, newest first
+    pub fn build_trace_list(
+        binary: bool,
+        use_hw_timestamp: bool,
+        trace: &VecDeque<(u128, CanFrame)>,
+    ) -> Element<'a, TracerMessage> {
+        let mut col = Column::new();
+        for (rx_time, f) in trace {
+            col = col.push(
+                Row::new()
+                    .push(
+                        Row::new()
+                            .push(Text::new(Self::format_timestamp(
+                                use_hw_timestamp,
+                                f,
+                                Some(*rx_time),
+                            )))
+                            .width(Length::Units(180)),
+                    )
+                    .push(
+                        Row::new()
+                            .push(Text::new(format!("CID: {:04X}", f.id)))
+                            .width(Length::Units(100)),
+                    )
+                    .push(
+                        Row::new()
+                            .push(Text::new(format!("[{}]", f.dlc)))
+                            .width(Length::Units(40)),
+                    )
+                    .push(Text::new(Self::format_data(binary, f.get_data()))),
+            );
+        }
+        col.into()
+    }
+
+    /// Lists each exchange of an ISO-TP analysis, with what each of its frames was
+    /// and the time since the frame before it
+    pub fn build_analysis_list(binary: bool, exchanges: &[Exchange]) -> Element<'a, TracerMessage> {
+        let problem_colour = Color::from_rgb8(192, 0, 0);
+        let mut col = Column::new().spacing(10);
+        for (i, e) in exchanges.iter().enumerate() {
+            let request = match &e.request {
+                Some(r) => Self::format_data(binary, r),
+                None => "Incomplete".into(),
+            };
+            let mut header = Text::new(format!(
+                "Exchange {}: Request [{}], {} response(s), {:.3}ms",
+                i + 1,
+                request,
+                e.responses.len(),
+                e.get_duration_us() as f32 / 1000.0
+            ));
+            if e.has_problems() {
+                header = header.color(problem_colour);
+            }
+            let mut frames = Column::new().push(header);
+            for f in &e.frames {
+                let dir = match f.direction {
+                    Direction::Request => "->",
+                    Direction::Response => "<-",
+                };
+                frames = frames.push(
+                    Row::new()
+                        .push(
+                            Row::new()
+                                .push(Text::new(format!("+{:.3}ms", f.delta_us as f32 / 1000.0)))
+                                .width(Length::Units(100)),
+                        )
+                        .push(
+                            Row::new()
+                                .push(Text::new(format!("{} CID: {:04X}", dir, f.id)))
+                                .width(Length::Units(130)),
+                        )
+                        .push(
+                            Row::new()
+                                .push(Text::new(Self::format_data(binary, &f.data)))
+                                .width(Length::Units(if binary { 640 } else { 240 })),
+                        )
+                        .push(Text::new(&f.desc)),
+                );
+                if let Some(problem) = &f.problem {
+                    frames =
+                        frames.push(Text::new(format!("  ! {}", problem)).color(problem_colour));
+                }
+            }
+            for r in &e.responses {
+                frames = frames.push(Text::new(format!(
+                    "Response [{}]",
+                    Self::format_data(binary, r)
+                )));
+            }
+            col = col.push(frames);
+        }
+        col.into()
+    }
+
     pub fn build_can_list(
         binary: &bool,
+        use_hw_timestamp: bool,
         curr_data: &HashMap<u32, CanFrame>,
         old_data: &mut HashMap<u32, CanFrame>,
+        host_timestamps: &HashMap<u32, u128>,
+        rx_times: &HashMap<u32, VecDeque<u128>>,
     ) -> Element<'a, TracerMessage> {
         let mut col = Column::new();
         let mut x: Vec<u32> = curr_data.keys().into_iter().copied().collect();
@@ -139,6 +537,22 @@ impl<'a> CanTracer {
                     .push(Text::new(format!("CID: {:04X}", i.id)))
                     .width(Length::Units(100)),
             );
+            container = container.push(
+                Row::new()
+                    .push(Text::new(Self::format_timestamp(
+                        use_hw_timestamp,
+                        i,
+                        host_timestamps.get(&cid).copied(),
+                    )))
+                    .width(Length::Units(180)),
+            );
+            // Frames received in the last second
+            let rate = rx_times.get(&cid).map(|t| t.len()).unwrap_or(0);
+            container = container.push(
+                Row::new()
+                    .push(Text::new(format!("{}/s", rate)))
+                    .width(Length::Units(60)),
+            );
             if let Some(old_frame) = old_data.get(&cid) {
                 // Old frame exists, try to work out what changed
                 let old_data = old_frame.get_data();
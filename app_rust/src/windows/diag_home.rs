@@ -3,6 +3,7 @@ use super::{
     diag_scanner::{DiagScanner, DiagScannerMessage},
 };
 use crate::commapi::comm_api::{Capability, ComServer};
+use crate::commapi::protocols::wake_up::WakeUpInit;
 use crate::themes::{button_outlined, text, title_text, ButtonType, TextType, TitleSize};
 use crate::windows::window::WindowMessage;
 use iced::{button, Align, Column, Element, Length, Row, Rule, Space, Subscription, Text};
@@ -25,11 +26,30 @@ pub struct ECUDiagSettings {
     pub(crate) sep_time_ms: u32,
     pub(crate) uds_support: bool,
     pub(crate) kwp_support: bool,
+    /// Pinned to the top of the ECU list. Stored in the settings rather than the save file
+    #[serde(skip)]
+    pub(crate) favorite: bool,
+    /// Run before starting a diagnostic session, for ECUs that need waking up first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) wake_up: Option<WakeUpInit>,
+    /// Talk to the ECU over CAN FD
+    #[serde(default)]
+    pub(crate) can_fd: bool,
+    /// Byte to pad frames sent to the ECU with. None sends frames unpadded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) padding: Option<u8>,
+    /// Address of the ECU, for ECUs that use extended ISO-TP addressing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) ext_addr: Option<u8>,
 }
 
 impl ToString for ECUDiagSettings {
     fn to_string(&self) -> String {
-        format!("{} (0x{:04X})", self.name, self.send_id)
+        if self.favorite {
+            format!("* {} (0x{:04X})", self.name, self.send_id)
+        } else {
+            format!("{} (0x{:04X})", self.name, self.send_id)
+        }
     }
 }
 
@@ -1,10 +1,24 @@
-use std::{fs::File, io::Read, path::Path, todo};
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+    todo,
+};
 
 use common::schema::OvdECU;
-use iced::{Align, Column, Element, Length, Row, Subscription};
+use iced::{Align, Checkbox, Column, Element, Length, Row, Subscription};
 
 use crate::{
-    commapi::comm_api::{ComServer, ISO15765Config},
+    commapi::{
+        comm_api::{CanFdConfig, Capability, ComServer, ISO15765Config},
+        protocols::{
+            baseline::{compare_to_baseline, take_snapshot, SnapshotTarget, VehicleSnapshot},
+            learn_response_id,
+            wake_up::{get_wake_up_routine, WakeUpInit, WAKE_UP_ROUTINES},
+            DiagProtocol,
+        },
+    },
+    settings::{get_ecu_profile, get_settings, set_settings, ECUProfile, FavoriteECU},
     themes::{
         button_outlined, elements::TextInput, picklist, text, text_input, title_text, ButtonType,
         TextType, TitleSize,
@@ -12,8 +26,11 @@ use crate::{
 };
 
 use super::{
-    diag_home::{ECUDiagSettings, VehicleECUList},
-    diag_session::{DiagMessageTrait, DiagSession, SessionMsg, SessionType},
+    diag_home::{DiagHomeMessage, ECUDiagSettings, VehicleECUList},
+    diag_session::{DiagMessageTrait, DiagSession, SessionError, SessionMsg, SessionType},
+    file_dialog::{open_file, save_file, FileType},
+    hw_task,
+    window::WindowMessage,
 };
 
 #[derive(Debug, Clone)]
@@ -21,6 +38,9 @@ pub enum DiagManualMessage {
     LaunchFileBrowser,
     LoadFile(String),
     PickECU(ECUDiagSettings),
+    ToggleFavorite(bool),
+    /// Wake up step picked for the selected ECU
+    WakeUpSelected(String),
     LaunchKWP,
     LaunchKWPCustom,
     LaunchUDS,
@@ -28,6 +48,22 @@ pub enum DiagManualMessage {
     LaunchCustom,
     LaunchCustomCustom,
     LaunchJSON,
+    /// Launch the JSON session that was stopped as the ECU variant did not match
+    LaunchJSONAnyway,
+    AutoDetect,
+    AutoDetectCustom,
+    LearnRecvID,
+    LearnRecvIDCustom,
+    /// Result of learning the response ID, and if it was for the manual ISO-TP settings
+    RecvIDLearned(bool, Result<u32, String>),
+    /// Result of detecting the ECU's protocol, and if it was for the manual ISO-TP settings
+    ProtocolDetected(bool, Result<DiagProtocol, String>),
+    /// Snapshot the vehicle's current state, and save it as its known good baseline
+    CaptureBaseline,
+    /// Load a baseline, and compare the vehicle's current state against it
+    CheckBaseline,
+    /// A snapshot of the vehicle has been taken
+    SnapshotTaken(VehicleSnapshot),
     Back,
     Session(SessionMsg),
 
@@ -36,6 +72,7 @@ pub enum DiagManualMessage {
     RecvIDEnter(String),
     SepEnter(String),
     BsEnter(String),
+    ToggleCanFd(bool),
 }
 
 #[derive(Debug, Clone)]
@@ -44,13 +81,31 @@ pub struct DiagManual {
     car: Option<VehicleECUList>,
     btn_state: iced::button::State,
     pick_state: iced::pick_list::State<ECUDiagSettings>,
+    wake_up_list: iced::pick_list::State<String>,
     status: String,
     curr_ecu: Option<ECUDiagSettings>,
     uds_btn_state: iced::button::State,
     kwp_btn_state: iced::button::State,
     custom_btn_state: iced::button::State,
     json_btn_state: iced::button::State,
+    json_anyway_btn_state: iced::button::State,
+    auto_btn_state: iced::button::State,
+    learn_btn_state: iced::button::State,
     session: Option<DiagSession>,
+    /// ECU definition whose variant did not match the ECU, waiting for the user to proceed anyway
+    pending_json: Option<OvdECU>,
+    /// Listening for the ECU's response ID
+    learning: bool,
+    /// Working out which protocol the ECU speaks
+    detecting: bool,
+    capture_baseline_btn_state: iced::button::State,
+    check_baseline_btn_state: iced::button::State,
+    /// Taking a snapshot of the vehicle
+    snapshot_running: bool,
+    /// Baseline the snapshot being taken will be compared against. None if it is being captured
+    check_against: Option<VehicleSnapshot>,
+    /// Result of the last baseline check, one line per deviation
+    baseline_results: Vec<String>,
 
     // Input for custom session!
     str_send_id: String,
@@ -62,10 +117,13 @@ pub struct DiagManual {
     input_recv_id: iced::text_input::State,
     input_bs: iced::text_input::State,
     input_sep: iced::text_input::State,
+    use_can_fd: bool,
 
     uds_btn_state_2: iced::button::State,
     kwp_btn_state_2: iced::button::State,
     custom_btn_state_2: iced::button::State,
+    auto_btn_state_2: iced::button::State,
+    learn_btn_state_2: iced::button::State,
 }
 
 impl DiagManual {
@@ -75,13 +133,25 @@ impl DiagManual {
             car: None,
             btn_state: Default::default(),
             pick_state: Default::default(),
+            wake_up_list: Default::default(),
             status: "".into(),
             curr_ecu: None,
             uds_btn_state: Default::default(),
             kwp_btn_state: Default::default(),
             custom_btn_state: Default::default(),
             json_btn_state: Default::default(),
+            json_anyway_btn_state: Default::default(),
+            auto_btn_state: Default::default(),
+            learn_btn_state: Default::default(),
             session: None,
+            pending_json: None,
+            learning: false,
+            detecting: false,
+            capture_baseline_btn_state: Default::default(),
+            check_baseline_btn_state: Default::default(),
+            snapshot_running: false,
+            check_against: None,
+            baseline_results: Vec::new(),
             str_send_id: Default::default(),
             str_recv_id: Default::default(),
             str_bs: Default::default(),
@@ -90,9 +160,12 @@ impl DiagManual {
             input_recv_id: Default::default(),
             input_bs: Default::default(),
             input_sep: Default::default(),
+            use_can_fd: false,
             uds_btn_state_2: Default::default(),
             kwp_btn_state_2: Default::default(),
             custom_btn_state_2: Default::default(),
+            auto_btn_state_2: Default::default(),
+            learn_btn_state_2: Default::default(),
         }
     }
 
@@ -119,9 +192,7 @@ impl DiagManual {
         match msg {
             DiagManualMessage::Back => {}
             DiagManualMessage::LaunchFileBrowser => {
-                if let nfd::Response::Okay(f_path) =
-                    nfd::open_file_dialog(Some("ovdjson"), None).unwrap_or(nfd::Response::Cancel)
-                {
+                if let Some(f_path) = open_file(FileType::VehicleList) {
                     let path = f_path.clone();
                     if let Ok(mut file) = File::open(f_path) {
                         let mut str = "".into();
@@ -131,9 +202,15 @@ impl DiagManual {
                             match parse {
                                 Ok(car) => {
                                     self.curr_ecu = None;
-                                    self.car = Some(car)
+                                    self.pending_json = None;
+                                    self.baseline_results.clear();
+                                    self.car = Some(car);
+                                    self.apply_favorites();
+                                }
+                                Err(e) => {
+                                    self.status =
+                                        format!("Error processing {}: {}", path.display(), e)
                                 }
-                                Err(e) => self.status = format!("Error processing {}: {}", path, e),
                             }
                         } else {
                             self.status = "Error reading save file".into()
@@ -143,7 +220,13 @@ impl DiagManual {
                     }
                 }
             }
-            DiagManualMessage::PickECU(e) => self.curr_ecu = Some(e.clone()),
+            DiagManualMessage::PickECU(e) => {
+                self.pending_json = None;
+                self.curr_ecu = Some(e.clone());
+                self.apply_profile();
+            }
+            DiagManualMessage::ToggleFavorite(b) => self.set_favorite(*b),
+            DiagManualMessage::WakeUpSelected(s) => self.set_wake_up(s),
             DiagManualMessage::LaunchKWP => self.launch_diag_session(SessionType::KWP, false),
             DiagManualMessage::LaunchUDS => self.launch_diag_session(SessionType::UDS, false),
             DiagManualMessage::LaunchCustom => self.launch_diag_session(SessionType::Custom, false),
@@ -152,19 +235,36 @@ impl DiagManual {
             DiagManualMessage::LaunchCustomCustom => {
                 self.launch_diag_session(SessionType::Custom, true)
             }
+            DiagManualMessage::AutoDetect => self.detect_and_launch(false),
+            DiagManualMessage::AutoDetectCustom => self.detect_and_launch(true),
+            DiagManualMessage::LearnRecvID => self.learn_recv_id(false),
+            DiagManualMessage::LearnRecvIDCustom => self.learn_recv_id(true),
+            DiagManualMessage::RecvIDLearned(use_custom, res) => {
+                self.recv_id_learned(*use_custom, res)
+            }
+            DiagManualMessage::ProtocolDetected(use_custom, res) => {
+                self.protocol_detected(*use_custom, res)
+            }
+            DiagManualMessage::CaptureBaseline => self.start_snapshot(None),
+            DiagManualMessage::CheckBaseline => self.check_baseline(),
+            DiagManualMessage::SnapshotTaken(snapshot) => self.snapshot_taken(snapshot),
+            DiagManualMessage::ToggleCanFd(b) => self.use_can_fd = *b,
 
             DiagManualMessage::LaunchJSON => {
-                if let nfd::Response::Okay(f_path) =
-                    nfd::open_file_dialog(Some("json"), None).unwrap_or(nfd::Response::Cancel)
-                {
+                if let Some(f_path) = open_file(FileType::ECUDefinition) {
                     let path = f_path.clone();
                     if let Ok(mut file) = File::open(f_path) {
                         let mut str = "".into();
                         if file.read_to_string(&mut str).is_ok() {
                             let parse: serde_json::Result<OvdECU> = serde_json::from_str(&str);
                             match parse {
-                                Ok(ecu) => self.launch_diag_session(SessionType::JSON(ecu), false),
-                                Err(e) => self.status = format!("Error processing {}: {}", path, e),
+                                Ok(ecu) => {
+                                    self.launch_diag_session(SessionType::JSON(ecu, false), false)
+                                }
+                                Err(e) => {
+                                    self.status =
+                                        format!("Error processing {}: {}", path.display(), e)
+                                }
                             }
                         } else {
                             self.status = "Error reading file to string".into()
@@ -174,6 +274,12 @@ impl DiagManual {
                     }
                 }
             }
+            DiagManualMessage::LaunchJSONAnyway => {
+                if let Some(ecu) = self.pending_json.take() {
+                    self.status.clear();
+                    self.launch_diag_session(SessionType::JSON(ecu, true), false)
+                }
+            }
             DiagManualMessage::BsEnter(s) => {
                 if s.is_empty() {
                     self.status.clear();
@@ -278,43 +384,473 @@ impl DiagManual {
         }
     }
 
+    /// Returns the ISO-TP settings from either the manual input, or the selected ECU
+    fn get_iso_tp_cfg(&self, use_custom: bool) -> Option<ISO15765Config> {
+        if use_custom {
+            let send_id = Self::decode_string_hex(&self.str_send_id)?;
+            let recv_id = Self::decode_string_hex(&self.str_recv_id)?;
+            let mut cfg = if self.str_bs.is_empty() && self.str_sep.is_empty() {
+                // No flow control overrides, work them out automatically
+                ISO15765Config::new_auto_fc(send_id, recv_id)
+            } else {
+                ISO15765Config {
+                    send_id,
+                    recv_id,
+                    block_size: Self::decode_string_int(&self.str_bs)?,
+                    sep_time: Self::decode_string_int(&self.str_sep)?,
+                    auto_fc: false,
+                    can_fd: None,
+                    padding: None,
+                    ext_addr: None,
+                }
+            };
+            if self.use_can_fd && self.can_fd_supported() {
+                cfg.can_fd = Some(CanFdConfig::default());
+            }
+            Some(cfg)
+        } else {
+            let fd_supported = self.can_fd_supported();
+            self.curr_ecu
+                .as_ref()
+                .map(|ecu| Self::get_ecu_iso_tp_cfg(ecu, fd_supported))
+        }
+    }
+
+    /// Returns true if the adapter can talk to ECUs over CAN FD
+    fn can_fd_supported(&self) -> bool {
+        self.server.get_capabilities().supports_can_fd() == Capability::Yes
+    }
+
+    /// Returns the ISO-TP settings of an ECU from the save file. ECUs marked as CAN FD are
+    /// talked to over classic CAN if the adapter does not support CAN FD
+    fn get_ecu_iso_tp_cfg(ecu: &ECUDiagSettings, fd_supported: bool) -> ISO15765Config {
+        ISO15765Config {
+            send_id: ecu.send_id,
+            recv_id: ecu.flow_control_id,
+            block_size: ecu.block_size,
+            sep_time: ecu.sep_time_ms,
+            auto_fc: false,
+            can_fd: if ecu.can_fd && fd_supported {
+                Some(CanFdConfig::default())
+            } else {
+                None
+            },
+            padding: ecu.padding,
+            ext_addr: ecu.ext_addr,
+        }
+    }
+
     pub fn launch_diag_session(&mut self, session_type: SessionType, use_custom: bool) {
         if self.session.is_some() {
             self.status = "Error. Diagnostic session already in progress??".into(); // How did this happen??
             return;
         }
 
-        if use_custom {
-            let cfg = ISO15765Config {
-                send_id: Self::decode_string_hex(&self.str_send_id).unwrap(),
-                recv_id: Self::decode_string_hex(&self.str_recv_id).unwrap(),
-                block_size: Self::decode_string_int(&self.str_bs).unwrap(),
-                sep_time: Self::decode_string_int(&self.str_sep).unwrap(),
-            };
-            match DiagSession::new(&session_type, self.server.clone(), cfg) {
+        // Manual ISO-TP settings are not tied to an ECU profile, so have no wake up step
+        let wake_up = if use_custom {
+            None
+        } else {
+            self.curr_ecu.as_ref().and_then(|e| e.wake_up.clone())
+        };
+        // Likewise, only settings of an ECU from the save file are remembered
+        let profile = if use_custom { None } else { self.get_profile() };
+        self.pending_json = None;
+        if let Some(cfg) = self.get_iso_tp_cfg(use_custom) {
+            match DiagSession::new(&session_type, self.server.clone(), cfg, wake_up, profile) {
                 Ok(session) => self.session = Some(session),
+                Err(SessionError::VariantMismatch(s)) => {
+                    // Let the user decide if the definition is close enough to use
+                    if let SessionType::JSON(ecu, _) = session_type {
+                        self.pending_json = Some(ecu);
+                    }
+                    self.status = format!("{}. Check the ECU file, or proceed anyway", s)
+                }
                 Err(e) => self.status = format!("Error init diag session: {}", e.get_description()),
             }
-        } else if let Some(ecu) = &self.curr_ecu {
-            let cfg = ISO15765Config {
+        } else {
+            self.status = "Error. No ECU selected?".into(); // How did this happen??
+        }
+    }
+
+    /// Works out if the ECU speaks UDS or KWP2000, then launches the matching session
+    fn detect_and_launch(&mut self, use_custom: bool) {
+        let cfg = match self.get_iso_tp_cfg(use_custom) {
+            Some(cfg) => cfg,
+            None => {
+                self.status = "Error. No ECU selected?".into();
+                return;
+            }
+        };
+        self.detecting = true;
+        self.status = "Detecting ECU protocol...".into();
+        let server = self.server.clone();
+        hw_task::run(
+            move || DiagProtocol::detect(server, &cfg).map_err(|e| e.get_text()),
+            move |res| {
+                WindowMessage::DiagHome(DiagHomeMessage::ManualSession(
+                    DiagManualMessage::ProtocolDetected(use_custom, res),
+                ))
+            },
+        );
+    }
+
+    /// Launches the session for the protocol the ECU was found to speak
+    fn protocol_detected(&mut self, use_custom: bool, res: &Result<DiagProtocol, String>) {
+        self.detecting = false;
+        self.status.clear();
+        match res {
+            Ok(protocol) => {
+                println!("Detected ECU protocol: {}", protocol);
+                self.launch_diag_session(
+                    match protocol {
+                        DiagProtocol::KWP2000 => SessionType::KWP,
+                        DiagProtocol::UDS => SessionType::UDS,
+                    },
+                    use_custom,
+                );
+                if self.session.is_some() {
+                    self.status = format!("Detected protocol: {}", protocol);
+                }
+            }
+            Err(e) => self.status = format!("Could not detect ECU protocol: {}", e),
+        }
+    }
+
+    fn get_vehicle_name(car: &VehicleECUList) -> String {
+        format!(
+            "{} {} ({})",
+            car.vehicle_brand, car.vehicle_name, car.vehicle_year
+        )
+    }
+
+    /// Marks the ECUs in the loaded save file that are favorites, and moves them to
+    /// the top of the ECU list
+    fn apply_favorites(&mut self) {
+        let favorites = get_settings().favorite_ecus;
+        if let Some(car) = self.car.as_mut() {
+            let vehicle = Self::get_vehicle_name(car);
+            for ecu in car.ecu_list.iter_mut() {
+                ecu.favorite = favorites.iter().any(|f| {
+                    f.vehicle == vehicle && f.name == ecu.name && f.send_id == ecu.send_id
+                });
+            }
+            // Stable sort, so ECUs otherwise keep the order of the save file
+            car.ecu_list.sort_by_key(|e| !e.favorite);
+            if let Some(curr) = self.curr_ecu.as_mut() {
+                curr.favorite = car
+                    .ecu_list
+                    .iter()
+                    .any(|e| e.name == curr.name && e.send_id == curr.send_id && e.favorite);
+            }
+        }
+    }
+
+    /// Adds or removes the selected ECU from the favorites
+    fn set_favorite(&mut self, favorite: bool) {
+        let entry = match (&self.car, &self.curr_ecu) {
+            (Some(car), Some(ecu)) => FavoriteECU {
+                vehicle: Self::get_vehicle_name(car),
+                name: ecu.name.clone(),
                 send_id: ecu.send_id,
-                recv_id: ecu.flow_control_id,
-                block_size: ecu.block_size,
-                sep_time: ecu.sep_time_ms,
+            },
+            _ => return,
+        };
+        let mut settings = get_settings();
+        settings.favorite_ecus.retain(|f| f != &entry);
+        if favorite {
+            settings.favorite_ecus.push(entry);
+        }
+        if let Err(e) = set_settings(settings) {
+            self.status = format!("Error saving favorite ECUs: {}", e)
+        }
+        self.apply_favorites();
+    }
+
+    /// Returns the profile of the selected ECU, with its current transport settings. Settings
+    /// only a diagnostic session can change are kept from the saved profile, if there is one
+    fn get_profile(&self) -> Option<ECUProfile> {
+        let (car, ecu) = match (&self.car, &self.curr_ecu) {
+            (Some(car), Some(ecu)) => (car, ecu),
+            _ => return None,
+        };
+        let vehicle = Self::get_vehicle_name(car);
+        let saved = get_ecu_profile(&vehicle, &ecu.name, ecu.send_id);
+        Some(ECUProfile {
+            vehicle,
+            name: ecu.name.clone(),
+            send_id: ecu.send_id,
+            recv_id: ecu.flow_control_id,
+            block_size: ecu.block_size,
+            sep_time: ecu.sep_time_ms,
+            can_fd: ecu.can_fd,
+            padding: ecu.padding,
+            ext_addr: ecu.ext_addr,
+            addressing: saved.as_ref().map(|p| p.addressing).unwrap_or_default(),
+            tester_present_addressing: saved
+                .as_ref()
+                .map(|p| p.tester_present_addressing)
+                .unwrap_or_default(),
+            timing: saved.and_then(|p| p.timing),
+            wake_up: ecu.wake_up.clone(),
+        })
+    }
+
+    /// Restores the transport settings saved the last time a session with the selected ECU worked
+    fn apply_profile(&mut self) {
+        let (car, ecu) = match (&self.car, self.curr_ecu.as_mut()) {
+            (Some(car), Some(ecu)) => (car, ecu),
+            _ => return,
+        };
+        if let Some(p) = get_ecu_profile(&Self::get_vehicle_name(car), &ecu.name, ecu.send_id) {
+            ecu.flow_control_id = p.recv_id;
+            ecu.block_size = p.block_size;
+            ecu.sep_time_ms = p.sep_time;
+            ecu.can_fd = p.can_fd;
+            ecu.padding = p.padding;
+            ecu.ext_addr = p.ext_addr;
+            ecu.wake_up = p.wake_up;
+            self.status = format!("Restored saved connection settings for {}", ecu.name)
+        }
+    }
+
+    /// Name shown in the wake up list for the wake up step of an ECU
+    fn get_wake_up_name(wake_up: &Option<WakeUpInit>) -> String {
+        match wake_up {
+            None => "No wake up".into(),
+            Some(WakeUpInit::Routine(name)) => name.clone(),
+            Some(w) => w.to_string(),
+        }
+    }
+
+    /// Sets the wake up step of the selected ECU, for this session. Wake up frames other
+    /// than the built in routines can be set in the save file
+    fn set_wake_up(&mut self, name: &str) {
+        let ecu = match self.curr_ecu.as_mut() {
+            Some(e) => e,
+            None => return,
+        };
+        if name == Self::get_wake_up_name(&ecu.wake_up) {
+            return;
+        }
+        ecu.wake_up = get_wake_up_routine(name).map(|r| WakeUpInit::Routine(r.name.into()));
+        // Keep the ECU list in sync, so picking the ECU again keeps the wake up step
+        if let Some(car) = self.car.as_mut() {
+            for e in car.ecu_list.iter_mut().filter(|e| e.name == ecu.name) {
+                e.wake_up = ecu.wake_up.clone();
+            }
+        }
+    }
+
+    /// Listens for which ID the ECU responds on, for when the receive ID is unknown or wrong
+    fn learn_recv_id(&mut self, use_custom: bool) {
+        let send_id = if use_custom {
+            Self::decode_string_hex(&self.str_send_id)
+        } else {
+            self.curr_ecu.as_ref().map(|ecu| ecu.send_id)
+        };
+        let send_id = match send_id {
+            Some(id) => id,
+            None => {
+                self.status = "Error. No send ID?".into();
+                return;
+            }
+        };
+        self.learning = true;
+        self.status = format!("Listening for responses to 0x{:04X}...", send_id);
+        let server = self.server.clone();
+        hw_task::run(
+            move || learn_response_id(server, send_id).map_err(|e| e.get_text()),
+            move |res| {
+                WindowMessage::DiagHome(DiagHomeMessage::ManualSession(
+                    DiagManualMessage::RecvIDLearned(use_custom, res),
+                ))
+            },
+        );
+    }
+
+    /// Updates the receive ID with the one the ECU responded on
+    fn recv_id_learned(&mut self, use_custom: bool, res: &Result<u32, String>) {
+        self.learning = false;
+        let id = match res {
+            Ok(id) => *id,
+            Err(e) => {
+                self.status = format!("Could not learn response ID: {}", e);
+                return;
+            }
+        };
+        println!("Learned ECU response ID: 0x{:04X}", id);
+        let configured = if use_custom {
+            let configured = Self::decode_string_hex(&self.str_recv_id);
+            self.str_recv_id = if id > 0x7FF {
+                format!("{:08X}", id)
+            } else {
+                format!("{:04X}", id)
             };
-            match DiagSession::new(&session_type, self.server.clone(), cfg) {
-                Ok(session) => self.session = Some(session),
-                Err(e) => self.status = format!("Error init diag session: {}", e.get_description()),
+            configured
+        } else if let Some(ecu) = self.curr_ecu.as_mut() {
+            let configured = ecu.flow_control_id;
+            ecu.flow_control_id = id;
+            // Keep the ECU list in sync, so picking the ECU again keeps the learned ID
+            if let Some(car) = self.car.as_mut() {
+                for e in car.ecu_list.iter_mut().filter(|e| e.name == ecu.name) {
+                    e.flow_control_id = id;
+                }
             }
+            Some(configured)
         } else {
-            self.status = "Error. No ECU selected?".into(); // How did this happen??
+            None
+        };
+        self.status = match configured {
+            Some(c) if c == id => format!("ECU responds on the configured ID (0x{:04X})", id),
+            Some(c) if use_custom => format!(
+                "ECU responded on 0x{:04X}, not 0x{:04X}. Receive ID updated",
+                id, c
+            ),
+            Some(c) => format!(
+                "ECU responded on 0x{:04X}, not 0x{:04X}. Receive ID updated for this session, \
+                consider updating the save file",
+                id, c
+            ),
+            None => format!("ECU responded on 0x{:04X}. Receive ID set", id),
+        };
+    }
+
+    /// Takes a snapshot of every ECU in the save file that supports KWP2000 or UDS. If
+    /// `check_against` is set, the snapshot is compared against it, otherwise it is saved
+    fn start_snapshot(&mut self, check_against: Option<VehicleSnapshot>) {
+        let car = match &self.car {
+            Some(car) => car,
+            None => return,
+        };
+        let vehicle = Self::get_vehicle_name(car);
+        let fd_supported = self.can_fd_supported();
+        let targets: Vec<SnapshotTarget> = car
+            .ecu_list
+            .iter()
+            .filter_map(|ecu| {
+                let protocol = if ecu.kwp_support {
+                    DiagProtocol::KWP2000
+                } else if ecu.uds_support {
+                    DiagProtocol::UDS
+                } else {
+                    return None;
+                };
+                Some(SnapshotTarget {
+                    name: ecu.name.clone(),
+                    cfg: Self::get_ecu_iso_tp_cfg(ecu, fd_supported),
+                    protocol,
+                })
+            })
+            .collect();
+        if targets.is_empty() {
+            self.status = "No ECUs in the save file support KWP2000 or UDS".into();
+            return;
+        }
+        self.snapshot_running = true;
+        self.check_against = check_against;
+        self.baseline_results.clear();
+        self.status = format!("Taking a snapshot of {} ECUs...", targets.len());
+        let server = self.server.clone();
+        hw_task::run(
+            move || take_snapshot(server, &vehicle, &targets),
+            |snapshot| {
+                WindowMessage::DiagHome(DiagHomeMessage::ManualSession(
+                    DiagManualMessage::SnapshotTaken(snapshot),
+                ))
+            },
+        );
+    }
+
+    /// Loads a baseline, then takes a snapshot to compare against it
+    fn check_baseline(&mut self) {
+        let path = match open_file(FileType::Baseline) {
+            Some(p) => p,
+            None => return,
+        };
+        let mut str = "".into();
+        if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_string(&mut str)) {
+            self.status = format!("Error reading {}: {}", path.display(), e);
+            return;
         }
+        match serde_json::from_str::<VehicleSnapshot>(&str) {
+            Ok(baseline) => self.start_snapshot(Some(baseline)),
+            Err(e) => self.status = format!("Error processing {}: {}", path.display(), e),
+        }
+    }
+
+    /// Returns why each ECU left out of a snapshot (Or without its DTCs) was left out
+    fn get_snapshot_errors(snapshot: &VehicleSnapshot) -> Vec<String> {
+        snapshot
+            .ecus
+            .iter()
+            .filter_map(|e| e.error.as_ref().map(|err| format!("{}: {}", e.name, err)))
+            .collect()
+    }
+
+    /// Saves a captured baseline, or shows how the vehicle differs from the loaded one
+    fn snapshot_taken(&mut self, snapshot: &VehicleSnapshot) {
+        self.snapshot_running = false;
+        let responded = snapshot.ecus.iter().filter(|e| e.responded).count();
+        if let Some(baseline) = self.check_against.take() {
+            let deviations = compare_to_baseline(&baseline, snapshot);
+            self.status = if deviations.is_empty() {
+                format!(
+                    "No deviations from the baseline captured at {}",
+                    baseline.time
+                )
+            } else {
+                format!(
+                    "{} deviations from the baseline captured at {}",
+                    deviations.len(),
+                    baseline.time
+                )
+            };
+            if baseline.vehicle != snapshot.vehicle {
+                self.status.push_str(&format!(
+                    ". Note: The baseline was captured on {}",
+                    baseline.vehicle
+                ));
+            }
+            self.baseline_results = deviations.iter().map(|d| d.to_string()).collect();
+            self.baseline_results
+                .extend(Self::get_snapshot_errors(snapshot));
+            return;
+        }
+        self.baseline_results = Self::get_snapshot_errors(snapshot);
+        if responded == 0 {
+            self.status = "No ECUs responded, baseline not saved. Is the vehicle on?".into();
+            return;
+        }
+        let path = match save_file(FileType::Baseline, "json") {
+            Some(p) => p,
+            None => {
+                self.status = "Baseline not saved".into();
+                return;
+            }
+        };
+        let json = match serde_json::to_string_pretty(snapshot) {
+            Ok(j) => j,
+            Err(e) => {
+                self.status = format!("Error creating baseline: {}", e);
+                return;
+            }
+        };
+        self.status = match File::create(&path).and_then(|mut f| f.write_all(json.as_bytes())) {
+            Ok(_) => format!(
+                "Baseline of {}/{} responding ECUs saved to {}",
+                responded,
+                snapshot.ecus.len(),
+                path.display()
+            ),
+            Err(e) => format!("Error saving baseline to {}: {}", path.display(), e),
+        };
     }
 
     pub fn view(&mut self) -> Element<DiagManualMessage> {
         if let Some(ref mut session) = self.session {
             return session.view().map(DiagManualMessage::Session);
         }
+        let fd_supported = self.can_fd_supported();
         let mut view = Column::new()
             .padding(20)
             .spacing(20)
@@ -329,11 +865,7 @@ impl DiagManual {
 
         if let Some(car) = &self.car {
             view = view.push(text(
-                format!(
-                    "Loaded car: {} {} ({})",
-                    car.vehicle_brand, car.vehicle_name, car.vehicle_year
-                )
-                .as_str(),
+                format!("Loaded car: {}", Self::get_vehicle_name(car)).as_str(),
                 TextType::Normal,
             ));
 
@@ -344,7 +876,60 @@ impl DiagManual {
                 DiagManualMessage::PickECU,
             ));
 
+            let mut capture_btn = button_outlined(
+                &mut self.capture_baseline_btn_state,
+                "Capture baseline",
+                ButtonType::Info,
+            )
+            .width(Length::Units(250));
+            let mut check_btn = button_outlined(
+                &mut self.check_baseline_btn_state,
+                "Check against baseline",
+                ButtonType::Info,
+            )
+            .width(Length::Units(250));
+            if !self.snapshot_running {
+                capture_btn = capture_btn.on_press(DiagManualMessage::CaptureBaseline);
+                check_btn = check_btn.on_press(DiagManualMessage::CheckBaseline);
+            }
+            view = view.push(Row::new().spacing(8).push(capture_btn).push(check_btn));
+            for r in &self.baseline_results {
+                view = view.push(text(r, TextType::Warning));
+            }
+
             if let Some(ecu) = &self.curr_ecu {
+                if ecu.can_fd && !fd_supported {
+                    view = view.push(text(
+                        "This ECU uses CAN FD, which the adapter does not support. Classic CAN will be used",
+                        TextType::Warning,
+                    ));
+                }
+                view = view.push(Checkbox::new(
+                    ecu.favorite,
+                    "Favorite (Pin to the top of the list)",
+                    DiagManualMessage::ToggleFavorite,
+                ));
+                let selected_wake_up = Self::get_wake_up_name(&ecu.wake_up);
+                let mut wake_up_names = vec![Self::get_wake_up_name(&None)];
+                wake_up_names.extend(WAKE_UP_ROUTINES.iter().map(|r| r.name.to_string()));
+                if !wake_up_names.contains(&selected_wake_up) {
+                    wake_up_names.push(selected_wake_up.clone());
+                }
+                view = view.push(
+                    Row::new()
+                        .spacing(8)
+                        .align_items(Align::Center)
+                        .push(text("Wake up before connecting:", TextType::Normal))
+                        .push(picklist(
+                            &mut self.wake_up_list,
+                            wake_up_names,
+                            Some(selected_wake_up.clone()),
+                            DiagManualMessage::WakeUpSelected,
+                        )),
+                );
+                if let Some(r) = get_wake_up_routine(&selected_wake_up) {
+                    view = view.push(text(r.description, TextType::Normal));
+                }
                 let kwp_text = if ecu.kwp_support {
                     "Launch KWP2000 session"
                 } else {
@@ -377,6 +962,16 @@ impl DiagManual {
                 .on_press(DiagManualMessage::LaunchCustom)
                 .width(Length::Units(250));
 
+                let mut auto_btn = button_outlined(
+                    &mut self.auto_btn_state,
+                    "Auto-detect protocol",
+                    ButtonType::Success,
+                )
+                .width(Length::Units(250));
+                if !self.detecting {
+                    auto_btn = auto_btn.on_press(DiagManualMessage::AutoDetect);
+                }
+
                 view = view.push(
                     Row::new()
                         .spacing(8)
@@ -384,6 +979,17 @@ impl DiagManual {
                         .push(uds_btn)
                         .push(custom_btn),
                 );
+                let mut learn_btn = button_outlined(
+                    &mut self.learn_btn_state,
+                    "Learn response ID",
+                    ButtonType::Info,
+                )
+                .width(Length::Units(250));
+                if !self.learning {
+                    learn_btn = learn_btn.on_press(DiagManualMessage::LearnRecvID);
+                }
+
+                view = view.push(Row::new().spacing(8).push(auto_btn).push(learn_btn));
                 view = view.push(
                     button_outlined(
                         &mut self.json_btn_state,
@@ -392,6 +998,16 @@ impl DiagManual {
                     )
                     .on_press(DiagManualMessage::LaunchJSON),
                 );
+                if self.pending_json.is_some() {
+                    view = view.push(
+                        button_outlined(
+                            &mut self.json_anyway_btn_state,
+                            "Proceed anyway with mismatched ECU variant",
+                            ButtonType::Warning,
+                        )
+                        .on_press(DiagManualMessage::LaunchJSONAnyway),
+                    );
+                }
             }
         }
 
@@ -436,7 +1052,7 @@ impl DiagManual {
                         .push(text("Separation time (ms)", TextType::Normal))
                         .push(text_input(
                             &mut self.input_sep,
-                            "Enter separation time",
+                            "Auto",
                             &self.str_sep,
                             DiagManualMessage::SepEnter,
                         )),
@@ -448,19 +1064,30 @@ impl DiagManual {
                         .push(text("Block size", TextType::Normal))
                         .push(text_input(
                             &mut self.input_bs,
-                            "Enter block size",
+                            "Auto",
                             &self.str_bs,
                             DiagManualMessage::BsEnter,
                         )),
                 ),
         );
 
+        if fd_supported {
+            view = view.push(Checkbox::new(
+                self.use_can_fd,
+                "Use CAN FD",
+                DiagManualMessage::ToggleCanFd,
+            ));
+        }
+
         let send = Self::decode_string_hex(&self.str_send_id);
         let recv = Self::decode_string_hex(&self.str_recv_id);
         let bs = Self::decode_string_int(&self.str_bs);
         let sep = Self::decode_string_int(&self.str_sep);
 
-        let can_launch = send.is_some() && recv.is_some() && bs.is_some() && sep.is_some();
+        // Leaving both block size and separation time empty lets OVD choose them
+        let auto_fc = self.str_bs.is_empty() && self.str_sep.is_empty();
+        let can_launch =
+            send.is_some() && recv.is_some() && (auto_fc || (bs.is_some() && sep.is_some()));
 
         let mut kwp_btn_2 = button_outlined(
             &mut self.kwp_btn_state_2,
@@ -492,6 +1119,16 @@ impl DiagManual {
             cust_btn_2 = cust_btn_2.on_press(DiagManualMessage::LaunchCustomCustom);
         }
 
+        let mut auto_btn_2 = button_outlined(
+            &mut self.auto_btn_state_2,
+            "Auto-detect protocol",
+            ButtonType::Success,
+        )
+        .width(Length::Units(250));
+        if can_launch && !self.detecting {
+            auto_btn_2 = auto_btn_2.on_press(DiagManualMessage::AutoDetectCustom);
+        }
+
         view = view.push(
             Row::new()
                 .padding(5)
@@ -500,6 +1137,23 @@ impl DiagManual {
                 .push(uds_btn_2)
                 .push(cust_btn_2),
         );
+        // Only the send ID is needed to learn the receive ID
+        let mut learn_btn_2 = button_outlined(
+            &mut self.learn_btn_state_2,
+            "Learn response ID",
+            ButtonType::Info,
+        )
+        .width(Length::Units(250));
+        if send.is_some() && !self.learning {
+            learn_btn_2 = learn_btn_2.on_press(DiagManualMessage::LearnRecvIDCustom);
+        }
+        view = view.push(
+            Row::new()
+                .padding(5)
+                .spacing(5)
+                .push(auto_btn_2)
+                .push(learn_btn_2),
+        );
 
         view = view.push(text(&self.status, TextType::Danger));
 
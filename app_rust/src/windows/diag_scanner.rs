@@ -1,7 +1,15 @@
-use std::{collections::HashMap, fs::File, io::Write, ops::Index, time::Instant, todo};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Write,
+    ops::Index,
+    time::{Duration, Instant},
+    todo,
+};
 
 use commapi::{
     comm_api::ISO15765Config,
+    iso_tp::{decode_frame, IsoTpFrame},
     protocols::{kwp2000::KWP2000ECU, uds::UDSECU, DiagServer, ProtocolServer},
 };
 use iced::{Align, Column, Container, Element, Length, Row, Space};
@@ -10,7 +18,7 @@ use J2534Common::FilterType;
 use crate::{
     commapi::{
         self,
-        comm_api::{CanFrame, ComServer},
+        comm_api::{find_active_bitrate, get_can_bitrate, CanFrame, ComServer},
     },
     themes::{
         button_coloured, button_outlined, progress_bar, text, title_text, ButtonType, TextType,
@@ -24,6 +32,53 @@ pub enum DiagScannerMessage {
     IncrementStage,
     ScanPoll,
     SaveResults,
+    /// Stop testing ECUs, keeping the results gathered so far
+    CancelScan,
+}
+
+/// Why a scan did not find any ECUs
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EmptyScanReason {
+    /// No CAN traffic was seen at all. Usually a wiring or ignition problem
+    BusSilent,
+    /// CAN traffic was seen, but nothing responded to the diagnostic requests
+    NoDiagResponders,
+    /// No CAN traffic was seen at the bitrate in the settings, but there was at another bitrate
+    WrongBitrate,
+}
+
+impl EmptyScanReason {
+    pub fn get_desc(&self) -> &'static str {
+        match self {
+            EmptyScanReason::BusSilent => "No CAN traffic was seen on the OBD-II port",
+            EmptyScanReason::NoDiagResponders => {
+                "CAN traffic was seen, but no ECU responded to the diagnostic requests"
+            }
+            EmptyScanReason::WrongBitrate => {
+                "No CAN traffic was seen at the bitrate in the settings, but there was traffic at another bitrate"
+            }
+        }
+    }
+
+    /// Steps the user can try before scanning again
+    pub fn get_guidance(&self) -> &'static [&'static str] {
+        match self {
+            EmptyScanReason::BusSilent => &[
+                "Turn the ignition on (Engine off). Most vehicles do not power the CAN bus otherwise",
+                "Check the adapter is fully plugged in, and that the OBD-II port has CAN on pins 6 (CAN-H) and 14 (CAN-L)",
+                "The vehicle may use an uncommon CAN bitrate. OVD also listened at 125, 250, 500 and 1000kbps, but saw no traffic",
+            ],
+            EmptyScanReason::NoDiagResponders => &[
+                "Turn the ignition on (Engine off). Some ECUs only respond to diagnostics with the ignition on",
+                "The vehicle may have a gateway that blocks diagnostic requests that are not sent to the OBD-II IDs. Try OBD Tools instead",
+                "The ECUs may not use ISO-TP (EG: K-Line only vehicles), which the scanner does not support",
+            ],
+            EmptyScanReason::WrongBitrate => &[
+                "Change the CAN bitrate in the settings to the bitrate traffic was seen at, then scan again",
+                "If the vehicle has more than one CAN bus on the OBD-II port, the other bus may not be the diagnostic one",
+            ],
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -35,10 +90,20 @@ pub struct DiagScanner {
     filter_idx: u32,
     clock: Instant,
     can_traffic_id_list: HashMap<u32, bool>,
+    /// Set if any CAN frames were seen during the scan
+    bus_traffic_seen: bool,
+    /// Bitrate CAN traffic was seen at, if the bus was silent at the bitrate in the settings
+    other_bitrate: Option<u32>,
     curr_scan_id: u32,
     stage2_results: HashMap<u32, Vec<u32>>,
     stage3_results: Vec<ISO15765Config>,
     stage4_results: Vec<ECUDiagSettings>, // Also used by stage 5
+    /// Time testing each ECU for KWP2000 and UDS started
+    ecu_scan_start: Instant,
+    /// Number of ECUs tested for UDS, which is less than the number found if the scan was cancelled
+    uds_tested: usize,
+    cancelled: bool,
+    cancel_btn: iced::button::State,
     save_attempted: bool,
     save_path: String,
 }
@@ -54,9 +119,15 @@ impl DiagScanner {
             clock: Instant::now(),
             curr_scan_id: 0,
             can_traffic_id_list: HashMap::new(),
+            bus_traffic_seen: false,
+            other_bitrate: None,
             stage2_results: HashMap::new(),
             stage3_results: Vec::new(),
             stage4_results: Vec::new(),
+            ecu_scan_start: Instant::now(),
+            uds_tested: 0,
+            cancelled: false,
+            cancel_btn: Default::default(),
             save_attempted: false,
             save_path: "".into(),
         }
@@ -70,7 +141,7 @@ impl DiagScanner {
                     return None;
                 }
                 // Try to setup CAN Iface with open filter
-                if let Err(e) = self.server.open_can_interface(500_000, false) {
+                if let Err(e) = self.server.open_can_interface(get_can_bitrate(), false) {
                     self.status = format!("Could open CAN Interface ({})", e)
                 } else {
                     // Opening interface was OK
@@ -125,7 +196,7 @@ impl DiagScanner {
                 }
                 self.curr_stage += 1;
                 self.curr_scan_id = 0; // First entry in our array
-                if let Err(e) = self.server.open_can_interface(500_000, false) {
+                if let Err(e) = self.server.open_can_interface(get_can_bitrate(), false) {
                     self.status = "Error opening new CAN Interface!".into();
                     return None;
                 }
@@ -147,6 +218,7 @@ impl DiagScanner {
                 self.curr_stage += 1;
                 self.curr_scan_id = 0; // First entry in our array
                                        // Move to stage 5 (KWP2000 scan)
+                self.ecu_scan_start = Instant::now();
                 return Some(DiagScannerMessage::ScanPoll);
             }
             5 => {
@@ -157,6 +229,11 @@ impl DiagScanner {
             }
             6 => {
                 // Done scan!
+                self.uds_tested = self.stage4_results.len();
+                if !self.bus_traffic_seen {
+                    // Check if the bus is silent because the bitrate is wrong
+                    self.other_bitrate = find_active_bitrate(self.server.as_mut(), 1000);
+                }
                 self.curr_stage += 1; // End page
                 return None;
             }
@@ -183,6 +260,7 @@ impl DiagScanner {
                 } else {
                     for frame in &self.server.read_can_packets(0, 10000).unwrap_or_default() {
                         self.can_traffic_id_list.insert(frame.id, true);
+                        self.bus_traffic_seen = true;
                     }
                     Some(DiagScannerMessage::ScanPoll) // Keep polling
                 }
@@ -210,10 +288,12 @@ impl DiagScanner {
                 } else {
                     // Keep scanning for response messages
                     for frame in &self.server.read_can_packets(0, 10000).unwrap_or_default() {
+                        self.bus_traffic_seen = true;
                         if self.can_traffic_id_list.get(&frame.id).is_none() {
                             // Its a new frame we haven't seen before!
-                            let payload = frame.get_data();
-                            if payload[0] == 0x30 && payload.len() == 8 {
+                            if let Some(IsoTpFrame::FlowControl { .. }) =
+                                decode_frame(frame.get_data())
+                            {
                                 // Possible recv ID? - It might pick up multiple IDs during the scan, we filter it later on
                                 if let Some(r) = self.stage2_results.get_mut(&self.curr_scan_id) {
                                     r.push(frame.id)
@@ -259,14 +339,22 @@ impl DiagScanner {
                     let keys: Vec<u32> = self.stage2_results.keys().copied().collect();
                     // Scanning current CAN ID entries
                     for frame in &self.server.read_can_packets(0, 10000).unwrap_or_default() {
-                        let payload = frame.get_data();
-                        if payload[0] == 0x30 && payload.len() == 8 {
+                        if let Some(IsoTpFrame::FlowControl {
+                            block_size,
+                            sep_time,
+                            ..
+                        }) = decode_frame(frame.get_data())
+                        {
                             // Not a false positive! We can add the Config to list!
                             self.stage3_results.push(ISO15765Config {
                                 send_id: *keys.get((self.curr_scan_id - 1) as usize).unwrap(), // -1 is current scan ID whilst in this loop
                                 recv_id: frame.id,
-                                block_size: payload[1] as u32,
-                                sep_time: payload[2] as u32,
+                                block_size: block_size as u32,
+                                sep_time: sep_time as u32,
+                                auto_fc: false,
+                                can_fd: None,
+                                padding: None,
+                                ext_addr: None,
                             })
                         }
                     }
@@ -295,6 +383,11 @@ impl DiagScanner {
                     sep_time_ms: ecu.sep_time,
                     uds_support: false,
                     kwp_support: false,
+                    favorite: false,
+                    wake_up: None,
+                    can_fd: false,
+                    padding: ecu.padding,
+                    ext_addr: ecu.ext_addr,
                 };
 
                 // Interrogate the ECU with extended diagnostic session
@@ -341,11 +434,60 @@ impl DiagScanner {
         }
     }
 
+    /// Returns the number of ECU tests done, and the total number of tests to do.
+    /// Each ECU found is tested twice, once for KWP2000 and once for UDS
+    fn get_ecu_scan_progress(&self) -> (usize, usize) {
+        let ecus = self.stage3_results.len();
+        let done = match self.curr_stage {
+            5 => self.curr_scan_id as usize,
+            6 => ecus + self.curr_scan_id as usize,
+            _ => 0,
+        };
+        (done, ecus * 2)
+    }
+
+    /// Estimates the time left to test the remaining ECUs, from the average time
+    /// each test has taken so far. None until the first test is done
+    fn get_ecu_scan_eta(&self) -> Option<Duration> {
+        let (done, total) = self.get_ecu_scan_progress();
+        if done == 0 {
+            return None;
+        }
+        let per_test = self.ecu_scan_start.elapsed() / done as u32;
+        Some(per_test * total.saturating_sub(done) as u32)
+    }
+
+    /// Returns why no ECUs were found, if the scan found none
+    pub fn get_empty_scan_reason(&self) -> Option<EmptyScanReason> {
+        if !self.stage4_results.is_empty() {
+            None
+        } else if self.bus_traffic_seen {
+            Some(EmptyScanReason::NoDiagResponders)
+        } else if self.other_bitrate.is_some() {
+            Some(EmptyScanReason::WrongBitrate)
+        } else {
+            Some(EmptyScanReason::BusSilent)
+        }
+    }
+
     pub fn update(&mut self, msg: &DiagScannerMessage) -> Option<DiagScannerMessage> {
         self.status.clear();
         match msg {
             DiagScannerMessage::IncrementStage => self.increment_stage(),
             DiagScannerMessage::ScanPoll => self.poll(),
+            DiagScannerMessage::CancelScan => {
+                if self.curr_stage == 5 || self.curr_stage == 6 {
+                    // ECUs not yet tested in stage 5 are left out of the results
+                    self.uds_tested = if self.curr_stage == 6 {
+                        self.curr_scan_id as usize
+                    } else {
+                        0
+                    };
+                    self.cancelled = true;
+                    self.curr_stage = 7;
+                }
+                None
+            }
             DiagScannerMessage::SaveResults => {
                 self.save_attempted = true;
                 let v = VehicleECUList {
@@ -547,56 +689,50 @@ impl DiagScanner {
     }
 
     fn draw_stage_5(&mut self) -> Element<DiagScannerMessage> {
-        Column::new()
-            .padding(10)
-            .spacing(10)
-            .align_items(Align::Center)
-            .width(Length::Fill)
-            .push(title_text(
-                "Testing ECUs for UDS Capabilities",
-                crate::themes::TitleSize::P2,
-            ))
-            .push(progress_bar(
-                0f32..=self.stage3_results.len() as f32,
-                self.curr_scan_id as f32,
-                ButtonType::Info,
-            ))
-            .push(text(
-                format!(
-                    "Testing {}/{}",
-                    self.curr_scan_id,
-                    self.can_traffic_id_list.len()
-                )
-                .as_str(),
-                TextType::Normal,
-            ))
-            .into()
+        self.draw_ecu_scan("KWP2000")
     }
 
     fn draw_stage_6(&mut self) -> Element<DiagScannerMessage> {
+        self.draw_ecu_scan("UDS")
+    }
+
+    fn draw_ecu_scan(&mut self, protocol: &str) -> Element<DiagScannerMessage> {
+        let ecus = self.stage3_results.len();
+        let (done, total) = self.get_ecu_scan_progress();
+        let percent = if total == 0 { 100 } else { done * 100 / total };
+        let eta = match self.get_ecu_scan_eta() {
+            Some(t) => format!("About {} seconds remaining", t.as_secs() + 1),
+            None => "Estimating time remaining...".into(),
+        };
         Column::new()
             .padding(10)
             .spacing(10)
             .align_items(Align::Center)
             .width(Length::Fill)
             .push(title_text(
-                "Testing ECUs for UDS Capabilities",
+                format!("Testing ECUs for {} Capabilities", protocol).as_str(),
                 crate::themes::TitleSize::P2,
             ))
             .push(progress_bar(
-                0f32..=self.stage3_results.len() as f32,
-                self.curr_scan_id as f32,
+                0f32..=total as f32,
+                done as f32,
                 ButtonType::Info,
             ))
             .push(text(
                 format!(
-                    "Testing {}/{}",
-                    self.curr_scan_id,
-                    self.can_traffic_id_list.len()
+                    "Scanning ECU {} of {} ({}% complete)",
+                    (self.curr_scan_id as usize + 1).min(ecus),
+                    ecus,
+                    percent
                 )
                 .as_str(),
                 TextType::Normal,
             ))
+            .push(text(eta.as_str(), TextType::Normal))
+            .push(
+                button_outlined(&mut self.cancel_btn, "Cancel scan", ButtonType::Danger)
+                    .on_press(DiagScannerMessage::CancelScan),
+            )
             .into()
     }
 
@@ -606,13 +742,38 @@ impl DiagScanner {
             .spacing(10)
             .align_items(Align::Center)
             .width(Length::Fill)
-            .push(title_text("Scan completed", crate::themes::TitleSize::P2));
+            .push(title_text(
+                if self.cancelled {
+                    "Scan cancelled"
+                } else {
+                    "Scan completed"
+                },
+                crate::themes::TitleSize::P2,
+            ));
 
-        for ecu in &self.stage4_results {
+        if self.cancelled {
+            c = c.push(text(
+                format!(
+                    "Partial results: {} of {} ECUs were tested for KWP2000, and {} for UDS",
+                    self.stage4_results.len(),
+                    self.stage3_results.len(),
+                    self.uds_tested
+                )
+                .as_str(),
+                TextType::Warning,
+            ));
+        }
+
+        for (i, ecu) in self.stage4_results.iter().enumerate() {
+            let uds = if i < self.uds_tested {
+                format!("{}", ecu.uds_support)
+            } else {
+                "Not tested".into()
+            };
             c = c.push(text(
                 format!(
                     "ECU 0x{:04X} - KWP2000?: {}, UDS?: {}",
-                    ecu.send_id, ecu.kwp_support, ecu.uds_support
+                    ecu.send_id, ecu.kwp_support, uds
                 )
                 .as_str(),
                 TextType::Normal,
@@ -620,11 +781,35 @@ impl DiagScanner {
         }
 
         // Allow the user to save the results to file
-        if self.stage4_results.is_empty() {
+        if self.cancelled && self.stage4_results.is_empty() {
+            c = c.push(text(
+                "The scan was cancelled before any ECUs were tested",
+                TextType::Normal,
+            ));
+        } else if let Some(reason) = self.get_empty_scan_reason() {
             c = c.push(text(
                 "Unfortunately, no ISO-TP capable ECUs were found in your vehicle",
                 TextType::Normal,
             ));
+            c = c.push(text(reason.get_desc(), TextType::Warning));
+            if let Some(b) = self.other_bitrate {
+                c = c.push(text(
+                    format!(
+                        "Traffic was seen at {}kbps. The settings are for {}kbps",
+                        b / 1000,
+                        get_can_bitrate() / 1000
+                    )
+                    .as_str(),
+                    TextType::Warning,
+                ));
+            }
+            c = c.push(text("Things to try:", TextType::Normal));
+            for (i, step) in reason.get_guidance().iter().enumerate() {
+                c = c.push(text(
+                    format!("{}. {}", i + 1, step).as_str(),
+                    TextType::Normal,
+                ));
+            }
         } else {
             c = c.push(text("Save results to file:", TextType::Normal));
             c = c.push(
@@ -1,38 +1,166 @@
-use iced::Subscription;
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use iced::{time, Column, Container, Length, Row, Space, Subscription};
 
 use crate::{
-    commapi::comm_api::{ComServer, ISO15765Config},
-    windows::diag_manual::DiagManualMessage,
+    commapi::{
+        comm_api::{ComServer, ISO15765Config},
+        protocols::{
+            registry::{get_protocols, start_protocol, DynProtocolServer},
+            wake_up::WakeUpInit,
+            DTC,
+        },
+    },
+    themes::{
+        button_outlined, picklist, text, text_input, title_text, ButtonType, TextType, TitleSize,
+    },
+    windows::{hw_task, window, window::WindowMessage},
 };
 
-use super::{DiagMessageTrait, SessionError, SessionMsg, SessionResult, SessionTrait};
+use super::{
+    auto_export_log, find_bitrate_hint,
+    log_view::{raw_response, LogType, LogView},
+    to_window_msg, wake_up_ecu, DiagMessageTrait, SessionMsg, SessionResult, SessionTrait,
+};
 
-#[derive(Debug, Clone, PartialEq)]
+/// Connection to the ECU, shared with the hardware operations ran off the UI thread
+pub type SharedServer = Arc<Mutex<Box<dyn DynProtocolServer>>>;
+
+#[derive(Debug, Clone)]
 pub enum CustomDiagSessionMsg {
+    ProtocolSelected(String),
+    ConnectECU,
+    /// Log lines of the connection attempt, and the protocol and ECU if it could be connected to
+    Connected(Vec<(String, LogType)>, Option<(String, SharedServer)>),
+    DisconnectECU,
+    PollServer(Instant),
+    ReadCodes,
+    CodesRead(Result<Vec<DTC>, String>),
+    ClearErrors,
+    ErrorsCleared(Result<(), String>),
+    ToggleDTCSetting,
+    /// DTC setting turned on (true) or off (false)
+    DTCSettingChanged(Result<bool, String>),
+    EnterPayload(String),
+    SendPayload,
+    /// Request and response log lines of a payload sent, and the log type
+    PayloadSent(String, String, LogType),
+    ClearLogs,
     Back,
 }
 
 impl DiagMessageTrait for CustomDiagSessionMsg {
     fn is_back(&self) -> bool {
-        self == &CustomDiagSessionMsg::Back
+        matches!(self, CustomDiagSessionMsg::Back)
     }
 }
 
+/// Diagnostic session using any protocol in the [protocol registry](crate::commapi::protocols::registry),
+/// including protocols registered by the user
 #[derive(Debug, Clone)]
 pub struct CustomDiagSession {
     server: Box<dyn ComServer>,
     ecu: ISO15765Config,
+    /// Run before every connection to the ECU
+    wake_up: Option<WakeUpInit>,
+    /// Names of the registered protocols
+    protocols: Vec<String>,
+    selected_protocol: Option<String>,
+    protocol_list: iced::pick_list::State<String>,
+    diag_server: Option<SharedServer>,
+    connect_btn: iced::button::State,
+    back_btn: iced::button::State,
+    read_codes_btn: iced::button::State,
+    clear_btn: iced::button::State,
+    dtc_setting_btn: iced::button::State,
+    /// Connected protocol can turn storing of DTCs on and off
+    can_control_dtc: bool,
+    /// ECU has been told to stop storing DTCs
+    dtc_setting_off: bool,
+    payload_string: String,
+    payload_input: iced::text_input::State,
+    payload_send_btn: iced::button::State,
+    logview: LogView,
+    /// A hardware operation is running, so no more can be started until it completes
+    busy: bool,
 }
 
 impl CustomDiagSession {
-    pub fn new(comm_server: Box<dyn ComServer>, ecu: ISO15765Config) -> SessionResult<Self> {
-        Err(SessionError::Other(
-            "Custom Session is not yet implemented".into(),
-        ))
-        //Ok(Self {
-        //    ecu,
-        //    server: comm_server
-        //})
+    pub fn new(
+        comm_server: Box<dyn ComServer>,
+        ecu: ISO15765Config,
+        wake_up: Option<WakeUpInit>,
+    ) -> SessionResult<Self> {
+        let protocols: Vec<String> = get_protocols().iter().map(|p| p.name.to_string()).collect();
+        Ok(Self {
+            server: comm_server,
+            ecu,
+            wake_up,
+            selected_protocol: protocols.first().cloned(),
+            protocols,
+            protocol_list: Default::default(),
+            diag_server: None,
+            connect_btn: Default::default(),
+            back_btn: Default::default(),
+            read_codes_btn: Default::default(),
+            clear_btn: Default::default(),
+            dtc_setting_btn: Default::default(),
+            can_control_dtc: false,
+            dtc_setting_off: false,
+            payload_string: String::new(),
+            payload_input: Default::default(),
+            payload_send_btn: Default::default(),
+            logview: LogView::new(),
+            busy: false,
+        })
+    }
+
+    fn task_msg(msg: CustomDiagSessionMsg) -> WindowMessage {
+        to_window_msg(SessionMsg::Custom(msg))
+    }
+
+    /// Name exported logs are saved under
+    fn get_log_name(&self) -> String {
+        format!(
+            "{}_{:04X}",
+            self.selected_protocol.as_deref().unwrap_or("custom"),
+            self.ecu.send_id
+        )
+    }
+
+    fn get_payload(s: &str) -> Option<Vec<u8>> {
+        let bytes = hex::decode(s.replace(' ', "")).ok()?;
+        if bytes.is_empty() {
+            None
+        } else {
+            Some(bytes)
+        }
+    }
+
+    fn end_session(&mut self) {
+        self.diag_server.take();
+        self.can_control_dtc = false;
+        self.dtc_setting_off = false;
+        let name = self.get_log_name();
+        auto_export_log(&mut self.logview, &name);
+        window::enable_home();
+    }
+}
+
+impl Drop for CustomDiagSession {
+    fn drop(&mut self) {
+        if let Some(server) = self.diag_server.take() {
+            // Also turns DTC setting back on, if it was turned off
+            if let Ok(mut server) = server.lock() {
+                server.exit_diag_session();
+            }
+            // Session did not end by disconnecting, so the log has not been exported yet
+            let name = self.get_log_name();
+            auto_export_log(&mut self.logview, &name);
+        }
     }
 }
 
@@ -40,14 +168,333 @@ impl SessionTrait for CustomDiagSession {
     type msg = CustomDiagSessionMsg;
 
     fn view(&mut self) -> iced::Element<Self::msg> {
-        todo!()
+        let mut ui = Column::new()
+            .spacing(5)
+            .push(title_text("Custom diagnostic session", TitleSize::P3));
+        if self.diag_server.is_none() {
+            ui = ui.push(text("Protocol", TextType::Normal)).push(picklist(
+                &mut self.protocol_list,
+                &self.protocols,
+                self.selected_protocol.clone(),
+                CustomDiagSessionMsg::ProtocolSelected,
+            ));
+            if let Some(p) = get_protocols()
+                .iter()
+                .find(|p| Some(p.name) == self.selected_protocol.as_deref())
+            {
+                ui = ui.push(text(p.description, TextType::Normal));
+            }
+            let mut connect_btn =
+                button_outlined(&mut self.connect_btn, "Connect ECU", ButtonType::Primary);
+            let mut back_btn = button_outlined(&mut self.back_btn, "Back", ButtonType::Secondary);
+            if !self.busy {
+                back_btn = back_btn.on_press(CustomDiagSessionMsg::Back);
+                if self.selected_protocol.is_some() {
+                    connect_btn = connect_btn.on_press(CustomDiagSessionMsg::ConnectECU);
+                }
+            }
+            ui = ui.push(connect_btn).push(back_btn);
+        } else {
+            ui = ui.push(
+                button_outlined(&mut self.connect_btn, "Disconnect ECU", ButtonType::Warning)
+                    .on_press(CustomDiagSessionMsg::DisconnectECU),
+            );
+            let mut read_btn = button_outlined(
+                &mut self.read_codes_btn,
+                "Read error codes",
+                ButtonType::Secondary,
+            );
+            let mut clear_btn = button_outlined(
+                &mut self.clear_btn,
+                "Clear error codes",
+                ButtonType::Secondary,
+            );
+            let mut send_btn = button_outlined(
+                &mut self.payload_send_btn,
+                "Send payload",
+                ButtonType::Secondary,
+            );
+            if !self.busy {
+                read_btn = read_btn.on_press(CustomDiagSessionMsg::ReadCodes);
+                clear_btn = clear_btn.on_press(CustomDiagSessionMsg::ClearErrors);
+                if Self::get_payload(&self.payload_string).is_some() {
+                    send_btn = send_btn.on_press(CustomDiagSessionMsg::SendPayload);
+                }
+            }
+            ui = ui.push(read_btn).push(clear_btn);
+            if self.can_control_dtc {
+                let label = if self.dtc_setting_off {
+                    "Enable DTC setting"
+                } else {
+                    "Disable DTC setting"
+                };
+                let mut dtc_btn =
+                    button_outlined(&mut self.dtc_setting_btn, label, ButtonType::Warning);
+                if !self.busy {
+                    dtc_btn = dtc_btn.on_press(CustomDiagSessionMsg::ToggleDTCSetting);
+                }
+                ui = ui.push(dtc_btn);
+                if self.dtc_setting_off {
+                    ui = ui.push(text(
+                        "DTC setting is disabled. The ECU will not store any new errors",
+                        TextType::Warning,
+                    ));
+                }
+            }
+            ui = ui.push(
+                Row::new()
+                    .spacing(5)
+                    .push(text_input(
+                        &mut self.payload_input,
+                        "Enter payload (EG: 22 F1 90)",
+                        &self.payload_string,
+                        CustomDiagSessionMsg::EnterPayload,
+                    ))
+                    .push(send_btn),
+            );
+        }
+        ui = ui.push(Space::with_height(Length::Fill));
+        if let (Some(_), Some(p)) = (&self.diag_server, &self.selected_protocol) {
+            ui = ui.push(text(format!("Protocol: {}", p).as_str(), TextType::Normal));
+        }
+
+        Row::new()
+            .spacing(8)
+            .padding(8)
+            .push(ui.width(Length::FillPortion(1)))
+            .push(
+                Container::new(self.logview.view(CustomDiagSessionMsg::ClearLogs))
+                    .width(Length::FillPortion(1)),
+            )
+            .into()
     }
 
     fn update(&mut self, msg: &Self::msg) -> Option<Self::msg> {
-        todo!()
+        match msg {
+            CustomDiagSessionMsg::ProtocolSelected(p) => self.selected_protocol = Some(p.clone()),
+            CustomDiagSessionMsg::ConnectECU => {
+                let name = self.selected_protocol.clone()?;
+                self.busy = true;
+                let server = self.server.clone();
+                let ecu = self.ecu;
+                let wake_up = self.wake_up.clone();
+                hw_task::run(
+                    move || {
+                        let mut logs = wake_up_ecu(server.clone(), &ecu, wake_up.as_ref());
+                        match start_protocol(&name, server.clone(), &ecu) {
+                            Ok(diag_server) => {
+                                (logs, Some((name, Arc::new(Mutex::new(diag_server)))))
+                            }
+                            Err(e) => {
+                                logs.push((
+                                    format!("Error connecting to ECU ({})", e.get_text()),
+                                    LogType::Info,
+                                ));
+                                if e.is_timeout() {
+                                    logs.extend(
+                                        find_bitrate_hint(server).map(|h| (h, LogType::Warn)),
+                                    );
+                                }
+                                (logs, None)
+                            }
+                        }
+                    },
+                    |(logs, res)| Self::task_msg(CustomDiagSessionMsg::Connected(logs, res)),
+                );
+            }
+            CustomDiagSessionMsg::Connected(logs, res) => {
+                self.busy = false;
+                for (msg, ltype) in logs {
+                    self.logview.add_msg(msg, *ltype)
+                }
+                if let Some((name, server)) = res {
+                    window::disable_home();
+                    self.can_control_dtc = server.lock().unwrap().can_control_dtc_setting();
+                    self.diag_server = Some(server.clone());
+                    self.logview.add_msg(
+                        format!("Connection to ECU established using {}", name),
+                        LogType::Info,
+                    )
+                }
+            }
+            CustomDiagSessionMsg::DisconnectECU => {
+                if self.dtc_setting_off {
+                    self.logview
+                        .add_msg("Turning DTC setting back on", LogType::Info);
+                }
+                if let Some(server) = &self.diag_server {
+                    server.lock().unwrap().exit_diag_session()
+                }
+                self.logview
+                    .add_msg("Connection to ECU terminated", LogType::Info);
+                self.end_session();
+            }
+            CustomDiagSessionMsg::PollServer(_) => {
+                // The server is locked whilst a hardware operation runs, so it is checked later
+                let lost = match self.diag_server.as_ref().map(|s| s.try_lock()) {
+                    Some(Ok(mut s)) if !s.is_in_diag_session() => {
+                        s.exit_diag_session();
+                        Some(s.get_last_error())
+                    }
+                    _ => None,
+                };
+                if let Some(err) = lost {
+                    self.logview
+                        .add_msg("Connection to ECU closed unexpectedly", LogType::Info);
+                    if let Some(desc) = err {
+                        self.logview.add_msg(format!("--> {}", desc), LogType::Info);
+                    }
+                    if self.dtc_setting_off {
+                        self.logview.add_msg(
+                            "DTC setting could not be turned back on. The ECU turns it back on once it returns to its default session",
+                            LogType::Warn,
+                        );
+                    }
+                    self.end_session();
+                }
+            }
+            CustomDiagSessionMsg::ReadCodes => {
+                if let Some(server) = self.diag_server.clone() {
+                    self.busy = true;
+                    hw_task::run(
+                        move || {
+                            server
+                                .lock()
+                                .unwrap()
+                                .read_errors()
+                                .map_err(|e| e.get_text())
+                        },
+                        |res| Self::task_msg(CustomDiagSessionMsg::CodesRead(res)),
+                    );
+                }
+            }
+            CustomDiagSessionMsg::CodesRead(res) => {
+                self.busy = false;
+                match res {
+                    Err(e) => self
+                        .logview
+                        .add_msg(format!("Error reading ECU errors: {}", e), LogType::Error),
+                    Ok(errors) if errors.is_empty() => {
+                        self.logview.add_msg("No ECU errors stored", LogType::Info)
+                    }
+                    Ok(errors) => {
+                        for e in errors {
+                            self.logview.add_msg(
+                                format!("{} ({})", e.error, e.get_status_text()),
+                                LogType::Warn,
+                            )
+                        }
+                    }
+                }
+            }
+            CustomDiagSessionMsg::ClearErrors => {
+                if let Some(server) = self.diag_server.clone() {
+                    self.busy = true;
+                    hw_task::run(
+                        move || {
+                            server
+                                .lock()
+                                .unwrap()
+                                .clear_errors()
+                                .map_err(|e| e.get_text())
+                        },
+                        |res| Self::task_msg(CustomDiagSessionMsg::ErrorsCleared(res)),
+                    );
+                }
+            }
+            CustomDiagSessionMsg::ErrorsCleared(res) => {
+                self.busy = false;
+                match res {
+                    Ok(_) => self.logview.add_msg("ECU errors cleared", LogType::Info),
+                    Err(e) => self
+                        .logview
+                        .add_msg(format!("Error clearing ECU errors: {}", e), LogType::Error),
+                }
+            }
+            CustomDiagSessionMsg::ToggleDTCSetting => {
+                if let Some(server) = self.diag_server.clone() {
+                    self.busy = true;
+                    let on = self.dtc_setting_off;
+                    hw_task::run(
+                        move || {
+                            server
+                                .lock()
+                                .unwrap()
+                                .control_dtc_setting(on)
+                                .map(|_| on)
+                                .map_err(|e| e.get_text())
+                        },
+                        |res| Self::task_msg(CustomDiagSessionMsg::DTCSettingChanged(res)),
+                    );
+                }
+            }
+            CustomDiagSessionMsg::DTCSettingChanged(res) => {
+                self.busy = false;
+                match res {
+                    Ok(true) => {
+                        self.dtc_setting_off = false;
+                        self.logview.add_msg(
+                            "DTC setting enabled. The ECU will store errors again",
+                            LogType::Info,
+                        )
+                    }
+                    Ok(false) => {
+                        self.dtc_setting_off = true;
+                        self.logview.add_msg(
+                            "DTC setting disabled. The ECU will not store any new errors until it is enabled again, or the session ends",
+                            LogType::Warn,
+                        )
+                    }
+                    Err(e) => self
+                        .logview
+                        .add_msg(format!("Error changing DTC setting: {}", e), LogType::Error),
+                }
+            }
+            CustomDiagSessionMsg::EnterPayload(s) => self.payload_string = s.clone(),
+            CustomDiagSessionMsg::SendPayload => {
+                if let (Some(server), Some(payload)) = (
+                    self.diag_server.clone(),
+                    Self::get_payload(&self.payload_string),
+                ) {
+                    self.busy = true;
+                    hw_task::run(
+                        move || {
+                            let res = server
+                                .lock()
+                                .unwrap()
+                                .run_command(payload[0], &payload[1..]);
+                            let ltype = if res.is_ok() {
+                                LogType::Info
+                            } else {
+                                LogType::Warn
+                            };
+                            (
+                                format!("Req: {:02X?}", payload),
+                                raw_response(&payload, &res),
+                                ltype,
+                            )
+                        },
+                        |(req, resp, ltype)| {
+                            Self::task_msg(CustomDiagSessionMsg::PayloadSent(req, resp, ltype))
+                        },
+                    );
+                }
+            }
+            CustomDiagSessionMsg::PayloadSent(req, resp, ltype) => {
+                self.busy = false;
+                self.logview.add_log(req.clone(), resp.clone(), *ltype)
+            }
+            CustomDiagSessionMsg::ClearLogs => self.logview.clear_logs(),
+            CustomDiagSessionMsg::Back => {}
+        }
+        None
     }
 
     fn subscription(&self) -> iced::Subscription<Self::msg> {
-        Subscription::none()
+        if self.diag_server.is_some() {
+            time::every(std::time::Duration::from_millis(250)).map(CustomDiagSessionMsg::PollServer)
+        } else {
+            Subscription::none()
+        }
     }
 }
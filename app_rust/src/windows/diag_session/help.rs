@@ -0,0 +1,84 @@
+use iced::Column;
+
+use crate::themes::{help_box, ButtonType};
+
+// Help text for diagnostic session controls. iced 0.2 has no hover tooltips, so help is
+// shown below each control whilst the session's "Show help" option is ticked.
+
+pub const READ_CODES: &str =
+    "Reads the diagnostic trouble codes stored in the ECU. This does not change anything in the ECU";
+
+pub const MONITOR_CODES: &str = "Re-reads the error codes every few seconds, and alerts you \
+    when a new one appears. The interval and alerts can be changed in the settings";
+
+pub const PROBE_SESSIONS: &str = "Asks the ECU to enter each standard diagnostic session, to \
+    show which ones it supports. The ECU is returned to its current session afterwards";
+
+pub const READ_VIN: &str = "Reads the vehicle identification number stored in the ECU. \
+    KWP2000 identification is tried first, then UDS and OBD-II, until one of them works";
+
+pub const TIMING_PARAMETERS: &str = "Reads or changes how long the ECU waits for requests \
+    (Access timing parameters, 83). OVD reads these when connecting, and keeps the session \
+    alive within P3max. Only change them if the ECU drops the connection or misses requests, \
+    values outside of what the ECU supports are rejected";
+
+pub const CLEAR_CODES: &str = "Erases the stored trouble codes and their freeze frame data. \
+    Only clear codes once the fault is repaired, the information is lost for good and some \
+    ECUs will need to re-learn values (EG: Readiness monitors) afterwards";
+
+pub const SEND_PAYLOAD: &str = "Sends raw bytes to the ECU. The first byte is the service ID. \
+    Powerful services can be sent this way, such as SecurityAccess (27), RoutineControl \
+    (31), InputOutputControl (2F/30) and writing data (2E/3B). These can move actuators, \
+    change coding or erase memory. Only send payloads you understand, with the vehicle parked. \
+    Multiple payloads are sent in order, stopping at the first error. Negative responses \
+    a payload is expected to get can be added after it, EG: '1A86/11' carries on if the ECU \
+    replies 'service not supported' (11)";
+
+pub const PREVIEW_FRAMES: &str = "Shows the CAN frames the payload(s) would be sent as, \
+    without sending anything. Long payloads are split into a first frame and consecutive \
+    frames, and OVD has to wait for the ECU's flow control before sending the rest";
+
+pub const LOOP_SEND: &str = "Sends the payload(s) again and again, waiting the interval \
+    between each send, until stopped or the ECU is disconnected. Useful for keeping an actuator \
+    running, or watching a value respond to a command. The payloads are read when the loop \
+    starts, and the loop stops at the first unexpected error";
+
+pub const SUPPRESS_RESPONSE: &str = "Sets the suppress positive response bit of the sub-function \
+    byte. The ECU will not reply if the command succeeds, so OVD cannot confirm it worked. \
+    Only services with a sub-function (EG: 10, 11, 27, 28, 3E, 85) can be sent this way";
+
+pub const REQUIREMENT_WARNINGS: &str = "Before sending, checks if each payload's service \
+    usually needs an extended diagnostic session or security access (27) that has not been \
+    entered yet, and adds a warning to the log. Payloads are still sent, as ECUs differ";
+
+pub const ADDRESSING: &str = "Physical requests go to this ECU's own ID. Functional requests \
+    go to the broadcast ID from the settings, so every ECU listening may act on them, and \
+    they must fit in a single frame (7 bytes). Tester present is often sent functionally, \
+    whilst reading data is physical. Picking the wrong one leads to no response, or to \
+    several ECUs responding. Only this ECU's response is shown";
+
+pub const PAYLOAD_PRESETS: &str =
+    "Saves the current payload(s) under a name so they can be picked from the list later";
+
+pub const ROUTINE_LAYOUT: &str = "Loads a JSON file that describes the results of a routine \
+    (RequestRoutineResults (33)), so they are shown decoded in the log";
+
+pub const KEY_CALCULATOR: &str = "Computes the security access (27) key for a seed, without \
+    sending anything, so a key algorithm can be checked against known seed / key pairs. Steps \
+    are applied in order, EG: 'xor 5A5A, rol 3, add 1234'. Values of xor, add, sub and mul are \
+    hex, rol and ror take a number of bits, and not inverts every bit. Once it works, the \
+    algorithm can be used to unlock the ECU again after a reconnect";
+
+/// Adds a help box below the last control in `col` if help is being shown
+pub(crate) fn with_help<'a, Msg: 'a>(
+    col: Column<'a, Msg>,
+    show: bool,
+    text: &str,
+    accent: ButtonType,
+) -> Column<'a, Msg> {
+    if show {
+        col.push(help_box(text, accent))
+    } else {
+        col
+    }
+}
@@ -16,8 +16,10 @@ use crate::{
     commapi::{
         self,
         comm_api::{ComServer, ISO15765Config},
+        mock_api::SIMULATION_API_NAME,
         protocols::{
-            kwp2000::KWP2000ECU, DiagProtocol, DiagServer, ProtocolResult, ProtocolServer,
+            kwp2000::KWP2000ECU, wake_up::WakeUpInit, DiagProtocol, DiagServer, ProtocolResult,
+            ProtocolServer,
         },
     },
     themes::{
@@ -28,8 +30,9 @@ use crate::{
 };
 
 use super::{
+    auto_export_log, log_clear_verification, log_session_support,
     log_view::{LogType, LogView},
-    DiagMessageTrait, SessionError, SessionMsg, SessionResult, SessionTrait,
+    wake_up_ecu, DiagMessageTrait, SessionError, SessionMsg, SessionResult, SessionTrait,
 };
 
 type DiagService = commapi::protocols::kwp2000::Service;
@@ -38,6 +41,7 @@ type DiagService = commapi::protocols::kwp2000::Service;
 pub enum JsonDiagSessionMsg {
     ReadErrors,
     ClearErrors,
+    ProbeSessions,
     RunService,
     ExecuteService(ServiceRef, Vec<u8>),
     ClearLogs,
@@ -74,8 +78,11 @@ pub struct JsonDiagSession {
     execute_service: iced::button::State,
     clear_log_btn: iced::button::State,
     read_errors: iced::button::State,
+    probe_sessions: iced::button::State,
     looping_text: String,
     looping_service: Option<ServiceRef>, // Allow only read-only services to be loop read
+    /// Last response of the service being loop read, and which of its bytes changed since the read before it
+    looping_resp: Option<(Vec<u8>, Vec<bool>)>,
 }
 
 impl JsonDiagSession {
@@ -83,88 +90,118 @@ impl JsonDiagSession {
         comm_server: Box<dyn ComServer>,
         ecu: ISO15765Config,
         ecu_data: OvdECU,
+        allow_variant_mismatch: bool,
+        wake_up: Option<WakeUpInit>,
     ) -> SessionResult<Self> {
+        let is_simulated = comm_server.get_api() == SIMULATION_API_NAME;
+        let mut log_view = LogView::new();
+        if is_simulated {
+            log_view.add_msg(
+                "SIMULATED SESSION - No real ECU is connected",
+                LogType::Warn,
+            );
+        }
+        for (msg, ltype) in wake_up_ecu(comm_server.clone(), &ecu, wake_up.as_ref()) {
+            log_view.add_msg(msg, ltype)
+        }
         match DiagServer::new(comm_server, &ecu, DiagProtocol::KWP2000) {
             Ok(mut server) => {
                 println!("Server started");
-                let res = server.run_cmd(DiagService::ReadECUID.into(), &[0x87])?;
-                let variant = (res[4] as u32) << 8 | (res[5] as u32);
-                let mut ecu_variant = ecu_data.variants.clone().into_iter().find(|x| {
-                    x.clone()
-                        .patterns
-                        .into_iter()
-                        .any(|p| p.vendor_id == variant)
-                });
-
-                if ecu_variant.is_none() {
-                    ecu_variant = Some(ecu_data.variants[0].clone());
+                if ecu_data.variants.is_empty() {
+                    return Err(SessionError::Other(format!(
+                        "{} has no ECU variants defined",
+                        ecu_data.name
+                    )));
                 }
-
-                if let Some(v) = ecu_variant {
-                    let pattern = v
-                        .clone()
-                        .patterns
-                        .into_iter()
-                        .find(|x| x.vendor_id == variant)
-                        .unwrap();
-                    println!("ECU Variant: {} (Vendor: {})", v.name, pattern.vendor);
-
-                    let read_functions: Vec<ServiceRef> = v
-                        .services
-                        .iter()
-                        .filter(|x| x.input_params.is_empty() && !x.output_params.is_empty())
-                        .cloned()
-                        .map(|service| ServiceRef {
-                            inner: RefCell::new(service),
-                        })
-                        .collect();
-
-                    let write_functions: Vec<ServiceRef> = v
-                        .services
-                        .iter()
-                        .filter(|x| !x.input_params.is_empty() && x.output_params.is_empty())
-                        .cloned()
-                        .map(|service| ServiceRef {
-                            inner: RefCell::new(service),
-                        })
-                        .collect();
-
-                    let actuation_functions: Vec<ServiceRef> = v
-                        .services
-                        .iter()
-                        .filter(|x| !x.input_params.is_empty() && !x.output_params.is_empty())
-                        .cloned() // Maybe - Functions that only return yes/no?
-                        .map(|service| ServiceRef {
-                            inner: RefCell::new(service),
-                        })
-                        .collect();
-
-                    Ok(Self {
-                        ecu,
-                        ecu_text: (ecu_data.name, ecu_data.description),
-                        server,
-                        ecu_data: v,
-                        pattern,
-                        service_selector: ServiceSelector::new(
-                            read_functions,
-                            write_functions,
-                            actuation_functions,
-                        ),
-                        can_clear: false,
-                        log_view: LogView::new(),
-                        read_errors: Default::default(),
-                        clear_errors: Default::default(),
-                        execute_service: Default::default(),
-                        clear_log_btn: Default::default(),
-                        looping_service: None,
-                        looping_text: String::new(),
+                let variant_id = server
+                    .run_cmd(DiagService::ReadECUID.into(), &[0x87])
+                    .ok()
+                    .filter(|r| r.len() >= 6)
+                    .map(|r| (r[4] as u32) << 8 | (r[5] as u32));
+                let (v, pattern) = match find_variant(&ecu_data, variant_id) {
+                    Some(found) => found,
+                    None if !allow_variant_mismatch => {
+                        return Err(SessionError::VariantMismatch(match variant_id {
+                            Some(id) => format!(
+                                "ECU variant 0x{:04X} is not in the definition for {}. It may be for a different ECU",
+                                id, ecu_data.name
+                            ),
+                            None => format!(
+                                "ECU did not report its variant, so it could not be matched to the definition for {}",
+                                ecu_data.name
+                            ),
+                        }));
+                    }
+                    None => {
+                        let v = ecu_data.variants[0].clone();
+                        log_view.add_msg(
+                            format!(
+                                "ECU variant does not match the definition. Using variant {}, services may fail",
+                                v.name
+                            ),
+                            LogType::Warn,
+                        );
+                        let pattern = ECUVariantPattern {
+                            vendor: "Unknown".into(),
+                            vendor_id: variant_id.unwrap_or_default(),
+                        };
+                        (v, pattern)
+                    }
+                };
+                println!("ECU Variant: {} (Vendor: {})", v.name, pattern.vendor);
+
+                let read_functions: Vec<ServiceRef> = v
+                    .services
+                    .iter()
+                    .filter(|x| x.input_params.is_empty() && !x.output_params.is_empty())
+                    .cloned()
+                    .map(|service| ServiceRef {
+                        inner: RefCell::new(service),
                     })
-                } else {
-                    Err(SessionError::Other(format!(
-                        "Could not locate ECU variant in JSON - Its variant: {}",
-                        variant
-                    )))
-                }
+                    .collect();
+
+                let write_functions: Vec<ServiceRef> = v
+                    .services
+                    .iter()
+                    .filter(|x| !x.input_params.is_empty() && x.output_params.is_empty())
+                    .cloned()
+                    .map(|service| ServiceRef {
+                        inner: RefCell::new(service),
+                    })
+                    .collect();
+
+                let actuation_functions: Vec<ServiceRef> = v
+                    .services
+                    .iter()
+                    .filter(|x| !x.input_params.is_empty() && !x.output_params.is_empty())
+                    .cloned() // Maybe - Functions that only return yes/no?
+                    .map(|service| ServiceRef {
+                        inner: RefCell::new(service),
+                    })
+                    .collect();
+
+                Ok(Self {
+                    ecu,
+                    ecu_text: (ecu_data.name, ecu_data.description),
+                    server,
+                    ecu_data: v,
+                    pattern,
+                    service_selector: ServiceSelector::new(
+                        read_functions,
+                        write_functions,
+                        actuation_functions,
+                    ),
+                    can_clear: false,
+                    log_view,
+                    read_errors: Default::default(),
+                    probe_sessions: Default::default(),
+                    clear_errors: Default::default(),
+                    execute_service: Default::default(),
+                    clear_log_btn: Default::default(),
+                    looping_service: None,
+                    looping_text: String::new(),
+                    looping_resp: None,
+                })
             }
             Err(e) => {
                 eprintln!("Could not setup diag server");
@@ -174,6 +211,27 @@ impl JsonDiagSession {
     }
 }
 
+/// Finds the variant in the ECU definition matching the variant ID the ECU reported
+fn find_variant(
+    ecu_data: &OvdECU,
+    variant_id: Option<u32>,
+) -> Option<(ECUVariantDefinition, ECUVariantPattern)> {
+    let id = variant_id?;
+    ecu_data.variants.iter().find_map(|v| {
+        v.patterns
+            .iter()
+            .find(|p| p.vendor_id == id)
+            .map(|p| (v.clone(), p.clone()))
+    })
+}
+
+impl Drop for JsonDiagSession {
+    fn drop(&mut self) {
+        let name = format!("{}_{}", self.ecu_text.0, self.ecu_data.name);
+        auto_export_log(&mut self.log_view, &name);
+    }
+}
+
 impl SessionTrait for JsonDiagSession {
     type msg = JsonDiagSessionMsg;
 
@@ -192,6 +250,15 @@ impl SessionTrait for JsonDiagSession {
             )
         }
 
+        btn_view = btn_view.push(
+            button_outlined(
+                &mut self.probe_sessions,
+                "Probe supported sessions",
+                ButtonType::Secondary,
+            )
+            .on_press(JsonDiagSessionMsg::ProbeSessions),
+        );
+
         btn_view = btn_view.push(
             self.service_selector
                 .view()
@@ -199,6 +266,9 @@ impl SessionTrait for JsonDiagSession {
         );
         if self.looping_service.is_some() {
             btn_view = btn_view.push(text(&self.looping_text, TextType::Normal));
+            if let Some((resp, changed)) = &self.looping_resp {
+                btn_view = btn_view.push(byte_diff_view(resp, changed));
+            }
         }
         Column::new()
             .align_items(Align::Center)
@@ -241,8 +311,17 @@ impl SessionTrait for JsonDiagSession {
                                 .into_iter()
                                 .find(|x| x.error_name.ends_with(e.error.as_str()));
                             let err_txt = match desc {
-                                Some(d) => format!("{} - {}", e.error, d.description),
-                                None => format!("{} - Unknown description", e.error),
+                                Some(d) => format!(
+                                    "{} - {} ({})",
+                                    e.error,
+                                    d.description,
+                                    e.get_status_text()
+                                ),
+                                None => format!(
+                                    "{} - Unknown description ({})",
+                                    e.error,
+                                    e.get_status_text()
+                                ),
                             };
                             self.log_view.add_msg(err_txt, LogType::Warn)
                         }
@@ -260,7 +339,10 @@ impl SessionTrait for JsonDiagSession {
             JsonDiagSessionMsg::ClearErrors => {
                 self.can_clear = false;
                 match self.server.clear_errors() {
-                    Ok(_) => self.log_view.add_msg("Clear ECU Errors OK!", LogType::Info),
+                    Ok(_) => {
+                        self.log_view.add_msg("Clear ECU Errors OK!", LogType::Info);
+                        log_clear_verification(&mut self.log_view, self.server.read_errors());
+                    }
                     Err(e) => self.log_view.add_msg(
                         format!("Error clearing ECU Errors: {}", e.get_text()),
                         LogType::Error,
@@ -268,10 +350,18 @@ impl SessionTrait for JsonDiagSession {
                 }
             }
 
+            JsonDiagSessionMsg::ProbeSessions => {
+                let sessions = self.server.probe_sessions();
+                log_session_support(&mut self.log_view, &sessions);
+            }
             JsonDiagSessionMsg::Selector(s) => match s {
-                SelectorMsg::PickLoopService(l) => self.looping_service = Some(l.clone()),
+                SelectorMsg::PickLoopService(l) => {
+                    self.looping_service = Some(l.clone());
+                    self.looping_resp = None;
+                }
                 SelectorMsg::StopLoopService => {
                     self.looping_service = None;
+                    self.looping_resp = None;
                     return self.service_selector.update(s);
                 }
                 _ => return self.service_selector.update(s),
@@ -279,6 +369,31 @@ impl SessionTrait for JsonDiagSession {
 
             JsonDiagSessionMsg::ExecuteService(s, args) => {
                 println!("Exec {}", s.inner.borrow().name);
+                let preconditions = s.inner.borrow().preconditions.clone();
+                if let Some(level) = preconditions.security_level {
+                    self.log_view.add_msg(
+                        format!(
+                            "{} requires security access level {}. The ECU will deny it if it is not unlocked",
+                            s.inner.borrow().name,
+                            level
+                        ),
+                        LogType::Warn,
+                    );
+                }
+                if let Some(session) = preconditions.session {
+                    if let Err(e) = self.server.enter_session(session) {
+                        self.log_view.add_msg(
+                            format!(
+                                "Could not enter the {:?} session required by {}: {}",
+                                session,
+                                s.inner.borrow().name,
+                                e.get_text()
+                            ),
+                            LogType::Error,
+                        );
+                        return None;
+                    }
+                }
                 match s.exec(args, &mut self.server) {
                     Ok(res) => self.log_view.add_log(
                         format!(
@@ -304,7 +419,12 @@ impl SessionTrait for JsonDiagSession {
                             s.inner.borrow().name,
                             s.inner.borrow().description,
                             s.args_to_string(&res)
-                        )
+                        );
+                        let changed = match &self.looping_resp {
+                            Some((prev, _)) => changed_bytes(prev, &res),
+                            None => vec![false; res.len()],
+                        };
+                        self.looping_resp = Some((res, changed));
                     }
                 }
             }
@@ -322,6 +442,37 @@ impl SessionTrait for JsonDiagSession {
     }
 }
 
+/// Returns which bytes of `curr` differ from `prev`. Bytes past the end of `prev` count as changed
+fn changed_bytes(prev: &[u8], curr: &[u8]) -> Vec<bool> {
+    curr.iter()
+        .enumerate()
+        .map(|(i, b)| prev.get(i) != Some(b))
+        .collect()
+}
+
+/// Shows a response in hex, 16 bytes per row, with the bytes that changed since the last read highlighted
+fn byte_diff_view<'a>(resp: &[u8], changed: &[bool]) -> iced::Element<'a, JsonDiagSessionMsg> {
+    let mut c = Column::new().spacing(2);
+    for (row_idx, row) in resp.chunks(16).enumerate() {
+        let mut r = Row::new().spacing(4);
+        for (i, b) in row.iter().enumerate() {
+            let txt_type = if changed.get(row_idx * 16 + i) == Some(&true) {
+                TextType::Warning
+            } else {
+                TextType::Normal
+            };
+            r = r.push(text(format!("{:02X}", b).as_str(), txt_type));
+        }
+        c = c.push(r);
+    }
+    let count = changed.iter().filter(|c| **c).count();
+    c.push(text(
+        format!("{} bytes changed since the last read", count).as_str(),
+        TextType::Normal,
+    ))
+    .into()
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct ServiceRef {
     // Read data from ECU
@@ -524,6 +675,13 @@ impl ServiceSelector {
                 format!("Description: {}", curr_service.inner.borrow().description).as_str(),
                 TextType::Normal,
             ));
+            let preconditions = curr_service.inner.borrow().preconditions.clone();
+            if !preconditions.is_empty() {
+                content_view = content_view.push(text(
+                    format!("Requires: {}", preconditions).as_str(),
+                    TextType::Warning,
+                ));
+            }
 
             if self.input_require {
                 for x in &curr_service.inner.borrow().input_params {
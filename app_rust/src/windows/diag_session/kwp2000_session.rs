@@ -6,43 +6,195 @@ use std::{
     time::Instant,
 };
 
-use iced::{time, Column, Container, Length, Row, Space, Subscription};
+use common::schema::diag::service::Parameter;
+use iced::{time, Align, Checkbox, Column, Container, Length, Row, Space, Subscription};
 use log_view::{LogType, LogView};
 
 use crate::{
     commapi::{
         comm_api::{ComServer, ISO15765Config},
-        protocols::{kwp2000::KWP2000ECU, ProtocolServer},
+        iso_tp::preview_frames,
+        mock_api::SIMULATION_API_NAME,
+        protocols::{
+            kwp2000::{
+                access_timing_parameter::TimingParameters,
+                get_service_requirement,
+                routine_control::RoutineResult,
+                security_access::KeyAlgorithmParams,
+                start_diag_session::{probe_sessions, DiagSession},
+                ConnectionEvent, Service, KWP2000ECU,
+            },
+            vin::{read_vin, KWP2000_VIN_METHODS},
+            wake_up::WakeUpInit,
+            Addressing, ProtocolError, ProtocolServer, SessionSupport, DTC,
+        },
+    },
+    settings::{get_settings, save_ecu_profile, set_settings, ECUProfile, PayloadPreset},
+    themes::{
+        button_outlined, help_box, picklist, text, text_input, title_text, ButtonType, TextType,
+        TitleSize,
+    },
+    windows::{
+        diag_manual::DiagManualMessage,
+        file_dialog::{open_file, FileType},
+        hw_task,
+        window::{self, WindowMessage},
     },
-    themes::{button_outlined, text, text_input, title_text, ButtonType, TextType, TitleSize},
-    windows::{diag_manual::DiagManualMessage, window},
 };
 
-use super::{log_view, DiagMessageTrait, SessionMsg, SessionResult, SessionTrait};
+use super::{
+    alert_beep, auto_export_log, find_bitrate_hint, find_new_dtcs,
+    help::{self, with_help},
+    log_clear_verification, log_session_support,
+    log_view::{self, decode_exchange, raw_response},
+    to_window_msg, wake_up_ecu, DiagMessageTrait, SessionMsg, SessionResult, SessionTrait,
+};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone)]
 pub enum KWP2000DiagSessionMsg {
     ConnectECU,
+    /// Log lines of the connection attempt, and the ECU if it could be connected to
+    Connected(Vec<(String, LogType)>, Option<KWP2000ECU>),
     DisconnectECU,
     Back,
     PollServer(Instant),
     LoadErrorDefinition,
     ClearLogs,
     ClearErrors,
+    ErrorsCleared(Result<(), String>),
+    ClearVerified(Result<Vec<DTC>, String>),
     ReadCodes,
+    CodesRead(Result<Vec<DTC>, String>),
+    ProbeSessions,
+    SessionsProbed(Vec<SessionSupport>),
+    ReadVIN,
+    VINRead(Result<String, String>),
+    ReadTiming,
+    EnterTiming(String),
+    SetTiming,
+    /// Timing parameters read from, or set on the ECU
+    TimingUpdated(Result<TimingParameters, String>),
     SendPayload,
+    PreviewFrames,
+    /// Log entries of the payloads sent, and the number of payloads not sent because
+    /// the sequence was stopped by an unexpected error
+    PayloadsSent(Vec<PayloadLog>, usize),
     EnterPayload(String),
+    LoadRoutineLayout,
+    PresetSelected(PayloadPreset),
+    EnterPresetName(String),
+    SavePreset,
+    ToggleSuppressResponse(bool),
+    ToggleRequirementWarnings(bool),
+    /// Addressing for payloads that do not specify one
+    AddressingSelected(Addressing),
+    /// Addressing for the tester present messages that keep the session alive
+    TesterPresentAddressingSelected(Addressing),
+    ToggleHelp(bool),
+    ToggleDecodedLog(bool),
+    ToggleMonitoring(bool),
+    MonitorTick(Instant),
+    MonitorRead(Result<Vec<DTC>, String>),
+    DismissAlert,
+    EnterLoopInterval(String),
+    /// Starts sending the payload repeatedly, or stops it if it is already being sent
+    ToggleLoopSend,
+    ToggleLoopChangesOnly(bool),
+    LoopTick(Instant),
+    /// Same as [PayloadsSent](KWP2000DiagSessionMsg::PayloadsSent), for one send of the loop
+    LoopSent(Vec<PayloadLog>, usize),
+    EnterSeed(String),
+    EnterKeyAlgorithm(String),
+    /// Use the entered key algorithm to restore security access after a reconnect
+    UseKeyAlgorithm,
+}
+
+/// Shortest interval payloads can be loop sent at in milliseconds
+const MIN_LOOP_INTERVAL_MS: u64 = 50;
+
+/// Log entry for a sent payload. Request, response, decoded form and log type
+pub type PayloadLog = (String, String, Vec<String>, LogType);
+
+/// A payload to send as part of a sequence, written as the hex payload followed by any
+/// negative response codes that are expected, each after a '/'. EG: '1A86/11/12'.
+/// The payload can start with 'F:' or 'P:' to send it functionally or physically, rather
+/// than with the session's default addressing. EG: 'F:3E01'
+#[derive(Debug, Clone)]
+struct PayloadStep {
+    payload: Vec<u8>,
+    /// Addressing to send the payload with. None uses the session's default
+    addressing: Option<Addressing>,
+    /// Negative responses that do not count as a failure, EG: When probing if a service is supported
+    accepted_nrcs: Vec<u8>,
+}
+
+impl PayloadStep {
+    /// Parses a payload step, returning a message saying what is wrong with it if it is invalid
+    fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let (addressing, body) = match s.get(..2).map(|p| p.to_uppercase()).as_deref() {
+            Some("F:") => (Some(Addressing::Functional), &s[2..]),
+            Some("P:") => (Some(Addressing::Physical), &s[2..]),
+            _ => (None, s),
+        };
+        let mut parts = body.split('/');
+        let payload = hex::decode(Self::normalize_hex(parts.next().unwrap_or_default()))
+            .map_err(|_| format!("Invalid payload '{}': not valid hex", s))?;
+        if payload.len() < 2 {
+            return Err(format!(
+                "Invalid payload '{}': payload must be at least 2 bytes",
+                s
+            ));
+        }
+        let accepted_nrcs = parts
+            .map(|nrc| {
+                u8::from_str_radix(&Self::normalize_hex(nrc), 16).map_err(|_| {
+                    format!(
+                        "Invalid payload '{}': '{}' is not a valid negative response code",
+                        s,
+                        nrc.trim()
+                    )
+                })
+            })
+            .collect::<Result<Vec<u8>, String>>()?;
+        Ok(Self {
+            payload,
+            addressing,
+            accepted_nrcs,
+        })
+    }
+
+    /// Removes whitespace and any '0x' prefixes from hex, so '0x10 0x03' is read as '1003'
+    fn normalize_hex(s: &str) -> String {
+        s.split_whitespace()
+            .map(|x| {
+                x.strip_prefix("0x")
+                    .or_else(|| x.strip_prefix("0X"))
+                    .unwrap_or(x)
+            })
+            .collect()
+    }
+
+    /// Returns true if the error is a negative response this step expects
+    fn is_expected(&self, err: &ProtocolError) -> bool {
+        match err {
+            ProtocolError::NegativeResponse { nrc, .. } => self.accepted_nrcs.contains(nrc),
+            _ => false,
+        }
+    }
 }
 
 impl DiagMessageTrait for KWP2000DiagSessionMsg {
     fn is_back(&self) -> bool {
-        self == &KWP2000DiagSessionMsg::Back
+        matches!(self, KWP2000DiagSessionMsg::Back)
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct KWP2000DiagSession {
     ecu: ISO15765Config,
+    /// Run before every connection to the ECU
+    wake_up: Option<WakeUpInit>,
     server: Box<dyn ComServer>,
     connect_btn: iced::button::State,
     disconnect_btn: iced::button::State,
@@ -50,33 +202,425 @@ pub struct KWP2000DiagSession {
     can_clear_codes: bool,
     clear_btn: iced::button::State,
     read_codes_btn: iced::button::State,
+    probe_sessions_btn: iced::button::State,
+    read_vin_btn: iced::button::State,
+    read_timing_btn: iced::button::State,
+    set_timing_btn: iced::button::State,
+    timing_input: iced::text_input::State,
+    /// Timing parameters entered by the user, as 'P2min,P2max,P3min,P3max,P4min'
+    timing_string: String,
+    /// Diagnostic sessions the ECU supports. None until probed
+    supported_sessions: Option<Vec<SessionSupport>>,
     diag_server: Option<KWP2000ECU>,
     payload_string: String,
     payload_send_btn: iced::button::State,
+    preview_btn: iced::button::State,
     payload_input: iced::text_input::State,
     can_send: bool,
     logview: LogView,
+    load_layout_btn: iced::button::State,
+    /// Layout used to decode routine results. Empty if no layout is loaded
+    routine_layout: Vec<Parameter>,
+    presets: Vec<PayloadPreset>,
+    selected_preset: Option<PayloadPreset>,
+    preset_list: iced::pick_list::State<PayloadPreset>,
+    preset_name: String,
+    preset_name_input: iced::text_input::State,
+    preset_save_btn: iced::button::State,
+    /// Send payloads with the suppressPosRspMsgIndicationBit set
+    suppress_response: bool,
+    /// Warn before sending payloads the ECU will probably reject in its current session
+    warn_requirements: bool,
+    /// Addressing for payloads that do not specify one
+    default_addressing: Addressing,
+    addressing_list: iced::pick_list::State<Addressing>,
+    /// Addressing for tester present messages
+    tester_present_addressing: Addressing,
+    tester_present_addressing_list: iced::pick_list::State<Addressing>,
+    /// Saved settings of the ECU, updated once they are known to work. None for manual ISO-TP settings
+    profile: Option<ECUProfile>,
+    /// Timing parameters being set were entered by the user, rather than read from the ECU
+    setting_timing: bool,
+    /// Show help text below each control
+    show_help: bool,
+    /// A hardware operation is running, so no more can be started until it completes
+    busy: bool,
+    /// Periodically re-read error codes, alerting the user to new ones
+    monitoring: bool,
+    /// Error codes found by the last monitor read. None until the first read completes
+    monitored_dtcs: Option<Vec<DTC>>,
+    /// Banner text for new error codes found whilst monitoring
+    new_dtc_alert: Option<String>,
+    dismiss_alert_btn: iced::button::State,
+    /// Payloads being sent repeatedly. None if loop send is stopped
+    loop_payloads: Option<Vec<PayloadStep>>,
+    loop_interval_string: String,
+    /// Interval the running loop send was started with
+    loop_interval_ms: u64,
+    loop_interval_input: iced::text_input::State,
+    loop_btn: iced::button::State,
+    /// Only log loop sends whose responses differ from the send before
+    loop_changes_only: bool,
+    /// Responses to the last loop send
+    loop_last_responses: Option<Vec<String>>,
+    /// Seed and key algorithm entered in the key calculator
+    key_seed_string: String,
+    key_seed_input: iced::text_input::State,
+    key_algo_string: String,
+    key_algo_input: iced::text_input::State,
+    use_key_algo_btn: iced::button::State,
 }
 
 impl KWP2000DiagSession {
-    pub fn new(comm_server: Box<dyn ComServer>, ecu: ISO15765Config) -> SessionResult<Self> {
+    pub fn new(
+        comm_server: Box<dyn ComServer>,
+        ecu: ISO15765Config,
+        wake_up: Option<WakeUpInit>,
+        profile: Option<ECUProfile>,
+    ) -> SessionResult<Self> {
+        let mut logview = LogView::new();
+        if comm_server.get_api() == SIMULATION_API_NAME {
+            logview.add_msg(
+                "SIMULATED SESSION - No real ECU is connected",
+                LogType::Warn,
+            );
+        }
         Ok(Self {
             ecu,
+            wake_up,
             server: comm_server,
             connect_btn: Default::default(),
             disconnect_btn: Default::default(),
             back_btn: Default::default(),
             diag_server: None,
-            logview: LogView::new(),
+            logview,
             can_clear_codes: false,
             clear_btn: Default::default(),
             read_codes_btn: Default::default(),
+            probe_sessions_btn: Default::default(),
+            read_vin_btn: Default::default(),
+            read_timing_btn: Default::default(),
+            set_timing_btn: Default::default(),
+            timing_input: Default::default(),
+            timing_string: String::new(),
+            supported_sessions: None,
             payload_string: String::new(),
             payload_send_btn: Default::default(),
+            preview_btn: Default::default(),
             payload_input: Default::default(),
             can_send: false,
+            load_layout_btn: Default::default(),
+            routine_layout: Vec::new(),
+            presets: get_settings().payload_presets,
+            selected_preset: None,
+            preset_list: Default::default(),
+            preset_name: String::new(),
+            preset_name_input: Default::default(),
+            preset_save_btn: Default::default(),
+            suppress_response: false,
+            warn_requirements: true,
+            default_addressing: profile.as_ref().map(|p| p.addressing).unwrap_or_default(),
+            addressing_list: Default::default(),
+            tester_present_addressing: profile
+                .as_ref()
+                .map(|p| p.tester_present_addressing)
+                .unwrap_or_default(),
+            tester_present_addressing_list: Default::default(),
+            profile,
+            setting_timing: false,
+            show_help: false,
+            busy: false,
+            monitoring: false,
+            monitored_dtcs: None,
+            new_dtc_alert: None,
+            dismiss_alert_btn: Default::default(),
+            loop_payloads: None,
+            loop_interval_string: "1000".into(),
+            loop_interval_ms: 1000,
+            loop_interval_input: Default::default(),
+            loop_btn: Default::default(),
+            loop_changes_only: false,
+            loop_last_responses: None,
+            key_seed_string: String::new(),
+            key_seed_input: Default::default(),
+            key_algo_string: String::new(),
+            key_algo_input: Default::default(),
+            use_key_algo_btn: Default::default(),
         })
     }
+
+    /// Sets the timing parameters saved in the ECU's profile (If there are any),
+    /// returning the log line saying if they were restored
+    fn restore_timing(server: &KWP2000ECU, timing: Option<&str>) -> Option<(String, LogType)> {
+        let timing = timing?;
+        let log = match TimingParameters::parse(timing) {
+            Some(t) => match server.set_timing_parameters(t) {
+                Ok(_) => (
+                    format!("Restored saved timing parameters ({})", t),
+                    LogType::Info,
+                ),
+                Err(e) => (
+                    format!("Error restoring saved timing parameters: {}", e.get_text()),
+                    LogType::Warn,
+                ),
+            },
+            None => (
+                format!(
+                    "Saved timing parameters '{}' are invalid, ignoring them",
+                    timing
+                ),
+                LogType::Warn,
+            ),
+        };
+        Some(log)
+    }
+
+    /// Saves the settings the ECU is connected with, so they are restored when it is next picked
+    fn save_profile(&mut self) {
+        let profile = match self.profile.as_mut() {
+            Some(p) => p,
+            None => return,
+        };
+        profile.addressing = self.default_addressing;
+        profile.tester_present_addressing = self.tester_present_addressing;
+        if let Err(e) = save_ecu_profile(profile.clone()) {
+            self.logview
+                .add_msg(format!("Error saving ECU profile: {}", e), LogType::Warn)
+        }
+    }
+
+    /// Computes the key for a seed entered in the key calculator, without sending it
+    fn calc_key(seed: &str, algo: &str) -> Result<Vec<u8>, String> {
+        let params = KeyAlgorithmParams::parse(algo)?;
+        let seed = hex::decode(PayloadStep::normalize_hex(seed))
+            .map_err(|_| "Seed is not valid hex".to_string())?;
+        params.compute(&seed).ok_or_else(|| {
+            format!(
+                "Seed must be 1-{} bytes long",
+                KeyAlgorithmParams::MAX_SEED_LEN
+            )
+        })
+    }
+
+    /// Starts sending the entered payloads repeatedly at the entered interval
+    fn start_loop_send(&mut self) {
+        let interval = match self.loop_interval_string.trim().parse::<u64>() {
+            Ok(i) if i >= MIN_LOOP_INTERVAL_MS => i,
+            _ => {
+                self.logview.add_msg(
+                    format!(
+                        "Invalid loop interval: must be at least {}ms",
+                        MIN_LOOP_INTERVAL_MS
+                    ),
+                    LogType::Error,
+                );
+                return;
+            }
+        };
+        let payloads = match Self::get_payloads(&self.payload_string) {
+            Ok(p) => p,
+            Err(e) => {
+                self.logview.add_msg(e, LogType::Error);
+                return;
+            }
+        };
+        if let (true, Some(server)) = (self.warn_requirements, &self.diag_server) {
+            for w in Self::get_requirement_warnings(server, &payloads) {
+                self.logview.add_msg(w, LogType::Warn);
+            }
+        }
+        self.logview.add_msg(
+            format!(
+                "Started sending '{}' every {}ms",
+                self.payload_string.trim(),
+                interval
+            ),
+            LogType::Info,
+        );
+        self.loop_payloads = Some(payloads);
+        self.loop_interval_ms = interval;
+        self.loop_last_responses = None;
+    }
+
+    /// Stops loop sending, if it is running. `cause` is logged if the loop did not stop by request
+    fn stop_loop_send(&mut self, cause: Option<&str>) {
+        if self.loop_payloads.take().is_none() {
+            return;
+        }
+        self.loop_last_responses = None;
+        match cause {
+            Some(c) => self
+                .logview
+                .add_msg(format!("Loop send stopped by {}", c), LogType::Warn),
+            None => self.logview.add_msg("Stopped loop send", LogType::Info),
+        }
+    }
+
+    /// Notifies the user of new error codes found whilst monitoring
+    fn alert_new_dtcs(&mut self, new_dtcs: &[DTC]) {
+        for x in new_dtcs {
+            self.logview.add_msg(
+                format!("New error code: {} ({})", x.error, x.get_status_text()),
+                LogType::Warn,
+            );
+        }
+        let settings = get_settings();
+        if settings.dtc_alert_banner {
+            let codes: Vec<&str> = new_dtcs.iter().map(|x| x.error.as_str()).collect();
+            self.new_dtc_alert = Some(format!(
+                "{} new error code(s) appeared: {}",
+                new_dtcs.len(),
+                codes.join(", ")
+            ));
+        }
+        if settings.dtc_alert_beep {
+            alert_beep();
+        }
+    }
+
+    /// Name exported logs are saved under
+    fn get_log_name(&self) -> String {
+        format!("KWP2000_{:X}", self.ecu.send_id)
+    }
+
+    /// Message delivering the result of a hardware operation back to this session
+    fn task_msg(msg: KWP2000DiagSessionMsg) -> WindowMessage {
+        to_window_msg(SessionMsg::KWP(msg))
+    }
+
+    /// Sends a single payload to the ECU, returning its log entry.
+    /// Ran off the UI thread, as the ECU may take a while to respond
+    fn send_payload(
+        server: &KWP2000ECU,
+        step: &PayloadStep,
+        suppress_response: bool,
+        routine_layout: &[Parameter],
+    ) -> PayloadLog {
+        let r = &step.payload;
+        let addressing = step
+            .addressing
+            .unwrap_or_else(|| server.get_default_addressing());
+        let req = match addressing {
+            Addressing::Physical => format!("Req:  {:02X?}", r),
+            Addressing::Functional => format!("Req (Functional):  {:02X?}", r),
+        };
+        if suppress_response {
+            let res = server.run_command_suppress_response_addressed(r[0], &r[1..], addressing);
+            return match res {
+                Ok(_) => (
+                    req,
+                    "Resp: None (Positive response suppressed)".into(),
+                    Vec::new(),
+                    LogType::Info,
+                ),
+                Err(e) => (
+                    req,
+                    format!("Exec error: {}", e.get_text()),
+                    Vec::new(),
+                    LogType::Error,
+                ),
+            };
+        }
+        let res = server.run_command_addressed(r[0], &r[1..], addressing);
+        let mut decoded = decode_exchange::<Service>(r, &res);
+        let mut resp_text = raw_response(r, &res);
+        match &res {
+            // Routine started, or its results. Show labeled fields if the layout is known
+            Ok(res) if r[0] == 0x31 || r[0] == 0x33 => {
+                let result = RoutineResult::decode(res.clone(), routine_layout);
+                let label = match r[0] {
+                    0x31 => "Routine started",
+                    _ => "Routine result",
+                };
+                resp_text = format!("{}: {}", label, result.to_display_string());
+            }
+            Ok(res) if r[0] == 0x18 && r.get(1) == Some(&0x02) => {
+                match KWP2000ECU::decode_dtcs(res) {
+                    Ok(dtcs) => {
+                        decoded.push(format!("{} DTCs", dtcs.len()));
+                        decoded.extend(
+                            dtcs.iter()
+                                .map(|x| format!("{} ({})", x.error, x.get_status_text())),
+                        );
+                    }
+                    Err(e) => decoded.push(format!("Invalid DTC list: {}", e)),
+                }
+            }
+            _ => {}
+        }
+        let ltype = match &res {
+            Ok(_) => LogType::Info,
+            Err(e) if step.is_expected(e) => {
+                resp_text = format!("{} (Expected negative)", resp_text);
+                LogType::Info
+            }
+            Err(_) => LogType::Error,
+        };
+        (req, resp_text, decoded, ltype)
+    }
+
+    /// Sends each payload in order, stopping at the first one that fails unexpectedly, as
+    /// later payloads may depend on it (EG: Entering a session before using a service).
+    /// Returns the log entries, and the number of payloads not sent
+    fn send_sequence(
+        server: &KWP2000ECU,
+        steps: &[PayloadStep],
+        suppress_response: bool,
+        routine_layout: &[Parameter],
+    ) -> (Vec<PayloadLog>, usize) {
+        let mut logs = Vec::new();
+        for step in steps {
+            let log = Self::send_payload(server, step, suppress_response, routine_layout);
+            let failed = log.3 == LogType::Error;
+            logs.push(log);
+            if failed {
+                break;
+            }
+        }
+        let not_sent = steps.len() - logs.len();
+        (logs, not_sent)
+    }
+
+    /// Returns a warning for each payload that typically needs a session or security access
+    /// the ECU will not be in when it is sent. Earlier payloads in the sequence are taken
+    /// into account, EG: '2701,2702AABB,3B0101' does not warn about security access for 3B
+    fn get_requirement_warnings(server: &KWP2000ECU, steps: &[PayloadStep]) -> Vec<String> {
+        let mut extended = server.get_session_type() != DiagSession::Default;
+        let mut unlocked = server.is_security_granted();
+        let mut warnings = Vec::new();
+        for step in steps {
+            let p = &step.payload;
+            let req = get_service_requirement(p[0]);
+            let mut missing = Vec::new();
+            if req.extended_session && !extended {
+                missing.push("an extended diagnostic session");
+            }
+            if req.security_access && !unlocked {
+                missing.push("security access");
+            }
+            if !missing.is_empty() {
+                warnings.push(format!(
+                    "{:02X?} usually needs {}. The ECU may reply with a negative response",
+                    p,
+                    missing.join(" and ")
+                ));
+            }
+            match (p[0], p[1]) {
+                (0x10, mode) => {
+                    extended = mode != DiagSession::Default as u8;
+                    unlocked = false;
+                }
+                (0x27, level) if level % 2 == 0 => unlocked = true,
+                _ => {}
+            }
+        }
+        warnings
+    }
+
+    /// Splits the payload input into each payload to send. Multiple payloads are separated by ','
+    fn get_payloads(s: &str) -> Result<Vec<PayloadStep>, String> {
+        s.split(',').map(PayloadStep::parse).collect()
+    }
 }
 
 impl SessionTrait for KWP2000DiagSession {
@@ -84,6 +628,17 @@ impl SessionTrait for KWP2000DiagSession {
 
     fn view(&mut self) -> iced::Element<Self::msg> {
         let mut ui = Column::new().push(title_text("KWP2000 diagnostic session", TitleSize::P3));
+        if let Some(alert) = &self.new_dtc_alert {
+            ui = ui.push(
+                Row::new()
+                    .spacing(5)
+                    .push(help_box(alert, ButtonType::Danger).width(Length::Fill))
+                    .push(
+                        button_outlined(&mut self.dismiss_alert_btn, "Dismiss", ButtonType::Danger)
+                            .on_press(KWP2000DiagSessionMsg::DismissAlert),
+                    ),
+            );
+        }
 
         let in_session = if let Some(ref s) = self.diag_server {
             s.is_in_diag_session()
@@ -99,39 +654,121 @@ impl SessionTrait for KWP2000DiagSession {
             )
             .on_press(KWP2000DiagSessionMsg::DisconnectECU)
         } else {
-            button_outlined(&mut self.disconnect_btn, "Connect ECU", ButtonType::Primary)
-                .on_press(KWP2000DiagSessionMsg::ConnectECU)
+            let btn = button_outlined(&mut self.disconnect_btn, "Connect ECU", ButtonType::Primary);
+            if self.busy {
+                btn
+            } else {
+                btn.on_press(KWP2000DiagSessionMsg::ConnectECU)
+            }
         };
 
         ui = ui.push(display_btn);
 
         if !in_session {
-            ui = ui.push(
-                button_outlined(&mut self.back_btn, "Back", ButtonType::Secondary)
-                    .on_press(KWP2000DiagSessionMsg::Back),
-            )
+            let mut back_btn = button_outlined(&mut self.back_btn, "Back", ButtonType::Secondary);
+            if !self.busy {
+                back_btn = back_btn.on_press(KWP2000DiagSessionMsg::Back);
+            }
+            ui = ui.push(back_btn)
         } else {
-            ui = ui.push(
-                button_outlined(
-                    &mut self.read_codes_btn,
-                    "Read error codes",
-                    ButtonType::Secondary,
-                )
-                .on_press(KWP2000DiagSessionMsg::ReadCodes),
+            let mut read_btn = button_outlined(
+                &mut self.read_codes_btn,
+                "Read error codes",
+                ButtonType::Secondary,
             );
+            if !self.busy {
+                read_btn = read_btn.on_press(KWP2000DiagSessionMsg::ReadCodes);
+            }
+            ui = ui.push(read_btn);
+            ui = with_help(ui, self.show_help, help::READ_CODES, ButtonType::Info);
+            ui = ui.push(Checkbox::new(
+                self.monitoring,
+                "Monitor error codes (Alerts on new codes)",
+                KWP2000DiagSessionMsg::ToggleMonitoring,
+            ));
+            ui = with_help(ui, self.show_help, help::MONITOR_CODES, ButtonType::Info);
             if self.can_clear_codes {
-                ui = ui.push(
-                    button_outlined(
-                        &mut self.clear_btn,
-                        "Clear error codes",
-                        ButtonType::Secondary,
-                    )
-                    .on_press(KWP2000DiagSessionMsg::ClearErrors),
+                let mut clear_btn = button_outlined(
+                    &mut self.clear_btn,
+                    "Clear error codes",
+                    ButtonType::Secondary,
                 );
+                if !self.busy {
+                    clear_btn = clear_btn.on_press(KWP2000DiagSessionMsg::ClearErrors);
+                }
+                ui = ui.push(clear_btn);
+                ui = with_help(ui, self.show_help, help::CLEAR_CODES, ButtonType::Warning);
+            }
+            let mut probe_btn = button_outlined(
+                &mut self.probe_sessions_btn,
+                "Probe supported sessions",
+                ButtonType::Secondary,
+            );
+            if !self.busy {
+                probe_btn = probe_btn.on_press(KWP2000DiagSessionMsg::ProbeSessions);
             }
+            ui = ui.push(probe_btn);
+            ui = with_help(ui, self.show_help, help::PROBE_SESSIONS, ButtonType::Info);
+            let mut vin_btn =
+                button_outlined(&mut self.read_vin_btn, "Read VIN", ButtonType::Secondary);
+            if !self.busy {
+                vin_btn = vin_btn.on_press(KWP2000DiagSessionMsg::ReadVIN);
+            }
+            ui = ui.push(vin_btn);
+            ui = with_help(ui, self.show_help, help::READ_VIN, ButtonType::Info);
+
+            // Timing parameters
+            ui = ui.push(text(
+                "Timing parameters (P2min,P2max,P3min,P3max,P4min in ms)",
+                TextType::Normal,
+            ));
+            let mut read_timing_btn = button_outlined(
+                &mut self.read_timing_btn,
+                "Read timing",
+                ButtonType::Secondary,
+            );
+            let mut set_timing_btn =
+                button_outlined(&mut self.set_timing_btn, "Set timing", ButtonType::Warning);
+            if !self.busy {
+                read_timing_btn = read_timing_btn.on_press(KWP2000DiagSessionMsg::ReadTiming);
+                if TimingParameters::parse(&self.timing_string).is_some() {
+                    set_timing_btn = set_timing_btn.on_press(KWP2000DiagSessionMsg::SetTiming);
+                }
+            }
+            ui = ui.push(
+                Row::new()
+                    .spacing(5)
+                    .push(text_input(
+                        &mut self.timing_input,
+                        "",
+                        &self.timing_string,
+                        KWP2000DiagSessionMsg::EnterTiming,
+                    ))
+                    .push(read_timing_btn)
+                    .push(set_timing_btn),
+            );
+            ui = with_help(
+                ui,
+                self.show_help,
+                help::TIMING_PARAMETERS,
+                ButtonType::Warning,
+            );
 
             // Payload input
-            ui = ui.push(text("Enter payload (Hex string)", TextType::Normal));
+            if !self.presets.is_empty() {
+                ui = ui.push(picklist(
+                    &mut self.preset_list,
+                    &self.presets[..],
+                    self.selected_preset.clone(),
+                    KWP2000DiagSessionMsg::PresetSelected,
+                ));
+            }
+            ui = ui.push(text(
+                "Enter payload (Hex string, separate multiple payloads with ',', \
+                add /NRC to expect a negative response, start with F: or P: to \
+                send functionally or physically)",
+                TextType::Normal,
+            ));
             ui = ui.push(text_input(
                 &mut self.payload_input,
                 "",
@@ -143,17 +780,194 @@ impl SessionTrait for KWP2000DiagSession {
                 "Send payload",
                 ButtonType::Warning,
             );
-            if self.can_send {
+            // Invalid payloads can still be sent, so the user is told what is wrong with them
+            if !self.payload_string.trim().is_empty() && !self.busy && self.loop_payloads.is_none()
+            {
                 btn = btn.on_press(KWP2000DiagSessionMsg::SendPayload);
             }
-            ui = ui.push(btn);
+            let mut preview_btn =
+                button_outlined(&mut self.preview_btn, "Preview frames", ButtonType::Info);
+            if self.can_send {
+                preview_btn = preview_btn.on_press(KWP2000DiagSessionMsg::PreviewFrames);
+            }
+            ui = ui.push(Row::new().spacing(5).push(btn).push(preview_btn));
+            ui = with_help(ui, self.show_help, help::SEND_PAYLOAD, ButtonType::Danger);
+            ui = with_help(ui, self.show_help, help::PREVIEW_FRAMES, ButtonType::Info);
+            let looping = self.loop_payloads.is_some();
+            let mut loop_btn = button_outlined(
+                &mut self.loop_btn,
+                if looping {
+                    "Stop loop send"
+                } else {
+                    "Start loop send"
+                },
+                ButtonType::Warning,
+            );
+            if looping || !self.payload_string.trim().is_empty() {
+                loop_btn = loop_btn.on_press(KWP2000DiagSessionMsg::ToggleLoopSend);
+            }
+            ui = ui.push(
+                Row::new()
+                    .spacing(5)
+                    .align_items(Align::Center)
+                    .push(text("Interval (ms)", TextType::Normal))
+                    .push(
+                        text_input(
+                            &mut self.loop_interval_input,
+                            "1000",
+                            &self.loop_interval_string,
+                            KWP2000DiagSessionMsg::EnterLoopInterval,
+                        )
+                        .width(Length::Units(100)),
+                    )
+                    .push(loop_btn)
+                    .push(Checkbox::new(
+                        self.loop_changes_only,
+                        "Only log changed responses",
+                        KWP2000DiagSessionMsg::ToggleLoopChangesOnly,
+                    )),
+            );
+            ui = with_help(ui, self.show_help, help::LOOP_SEND, ButtonType::Warning);
+            ui = ui.push(Checkbox::new(
+                self.suppress_response,
+                "Suppress positive response (ECU will not reply on success)",
+                KWP2000DiagSessionMsg::ToggleSuppressResponse,
+            ));
+            ui = with_help(
+                ui,
+                self.show_help,
+                help::SUPPRESS_RESPONSE,
+                ButtonType::Info,
+            );
+            ui = ui.push(Checkbox::new(
+                self.warn_requirements,
+                "Warn about session / security access requirements",
+                KWP2000DiagSessionMsg::ToggleRequirementWarnings,
+            ));
+            ui = with_help(
+                ui,
+                self.show_help,
+                help::REQUIREMENT_WARNINGS,
+                ButtonType::Info,
+            );
+            ui = ui.push(
+                Row::new()
+                    .spacing(5)
+                    .align_items(Align::Center)
+                    .push(text("Send payloads", TextType::Normal))
+                    .push(picklist(
+                        &mut self.addressing_list,
+                        &Addressing::ALL[..],
+                        Some(self.default_addressing),
+                        KWP2000DiagSessionMsg::AddressingSelected,
+                    ))
+                    .push(text("Send tester present", TextType::Normal))
+                    .push(picklist(
+                        &mut self.tester_present_addressing_list,
+                        &Addressing::ALL[..],
+                        Some(self.tester_present_addressing),
+                        KWP2000DiagSessionMsg::TesterPresentAddressingSelected,
+                    )),
+            );
+            ui = with_help(ui, self.show_help, help::ADDRESSING, ButtonType::Warning);
+            ui = ui.push(
+                Row::new()
+                    .spacing(5)
+                    .push(text_input(
+                        &mut self.preset_name_input,
+                        "Preset name",
+                        &self.preset_name,
+                        KWP2000DiagSessionMsg::EnterPresetName,
+                    ))
+                    .push({
+                        let mut save_btn = button_outlined(
+                            &mut self.preset_save_btn,
+                            "Save preset",
+                            ButtonType::Secondary,
+                        );
+                        if self.can_send && !self.preset_name.is_empty() {
+                            save_btn = save_btn.on_press(KWP2000DiagSessionMsg::SavePreset);
+                        }
+                        save_btn
+                    }),
+            );
+            ui = with_help(ui, self.show_help, help::PAYLOAD_PRESETS, ButtonType::Info);
+            ui = ui.push(
+                button_outlined(
+                    &mut self.load_layout_btn,
+                    "Load routine result layout",
+                    ButtonType::Secondary,
+                )
+                .on_press(KWP2000DiagSessionMsg::LoadRoutineLayout),
+            );
+            ui = with_help(ui, self.show_help, help::ROUTINE_LAYOUT, ButtonType::Info);
+            ui = ui.push(text("Security access key calculator", TextType::Normal));
+            ui = ui.push(
+                Row::new()
+                    .spacing(5)
+                    .push(
+                        text_input(
+                            &mut self.key_seed_input,
+                            "Seed (Hex)",
+                            &self.key_seed_string,
+                            KWP2000DiagSessionMsg::EnterSeed,
+                        )
+                        .width(Length::FillPortion(1)),
+                    )
+                    .push(
+                        text_input(
+                            &mut self.key_algo_input,
+                            "Algorithm. EG: xor 5A5A, rol 3",
+                            &self.key_algo_string,
+                            KWP2000DiagSessionMsg::EnterKeyAlgorithm,
+                        )
+                        .width(Length::FillPortion(2)),
+                    ),
+            );
+            if !self.key_seed_string.is_empty() && !self.key_algo_string.is_empty() {
+                ui = ui.push(
+                    match Self::calc_key(&self.key_seed_string, &self.key_algo_string) {
+                        Ok(key) => text(
+                            format!("Key: {:02X?} (Not sent)", key).as_str(),
+                            TextType::Success,
+                        ),
+                        Err(e) => text(e.as_str(), TextType::Warning),
+                    },
+                );
+            }
+            let mut use_algo_btn = button_outlined(
+                &mut self.use_key_algo_btn,
+                "Use algorithm to restore security access",
+                ButtonType::Secondary,
+            );
+            if KeyAlgorithmParams::parse(&self.key_algo_string).is_ok() {
+                use_algo_btn = use_algo_btn.on_press(KWP2000DiagSessionMsg::UseKeyAlgorithm);
+            }
+            ui = ui.push(use_algo_btn);
+            ui = with_help(ui, self.show_help, help::KEY_CALCULATOR, ButtonType::Info);
         }
+        ui = ui.push(Checkbox::new(
+            self.show_help,
+            "Show help",
+            KWP2000DiagSessionMsg::ToggleHelp,
+        ));
         ui = ui.push(Space::with_height(Length::Fill));
         if let Some(se) = &self.diag_server {
             ui = ui.push(Row::new().push(text(
                 format!("Current session type: {:?}", se.get_session_type()).as_str(),
                 TextType::Normal,
             )));
+            if let Some(sessions) = &self.supported_sessions {
+                let supported: Vec<&str> = sessions
+                    .iter()
+                    .filter(|s| s.is_supported())
+                    .map(|s| s.session.as_str())
+                    .collect();
+                ui = ui.push(text(
+                    format!("Supported sessions: {}", supported.join(", ")).as_str(),
+                    TextType::Normal,
+                ));
+            }
         }
 
         Row::new()
@@ -161,8 +975,11 @@ impl SessionTrait for KWP2000DiagSession {
             .padding(8)
             .push(ui.width(Length::FillPortion(1)))
             .push(
-                Container::new(self.logview.view(KWP2000DiagSessionMsg::ClearLogs))
-                    .width(Length::FillPortion(1)),
+                Container::new(self.logview.view_with_decoding(
+                    KWP2000DiagSessionMsg::ClearLogs,
+                    KWP2000DiagSessionMsg::ToggleDecodedLog,
+                ))
+                .width(Length::FillPortion(1)),
             )
             .into()
     }
@@ -170,31 +987,78 @@ impl SessionTrait for KWP2000DiagSession {
     fn update(&mut self, msg: &Self::msg) -> Option<Self::msg> {
         match msg {
             KWP2000DiagSessionMsg::ConnectECU => {
-                match KWP2000ECU::start_diag_session(self.server.clone(), &self.ecu) {
-                    Ok(server) => {
-                        window::disable_home();
-                        self.diag_server = Some(server);
-                        self.logview
-                            .add_msg("Connection to ECU established", LogType::Info)
+                self.busy = true;
+                let server = self.server.clone();
+                let ecu = self.ecu;
+                let wake_up = self.wake_up.clone();
+                let timing = self.profile.as_ref().and_then(|p| p.timing.clone());
+                hw_task::run(
+                    move || {
+                        let mut logs = wake_up_ecu(server.clone(), &ecu, wake_up.as_ref());
+                        match KWP2000ECU::start_diag_session(server.clone(), &ecu) {
+                            Ok(diag_server) => {
+                                logs.extend(Self::restore_timing(&diag_server, timing.as_deref()));
+                                (logs, Some(diag_server))
+                            }
+                            Err(e) => {
+                                logs.push((
+                                    format!("Error connecting to ECU ({})", e.get_text()),
+                                    LogType::Info,
+                                ));
+                                if e.is_timeout() {
+                                    logs.extend(
+                                        find_bitrate_hint(server).map(|h| (h, LogType::Warn)),
+                                    );
+                                }
+                                (logs, None)
+                            }
+                        }
+                    },
+                    |(logs, res)| Self::task_msg(KWP2000DiagSessionMsg::Connected(logs, res)),
+                );
+            }
+            KWP2000DiagSessionMsg::Connected(logs, res) => {
+                self.busy = false;
+                for (msg, ltype) in logs {
+                    self.logview.add_msg(msg, *ltype)
+                }
+                if let Some(server) = res {
+                    window::disable_home();
+                    if let Some(t) = server.get_timing_parameters() {
+                        self.timing_string = t.to_string();
                     }
-                    Err(e) => self.logview.add_msg(
-                        format!("Error connecting to ECU ({})", e.get_text()),
-                        LogType::Info,
-                    ),
+                    server.set_default_addressing(self.default_addressing);
+                    server.set_tester_present_addressing(self.tester_present_addressing);
+                    self.diag_server = Some(server.clone());
+                    self.logview
+                        .add_msg("Connection to ECU established", LogType::Info);
+                    self.save_profile();
                 }
             }
             KWP2000DiagSessionMsg::DisconnectECU => {
                 if let Some(ref mut server) = self.diag_server {
                     server.borrow_mut().exit_diag_session()
                 }
+                self.stop_loop_send(None);
                 self.logview
                     .add_msg("Connection to ECU terminated", LogType::Info);
                 self.diag_server.take();
+                let name = self.get_log_name();
+                auto_export_log(&mut self.logview, &name);
                 window::enable_home();
             }
 
             KWP2000DiagSessionMsg::PollServer(_) => {
                 if let Some(ref mut server) = self.diag_server {
+                    for event in server.take_connection_events() {
+                        match event {
+                            ConnectionEvent::Message(msg) => self.logview.add_msg(msg, LogType::Warn),
+                            ConnectionEvent::EcuReset(cause) => self.logview.add_msg(
+                                format!("Possible ECU reset - {}. The ECU is back in its default session and is locked. If the ECU was being programmed, check the vehicle's power supply before continuing", cause),
+                                LogType::Error,
+                            ),
+                        }
+                    }
                     if !server.is_in_diag_session() {
                         // Woops server terminated without interaction
                         server.exit_diag_session();
@@ -204,75 +1068,454 @@ impl SessionTrait for KWP2000DiagSession {
                             self.logview.add_msg(format!("--> {}", desc), LogType::Info);
                         }
                         self.diag_server.take();
+                        let name = self.get_log_name();
+                        auto_export_log(&mut self.logview, &name);
                         window::enable_home();
                     }
                 }
             }
             KWP2000DiagSessionMsg::ClearLogs => self.logview.clear_logs(),
             KWP2000DiagSessionMsg::ClearErrors => {
-                if let Some(s) = &self.diag_server {
-                    match s.clear_errors() {
-                        Err(e) => self.logview.add_msg(
-                            format!("Error clearing ECU errors: {}", e.get_text()).as_str(),
-                            LogType::Error,
-                        ),
-                        Ok(_) => self
-                            .logview
-                            .add_msg("ECU Errors cleared successfully", LogType::Error),
+                if let Some(server) = self.diag_server.clone() {
+                    self.busy = true;
+                    hw_task::run(
+                        move || server.clear_errors().map_err(|e| e.get_text()),
+                        |res| Self::task_msg(KWP2000DiagSessionMsg::ErrorsCleared(res)),
+                    );
+                }
+            }
+            KWP2000DiagSessionMsg::ErrorsCleared(res) => match res {
+                Err(e) => {
+                    self.busy = false;
+                    self.logview.add_msg(
+                        format!("Error clearing ECU errors: {}", e).as_str(),
+                        LogType::Error,
+                    )
+                }
+                Ok(_) => {
+                    self.logview
+                        .add_msg("ECU Errors cleared successfully", LogType::Error);
+                    if let Some(server) = self.diag_server.clone() {
+                        hw_task::run(
+                            move || server.read_errors().map_err(|e| e.get_text()),
+                            |res| Self::task_msg(KWP2000DiagSessionMsg::ClearVerified(res)),
+                        );
+                    } else {
+                        self.busy = false;
                     }
                 }
+            },
+            KWP2000DiagSessionMsg::ClearVerified(res) => {
+                self.busy = false;
+                log_clear_verification(
+                    &mut self.logview,
+                    res.clone().map_err(ProtocolError::CustomError),
+                );
             }
             KWP2000DiagSessionMsg::ReadCodes => {
                 self.can_clear_codes = false;
-                if let Some(s) = &self.diag_server {
-                    match s.read_errors() {
-                        Err(e) => self.logview.add_msg(
-                            format!("Error reading ECU errors: {}", e.get_text()).as_str(),
-                            LogType::Error,
-                        ),
-                        Ok(errors) => {
-                            if errors.is_empty() {
-                                self.logview.add_msg("No ECU Errors found", LogType::Info)
-                            } else {
+                if let Some(server) = self.diag_server.clone() {
+                    self.busy = true;
+                    hw_task::run(
+                        move || server.read_errors().map_err(|e| e.get_text()),
+                        |res| Self::task_msg(KWP2000DiagSessionMsg::CodesRead(res)),
+                    );
+                }
+            }
+            KWP2000DiagSessionMsg::CodesRead(res) => {
+                self.busy = false;
+                match res {
+                    Err(e) => self.logview.add_msg(
+                        format!("Error reading ECU errors: {}", e).as_str(),
+                        LogType::Error,
+                    ),
+                    Ok(errors) => {
+                        if errors.is_empty() {
+                            self.logview.add_msg("No ECU Errors found", LogType::Info)
+                        } else {
+                            self.logview
+                                .add_msg(format!("Found {} errors", errors.len()), LogType::Warn);
+                            self.can_clear_codes = true;
+                            for x in errors {
                                 self.logview.add_msg(
-                                    format!("Found {} errors", errors.len()),
+                                    format!("{} ({})", x.error, x.get_status_text()),
                                     LogType::Warn,
                                 );
-                                self.can_clear_codes = true;
-                                for x in &errors {
-                                    self.logview.add_msg(x.error.as_str(), LogType::Warn);
-                                }
                             }
                         }
                     }
                 }
             }
+            KWP2000DiagSessionMsg::ProbeSessions => {
+                if let Some(mut server) = self.diag_server.clone() {
+                    self.busy = true;
+                    hw_task::run(
+                        move || probe_sessions(&mut server),
+                        |res| Self::task_msg(KWP2000DiagSessionMsg::SessionsProbed(res)),
+                    );
+                }
+            }
+            KWP2000DiagSessionMsg::SessionsProbed(sessions) => {
+                self.busy = false;
+                log_session_support(&mut self.logview, sessions);
+                self.supported_sessions = Some(sessions.clone());
+            }
+            KWP2000DiagSessionMsg::ReadVIN => {
+                if let Some(server) = self.diag_server.clone() {
+                    self.busy = true;
+                    hw_task::run(
+                        move || {
+                            read_vin(|c, a| server.run_command(c, a), &KWP2000_VIN_METHODS)
+                                .map(|v| v.raw)
+                                .map_err(|e| e.get_text())
+                        },
+                        |res| Self::task_msg(KWP2000DiagSessionMsg::VINRead(res)),
+                    );
+                }
+            }
+            KWP2000DiagSessionMsg::VINRead(res) => {
+                self.busy = false;
+                match res {
+                    Ok(vin) => self.logview.add_msg(format!("VIN: {}", vin), LogType::Info),
+                    Err(e) => self
+                        .logview
+                        .add_msg(format!("Error reading VIN: {}", e), LogType::Error),
+                }
+            }
+            KWP2000DiagSessionMsg::ReadTiming => {
+                if let Some(server) = self.diag_server.clone() {
+                    self.busy = true;
+                    hw_task::run(
+                        move || server.read_timing_parameters().map_err(|e| e.get_text()),
+                        |res| Self::task_msg(KWP2000DiagSessionMsg::TimingUpdated(res)),
+                    );
+                }
+            }
+            KWP2000DiagSessionMsg::EnterTiming(s) => self.timing_string = s.clone(),
+            KWP2000DiagSessionMsg::SetTiming => {
+                if let (Some(server), Some(timing)) = (
+                    self.diag_server.clone(),
+                    TimingParameters::parse(&self.timing_string),
+                ) {
+                    self.busy = true;
+                    self.setting_timing = true;
+                    hw_task::run(
+                        move || {
+                            server
+                                .set_timing_parameters(timing)
+                                .map(|_| timing)
+                                .map_err(|e| e.get_text())
+                        },
+                        |res| Self::task_msg(KWP2000DiagSessionMsg::TimingUpdated(res)),
+                    );
+                }
+            }
+            KWP2000DiagSessionMsg::TimingUpdated(res) => {
+                self.busy = false;
+                let user_set = std::mem::take(&mut self.setting_timing);
+                match res {
+                    Ok(t) => {
+                        self.timing_string = t.to_string();
+                        self.logview.add_msg(
+                            format!(
+                                "Timing parameters: P2 {}-{}ms, P3 {}-{}ms, P4 {}ms",
+                                t.p2_min, t.p2_max, t.p3_min, t.p3_max, t.p4_min
+                            ),
+                            LogType::Info,
+                        );
+                        if user_set {
+                            if let Some(p) = self.profile.as_mut() {
+                                p.timing = Some(t.to_string());
+                            }
+                            self.save_profile();
+                        }
+                    }
+                    Err(e) => self.logview.add_msg(
+                        format!("Error accessing timing parameters: {}", e),
+                        LogType::Error,
+                    ),
+                }
+            }
             KWP2000DiagSessionMsg::EnterPayload(s) => {
                 self.payload_string = s.clone();
-                if s.is_empty() {
-                    self.can_send = false;
-                } else if hex::decode(s).is_ok() && s.len() >= 4 {
-                    self.can_send = true;
+                self.can_send = Self::get_payloads(s).is_ok();
+            }
+            KWP2000DiagSessionMsg::PresetSelected(p) => {
+                self.payload_string = p.payloads.join(",");
+                self.can_send = Self::get_payloads(&self.payload_string).is_ok();
+                self.preset_name = p.name.clone();
+                self.selected_preset = Some(p.clone());
+            }
+            KWP2000DiagSessionMsg::EnterPresetName(s) => self.preset_name = s.clone(),
+            KWP2000DiagSessionMsg::ToggleSuppressResponse(b) => self.suppress_response = *b,
+            KWP2000DiagSessionMsg::ToggleRequirementWarnings(b) => self.warn_requirements = *b,
+            KWP2000DiagSessionMsg::AddressingSelected(a) => {
+                self.default_addressing = *a;
+                if let Some(server) = &self.diag_server {
+                    server.set_default_addressing(*a);
+                    self.save_profile();
+                }
+            }
+            KWP2000DiagSessionMsg::TesterPresentAddressingSelected(a) => {
+                self.tester_present_addressing = *a;
+                if let Some(server) = &self.diag_server {
+                    server.set_tester_present_addressing(*a);
+                    self.save_profile();
                 }
             }
+            KWP2000DiagSessionMsg::ToggleHelp(b) => self.show_help = *b,
+            KWP2000DiagSessionMsg::ToggleDecodedLog(b) => self.logview.set_decoded_view(*b),
+            KWP2000DiagSessionMsg::EnterSeed(s) => self.key_seed_string = s.clone(),
+            KWP2000DiagSessionMsg::EnterKeyAlgorithm(s) => self.key_algo_string = s.clone(),
+            KWP2000DiagSessionMsg::UseKeyAlgorithm => {
+                if let (Some(server), Ok(params)) = (
+                    &self.diag_server,
+                    KeyAlgorithmParams::parse(&self.key_algo_string),
+                ) {
+                    server.set_key_algorithm(Some(params.to_algorithm()));
+                    self.logview.add_msg(
+                        format!(
+                            "Security access will be restored with key algorithm '{}'",
+                            self.key_algo_string.trim()
+                        ),
+                        LogType::Info,
+                    );
+                }
+            }
+            KWP2000DiagSessionMsg::ToggleMonitoring(b) => {
+                self.monitoring = *b;
+                self.monitored_dtcs = None;
+                self.logview.add_msg(
+                    if *b {
+                        "Started monitoring error codes"
+                    } else {
+                        "Stopped monitoring error codes"
+                    },
+                    LogType::Info,
+                );
+            }
+            KWP2000DiagSessionMsg::MonitorTick(_) => {
+                // Skip this tick if the ECU is still busy with the last operation
+                if let (true, false, Some(server)) =
+                    (self.monitoring, self.busy, self.diag_server.clone())
+                {
+                    self.busy = true;
+                    hw_task::run(
+                        move || server.read_errors().map_err(|e| e.get_text()),
+                        |res| Self::task_msg(KWP2000DiagSessionMsg::MonitorRead(res)),
+                    );
+                }
+            }
+            KWP2000DiagSessionMsg::MonitorRead(res) => {
+                self.busy = false;
+                match res {
+                    Ok(dtcs) if self.monitoring => {
+                        if let Some(known) = &self.monitored_dtcs {
+                            let new_dtcs = find_new_dtcs(known, dtcs);
+                            if !new_dtcs.is_empty() {
+                                self.alert_new_dtcs(&new_dtcs);
+                            }
+                        }
+                        self.can_clear_codes = !dtcs.is_empty();
+                        self.monitored_dtcs = Some(dtcs.clone());
+                    }
+                    Ok(_) => {} // Monitoring was stopped whilst reading
+                    Err(e) => self.logview.add_msg(
+                        format!("Error reading ECU errors whilst monitoring: {}", e),
+                        LogType::Error,
+                    ),
+                }
+            }
+            KWP2000DiagSessionMsg::DismissAlert => self.new_dtc_alert = None,
+            KWP2000DiagSessionMsg::SavePreset => {
+                let preset = PayloadPreset {
+                    name: self.preset_name.clone(),
+                    payloads: self
+                        .payload_string
+                        .split(',')
+                        .map(|p| p.trim().to_uppercase())
+                        .collect(),
+                };
+                let mut settings = get_settings();
+                // Saving with an existing name replaces that preset
+                settings.payload_presets.retain(|p| p.name != preset.name);
+                settings.payload_presets.push(preset.clone());
+                match set_settings(settings) {
+                    Ok(_) => self
+                        .logview
+                        .add_msg(format!("Saved preset '{}'", preset.name), LogType::Info),
+                    Err(e) => self
+                        .logview
+                        .add_msg(format!("Error saving preset: {}", e), LogType::Error),
+                }
+                self.presets = get_settings().payload_presets;
+                self.selected_preset = Some(preset);
+            }
             KWP2000DiagSessionMsg::SendPayload => {
-                if let Ok(r) = hex::decode(&self.payload_string) {
-                    if r.len() >= 2 {
-                        if let Some(server) = &self.diag_server {
-                            match server.run_command(r[0], &r[1..]) {
-                                Ok(res) => self.logview.add_log(
-                                    format!("Req:  {:02X?}", r),
-                                    format!("Resp: {:02X?}", res),
-                                    LogType::Info,
-                                ),
-                                Err(e) => self.logview.add_log(
-                                    format!("Req:  {:02X?}", r),
-                                    format!("Exec error: {}", e.get_text()),
-                                    LogType::Error,
-                                ),
+                if let Some(server) = self.diag_server.clone() {
+                    let payloads = match Self::get_payloads(&self.payload_string) {
+                        Ok(p) => p,
+                        Err(e) => {
+                            self.logview.add_msg(e, LogType::Error);
+                            return None;
+                        }
+                    };
+                    if self.warn_requirements {
+                        // Only a warning, as some ECUs accept these services regardless
+                        for w in Self::get_requirement_warnings(&server, &payloads) {
+                            self.logview.add_msg(w, LogType::Warn);
+                        }
+                    }
+                    let suppress_response = self.suppress_response;
+                    let routine_layout = self.routine_layout.clone();
+                    self.busy = true;
+                    hw_task::run(
+                        move || {
+                            Self::send_sequence(
+                                &server,
+                                &payloads,
+                                suppress_response,
+                                &routine_layout,
+                            )
+                        },
+                        |(logs, not_sent)| {
+                            Self::task_msg(KWP2000DiagSessionMsg::PayloadsSent(logs, not_sent))
+                        },
+                    );
+                }
+            }
+            KWP2000DiagSessionMsg::PreviewFrames => {
+                for step in Self::get_payloads(&self.payload_string).unwrap_or_default() {
+                    let mut payload = step.payload;
+                    if self.suppress_response {
+                        match KWP2000ECU::suppress_response_args(payload[0], &payload[1..]) {
+                            Ok(args) => payload = [&payload[..1], &args[..]].concat(),
+                            Err(e) => {
+                                self.logview.add_msg(
+                                    format!("{:02X?} cannot be sent: {}", payload, e.get_text()),
+                                    LogType::Warn,
+                                );
+                                continue;
                             }
                         }
                     }
+                    let addressing = step.addressing.unwrap_or(self.default_addressing);
+                    if let Err(e) = addressing.check_request_len(payload.len()) {
+                        self.logview.add_msg(
+                            format!("{:02X?} cannot be sent: {}", payload, e),
+                            LogType::Warn,
+                        );
+                        continue;
+                    }
+                    let frames = preview_frames(
+                        &payload,
+                        addressing.get_send_id(&self.ecu),
+                        self.ecu.recv_id,
+                        self.ecu.can_fd.is_some(),
+                    );
+                    self.logview.add_msg(
+                        format!("Preview of {:02X?} (Not sent)", payload),
+                        LogType::Info,
+                    );
+                    for f in frames {
+                        self.logview.add_msg(format!("  {}", f), LogType::Info);
+                    }
+                }
+            }
+            KWP2000DiagSessionMsg::PayloadsSent(logs, not_sent) => {
+                self.busy = false;
+                for (req, resp, decoded, ltype) in logs {
+                    self.logview
+                        .add_log_decoded(req.clone(), resp.clone(), decoded.clone(), *ltype)
+                }
+                if *not_sent > 0 {
+                    self.logview.add_msg(
+                        format!(
+                            "Sequence stopped by an unexpected error, {} payload(s) not sent",
+                            not_sent
+                        ),
+                        LogType::Warn,
+                    );
+                }
+            }
+            KWP2000DiagSessionMsg::EnterLoopInterval(s) => self.loop_interval_string = s.clone(),
+            KWP2000DiagSessionMsg::ToggleLoopChangesOnly(b) => self.loop_changes_only = *b,
+            KWP2000DiagSessionMsg::ToggleLoopSend => {
+                if self.loop_payloads.is_some() {
+                    self.stop_loop_send(None);
+                } else {
+                    self.start_loop_send();
+                }
+            }
+            KWP2000DiagSessionMsg::LoopTick(_) => {
+                // Skip this tick if the ECU is still busy with the last operation
+                if let (false, Some(server), Some(payloads)) = (
+                    self.busy,
+                    self.diag_server.clone(),
+                    self.loop_payloads.clone(),
+                ) {
+                    let suppress_response = self.suppress_response;
+                    let routine_layout = self.routine_layout.clone();
+                    self.busy = true;
+                    hw_task::run(
+                        move || {
+                            Self::send_sequence(
+                                &server,
+                                &payloads,
+                                suppress_response,
+                                &routine_layout,
+                            )
+                        },
+                        |(logs, not_sent)| {
+                            Self::task_msg(KWP2000DiagSessionMsg::LoopSent(logs, not_sent))
+                        },
+                    );
+                }
+            }
+            KWP2000DiagSessionMsg::LoopSent(logs, not_sent) => {
+                self.busy = false;
+                if self.loop_payloads.is_none() {
+                    return None; // Stopped whilst sending, the stop has already been logged
+                }
+                let responses: Vec<String> = logs.iter().map(|l| l.1.clone()).collect();
+                let changed = self.loop_last_responses.as_ref() != Some(&responses);
+                if changed || !self.loop_changes_only {
+                    for (req, resp, decoded, ltype) in logs {
+                        self.logview.add_log_decoded(
+                            req.clone(),
+                            resp.clone(),
+                            decoded.clone(),
+                            *ltype,
+                        )
+                    }
+                }
+                self.loop_last_responses = Some(responses);
+                if *not_sent > 0 || logs.iter().any(|l| l.3 == LogType::Error) {
+                    self.stop_loop_send(Some("an unexpected error"));
+                }
+            }
+            KWP2000DiagSessionMsg::LoadRoutineLayout => {
+                if let Some(f_path) = open_file(FileType::RoutineLayout) {
+                    match std::fs::read_to_string(&f_path)
+                        .map_err(|e| e.to_string())
+                        .and_then(|s| {
+                            serde_json::from_str::<Vec<Parameter>>(&s).map_err(|e| e.to_string())
+                        }) {
+                        Ok(layout) => {
+                            self.logview.add_msg(
+                                format!(
+                                    "Loaded routine result layout with {} fields",
+                                    layout.len()
+                                ),
+                                LogType::Info,
+                            );
+                            self.routine_layout = layout;
+                        }
+                        Err(e) => self.logview.add_msg(
+                            format!("Error loading routine result layout: {}", e),
+                            LogType::Error,
+                        ),
+                    }
                 }
             }
             _ => {}
@@ -282,8 +1525,22 @@ impl SessionTrait for KWP2000DiagSession {
 
     fn subscription(&self) -> iced::Subscription<Self::msg> {
         if self.diag_server.is_some() {
-            time::every(std::time::Duration::from_millis(250))
-                .map(KWP2000DiagSessionMsg::PollServer)
+            let mut subs = vec![time::every(std::time::Duration::from_millis(250))
+                .map(KWP2000DiagSessionMsg::PollServer)];
+            if self.monitoring {
+                let interval = get_settings().dtc_monitor_interval_ms;
+                subs.push(
+                    time::every(std::time::Duration::from_millis(interval))
+                        .map(KWP2000DiagSessionMsg::MonitorTick),
+                );
+            }
+            if self.loop_payloads.is_some() {
+                subs.push(
+                    time::every(std::time::Duration::from_millis(self.loop_interval_ms))
+                        .map(KWP2000DiagSessionMsg::LoopTick),
+                );
+            }
+            Subscription::batch(subs)
         } else {
             Subscription::none()
         }
@@ -293,7 +1550,77 @@ impl SessionTrait for KWP2000DiagSession {
 impl Drop for KWP2000DiagSession {
     fn drop(&mut self) {
         if let Some(ref mut session) = self.diag_server {
-            session.exit_diag_session()
+            session.exit_diag_session();
+            // Session did not end by disconnecting, so the log has not been exported yet
+            let name = self.get_log_name();
+            auto_export_log(&mut self.logview, &name);
         }
     }
 }
+
+#[cfg(test)]
+mod kwp2000_session_test {
+    use common::schema::diag::{
+        service::{ParamByteOrder, Parameter},
+        DataFormat,
+    };
+
+    use super::{KWP2000DiagSession, PayloadStep};
+    use crate::commapi::{
+        comm_api::ISO15765Config, mock_api::MockComServer, protocols::kwp2000::KWP2000ECU,
+    };
+
+    fn start_session() -> KWP2000ECU {
+        KWP2000ECU::start_diag_session(
+            Box::new(MockComServer::new()),
+            &ISO15765Config {
+                send_id: 0x7E0,
+                recv_id: 0x7E8,
+                block_size: 8,
+                sep_time: 20,
+                auto_fc: false,
+                can_fd: None,
+                padding: None,
+                ext_addr: None,
+            },
+        )
+        .unwrap()
+    }
+
+    /// Layout with a single byte field after the service ID and local ID
+    fn layout() -> Vec<Parameter> {
+        vec![Parameter {
+            name: "cyl1".into(),
+            unit: "bar".into(),
+            start_bit: 16,
+            length_bits: 8,
+            byte_order: ParamByteOrder::BigEndian,
+            data_format: DataFormat::Identical,
+            limits: None,
+            signed: false,
+        }]
+    }
+
+    fn send(ecu: &KWP2000ECU, payload: &str, layout: &[Parameter]) -> String {
+        let step = PayloadStep::parse(payload).unwrap();
+        KWP2000DiagSession::send_payload(ecu, &step, false, layout).1
+    }
+
+    #[test]
+    fn start_routine_is_decoded() {
+        let ecu = start_session();
+        // The simulated ECU echoes the routine's arguments back
+        assert_eq!(
+            send(&ecu, "3101AA", &layout()),
+            "Routine started: cyl1=170 bar"
+        );
+        assert_eq!(send(&ecu, "3101AA", &[]), "Routine started: [71, 01, AA]");
+    }
+
+    #[test]
+    fn routine_results_are_decoded() {
+        let ecu = start_session();
+        assert_eq!(send(&ecu, "3301", &layout()), "Routine result: cyl1=12 bar");
+        assert_eq!(send(&ecu, "3301", &[]), "Routine result: [73, 01, 0C, 0D]");
+    }
+}
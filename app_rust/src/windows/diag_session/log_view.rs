@@ -1,10 +1,16 @@
-use std::collections::VecDeque;
+use std::{collections::VecDeque, io::Write, path::PathBuf};
 
-use iced::{scrollable, Column, Element, Length, Row, Scrollable, Space};
+use chrono::{DateTime, Local};
+use iced::{scrollable, Checkbox, Column, Element, Length, Row, Scrollable, Space};
+use serde::Serialize;
 
-use crate::themes::{button_outlined, text, title_text, ButtonType, TextType};
+use crate::{
+    commapi::protocols::{ECUCommand, ProtocolError, ProtocolResult},
+    settings::{get_settings, LogExportFormat},
+    themes::{button_outlined, text, title_text, ButtonType, TextType},
+};
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
 pub enum LogType {
     Error,
     Warn,
@@ -15,7 +21,21 @@ pub enum LogType {
 struct LogOperation {
     request: Option<String>,
     response: Option<String>,
+    /// Interpretation of the request and response, shown in the decoded view
+    decoded: Vec<String>,
     log_type: LogType,
+    time: DateTime<Local>,
+}
+
+/// A log entry as written to an exported log
+#[derive(Serialize)]
+struct ExportedLogEntry<'a> {
+    time: String,
+    log_type: LogType,
+    request: &'a Option<String>,
+    response: &'a Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    decoded: &'a Vec<String>,
 }
 
 impl LogOperation {
@@ -23,11 +43,23 @@ impl LogOperation {
         Self {
             request: request.map(|x| x.to_string()),
             response: response.map(|x| x.to_string()),
+            decoded: Vec::new(),
             log_type: ltype,
+            time: Local::now(),
+        }
+    }
+
+    fn to_exported(&self) -> ExportedLogEntry {
+        ExportedLogEntry {
+            time: self.time.format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+            log_type: self.log_type,
+            request: &self.request,
+            response: &self.response,
+            decoded: &self.decoded,
         }
     }
 
-    fn render<'a, T>(&self) -> Element<'a, T>
+    fn render<'a, T>(&self, show_decoded: bool) -> Element<'a, T>
     where
         T: 'a,
     {
@@ -43,6 +75,11 @@ impl LogOperation {
         if let Some(r) = &self.response {
             c = c.push(text(&r, text_type).size(16))
         }
+        if show_decoded {
+            for d in &self.decoded {
+                c = c.push(text(&format!("  -> {}", d), TextType::Disabled).size(16))
+            }
+        }
         c.into()
     }
 }
@@ -52,6 +89,8 @@ pub struct LogView {
     logs: VecDeque<LogOperation>,
     scroll_state: scrollable::State,
     btn_state: iced::button::State,
+    /// Show the decoded form of each exchange below its raw bytes
+    show_decoded: bool,
 }
 
 impl LogView {
@@ -60,29 +99,60 @@ impl LogView {
             logs: VecDeque::new(),
             scroll_state: Default::default(),
             btn_state: Default::default(),
+            show_decoded: false,
         }
     }
 
     pub fn view<'a, T: Clone>(&'a mut self, clear_log_msg: T) -> Element<'a, T>
+    where
+        T: 'a,
+    {
+        self.view_inner(clear_log_msg, None)
+    }
+
+    /// Same as [view](LogView::view), but with a toggle to show the decoded form of each
+    /// exchange added with [add_log_decoded](LogView::add_log_decoded)
+    pub fn view_with_decoding<'a, T: Clone, F>(
+        &'a mut self,
+        clear_log_msg: T,
+        toggle_decoded_msg: F,
+    ) -> Element<'a, T>
+    where
+        T: 'a,
+        F: 'static + Fn(bool) -> T,
+    {
+        let toggle = Checkbox::new(self.show_decoded, "Decoded view", toggle_decoded_msg);
+        self.view_inner(clear_log_msg, Some(toggle))
+    }
+
+    fn view_inner<'a, T: Clone>(
+        &'a mut self,
+        clear_log_msg: T,
+        decoded_toggle: Option<Checkbox<T>>,
+    ) -> Element<'a, T>
     where
         T: 'a,
     {
         let mut c = Column::new().spacing(5).width(Length::Fill);
+        let mut title_row = Row::new()
+            .width(Length::Fill)
+            .spacing(5)
+            .push(title_text("Log view", crate::themes::TitleSize::P3))
+            .push(Space::with_width(Length::Fill));
+        if let Some(toggle) = decoded_toggle {
+            title_row = title_row.push(toggle);
+        }
         c = c.push(
-            Row::new()
-                .width(Length::Fill)
-                .push(title_text("Log view", crate::themes::TitleSize::P3))
-                .push(Space::with_width(Length::Fill))
-                .push(
-                    button_outlined(&mut self.btn_state, "Clear logs", ButtonType::Success)
-                        .on_press(clear_log_msg),
-                ),
+            title_row.push(
+                button_outlined(&mut self.btn_state, "Clear logs", ButtonType::Success)
+                    .on_press(clear_log_msg),
+            ),
         );
         let mut s = Scrollable::new(&mut self.scroll_state)
             .width(Length::Fill)
             .height(Length::Fill);
         for l in &self.logs {
-            s = s.push(l.render())
+            s = s.push(l.render(self.show_decoded))
         }
         c = c.push(s);
         c.into()
@@ -93,6 +163,24 @@ impl LogView {
             .push_back(LogOperation::create(Some(request), Some(response), ltype))
     }
 
+    /// Adds a request / response exchange in raw form, along with its decoded form.
+    /// The decoded form is only shown when the decoded view is enabled
+    pub fn add_log_decoded<X: ToString>(
+        &mut self,
+        request: X,
+        response: X,
+        decoded: Vec<String>,
+        ltype: LogType,
+    ) {
+        let mut op = LogOperation::create(Some(request), Some(response), ltype);
+        op.decoded = decoded;
+        self.logs.push_back(op)
+    }
+
+    pub fn set_decoded_view(&mut self, show: bool) {
+        self.show_decoded = show
+    }
+
     pub fn add_msg<X: ToString>(&mut self, msg: X, ltype: LogType) {
         self.logs
             .push_back(LogOperation::create(Some(msg), None, ltype))
@@ -101,4 +189,109 @@ impl LogView {
     pub fn clear_logs(&mut self) {
         self.logs.clear()
     }
+
+    /// Writes the log to `out` in the given format
+    pub fn export(&self, out: &mut dyn Write, format: LogExportFormat) -> std::io::Result<()> {
+        let entries: Vec<ExportedLogEntry> = self.logs.iter().map(|l| l.to_exported()).collect();
+        match format {
+            LogExportFormat::Json => serde_json::to_writer_pretty(out, &entries)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)),
+            LogExportFormat::Csv => {
+                writeln!(out, "time,type,request,response,decoded")?;
+                for e in entries {
+                    writeln!(
+                        out,
+                        "{},{:?},{},{},{}",
+                        e.time,
+                        e.log_type,
+                        csv_field(e.request.as_deref().unwrap_or_default()),
+                        csv_field(e.response.as_deref().unwrap_or_default()),
+                        csv_field(&e.decoded.join("; "))
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Exports the log to the log directory if automatic log export is enabled, when a session ends.
+    /// The file is named after the time and `name`, which should identify the ECU or vehicle.
+    ///
+    /// ## Returns
+    /// The path of the exported log, or None if automatic export is disabled or the log is empty
+    pub fn auto_export(&self, name: &str) -> Option<std::io::Result<PathBuf>> {
+        let settings = get_settings();
+        if !settings.auto_export_logs || self.logs.is_empty() {
+            return None;
+        }
+        let format = settings.log_export_format;
+        let mut path = PathBuf::from(&settings.log_dir);
+        path.push(format!(
+            "ovd_{}_{}.{}",
+            Local::now().format("%Y%m%d_%H%M%S"),
+            name.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_"),
+            format.get_extension()
+        ));
+        Some(
+            std::fs::File::create(&path)
+                .and_then(|mut f| self.export(&mut f, format))
+                .map(|_| path),
+        )
+    }
+}
+
+/// Decodes a request / response exchange with protocol `S`, into the service name,
+/// and the meaning of the response (Including the NRC description for negative responses)
+pub fn decode_exchange<S: ECUCommand + Copy>(
+    request: &[u8],
+    response: &ProtocolResult<Vec<u8>>,
+) -> Vec<String> {
+    let sid = match request.get(0) {
+        Some(sid) => *sid,
+        None => return Vec::new(),
+    };
+    let service = S::get_cmd_list()
+        .into_iter()
+        .find(|s| Into::<u8>::into(*s) == sid);
+    let mut res = vec![match service {
+        Some(s) => format!("Service: {} ({})", s.get_desc(), s.get_name()),
+        None => format!("Service: Unknown (0x{:02X})", sid),
+    }];
+    match response {
+        Ok(_) => res.push("Positive response".into()),
+        Err(ProtocolError::NegativeResponse { nrc, error }) => {
+            res.push(format!(
+                "Negative response: {} (NRC 0x{:02X})",
+                error.get_desc(),
+                nrc
+            ));
+            if let Some(help) = error.get_help() {
+                res.push(help)
+            }
+        }
+        Err(e) => res.push(format!("No response: {}", e.get_text())),
+    }
+    res
+}
+
+/// Raw form of a response to show in the log. Negative responses are shown as the bytes
+/// the ECU sent, rather than as an error
+pub fn raw_response(request: &[u8], response: &ProtocolResult<Vec<u8>>) -> String {
+    match response {
+        Ok(res) => format!("Resp: {:02X?}", res),
+        Err(ProtocolError::NegativeResponse { nrc, .. }) => format!(
+            "Resp: {:02X?}",
+            [0x7F, request.get(0).copied().unwrap_or_default(), *nrc]
+        ),
+        Err(e) => format!("Exec error: {}", e.get_text()),
+    }
+}
+
+/// Quotes a field for CSV, if it contains characters that would break the row
+fn csv_field(s: &str) -> String {
+    if s.contains(|c| c == ',' || c == '"' || c == '\n') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
 }
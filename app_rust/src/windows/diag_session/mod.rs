@@ -6,25 +6,159 @@ use kwp2000_session::KWP2000DiagSession;
 use uds_session::{UDSDiagSession, UDSDiagSessionMsg};
 
 use crate::commapi::{
-    comm_api::{ComServer, ISO15765Config},
-    protocols::ProtocolError,
+    comm_api::{find_active_bitrate, get_can_bitrate, ComServer, ISO15765Config},
+    protocols::{
+        wake_up::{run_wake_up, WakeUpInit},
+        ProtocolError, ProtocolResult, SessionSupport, DTC,
+    },
 };
+use crate::settings::ECUProfile;
 
 use self::{json_session::JsonDiagSessionMsg, kwp2000_session::KWP2000DiagSessionMsg};
 
-use super::diag_manual::DiagManualMessage;
+use super::{diag_home::DiagHomeMessage, diag_manual::DiagManualMessage, window::WindowMessage};
+use log_view::{LogType, LogView};
 
 pub mod custom_session;
+pub mod help;
 pub mod json_session;
 pub mod kwp2000_session;
 pub mod log_view;
 pub mod uds_session;
 
+/// Wraps a session message in the window message that routes it back to the session.
+/// Used to deliver the results of [hw_task](crate::windows::hw_task) operations
+pub(crate) fn to_window_msg(msg: SessionMsg) -> WindowMessage {
+    WindowMessage::DiagHome(DiagHomeMessage::ManualSession(DiagManualMessage::Session(
+        msg,
+    )))
+}
+
+/// Exports the session log if automatic log export is enabled, as the session has ended.
+/// The outcome is added to the log, so the user knows where to find it
+pub(crate) fn auto_export_log(logview: &mut LogView, name: &str) {
+    match logview.auto_export(name) {
+        Some(Ok(path)) => logview.add_msg(
+            format!("Session log exported to {}", path.display()),
+            LogType::Info,
+        ),
+        Some(Err(e)) => logview.add_msg(
+            format!("Error exporting session log: {}", e),
+            LogType::Error,
+        ),
+        None => {}
+    }
+}
+
+/// Returns the DTCs in `current` that were not in `known`, when monitoring an ECU's error codes
+pub(crate) fn find_new_dtcs(known: &[DTC], current: &[DTC]) -> Vec<DTC> {
+    current
+        .iter()
+        .filter(|d| !known.iter().any(|k| k.error == d.error))
+        .cloned()
+        .collect()
+}
+
+/// Sounds the system beep, to get the user's attention when they are not watching the screen
+pub(crate) fn alert_beep() {
+    use std::io::Write;
+    let mut out = std::io::stdout();
+    let _ = out.write_all(b"\x07");
+    let _ = out.flush();
+}
+
+/// Runs the ECU's wake up step (If it has one) before its diagnostic session is started,
+/// returning the log lines of the frames exchanged. If the wake up fails, the session is
+/// still started, as the ECU may already be awake
+pub(crate) fn wake_up_ecu(
+    server: Box<dyn ComServer>,
+    ecu: &ISO15765Config,
+    wake_up: Option<&WakeUpInit>,
+) -> Vec<(String, LogType)> {
+    let init = match wake_up {
+        Some(w) => w,
+        None => return Vec::new(),
+    };
+    let mut logs = vec![(format!("Waking up ECU ({})", init), LogType::Info)];
+    match run_wake_up(server, ecu, init) {
+        Ok(exchange) => logs.extend(exchange.into_iter().map(|line| (line, LogType::Info))),
+        Err(e) => logs.push((
+            format!("Error waking up ECU: {}", e.get_text()),
+            LogType::Warn,
+        )),
+    }
+    logs
+}
+
+/// Checks if the ECU did not respond because the CAN bitrate in the settings does not match
+/// the bus, and if so returns a hint with the bitrate CAN traffic was seen at
+pub(crate) fn find_bitrate_hint(mut server: Box<dyn ComServer>) -> Option<String> {
+    find_active_bitrate(server.as_mut(), 500).map(|b| {
+        format!(
+            "No response at {}kbps, but CAN traffic was seen at {}kbps. Try changing the CAN bitrate in the settings",
+            get_can_bitrate() / 1000,
+            b / 1000
+        )
+    })
+}
+
+/// Logs which diagnostic sessions the ECU supports, as found by probing it
+pub(crate) fn log_session_support(logview: &mut LogView, sessions: &[SessionSupport]) {
+    let supported: Vec<&str> = sessions
+        .iter()
+        .filter(|s| s.is_supported())
+        .map(|s| s.session.as_str())
+        .collect();
+    logview.add_msg(
+        format!("ECU supports sessions: {}", supported.join(", ")),
+        LogType::Info,
+    );
+    for s in sessions.iter().filter(|s| !s.is_supported()) {
+        logview.add_msg(s.to_string(), LogType::Warn)
+    }
+}
+
+/// Logs the DTCs read back from the ECU after clearing them. Permanent DTCs cannot
+/// be cleared by command, so they are not counted as DTCs that failed to clear
+pub(crate) fn log_clear_verification(logview: &mut LogView, remaining: ProtocolResult<Vec<DTC>>) {
+    match remaining {
+        Ok(dtcs) => {
+            let permanent = dtcs.iter().filter(|d| !d.can_clear()).count();
+            let uncleared = dtcs.len() - permanent;
+            if uncleared == 0 {
+                logview.add_msg(
+                    "Verified all clearable ECU errors were cleared",
+                    LogType::Info,
+                )
+            } else {
+                logview.add_msg(
+                    format!("{} ECU errors are still present after clearing", uncleared),
+                    LogType::Warn,
+                )
+            }
+            if permanent > 0 {
+                logview.add_msg(
+                    format!(
+                        "{} permanent ECU errors remain. These are cleared by the ECU once the fault is verified as fixed",
+                        permanent
+                    ),
+                    LogType::Info,
+                )
+            }
+        }
+        Err(e) => logview.add_msg(
+            format!("Could not verify ECU errors were cleared: {}", e.get_text()),
+            LogType::Warn,
+        ),
+    }
+}
+
 pub enum SessionType {
     UDS,
     KWP,
     Custom,
-    JSON(OvdECU),
+    /// ECU definition, and if the session should start even if the ECU's variant is not in it
+    JSON(OvdECU, bool),
 }
 
 #[derive(Debug, Clone)]
@@ -51,6 +185,8 @@ impl DiagMessageTrait for SessionMsg {
 #[derive(Debug)]
 pub enum SessionError {
     ServerError(ProtocolError),
+    /// The ECU does not match the ECU definition the session was started with
+    VariantMismatch(String),
     Other(String),
 }
 
@@ -64,6 +200,7 @@ impl SessionError {
     pub fn get_description(&self) -> String {
         match self {
             Self::ServerError(e) => e.get_text(),
+            Self::VariantMismatch(s) => s.clone(),
             Self::Other(s) => s.clone(),
         }
     }
@@ -84,14 +221,24 @@ impl DiagSession {
         session_type: &SessionType,
         comm_server: Box<dyn ComServer>,
         ecu: ISO15765Config,
+        wake_up: Option<WakeUpInit>,
+        profile: Option<ECUProfile>,
     ) -> SessionResult<Self> {
         Ok(match session_type {
             SessionType::UDS => Self::UDS(UDSDiagSession::new(comm_server, ecu)?),
-            SessionType::KWP => Self::KWP(KWP2000DiagSession::new(comm_server, ecu)?),
-            SessionType::JSON(ecu_data) => {
-                Self::JSON(JsonDiagSession::new(comm_server, ecu, ecu_data.clone())?)
+            SessionType::KWP => {
+                Self::KWP(KWP2000DiagSession::new(comm_server, ecu, wake_up, profile)?)
+            }
+            SessionType::JSON(ecu_data, allow_variant_mismatch) => {
+                Self::JSON(JsonDiagSession::new(
+                    comm_server,
+                    ecu,
+                    ecu_data.clone(),
+                    *allow_variant_mismatch,
+                    wake_up,
+                )?)
             }
-            SessionType::Custom => Self::Custom(CustomDiagSession::new(comm_server, ecu)?),
+            SessionType::Custom => Self::Custom(CustomDiagSession::new(comm_server, ecu, wake_up)?),
         })
     }
 
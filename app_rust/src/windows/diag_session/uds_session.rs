@@ -1,52 +1,409 @@
-use iced::{Column, Subscription};
+use std::time::Instant;
+
+use iced::{time, Column, Container, Length, Row, Space, Subscription};
 
 use crate::{
-    commapi::comm_api::{ComServer, ISO15765Config},
-    themes::{title_text, TitleSize},
-    windows::diag_manual::DiagManualMessage,
+    commapi::{
+        comm_api::{ComServer, ISO15765Config},
+        protocols::{
+            uds::{
+                read_memory_by_address::{read_memory_region, MAX_BLOCK_LEN},
+                UDSECU,
+            },
+            ProtocolServer,
+        },
+    },
+    themes::{button_outlined, text, text_input, title_text, ButtonType, TextType, TitleSize},
+    windows::{
+        file_dialog::{save_file, FileType},
+        hw_task, window,
+        window::WindowMessage,
+    },
+};
+
+use super::{
+    auto_export_log, find_bitrate_hint,
+    log_view::{LogType, LogView},
+    to_window_msg, DiagMessageTrait, SessionMsg, SessionResult, SessionTrait,
 };
 
-use super::{DiagMessageTrait, SessionError, SessionMsg, SessionResult, SessionTrait};
+/// Most bytes of a memory read shown in the log. Larger reads can be saved to a file
+const MAX_LOGGED_BYTES: usize = 256;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone)]
 pub enum UDSDiagSessionMsg {
+    ConnectECU,
+    /// The connected ECU, or the error connecting to it, and a hint at
+    /// the CAN bitrate to use if the ECU did not respond
+    Connected(Result<UDSECU, (String, Option<String>)>),
+    DisconnectECU,
+    PollServer(Instant),
+    EnterAddress(String),
+    EnterAddrBytes(String),
+    EnterLength(String),
+    EnterBlockLen(String),
+    ReadMemory,
+    /// Start address of the memory read, and the bytes read
+    MemoryRead(Result<(u32, Vec<u8>), String>),
+    SaveDump,
+    ClearLogs,
     Back,
 }
 
 impl DiagMessageTrait for UDSDiagSessionMsg {
     fn is_back(&self) -> bool {
-        self == &UDSDiagSessionMsg::Back
+        matches!(self, UDSDiagSessionMsg::Back)
     }
 }
 
+/// Memory region to read from the ECU, as entered by the user
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct MemoryRequest {
+    address: u32,
+    addr_bytes: u8,
+    len: usize,
+    max_block_len: usize,
+}
+
 #[derive(Debug, Clone)]
 pub struct UDSDiagSession {
     ecu: ISO15765Config,
     server: Box<dyn ComServer>,
+    diag_server: Option<UDSECU>,
+    connect_btn: iced::button::State,
+    back_btn: iced::button::State,
+    str_address: String,
+    input_address: iced::text_input::State,
+    str_addr_bytes: String,
+    input_addr_bytes: iced::text_input::State,
+    str_len: String,
+    input_len: iced::text_input::State,
+    str_block_len: String,
+    input_block_len: iced::text_input::State,
+    read_mem_btn: iced::button::State,
+    save_dump_btn: iced::button::State,
+    /// Start address and contents of the last memory read
+    last_dump: Option<(u32, Vec<u8>)>,
+    logview: LogView,
+    /// A hardware operation is running, so no more can be started until it completes
+    busy: bool,
 }
 
 impl UDSDiagSession {
     pub fn new(comm_server: Box<dyn ComServer>, ecu: ISO15765Config) -> SessionResult<Self> {
-        //Err(SessionError::Other("UDS Session is not yet implemented".into()))
         Ok(Self {
             ecu,
             server: comm_server,
+            diag_server: None,
+            connect_btn: Default::default(),
+            back_btn: Default::default(),
+            str_address: String::new(),
+            input_address: Default::default(),
+            str_addr_bytes: "4".into(),
+            input_addr_bytes: Default::default(),
+            str_len: String::new(),
+            input_len: Default::default(),
+            str_block_len: String::new(),
+            input_block_len: Default::default(),
+            read_mem_btn: Default::default(),
+            save_dump_btn: Default::default(),
+            last_dump: None,
+            logview: LogView::new(),
+            busy: false,
         })
     }
+
+    fn task_msg(msg: UDSDiagSessionMsg) -> WindowMessage {
+        to_window_msg(SessionMsg::UDS(msg))
+    }
+
+    /// Name exported logs are saved under
+    fn get_log_name(&self) -> String {
+        format!("uds_{:04X}", self.ecu.send_id)
+    }
+
+    /// Returns the memory region entered by the user, if it is valid. The address is hex,
+    /// and the lengths are decimal. No max block length reads blocks as large as ISO-TP allows
+    fn get_memory_request(&self) -> Option<MemoryRequest> {
+        let address = u32::from_str_radix(
+            self.str_address
+                .trim()
+                .trim_start_matches("0x")
+                .trim_start_matches("0X"),
+            16,
+        )
+        .ok()?;
+        let addr_bytes = self.str_addr_bytes.trim().parse::<u8>().ok()?;
+        let len = self.str_len.trim().parse::<usize>().ok()?;
+        let max_block_len = match self.str_block_len.trim() {
+            "" => MAX_BLOCK_LEN,
+            s => s.parse::<usize>().ok()?,
+        };
+        if !(1..=4).contains(&addr_bytes) || len == 0 || max_block_len == 0 {
+            return None;
+        }
+        Some(MemoryRequest {
+            address,
+            addr_bytes,
+            len,
+            max_block_len,
+        })
+    }
+
+    fn end_session(&mut self) {
+        self.diag_server.take();
+        let name = self.get_log_name();
+        auto_export_log(&mut self.logview, &name);
+        window::enable_home();
+    }
+}
+
+impl Drop for UDSDiagSession {
+    fn drop(&mut self) {
+        if let Some(ref mut session) = self.diag_server {
+            session.exit_diag_session();
+            // Session did not end by disconnecting, so the log has not been exported yet
+            let name = self.get_log_name();
+            auto_export_log(&mut self.logview, &name);
+        }
+    }
 }
 
 impl SessionTrait for UDSDiagSession {
     type msg = UDSDiagSessionMsg;
 
     fn view(&mut self) -> iced::Element<Self::msg> {
-        todo!()
+        let mut ui = Column::new()
+            .spacing(5)
+            .push(title_text("UDS diagnostic session", TitleSize::P3));
+        if self.diag_server.is_none() {
+            let mut connect_btn =
+                button_outlined(&mut self.connect_btn, "Connect ECU", ButtonType::Primary);
+            let mut back_btn = button_outlined(&mut self.back_btn, "Back", ButtonType::Secondary);
+            if !self.busy {
+                connect_btn = connect_btn.on_press(UDSDiagSessionMsg::ConnectECU);
+                back_btn = back_btn.on_press(UDSDiagSessionMsg::Back);
+            }
+            ui = ui.push(connect_btn).push(back_btn);
+        } else {
+            let mut disconnect_btn =
+                button_outlined(&mut self.connect_btn, "Disconnect ECU", ButtonType::Warning);
+            if !self.busy {
+                disconnect_btn = disconnect_btn.on_press(UDSDiagSessionMsg::DisconnectECU);
+            }
+            ui = ui
+                .push(disconnect_btn)
+                .push(text("Read memory by address", TextType::Normal))
+                .push(
+                    Row::new()
+                        .spacing(5)
+                        .push(text_input(
+                            &mut self.input_address,
+                            "Address (Hex)",
+                            &self.str_address,
+                            UDSDiagSessionMsg::EnterAddress,
+                        ))
+                        .push(text_input(
+                            &mut self.input_addr_bytes,
+                            "Address bytes (1-4)",
+                            &self.str_addr_bytes,
+                            UDSDiagSessionMsg::EnterAddrBytes,
+                        )),
+                )
+                .push(
+                    Row::new()
+                        .spacing(5)
+                        .push(text_input(
+                            &mut self.input_len,
+                            "Length (Bytes)",
+                            &self.str_len,
+                            UDSDiagSessionMsg::EnterLength,
+                        ))
+                        .push(text_input(
+                            &mut self.input_block_len,
+                            "ECU max block length",
+                            &self.str_block_len,
+                            UDSDiagSessionMsg::EnterBlockLen,
+                        )),
+                );
+            let mut read_btn =
+                button_outlined(&mut self.read_mem_btn, "Read memory", ButtonType::Secondary);
+            let mut save_btn = button_outlined(
+                &mut self.save_dump_btn,
+                "Save memory to file",
+                ButtonType::Secondary,
+            );
+            if !self.busy && self.get_memory_request().is_some() {
+                read_btn = read_btn.on_press(UDSDiagSessionMsg::ReadMemory);
+            }
+            if self.last_dump.is_some() {
+                save_btn = save_btn.on_press(UDSDiagSessionMsg::SaveDump);
+            }
+            ui = ui.push(Row::new().spacing(5).push(read_btn).push(save_btn));
+        }
+        ui = ui.push(Space::with_height(Length::Fill));
+
+        Row::new()
+            .spacing(8)
+            .padding(8)
+            .push(ui.width(Length::FillPortion(1)))
+            .push(
+                Container::new(self.logview.view(UDSDiagSessionMsg::ClearLogs))
+                    .width(Length::FillPortion(1)),
+            )
+            .into()
     }
 
     fn update(&mut self, msg: &Self::msg) -> Option<Self::msg> {
-        todo!()
+        match msg {
+            UDSDiagSessionMsg::ConnectECU => {
+                self.busy = true;
+                let server = self.server.clone();
+                let ecu = self.ecu;
+                hw_task::run(
+                    move || {
+                        UDSECU::start_diag_session(server.clone(), &ecu).map_err(|e| {
+                            let hint = if e.is_timeout() {
+                                find_bitrate_hint(server)
+                            } else {
+                                None
+                            };
+                            (e.get_text(), hint)
+                        })
+                    },
+                    |res| Self::task_msg(UDSDiagSessionMsg::Connected(res)),
+                );
+            }
+            UDSDiagSessionMsg::Connected(res) => {
+                self.busy = false;
+                match res {
+                    Ok(server) => {
+                        window::disable_home();
+                        self.diag_server = Some(server.clone());
+                        self.logview
+                            .add_msg("Connection to ECU established", LogType::Info)
+                    }
+                    Err((e, hint)) => {
+                        self.logview
+                            .add_msg(format!("Error connecting to ECU ({})", e), LogType::Error);
+                        if let Some(hint) = hint {
+                            self.logview.add_msg(hint, LogType::Warn)
+                        }
+                    }
+                }
+            }
+            UDSDiagSessionMsg::DisconnectECU => {
+                if let Some(ref mut server) = self.diag_server {
+                    server.exit_diag_session()
+                }
+                self.logview
+                    .add_msg("Connection to ECU terminated", LogType::Info);
+                self.end_session();
+            }
+            UDSDiagSessionMsg::PollServer(_) => {
+                let lost = match self.diag_server.as_mut() {
+                    Some(s) if !s.is_in_diag_session() => {
+                        s.exit_diag_session();
+                        Some(s.get_last_error())
+                    }
+                    _ => None,
+                };
+                if let Some(err) = lost {
+                    self.logview
+                        .add_msg("Connection to ECU closed unexpectedly", LogType::Info);
+                    if let Some(desc) = err {
+                        self.logview.add_msg(format!("--> {}", desc), LogType::Info);
+                    }
+                    self.end_session();
+                }
+            }
+            UDSDiagSessionMsg::EnterAddress(s) => self.str_address = s.clone(),
+            UDSDiagSessionMsg::EnterAddrBytes(s) => self.str_addr_bytes = s.clone(),
+            UDSDiagSessionMsg::EnterLength(s) => self.str_len = s.clone(),
+            UDSDiagSessionMsg::EnterBlockLen(s) => self.str_block_len = s.clone(),
+            UDSDiagSessionMsg::ReadMemory => {
+                if let (Some(server), Some(req)) =
+                    (self.diag_server.clone(), self.get_memory_request())
+                {
+                    self.busy = true;
+                    self.logview.add_msg(
+                        format!("Reading {} bytes from 0x{:08X}", req.len, req.address),
+                        LogType::Info,
+                    );
+                    hw_task::run(
+                        move || {
+                            read_memory_region(
+                                &server,
+                                req.address,
+                                req.addr_bytes,
+                                req.len,
+                                req.max_block_len,
+                                |_, _| {},
+                            )
+                            .map(|data| (req.address, data))
+                            .map_err(|e| e.get_text())
+                        },
+                        |res| Self::task_msg(UDSDiagSessionMsg::MemoryRead(res)),
+                    );
+                }
+            }
+            UDSDiagSessionMsg::MemoryRead(res) => {
+                self.busy = false;
+                match res {
+                    Ok((address, data)) => {
+                        for (i, line) in data.chunks(16).take(MAX_LOGGED_BYTES / 16).enumerate() {
+                            self.logview.add_msg(
+                                format!("0x{:08X}: {:02X?}", *address as usize + i * 16, line),
+                                LogType::Info,
+                            )
+                        }
+                        if data.len() > MAX_LOGGED_BYTES {
+                            self.logview.add_msg(
+                                format!(
+                                    "{} more bytes read. Save the memory to a file to see them all",
+                                    data.len() - MAX_LOGGED_BYTES
+                                ),
+                                LogType::Info,
+                            )
+                        }
+                        self.last_dump = Some((*address, data.clone()));
+                    }
+                    Err(e) => self
+                        .logview
+                        .add_msg(format!("Error reading memory: {}", e), LogType::Error),
+                }
+            }
+            UDSDiagSessionMsg::SaveDump => {
+                if let Some((address, data)) = &self.last_dump {
+                    if let Some(path) = save_file(FileType::MemoryDump, "bin") {
+                        match std::fs::write(&path, data) {
+                            Ok(_) => self.logview.add_msg(
+                                format!(
+                                    "Saved {} bytes from 0x{:08X} to {}",
+                                    data.len(),
+                                    address,
+                                    path.display()
+                                ),
+                                LogType::Info,
+                            ),
+                            Err(e) => self
+                                .logview
+                                .add_msg(format!("Error saving memory: {}", e), LogType::Error),
+                        }
+                    }
+                }
+            }
+            UDSDiagSessionMsg::ClearLogs => self.logview.clear_logs(),
+            UDSDiagSessionMsg::Back => {}
+        }
+        None
     }
 
     fn subscription(&self) -> iced::Subscription<Self::msg> {
-        Subscription::none()
+        if self.diag_server.is_some() {
+            time::every(std::time::Duration::from_millis(250)).map(UDSDiagSessionMsg::PollServer)
+        } else {
+            Subscription::none()
+        }
     }
 }
@@ -0,0 +1,113 @@
+use std::path::{Path, PathBuf};
+
+use crate::settings::{get_settings, set_last_file_dir};
+
+// Native file dialogs used by OVD's import and export features. Each type of file remembers
+// the directory it was last opened from or saved to (Kept in the settings), so the user does
+// not have to find the same directory again every time. File types that have not been used
+// yet start in the default directory from the settings, or the OS's choice if there is none.
+
+/// Types of file OVD opens or saves, each with its own remembered directory
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileType {
+    /// List of a vehicle's ECUs
+    VehicleList,
+    /// ECU definition used by a JSON diagnostic session
+    ECUDefinition,
+    /// Layout of a KWP2000 routine's result
+    RoutineLayout,
+    /// Captured CAN traffic
+    CanTrace,
+    /// Known good snapshot of a vehicle
+    Baseline,
+    /// Memory read from an ECU
+    MemoryDump,
+}
+
+impl FileType {
+    /// Name the file type's last directory is saved under in the settings
+    fn get_key(&self) -> &'static str {
+        match self {
+            FileType::VehicleList => "vehicle_list",
+            FileType::ECUDefinition => "ecu_definition",
+            FileType::RoutineLayout => "routine_layout",
+            FileType::CanTrace => "can_trace",
+            FileType::Baseline => "baseline",
+            FileType::MemoryDump => "memory_dump",
+        }
+    }
+
+    /// Extension (Without the '.') the open dialog shows files of this type with
+    fn get_extension(&self) -> &'static str {
+        match self {
+            FileType::VehicleList => "ovdjson",
+            FileType::ECUDefinition => "json",
+            FileType::RoutineLayout => "json",
+            FileType::CanTrace => "asc",
+            FileType::Baseline => "json",
+            FileType::MemoryDump => "bin",
+        }
+    }
+}
+
+/// Returns the directory a dialog for `file_type` starts in. None leaves it up to the OS
+fn get_start_dir(file_type: FileType) -> Option<String> {
+    let mut settings = get_settings();
+    settings
+        .last_file_dirs
+        .remove(file_type.get_key())
+        .into_iter()
+        .chain(std::iter::once(settings.file_dialog_dir))
+        .find(|d| !d.is_empty() && Path::new(d).is_dir())
+}
+
+/// Remembers the directory of a file the user picked, for the next dialog of its type
+fn remember_dir(file_type: FileType, path: &Path) {
+    if let Some(dir) = path.parent().and_then(|d| d.to_str()) {
+        if let Err(e) = set_last_file_dir(file_type.get_key(), dir) {
+            eprintln!(
+                "Could not save the last directory of {:?} files: {}",
+                file_type, e
+            )
+        }
+    }
+}
+
+/// Asks the user for a file of `file_type` to open. None if the user cancelled
+pub fn open_file(file_type: FileType) -> Option<PathBuf> {
+    let start = get_start_dir(file_type);
+    match nfd::open_file_dialog(Some(file_type.get_extension()), start.as_deref()) {
+        Ok(nfd::Response::Okay(p)) => {
+            let path = PathBuf::from(p);
+            remember_dir(file_type, &path);
+            Some(path)
+        }
+        _ => None,
+    }
+}
+
+/// Asks the user where to save a file of `file_type`. If the user does not give the file an
+/// extension, `ext` is used. None if the user cancelled
+pub fn save_file(file_type: FileType, ext: &str) -> Option<PathBuf> {
+    let start = get_start_dir(file_type);
+    match nfd::open_save_dialog(Some(ext), start.as_deref()) {
+        Ok(nfd::Response::Okay(p)) => {
+            let mut path = PathBuf::from(p);
+            if path.extension().is_none() {
+                path.set_extension(ext);
+            }
+            remember_dir(file_type, &path);
+            Some(path)
+        }
+        _ => None,
+    }
+}
+
+/// Asks the user to pick a directory, starting in `start` if it exists. None if the user cancelled
+pub fn pick_dir(start: &str) -> Option<String> {
+    let start = Some(start).filter(|d| !d.is_empty() && Path::new(d).is_dir());
+    match nfd::open_pick_folder(start) {
+        Ok(nfd::Response::Okay(p)) => Some(p),
+        _ => None,
+    }
+}
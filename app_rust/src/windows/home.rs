@@ -12,6 +12,7 @@ pub struct Home {
     can_state: button::State,
     uds_state: button::State,
     obd_state: button::State,
+    settings_state: button::State,
 }
 
 impl Home {
@@ -21,6 +22,7 @@ impl Home {
             can_state: button::State::default(),
             uds_state: button::State::default(),
             obd_state: button::State::default(),
+            settings_state: button::State::default(),
         };
         // To guarantee everything works as it should, home screen should have NO interfaces open
         if let Err(e) = ret.server.close_can_interface() {
@@ -76,6 +78,14 @@ impl Home {
                 .as_str(),
                 TextType::Normal,
             ))
+            .push(text(
+                match cap.get_max_filters() {
+                    Some(max) => format!("Message filters per channel: {}", max),
+                    None => "Message filters per channel: No limit".into(),
+                }
+                .as_str(),
+                TextType::Normal,
+            ))
             .push(title_text("Supported protocols", TitleSize::P3))
             .push(
                 Row::new()
@@ -83,13 +93,15 @@ impl Home {
                     .push(
                         Column::new()
                             .push(text("CAN", TextType::Normal))
+                            .push(text("CAN FD", TextType::Normal))
                             .push(text("ISO-TP", TextType::Normal))
                             .push(text("ISO9141", TextType::Normal))
                             .push(text("ISO14230", TextType::Normal)),
                     )
                     .push(
                         Column::new()
-                            .push(Home::gen_cap_contents(cap.support_can_fd()))
+                            .push(Home::gen_cap_contents(cap.supports_can()))
+                            .push(Home::gen_cap_contents(cap.supports_can_fd()))
                             .push(Home::gen_cap_contents(cap.supports_iso15765()))
                             .push(Home::gen_cap_contents(cap.supports_iso9141()))
                             .push(Home::gen_cap_contents(cap.supports_iso14230())),
@@ -128,6 +140,10 @@ impl Home {
                     .push(
                         button_outlined(&mut self.obd_state, "OBD Tools", ButtonType::Primary)
                             .on_press(WindowMessage::GoOBD),
+                    )
+                    .push(
+                        button_outlined(&mut self.settings_state, "Settings", ButtonType::Secondary)
+                            .on_press(WindowMessage::GoSettings),
                     ),
             );
         contents.into()
@@ -0,0 +1,43 @@
+use std::sync::Mutex;
+
+use iced::Command;
+use lazy_static::lazy_static;
+
+use super::window::WindowMessage;
+
+// Talking to an ECU can take seconds (EG: Slow ECUs, or timeouts), and if done inside of `update`,
+// the UI freezes until the ECU responds. Instead, pages queue hardware operations here, and the
+// main window runs them as iced commands on the executor's thread pool after each update.
+// Once an operation completes, its result is delivered back to the page as a message.
+
+type Task = Box<dyn FnOnce() -> WindowMessage + Send>;
+
+lazy_static! {
+    static ref PENDING_TASKS: Mutex<Vec<Task>> = Mutex::new(Vec::new());
+}
+
+/// Queues a hardware operation to be ran off the UI thread.
+///
+/// ## Params
+/// * op - The hardware operation to run
+/// * on_done - Converts the result of `op` to the message that is delivered back to the page
+pub fn run<T, F, M>(op: F, on_done: M)
+where
+    F: FnOnce() -> T + Send + 'static,
+    M: FnOnce(T) -> WindowMessage + Send + 'static,
+{
+    PENDING_TASKS
+        .lock()
+        .unwrap()
+        .push(Box::new(move || on_done(op())))
+}
+
+/// Takes all the queued hardware operations, as a command that runs them
+pub(crate) fn take_pending() -> Command<WindowMessage> {
+    let tasks: Vec<Task> = PENDING_TASKS.lock().unwrap().drain(..).collect();
+    Command::batch(
+        tasks
+            .into_iter()
+            .map(|t| Command::perform(async move { t() }, |x| x)),
+    )
+}
@@ -1,14 +1,19 @@
 use std::process::Command;
 
+use crate::commapi::adapter::ADAPTER;
+use crate::commapi::can_trace::{import_asc, TraceFrame};
 use crate::commapi::comm_api::{ComServer, ComServerError};
+use crate::commapi::mock_api::MockComServer;
 use crate::commapi::passthru_api::PassthruApi;
+use crate::commapi::replay_api::ReplayComServer;
 use crate::themes::{button_coloured, container, picklist, radio_btn, text, ButtonType, TextType};
+use crate::windows::file_dialog::{open_file, FileType};
 use crate::windows::launcher::LauncherMessage::LaunchRequested;
 use crate::windows::window::ApplicationError::DriverError;
 use crate::windows::window::{ApplicationError, WindowMessage};
 use crate::{
     commapi::socket_can_api::SocketCanAPI,
-    passthru::{PassthruDevice, PassthruDrv},
+    passthru::{force_reset_driver, PassthruDevice, PassthruDrv},
     themes::images::{pix_to_iced_image, LAUNCHER_IMG},
 };
 use iced::{button, pick_list, Align, Column, Element, Image, Length, Row, Text};
@@ -32,6 +37,13 @@ pub struct Launcher {
     api_selection: API,
 
     launch_state: button::State,
+    reset_state: button::State,
+    open_trace_state: button::State,
+
+    /// Frames of the trace to replay, once one has been opened
+    replay_frames: Option<Vec<TraceFrame>>,
+    /// Driver left loaded by resetting an adapter, and the name of the adapter
+    reset_driver: Option<(String, PassthruDrv)>,
 
     status_text: String,
 }
@@ -41,6 +53,8 @@ pub enum API {
     DPdu,
     Passthru,
     SocketCAN,
+    Simulation,
+    Replay,
 }
 
 #[derive(Debug, Clone)]
@@ -48,6 +62,8 @@ pub enum LauncherMessage {
     SwitchAPI(API),
     DeviceSelected(String),
     LaunchRequested,
+    ResetRequested,
+    OpenTrace,
 }
 
 impl ToString for ApplicationError {
@@ -61,7 +77,7 @@ impl ToString for ApplicationError {
 type Result<T> = std::result::Result<T, ApplicationError>;
 impl Launcher {
     pub fn new() -> Self {
-        let passthru_devices = PassthruDevice::find_all().unwrap_or_default();
+        let passthru_devices = ADAPTER.get_device_list();
         let passthru_device_names: Vec<String> =
             passthru_devices.iter().map(|d| d.name.clone()).collect();
         let selected_passthru_device: String =
@@ -84,6 +100,10 @@ impl Launcher {
             selection: pick_list::State::default(),
             api_selection: API::Passthru,
             launch_state: button::State::default(),
+            reset_state: button::State::default(),
+            open_trace_state: button::State::default(),
+            replay_frames: None,
+            reset_driver: None,
             status_text: "".into(),
         }
     }
@@ -107,27 +127,65 @@ impl Launcher {
                 if self.api_selection == API::Passthru {
                     match self.get_device_passthru() {
                         Ok((details, driver)) => {
-                            let mut server = PassthruApi::new(details, driver);
-                            if let Err(e) = server.open_device() {
-                                self.status_text = e.to_string()
-                            } else {
-                                // Ready to launch OVD!
-                                return Some(WindowMessage::StartApp(server.clone_box()));
-                            }
+                            return self.launch(Box::new(PassthruApi::new(details, driver)))
                         }
                         Err(x) => self.status_text = x.to_string(),
                     }
                 } else if self.api_selection == API::DPdu {
                     // TODO D-PDU Launching
+                } else if self.api_selection == API::Simulation {
+                    return self.launch(Box::new(MockComServer::new()));
+                } else if self.api_selection == API::Replay {
+                    if let Some(frames) = self.replay_frames.clone() {
+                        return self.launch(Box::new(ReplayComServer::new(frames)));
+                    }
                 } else if self.api_selection == API::SocketCAN {
                     #[cfg(target_os = "linux")]
                     {
-                        let mut server = SocketCanAPI::new(self.selected_device_socketcan.clone());
-                        if let Err(e) = server.open_device() {
-                            self.status_text = e.to_string()
-                        } else {
-                            // Ready to launch OVD!
-                            return Some(WindowMessage::StartApp(server.clone_box()));
+                        let server = SocketCanAPI::new(self.selected_device_socketcan.clone());
+                        return self.launch(Box::new(server));
+                    }
+                }
+            }
+            LauncherMessage::ResetRequested => {
+                if let Some(d) = self
+                    .device_list_passthru
+                    .iter()
+                    .find(|d| d.name == self.selected_device_passthru)
+                {
+                    self.status_text = match force_reset_driver(d) {
+                        Ok((drv, 0)) => {
+                            self.reset_driver = Some((d.name.clone(), drv));
+                            "No handles left open by OVD were found. \
+                            If the adapter is still stuck, reconnect it"
+                                .into()
+                        }
+                        Ok((drv, closed)) => {
+                            self.reset_driver = Some((d.name.clone(), drv));
+                            format!(
+                                "Adapter reset complete, closed {} handle(s). Try launching again",
+                                closed
+                            )
+                        }
+                        Err(e) => format!("Adapter reset failed: {}", e.get_err_desc()),
+                    }
+                }
+            }
+            LauncherMessage::OpenTrace => {
+                if let Some(f_path) = open_file(FileType::CanTrace) {
+                    match std::fs::read_to_string(&f_path) {
+                        Ok(text) => {
+                            let res = import_asc(&text);
+                            self.status_text = res.to_string();
+                            self.replay_frames = if res.frames.is_empty() {
+                                None
+                            } else {
+                                Some(res.frames)
+                            };
+                        }
+                        Err(e) => {
+                            self.status_text = format!("Cannot read {}: {}", f_path.display(), e);
+                            self.replay_frames = None;
                         }
                     }
                 }
@@ -157,6 +215,22 @@ impl Launcher {
             .spacing(10)
             .align_items(Align::Center);
 
+        selection = selection.push(radio_btn(
+            API::Simulation,
+            "Simulation mode",
+            Some(self.api_selection),
+            LauncherMessage::SwitchAPI,
+            ButtonType::Primary,
+        ));
+
+        selection = selection.push(radio_btn(
+            API::Replay,
+            "Trace replay",
+            Some(self.api_selection),
+            LauncherMessage::SwitchAPI,
+            ButtonType::Primary,
+        ));
+
         #[cfg(target_os = "linux")] // Only available on Linux
         {
                 selection = selection.push(radio_btn(
@@ -180,6 +254,46 @@ impl Launcher {
                     "D-PDU API is unimplemented, check back in a future release!",
                 ))
                 .spacing(10)
+        } else if self.api_selection == API::Simulation {
+            Column::new()
+                .push(
+                    pix_to_iced_image(LAUNCHER_IMG)
+                        .width(Length::Units(300))
+                        .height(Length::Units(300)),
+                )
+                .push(selection)
+                .push(Text::new(
+                    "Simulation mode uses a simulated vehicle, no adapter or car is required",
+                ))
+                .push(
+                    button_coloured(&mut self.launch_state, "Launch OVD", ButtonType::Primary)
+                        .on_press(LaunchRequested),
+                )
+                .push(Text::new(&self.status_text))
+                .spacing(10)
+        } else if self.api_selection == API::Replay {
+            let mut launch_btn =
+                button_coloured(&mut self.launch_state, "Launch OVD", ButtonType::Primary);
+            if self.replay_frames.is_some() {
+                launch_btn = launch_btn.on_press(LaunchRequested);
+            }
+            Column::new()
+                .push(
+                    pix_to_iced_image(LAUNCHER_IMG)
+                        .width(Length::Units(300))
+                        .height(Length::Units(300)),
+                )
+                .push(selection)
+                .push(Text::new(
+                    "Trace replay plays back a CAN trace (Vector ASC) in the CAN tracer, no adapter or car is required",
+                ))
+                .push(
+                    button_coloured(&mut self.open_trace_state, "Open trace", ButtonType::Info)
+                        .on_press(LauncherMessage::OpenTrace),
+                )
+                .push(launch_btn)
+                .push(Text::new(&self.status_text))
+                .spacing(10)
         } else if self.api_selection == API::SocketCAN {
             let mut c = Column::new()
                 .push(
@@ -248,6 +362,14 @@ impl Launcher {
                         button_coloured(&mut self.launch_state, "Launch OVD", ButtonType::Primary)
                             .on_press(LaunchRequested),
                     )
+                    .push(
+                        button_coloured(
+                            &mut self.reset_state,
+                            "Reset adapter",
+                            ButtonType::Warning,
+                        )
+                        .on_press(LauncherMessage::ResetRequested),
+                    )
                     .push(Text::new(&self.status_text));
             }
             c.align_items(Align::Center)
@@ -260,18 +382,35 @@ impl Launcher {
             .into()
     }
 
-    fn get_device_passthru(&self) -> Result<(PassthruDevice, PassthruDrv)> {
+    /// Connects to the adapter, and launches OVD with it if it could be opened
+    fn launch(&mut self, server: Box<dyn ComServer>) -> Option<WindowMessage> {
+        match ADAPTER.connect_device(server) {
+            // Ready to launch OVD!
+            Ok(server) => Some(WindowMessage::StartApp(server)),
+            Err(e) => {
+                self.status_text = e.to_string();
+                None
+            }
+        }
+    }
+
+    fn get_device_passthru(&mut self) -> Result<(PassthruDevice, PassthruDrv)> {
+        let reset_driver = self.reset_driver.take();
         match self
             .device_list_passthru
             .iter()
             .find(|d| d.name == self.selected_device_passthru)
         {
-            Some(d) => match PassthruDrv::load_lib(d.drv_path.clone()) {
-                Ok(lib) => Ok((d.clone(), lib)),
-                Err(_) => Err(DriverError(ComServerError {
-                    err_code: 99,
-                    err_desc: format!("Cannot locate driver at {}", d.drv_path),
-                })),
+            Some(d) => match reset_driver {
+                // Use the driver the adapter was reset with, rather than loading it again
+                Some((name, drv)) if name == d.name => Ok((d.clone(), drv)),
+                _ => match PassthruDrv::load_lib(d.drv_path.clone()) {
+                    Ok(lib) => Ok((d.clone(), lib)),
+                    Err(_) => Err(DriverError(ComServerError {
+                        err_code: 99,
+                        err_desc: format!("Cannot locate driver at {}", d.drv_path),
+                    })),
+                },
             },
             // This should NEVER happen.
             None => Err(DriverError(ComServerError {
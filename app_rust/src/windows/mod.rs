@@ -3,7 +3,10 @@ pub(crate) mod diag_home;
 pub(crate) mod diag_manual;
 pub(crate) mod diag_scanner;
 pub(crate) mod diag_session;
+pub(crate) mod file_dialog;
 pub(crate) mod home;
+pub(crate) mod hw_task;
 pub(crate) mod launcher;
 pub(crate) mod obd;
+pub(crate) mod settings;
 pub mod window;
@@ -1,12 +1,21 @@
 use crate::commapi::comm_api::{Capability, ComServer};
-use crate::commapi::protocols::obd2::{Service01, Service09};
+use crate::commapi::protocols::obd2::{
+    pids::{PidValue, SERVICE01_PIDS},
+    read_write_payload_all,
+    readiness::Readiness,
+    OBDRequest, OBDResponse, Service01, Service03, Service07, Service09, Service0A,
+};
 use crate::commapi::protocols::vin::Vin;
+use crate::commapi::protocols::DTC;
 use crate::themes::{button_outlined, text, title_text, ButtonType, TextType, TitleSize};
 use iced::{button, Align, Button, Column, Element, Length, Row, Space, Text};
+use std::collections::BTreeMap;
 
 #[derive(Debug, Clone)]
 pub enum OBDMessage {
     InitOBD,
+    ReadLiveData,
+    ReadReadiness,
 }
 
 #[derive(Debug, Clone)]
@@ -14,9 +23,17 @@ pub struct OBDHome {
     server: Box<dyn ComServer>,
     kline_state: button::State,
     can_state: button::State,
+    live_data_state: button::State,
+    readiness_state: button::State,
     vin: Option<Vin>,
     s1: Option<Service01>,
     s9: Option<Service09>,
+    responding_ecus: BTreeMap<u32, OBDResponse>,
+    dtcs: Vec<DTC>,
+    /// Last read value of each supported PID with a known scaling
+    live_data: Vec<PidValue>,
+    /// Emissions readiness, if the ECU supports PID 01
+    readiness: Option<Readiness>,
 }
 
 impl OBDHome {
@@ -25,16 +42,27 @@ impl OBDHome {
             server,
             kline_state: Default::default(),
             can_state: Default::default(),
+            live_data_state: Default::default(),
+            readiness_state: Default::default(),
             vin: None,
             s1: None,
             s9: None,
+            responding_ecus: BTreeMap::new(),
+            dtcs: Vec::new(),
+            live_data: Vec::new(),
+            readiness: None,
         }
     }
 
     pub fn update(&mut self, msg: &OBDMessage) -> Option<OBDMessage> {
         match msg {
             OBDMessage::InitOBD => {
+                // Ask every ECU on the bus which Service 01 PIDs it supports
+                self.responding_ecus =
+                    read_write_payload_all(&mut self.server, true, &OBDRequest::new(0x01, 0x00))
+                        .unwrap_or_default();
                 if let Ok(s1) = Service01::init(&mut self.server, true) {
+                    self.readiness = s1.get_readiness(&mut self.server, true).ok();
                     self.s1 = Some(s1)
                 }
                 if let Ok(s9) = Service09::init(&mut self.server, true) {
@@ -43,11 +71,80 @@ impl OBDHome {
                     }
                     self.s9 = Some(s9)
                 }
+                // Stored, pending and permanent DTCs
+                self.dtcs.clear();
+                self.dtcs
+                    .extend(Service03::get_error_codes(&mut self.server, true).unwrap_or_default());
+                self.dtcs.extend(
+                    Service07::get_pending_codes(&mut self.server, true).unwrap_or_default(),
+                );
+                self.dtcs.extend(
+                    Service0A::get_permanent_codes(&mut self.server, true).unwrap_or_default(),
+                );
+            }
+            OBDMessage::ReadLiveData => {
+                if let Some(s1) = self.s1 {
+                    let supported = s1.get_supported_pids();
+                    self.live_data = SERVICE01_PIDS
+                        .iter()
+                        .filter(|p| supported.contains(&p.pid))
+                        .filter_map(|p| s1.read_pid(&mut self.server, true, p.pid).ok())
+                        .collect();
+                }
+            }
+            OBDMessage::ReadReadiness => {
+                if let Some(s1) = self.s1 {
+                    self.readiness = s1.get_readiness(&mut self.server, true).ok();
+                }
             }
         }
         None
     }
 
+    /// Shows the MIL status, and which readiness monitors have completed since DTCs were cleared
+    fn readiness_view<'a>(r: &Readiness) -> Column<'a, OBDMessage> {
+        let mut c = Column::new().spacing(5).align_items(Align::Center);
+        c = c.push(text(
+            format!(
+                "Check engine light (MIL): {}",
+                if r.mil_on { "On" } else { "Off" }
+            )
+            .as_str(),
+            if r.mil_on {
+                TextType::Danger
+            } else {
+                TextType::Success
+            },
+        ));
+        c = c.push(text(
+            format!("Emissions DTCs stored: {}", r.dtc_count).as_str(),
+            TextType::Normal,
+        ));
+        c = c.push(text(
+            format!("Engine type: {}", r.ignition).as_str(),
+            TextType::Normal,
+        ));
+        for m in r.monitors.iter() {
+            let (status, ttype) = if m.complete {
+                ("Complete", TextType::Success)
+            } else {
+                ("Incomplete", TextType::Warning)
+            };
+            c = c.push(text(format!("{}: {}", m.name, status).as_str(), ttype));
+        }
+        let incomplete = r.get_incomplete().len();
+        c.push(if incomplete == 0 && !r.mil_on {
+            text("All monitors complete. Ready for an emissions test", TextType::Success)
+        } else if incomplete == 0 {
+            text("All monitors complete, but the MIL is on", TextType::Danger)
+        } else {
+            text(
+                format!("{} monitor(s) incomplete. Drive the vehicle to let them run before an emissions test", incomplete).as_str(),
+                TextType::Warning,
+            )
+        })
+    }
+
     pub fn view(&mut self) -> Element<OBDMessage> {
         let obd_btn = button_outlined(
             &mut self.kline_state,
@@ -108,6 +205,62 @@ impl OBDHome {
                 pid_row = pid_row.push(Text::new(format!("{:02X} ", pid)));
             }
             c = c.push(pid_row);
+
+            c = c.push(Space::with_height(Length::Units(10)));
+            c = c.push(title_text("Live data", TitleSize::P4));
+            c = c.push(
+                button_outlined(
+                    &mut self.live_data_state,
+                    "Read live data",
+                    ButtonType::Primary,
+                )
+                .on_press(OBDMessage::ReadLiveData),
+            );
+            for value in self.live_data.iter() {
+                c = c.push(text(value.to_string().as_str(), TextType::Normal));
+            }
+
+            c = c.push(Space::with_height(Length::Units(10)));
+            c = c.push(title_text("Emissions readiness", TitleSize::P4));
+            c = c.push(
+                button_outlined(
+                    &mut self.readiness_state,
+                    "Read readiness",
+                    ButtonType::Primary,
+                )
+                .on_press(OBDMessage::ReadReadiness),
+            );
+            match &self.readiness {
+                Some(r) => c = c.push(Self::readiness_view(r)),
+                None => c = c.push(text("Readiness (PID 01) not supported", TextType::Disabled)),
+            }
+        }
+
+        if !self.responding_ecus.is_empty() {
+            c = c.push(Space::with_height(Length::Units(10)));
+            c = c.push(title_text("Responding ECUs", TitleSize::P4));
+            for (id, resp) in self.responding_ecus.iter() {
+                c = c.push(text(
+                    format!("ECU 0x{:04X}: {:02X?}", id, resp.get_data()).as_str(),
+                    TextType::Normal,
+                ));
+            }
+        }
+        if !self.dtcs.is_empty() {
+            c = c.push(Space::with_height(Length::Units(10)));
+            c = c.push(title_text("Diagnostic trouble codes", TitleSize::P4));
+            for dtc in self.dtcs.iter() {
+                c = c.push(text(
+                    format!("{} ({})", dtc.error, dtc.category).as_str(),
+                    TextType::Normal,
+                ));
+            }
+            if self.dtcs.iter().any(|d| !d.can_clear()) {
+                c = c.push(text(
+                    "Permanent codes cannot be cleared by command. The ECU clears them once it has verified the fault is gone",
+                    TextType::Warning,
+                ));
+            }
         }
         c.width(Length::Fill).align_items(Align::Center).into()
     }
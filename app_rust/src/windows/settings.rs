@@ -0,0 +1,419 @@
+use crate::hw_log;
+use crate::settings::{get_settings, set_settings, LogExportFormat, Settings};
+use crate::themes::{
+    button_coloured, picklist, set_dark_theme, set_light_theme, text, text_input, title_text,
+    ButtonType, TextType, TitleSize,
+};
+use crate::windows::file_dialog::pick_dir;
+use iced::{button, pick_list, text_input, Align, Checkbox, Column, Element, Length, Row};
+
+#[derive(Debug, Clone)]
+pub enum SettingsMessage {
+    ToggleDarkTheme(bool),
+    ToggleHwLogging(bool),
+    ToggleDtcAlertBanner(bool),
+    ToggleDtcAlertBeep(bool),
+    ToggleAutoExport(bool),
+    ExportFormatSelected(LogExportFormat),
+    TimeoutEnter(String),
+    MultiFrameTimeoutEnter(String),
+    LogDirEnter(String),
+    BrowseLogDir,
+    FileDialogDirEnter(String),
+    BrowseFileDialogDir,
+    PollIntervalEnter(String),
+    DtcMonitorIntervalEnter(String),
+    ResetGapEnter(String),
+    FunctionalIdEnter(String),
+    RetriesEnter(String),
+    BitrateEnter(String),
+    Save,
+    Reset,
+}
+
+#[derive(Debug, Clone)]
+pub struct SettingsWindow {
+    dark_theme: bool,
+    verbose_hw_logging: bool,
+    dtc_alert_banner: bool,
+    dtc_alert_beep: bool,
+    auto_export_logs: bool,
+    log_export_format: LogExportFormat,
+    export_format_list: pick_list::State<LogExportFormat>,
+
+    str_timeout: String,
+    input_timeout: text_input::State,
+
+    str_mf_timeout: String,
+    input_mf_timeout: text_input::State,
+
+    str_log_dir: String,
+    input_log_dir: text_input::State,
+    browse_log_dir: button::State,
+
+    str_file_dialog_dir: String,
+    input_file_dialog_dir: text_input::State,
+    browse_file_dialog_dir: button::State,
+
+    str_poll: String,
+    input_poll: text_input::State,
+
+    str_dtc_monitor: String,
+    input_dtc_monitor: text_input::State,
+
+    str_reset_gap: String,
+    input_reset_gap: text_input::State,
+
+    str_functional_id: String,
+    input_functional_id: text_input::State,
+
+    str_retries: String,
+    input_retries: text_input::State,
+
+    str_bitrate: String,
+    input_bitrate: text_input::State,
+
+    save_state: button::State,
+    reset_state: button::State,
+    status: String,
+}
+
+impl SettingsWindow {
+    pub(crate) fn new() -> Self {
+        let mut ret = Self {
+            dark_theme: false,
+            verbose_hw_logging: false,
+            dtc_alert_banner: false,
+            dtc_alert_beep: false,
+            auto_export_logs: false,
+            log_export_format: LogExportFormat::Csv,
+            export_format_list: Default::default(),
+            str_timeout: "".into(),
+            input_timeout: Default::default(),
+            str_mf_timeout: "".into(),
+            input_mf_timeout: Default::default(),
+            str_log_dir: "".into(),
+            input_log_dir: Default::default(),
+            browse_log_dir: Default::default(),
+            str_file_dialog_dir: "".into(),
+            input_file_dialog_dir: Default::default(),
+            browse_file_dialog_dir: Default::default(),
+            str_poll: "".into(),
+            input_poll: Default::default(),
+            str_dtc_monitor: "".into(),
+            input_dtc_monitor: Default::default(),
+            str_reset_gap: "".into(),
+            input_reset_gap: Default::default(),
+            str_functional_id: "".into(),
+            input_functional_id: Default::default(),
+            str_retries: "".into(),
+            input_retries: Default::default(),
+            str_bitrate: "".into(),
+            input_bitrate: Default::default(),
+            save_state: Default::default(),
+            reset_state: Default::default(),
+            status: "".into(),
+        };
+        ret.load_from(&get_settings());
+        ret
+    }
+
+    fn load_from(&mut self, s: &Settings) {
+        self.dark_theme = s.dark_theme;
+        self.verbose_hw_logging = s.verbose_hw_logging;
+        self.str_timeout = format!("{}", s.cmd_timeout_ms);
+        self.str_mf_timeout = format!("{}", s.multi_frame_timeout_ms);
+        self.str_log_dir = s.log_dir.clone();
+        self.str_file_dialog_dir = s.file_dialog_dir.clone();
+        self.str_poll = format!("{}", s.poll_interval_ms);
+        self.dtc_alert_banner = s.dtc_alert_banner;
+        self.dtc_alert_beep = s.dtc_alert_beep;
+        self.auto_export_logs = s.auto_export_logs;
+        self.log_export_format = s.log_export_format;
+        self.str_dtc_monitor = format!("{}", s.dtc_monitor_interval_ms);
+        self.str_reset_gap = format!("{}", s.reset_detect_gap_ms);
+        self.str_functional_id = format!("{:04X}", s.functional_send_id);
+        self.str_retries = format!("{}", s.cmd_retries);
+        self.str_bitrate = format!("{}", s.can_bitrate / 1000);
+    }
+
+    pub fn update(&mut self, msg: &SettingsMessage) -> Option<SettingsMessage> {
+        match msg {
+            SettingsMessage::ToggleDarkTheme(b) => self.dark_theme = *b,
+            SettingsMessage::ToggleHwLogging(b) => self.verbose_hw_logging = *b,
+            SettingsMessage::TimeoutEnter(s) => self.str_timeout = s.clone(),
+            SettingsMessage::MultiFrameTimeoutEnter(s) => self.str_mf_timeout = s.clone(),
+            SettingsMessage::LogDirEnter(s) => self.str_log_dir = s.clone(),
+            SettingsMessage::BrowseLogDir => {
+                if let Some(dir) = pick_dir(&self.str_log_dir) {
+                    self.str_log_dir = dir
+                }
+            }
+            SettingsMessage::FileDialogDirEnter(s) => self.str_file_dialog_dir = s.clone(),
+            SettingsMessage::BrowseFileDialogDir => {
+                if let Some(dir) = pick_dir(&self.str_file_dialog_dir) {
+                    self.str_file_dialog_dir = dir
+                }
+            }
+            SettingsMessage::PollIntervalEnter(s) => self.str_poll = s.clone(),
+            SettingsMessage::ToggleDtcAlertBanner(b) => self.dtc_alert_banner = *b,
+            SettingsMessage::ToggleDtcAlertBeep(b) => self.dtc_alert_beep = *b,
+            SettingsMessage::ToggleAutoExport(b) => self.auto_export_logs = *b,
+            SettingsMessage::ExportFormatSelected(f) => self.log_export_format = *f,
+            SettingsMessage::DtcMonitorIntervalEnter(s) => self.str_dtc_monitor = s.clone(),
+            SettingsMessage::ResetGapEnter(s) => self.str_reset_gap = s.clone(),
+            SettingsMessage::FunctionalIdEnter(s) => self.str_functional_id = s.clone(),
+            SettingsMessage::RetriesEnter(s) => self.str_retries = s.clone(),
+            SettingsMessage::BitrateEnter(s) => self.str_bitrate = s.clone(),
+            SettingsMessage::Reset => {
+                self.load_from(&Settings::default());
+                self.status = "Defaults restored. Press save to apply".into();
+            }
+            SettingsMessage::Save => {
+                let mut s = get_settings();
+                s.dark_theme = self.dark_theme;
+                s.log_dir = self.str_log_dir.clone();
+                s.file_dialog_dir = self.str_file_dialog_dir.trim().to_string();
+                s.verbose_hw_logging = self.verbose_hw_logging;
+                s.dtc_alert_banner = self.dtc_alert_banner;
+                s.dtc_alert_beep = self.dtc_alert_beep;
+                s.auto_export_logs = self.auto_export_logs;
+                s.log_export_format = self.log_export_format;
+                match self.str_timeout.parse::<u64>() {
+                    Ok(t) => s.cmd_timeout_ms = t,
+                    Err(_) => {
+                        self.status = "Command timeout is not a valid number".into();
+                        return None;
+                    }
+                }
+                match self.str_mf_timeout.parse::<u64>() {
+                    Ok(t) => s.multi_frame_timeout_ms = t,
+                    Err(_) => {
+                        self.status = "Multi-frame timeout is not a valid number".into();
+                        return None;
+                    }
+                }
+                match self.str_poll.parse::<u64>() {
+                    Ok(p) => s.poll_interval_ms = p,
+                    Err(_) => {
+                        self.status = "Poll interval is not a valid number".into();
+                        return None;
+                    }
+                }
+                match self.str_dtc_monitor.parse::<u64>() {
+                    Ok(p) if p > 0 => s.dtc_monitor_interval_ms = p,
+                    _ => {
+                        self.status = "Error code monitor interval is not a valid number".into();
+                        return None;
+                    }
+                }
+                match self.str_reset_gap.parse::<u64>() {
+                    Ok(g) => s.reset_detect_gap_ms = g,
+                    Err(_) => {
+                        self.status = "ECU reset detection gap is not a valid number".into();
+                        return None;
+                    }
+                }
+                match u32::from_str_radix(self.str_functional_id.trim(), 16) {
+                    Ok(id) if id <= 0x1FFFFFFF => s.functional_send_id = id,
+                    _ => {
+                        self.status = "Functional request ID is not a valid CAN ID".into();
+                        return None;
+                    }
+                }
+                match self.str_retries.parse::<u32>() {
+                    Ok(r) => s.cmd_retries = r,
+                    Err(_) => {
+                        self.status = "Command retries is not a valid number".into();
+                        return None;
+                    }
+                }
+                match self.str_bitrate.parse::<u32>() {
+                    Ok(b) if b > 0 && b <= 1000 => s.can_bitrate = b * 1000,
+                    _ => {
+                        self.status = "CAN bitrate must be between 1 and 1000kbps".into();
+                        return None;
+                    }
+                }
+                match s.dark_theme {
+                    true => set_dark_theme(),
+                    false => set_light_theme(),
+                }
+                hw_log::configure(&s);
+                self.status = match set_settings(s) {
+                    Ok(_) => "Settings saved".into(),
+                    Err(e) => format!("Error saving settings: {}", e),
+                }
+            }
+        }
+        None
+    }
+
+    pub fn view(&mut self) -> Element<SettingsMessage> {
+        Column::new()
+            .padding(10)
+            .spacing(10)
+            .align_items(Align::Center)
+            .push(title_text("Settings", TitleSize::P2))
+            .push(Checkbox::new(
+                self.dark_theme,
+                "Use dark theme",
+                SettingsMessage::ToggleDarkTheme,
+            ))
+            .push(text("ECU command timeout (ms)", TextType::Normal))
+            .push(text_input(
+                &mut self.input_timeout,
+                "1000",
+                &self.str_timeout,
+                SettingsMessage::TimeoutEnter,
+            ))
+            .push(text(
+                "Multi-frame response timeout (ms, after first frame)",
+                TextType::Normal,
+            ))
+            .push(text_input(
+                &mut self.input_mf_timeout,
+                "2000",
+                &self.str_mf_timeout,
+                SettingsMessage::MultiFrameTimeoutEnter,
+            ))
+            .push(text(
+                "Treat an ECU as reset if it stops responding for (ms, 0 to disable)",
+                TextType::Normal,
+            ))
+            .push(text_input(
+                &mut self.input_reset_gap,
+                "2500",
+                &self.str_reset_gap,
+                SettingsMessage::ResetGapEnter,
+            ))
+            .push(text(
+                "Functional (broadcast) request CAN ID (hex)",
+                TextType::Normal,
+            ))
+            .push(text_input(
+                &mut self.input_functional_id,
+                "07DF",
+                &self.str_functional_id,
+                SettingsMessage::FunctionalIdEnter,
+            ))
+            .push(text(
+                "Retries of read commands that time out or fail to send",
+                TextType::Normal,
+            ))
+            .push(text_input(
+                &mut self.input_retries,
+                "2",
+                &self.str_retries,
+                SettingsMessage::RetriesEnter,
+            ))
+            .push(text("CAN bitrate (kbps)", TextType::Normal))
+            .push(text_input(
+                &mut self.input_bitrate,
+                "500",
+                &self.str_bitrate,
+                SettingsMessage::BitrateEnter,
+            ))
+            .push(text("Log directory", TextType::Normal))
+            .push(
+                Row::new()
+                    .spacing(10)
+                    .push(text_input(
+                        &mut self.input_log_dir,
+                        ".",
+                        &self.str_log_dir,
+                        SettingsMessage::LogDirEnter,
+                    ))
+                    .push(
+                        button_coloured(&mut self.browse_log_dir, "Browse", ButtonType::Info)
+                            .on_press(SettingsMessage::BrowseLogDir),
+                    ),
+            )
+            .push(text(
+                "Default directory to open and save files in (Empty for the OS default)",
+                TextType::Normal,
+            ))
+            .push(
+                Row::new()
+                    .spacing(10)
+                    .push(text_input(
+                        &mut self.input_file_dialog_dir,
+                        "",
+                        &self.str_file_dialog_dir,
+                        SettingsMessage::FileDialogDirEnter,
+                    ))
+                    .push(
+                        button_coloured(
+                            &mut self.browse_file_dialog_dir,
+                            "Browse",
+                            ButtonType::Info,
+                        )
+                        .on_press(SettingsMessage::BrowseFileDialogDir),
+                    ),
+            )
+            .push(
+                Row::new()
+                    .spacing(10)
+                    .align_items(Align::Center)
+                    .push(Checkbox::new(
+                        self.auto_export_logs,
+                        "Export session logs to the log directory when a session ends, as",
+                        SettingsMessage::ToggleAutoExport,
+                    ))
+                    .push(picklist(
+                        &mut self.export_format_list,
+                        &LogExportFormat::ALL[..],
+                        Some(self.log_export_format),
+                        SettingsMessage::ExportFormatSelected,
+                    )),
+            )
+            .push(Checkbox::new(
+                self.verbose_hw_logging,
+                "Verbose hardware logging (Records every adapter call in the log directory)",
+                SettingsMessage::ToggleHwLogging,
+            ))
+            .push(text("Battery voltage poll interval (ms)", TextType::Normal))
+            .push(text_input(
+                &mut self.input_poll,
+                "2000",
+                &self.str_poll,
+                SettingsMessage::PollIntervalEnter,
+            ))
+            .push(text("Error code monitor interval (ms)", TextType::Normal))
+            .push(text_input(
+                &mut self.input_dtc_monitor,
+                "5000",
+                &self.str_dtc_monitor,
+                SettingsMessage::DtcMonitorIntervalEnter,
+            ))
+            .push(Checkbox::new(
+                self.dtc_alert_banner,
+                "Show a banner when monitoring finds a new error code",
+                SettingsMessage::ToggleDtcAlertBanner,
+            ))
+            .push(Checkbox::new(
+                self.dtc_alert_beep,
+                "Beep when monitoring finds a new error code",
+                SettingsMessage::ToggleDtcAlertBeep,
+            ))
+            .push(
+                Row::new()
+                    .spacing(10)
+                    .push(
+                        button_coloured(&mut self.save_state, "Save", ButtonType::Success)
+                            .on_press(SettingsMessage::Save),
+                    )
+                    .push(
+                        button_coloured(
+                            &mut self.reset_state,
+                            "Restore defaults",
+                            ButtonType::Warning,
+                        )
+                        .on_press(SettingsMessage::Reset),
+                    ),
+            )
+            .push(text(&self.status, TextType::Normal))
+            .width(Length::Units(500))
+            .into()
+    }
+}
@@ -4,18 +4,21 @@ use crate::windows::diag_home::DiagHomeMessage;
 use crate::windows::home::{Home, HomeMessage};
 use crate::windows::launcher::{Launcher, LauncherMessage};
 use crate::windows::obd::{OBDHome, OBDMessage};
+use crate::windows::settings::{SettingsMessage, SettingsWindow};
 use crate::{
+    commapi::adapter::ADAPTER,
     commapi::comm_api::{Capability, ComServer, ComServerError},
-    themes, WIN_HEIGHT,
+    settings, themes, WIN_HEIGHT,
 };
 use iced::{
     button, executor, time, Align, Application, Column, Command, Container, Element, Length, Row,
     Rule, Space, Subscription, Text,
 };
 use std::fmt::Debug;
+use std::io::Write;
 use std::time::Instant;
 
-use super::diag_home::DiagHome;
+use super::{diag_home::DiagHome, hw_task};
 
 // This can be modified by diagnostic sessions in order to disable going
 // home option in case a sensitive operation is in progress!
@@ -45,6 +48,9 @@ pub enum WindowState {
     CanTracer(CanTracer),
     DiagHome(DiagHome),
     OBDTools(OBDHome),
+    Settings(SettingsWindow),
+    /// OVD is exiting. Replaces the current page, so it is dropped
+    Closing,
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -54,6 +60,8 @@ pub enum WindowStateName {
     CanTracer,
     DiagHome,
     OBDTools,
+    Settings,
+    Closing,
 }
 
 impl<'a> WindowState {
@@ -64,6 +72,8 @@ impl<'a> WindowState {
             Self::CanTracer(tracer) => tracer.view().map(WindowMessage::CanTracer),
             Self::DiagHome(h) => h.view().map(WindowMessage::DiagHome),
             Self::OBDTools(h) => h.view().map(WindowMessage::OBDTools),
+            Self::Settings(s) => s.view().map(WindowMessage::Settings),
+            Self::Closing => Space::new(Length::Fill, Length::Fill).into(),
         }
     }
 
@@ -94,6 +104,12 @@ impl<'a> WindowState {
                     return o.update(x).map(WindowMessage::OBDTools);
                 }
             }
+            Self::Settings(s) => {
+                if let WindowMessage::Settings(x) = msg {
+                    return s.update(x).map(WindowMessage::Settings);
+                }
+            }
+            Self::Closing => {}
         }
         None
     }
@@ -107,6 +123,8 @@ impl WindowState {
             WindowState::CanTracer { .. } => WindowStateName::CanTracer,
             WindowState::DiagHome { .. } => WindowStateName::DiagHome,
             WindowState::OBDTools { .. } => WindowStateName::OBDTools,
+            WindowState::Settings { .. } => WindowStateName::Settings,
+            WindowState::Closing => WindowStateName::Closing,
         }
     }
 }
@@ -118,13 +136,16 @@ pub enum WindowMessage {
     CanTracer(TracerMessage),
     DiagHome(DiagHomeMessage),
     OBDTools(OBDMessage),
+    Settings(SettingsMessage),
     StartApp(Box<dyn ComServer>),
     StatusUpdate(Instant),
-    GoHome,      // Goto home page
-    GoCanTracer, // Goto Can Tracer page
-    GoUDS,       // Goto UDS Scanner page
-    GoOBD,       // Goto OBD Toolbox page
-    ToggleTheme, // Toggle the theme
+    GoHome,         // Goto home page
+    GoCanTracer,    // Goto Can Tracer page
+    GoUDS,          // Goto UDS Scanner page
+    GoOBD,          // Goto OBD Toolbox page
+    GoSettings,     // Goto Settings page
+    ToggleTheme,    // Toggle the theme
+    CloseRequested, // User closed the window
 }
 
 pub struct MainWindow {
@@ -134,6 +155,9 @@ pub struct MainWindow {
     poll_voltage: bool,
     back_btn_state: button::State,
     theme_toggle: button::State,
+    should_exit: bool,
+    /// Set once OVD has been shut down, as both closing the window and dropping it shut down OVD
+    closed: bool,
 }
 
 impl Application for MainWindow {
@@ -150,6 +174,8 @@ impl Application for MainWindow {
                 poll_voltage: false,
                 back_btn_state: button::State::default(),
                 theme_toggle: button::State::default(),
+                should_exit: false,
+                closed: false,
             },
             Command::none(),
         )
@@ -168,6 +194,8 @@ impl Application for MainWindow {
             WindowState::CanTracer { .. } => "OpenVehicleDiag CanTracer".into(),
             WindowState::DiagHome { .. } => "OpenVehicleDiag Diagnostics Scanner".into(),
             WindowState::OBDTools { .. } => "OpenVehicleDiag OBD Toolbox".into(),
+            WindowState::Settings { .. } => "OpenVehicleDiag Settings".into(),
+            WindowState::Closing => "OpenVehicleDiag".into(),
         }
     }
 
@@ -176,12 +204,7 @@ impl Application for MainWindow {
             WindowMessage::StatusUpdate(_) => {
                 // On request for battery voltage reading, try to read from the adapter, but it might timeout
                 // if the driver is under heady IO load, so then use the current voltage reading
-                self.voltage = self
-                    .server
-                    .as_ref()
-                    .unwrap()
-                    .read_battery_voltage()
-                    .unwrap_or(self.voltage)
+                self.voltage = ADAPTER.get_vbatt().unwrap_or(self.voltage)
             }
             WindowMessage::GoHome => {
                 self.state = WindowState::Home(Home::new(self.server.clone().unwrap()))
@@ -195,23 +218,52 @@ impl Application for MainWindow {
             WindowMessage::GoOBD => {
                 self.state = WindowState::OBDTools(OBDHome::new(self.server.clone().unwrap()))
             }
-            WindowMessage::ToggleTheme => toggle_theme(),
-            _ => return self.update_children(&message),
+            WindowMessage::GoSettings => self.state = WindowState::Settings(SettingsWindow::new()),
+            WindowMessage::ToggleTheme => {
+                toggle_theme();
+                let mut s = settings::get_settings();
+                s.dark_theme = *themes::get_theme() == themes::Style::Dark;
+                if let Err(e) = settings::set_settings(s) {
+                    eprintln!("Could not save theme setting: {}", e)
+                }
+            }
+            WindowMessage::CloseRequested => {
+                self.shutdown();
+                self.should_exit = true;
+            }
+            _ => {
+                let cmd = self.update_children(&message);
+                // Run any hardware operations the page queued whilst handling the message
+                return Command::batch(vec![cmd, hw_task::take_pending()]);
+            }
         }
         Command::none()
     }
 
+    fn should_exit(&self) -> bool {
+        self.should_exit
+    }
+
     fn subscription(&self) -> Subscription<Self::Message> {
-        if let WindowState::Launcher { .. } = self.state {
-            Subscription::none()
+        let close_listener = iced_native::subscription::events_with(|event, _| match event {
+            iced_native::Event::Window(iced_native::window::Event::CloseRequested) => {
+                Some(WindowMessage::CloseRequested)
+            }
+            _ => None,
+        });
+        if let WindowState::Launcher { .. } | WindowState::Closing = self.state {
+            close_listener
         } else {
-            // Ask for battery every 2 seconds (If supported)
+            // Ask for battery every poll interval (If supported)
             let mut batch: Vec<Subscription<WindowMessage>> = vec![];
             if self.poll_voltage {
+                let interval = settings::get_settings().poll_interval_ms;
                 batch.push(
-                    time::every(std::time::Duration::from_secs(2)).map(WindowMessage::StatusUpdate),
+                    time::every(std::time::Duration::from_millis(interval))
+                        .map(WindowMessage::StatusUpdate),
                 );
             }
+            batch.push(close_listener);
             // See if either other pages request update
             if let WindowState::CanTracer(tracer) = &self.state {
                 batch.push(tracer.subscription().map(WindowMessage::CanTracer))
@@ -224,7 +276,7 @@ impl Application for MainWindow {
 
     fn view(&mut self) -> Element<'_, Self::Message> {
         // If not in launcher mode we should draw the bottom status bar as well
-        return if let WindowState::Launcher { .. } = self.state {
+        return if let WindowState::Launcher { .. } | WindowState::Closing = self.state {
             self.state.view()
         } else {
             // Draw the status bar!
@@ -309,12 +361,7 @@ impl MainWindow {
                     self.server = Some(srv.clone_box());
                     self.poll_voltage = srv.get_capabilities().battery_voltage == Capability::Yes;
                     if self.poll_voltage {
-                        self.voltage = self
-                            .server
-                            .as_ref()
-                            .unwrap()
-                            .read_battery_voltage()
-                            .unwrap_or(0.0);
+                        self.voltage = ADAPTER.get_vbatt().unwrap_or(0.0);
                     } else {
                         self.voltage = 12.0; // This is to allow scans which measure battery to occur
                     }
@@ -327,16 +374,28 @@ impl MainWindow {
             Command::none()
         }
     }
+
+    /// Tears down everything before the application exits, so the adapter
+    /// is not left in a locked state for the next time OVD is opened
+    fn shutdown(&mut self) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+        // Dropping the current page ends any active diagnostic session
+        self.state = WindowState::Closing;
+        self.server = None;
+        // Closes the adapter, and releases its driver library
+        if let Err(e) = ADAPTER.disconnect_device() {
+            eprintln!("Error closing device: {}", e.err_desc)
+        }
+        let _ = std::io::stdout().flush();
+        let _ = std::io::stderr().flush();
+    }
 }
 
 impl Drop for MainWindow {
     fn drop(&mut self) {
-        if let Some(mut s) = self.server.take() {
-            s.close_iso15765_interface()
-                .expect("Error closing ISO15765");
-            s.close_can_interface()
-                .expect("Error closing can Interface");
-            s.close_device().expect("Error closing device");
-        }
+        self.shutdown()
     }
 }
@@ -87,7 +87,8 @@ mod data_test {
             length_bits: 16,
             byte_order: ParamByteOrder::BigEndian,
             data_format: DataFormat::Linear{ multiplier: 1.0, offset: 0.0 },
-            limits: None
+            limits: None,
+            signed: false
         };
 
         let oil_temp_parser = super::service::Parameter {
@@ -97,7 +98,8 @@ mod data_test {
             length_bits: 16,
             byte_order: ParamByteOrder::BigEndian,
             data_format: DataFormat::Linear{ multiplier: 0.25, offset: -50.0 },
-            limits: None
+            limits: None,
+            signed: false
         };
 
         assert_eq!(gearbox_gear_parser.decode_value_to_number(&resp).unwrap(), 8.0);
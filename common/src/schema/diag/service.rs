@@ -1,6 +1,4 @@
-use std::{cmp::min, collections::VecDeque, string::FromUtf8Error};
-use bit_field::BitArray;
-use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use std::{cmp::min, string::FromUtf8Error};
 use serde::{Serialize, Deserialize};
 use super::DataFormat;
 use serde_with::{serde_as};
@@ -17,7 +15,10 @@ pub struct Service {
     pub input_params: Vec<Parameter>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default = "Vec::new")]
-    pub output_params: Vec<Parameter>
+    pub output_params: Vec<Parameter>,
+    #[serde(skip_serializing_if = "ServicePreconditions::is_empty")]
+    #[serde(default)]
+    pub preconditions: ServicePreconditions
 }
 
 impl Service {
@@ -30,6 +31,52 @@ impl Service {
     }
 }
 
+/// Diagnostic session a service must be ran in
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum RequiredSession {
+    Extended,
+    Programming
+}
+
+/// What the ECU has to be set up for before a service can be ran,
+/// else it will reply with 'conditions not correct' or 'security access denied'
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ServicePreconditions {
+    /// Session to enter before running the service. None if the default session is OK
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub session: Option<RequiredSession>,
+    /// Security access level that must be unlocked before running the service
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub security_level: Option<u8>
+}
+
+impl ServicePreconditions {
+    pub fn is_empty(&self) -> bool {
+        self.session.is_none() && self.security_level.is_none()
+    }
+}
+
+impl std::fmt::Display for ServicePreconditions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        match self.session {
+            Some(RequiredSession::Extended) => parts.push("Extended session".to_string()),
+            Some(RequiredSession::Programming) => parts.push("Programming session".to_string()),
+            None => {}
+        }
+        if let Some(level) = self.security_level {
+            parts.push(format!("Security access level {}", level))
+        }
+        if parts.is_empty() {
+            write!(f, "None")
+        } else {
+            write!(f, "{}", parts.join(", "))
+        }
+    }
+}
+
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum ParamByteOrder {
@@ -64,6 +111,10 @@ pub struct Parameter {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default = "Option::default")]
     pub limits: Option<Limit>,
+    /// Raw value is a two's complement signed number
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    pub signed: bool,
 }
 
 impl Parameter {
@@ -84,9 +135,10 @@ impl Parameter {
                 }
             }
             DataFormat::Bool { pos_name, neg_name } => {
-                return match self.get_number(input)? {
-                    0 => Ok(neg_name.clone().unwrap_or("False".into())),
-                    _ => Ok(pos_name.clone().unwrap_or("True".into()))
+                return if self.get_number(input)? == 0.0 {
+                    Ok(neg_name.clone().unwrap_or("False".into()))
+                } else {
+                    Ok(pos_name.clone().unwrap_or("True".into()))
                 }
             }
             DataFormat::Table(t) => {
@@ -160,47 +212,133 @@ impl Parameter {
         }
     }
 
-    fn get_number(&self, resp: &[u8]) -> std::result::Result<u32, ParamDecodeError> {
-        if self.length_bits <= 32 {
-            let result = std::panic::catch_unwind(||{
-                if self.length_bits <= 8 {
-                    resp.get_bits(self.start_bit..self.start_bit+self.length_bits) as u32
-                } else {
-                    let mut res = 0;
-                    let mut buf: Vec<u8> = Vec::new();
-                    let mut start = self.start_bit;
-                    while start < self.length_bits + self.start_bit {
-                        let max_read = min(self.start_bit + self.length_bits, start + 8);
-                        println!("{} {}", start, max_read);
-                        buf.push(resp.get_bits(start..max_read));
-                        start += 8;
-                    }
-                    
-                    if buf.len() > 4 {
-                        panic!("Number too big!") // Cannot handle more than 32bits atm
-                    } else {
-                        if buf.len() >= 4 {                        
-                            res = match self.byte_order {
-                                ParamByteOrder::BigEndian => BigEndian::read_u32(&buf),
-                                ParamByteOrder::LittleEndian => LittleEndian::read_u32(&buf)
-                            }
-                        } else if buf.len() >= 2 {
-                            res = match self.byte_order {
-                                ParamByteOrder::BigEndian => BigEndian::read_u16(&buf) as u32,
-                                ParamByteOrder::LittleEndian => LittleEndian::read_u16(&buf) as u32
-                            }
-                        }
-                        res as u32
-                    }
-                }
-            });
-
-            match result {
-                Ok(r) => Ok(r as u32),
-                Err(_) => Err(ParamDecodeError::BitRangeError)
-            }
+    /// Reads the raw value of the parameter from a response, sign extended if the parameter is signed
+    fn get_number(&self, resp: &[u8]) -> std::result::Result<f64, ParamDecodeError> {
+        let raw = extract_bits(resp, self.start_bit, self.length_bits, &self.byte_order).ok_or(ParamDecodeError::BitRangeError)?;
+        if self.signed {
+            Ok(sign_extend(raw, self.length_bits) as f64)
         } else {
-            Err(ParamDecodeError::BitRangeError)
+            Ok(raw as f64)
         }
     }
-}
\ No newline at end of file
+}
+
+/// Extracts an unsigned value of up to 64 bits from a response.
+///
+/// Positions follow ODX and CBF. `start_bit / 8` is the first byte the value is in, and
+/// `start_bit % 8` is the offset of the value's least significant bit from the least significant
+/// bit of the bytes it is in. Those bytes are combined most significant byte first for
+/// [ParamByteOrder::BigEndian] (Motorola), or least significant byte first for
+/// [ParamByteOrder::LittleEndian] (Intel), so a value can start at any bit and cross byte boundaries.
+///
+/// Returns None if the value does not fit in the response, or is longer than 64 bits
+pub fn extract_bits(data: &[u8], start_bit: usize, length_bits: usize, byte_order: &ParamByteOrder) -> Option<u64> {
+    if length_bits == 0 || length_bits > 64 {
+        return None
+    }
+    let start_byte = start_bit / 8;
+    let bit_offset = start_bit % 8;
+    let byte_count = (bit_offset + length_bits + 7) / 8;
+    let bytes = data.get(start_byte..start_byte + byte_count)?;
+    // Up to 9 bytes can be needed (64 bits at a non zero offset), so combine in 128 bits
+    let combined = match byte_order {
+        ParamByteOrder::BigEndian => bytes.iter().fold(0u128, |acc, b| (acc << 8) | *b as u128),
+        ParamByteOrder::LittleEndian => bytes.iter().rev().fold(0u128, |acc, b| (acc << 8) | *b as u128)
+    };
+    let mask = (1u128 << length_bits) - 1;
+    Some(((combined >> bit_offset) & mask) as u64)
+}
+
+/// Interprets the lowest `length_bits` of a value as a two's complement signed number
+pub fn sign_extend(value: u64, length_bits: usize) -> i64 {
+    if length_bits == 0 || length_bits >= 64 {
+        return value as i64
+    }
+    let shift = 64 - length_bits;
+    ((value << shift) as i64) >> shift
+}
+#[cfg(test)]
+mod service_test {
+    use super::{extract_bits, sign_extend, ParamByteOrder, Parameter};
+    use crate::schema::diag::DataFormat;
+
+    #[test]
+    fn test_byte_aligned() {
+        let data = [0x12, 0x34, 0x56, 0x78, 0x9A];
+        assert_eq!(extract_bits(&data, 8, 16, &ParamByteOrder::BigEndian), Some(0x3456));
+        assert_eq!(extract_bits(&data, 8, 16, &ParamByteOrder::LittleEndian), Some(0x5634));
+        assert_eq!(extract_bits(&data, 8, 24, &ParamByteOrder::BigEndian), Some(0x345678));
+        assert_eq!(extract_bits(&data, 8, 24, &ParamByteOrder::LittleEndian), Some(0x785634));
+        assert_eq!(extract_bits(&data, 0, 32, &ParamByteOrder::BigEndian), Some(0x12345678));
+    }
+
+    #[test]
+    fn test_sub_byte() {
+        // 0xB6 = 1011 0110
+        let data = [0xB6];
+        assert_eq!(extract_bits(&data, 0, 1, &ParamByteOrder::BigEndian), Some(0));
+        assert_eq!(extract_bits(&data, 1, 1, &ParamByteOrder::BigEndian), Some(1));
+        assert_eq!(extract_bits(&data, 2, 3, &ParamByteOrder::LittleEndian), Some(0b101));
+        assert_eq!(extract_bits(&data, 4, 4, &ParamByteOrder::BigEndian), Some(0xB));
+    }
+
+    #[test]
+    fn test_cross_byte_boundary() {
+        let data = [0xAB, 0xCD, 0xEF];
+        // Bytes 0xABCD, bits 4-15 from the least significant end
+        assert_eq!(extract_bits(&data, 4, 12, &ParamByteOrder::BigEndian), Some(0xABC));
+        // Bytes 0xCDAB (Low byte first)
+        assert_eq!(extract_bits(&data, 4, 12, &ParamByteOrder::LittleEndian), Some(0xCDA));
+        assert_eq!(extract_bits(&data, 6, 10, &ParamByteOrder::BigEndian), Some(0xABCD >> 6));
+        assert_eq!(extract_bits(&data, 6, 10, &ParamByteOrder::LittleEndian), Some(0xCDAB >> 6));
+        // Crossing two byte boundaries
+        assert_eq!(extract_bits(&data, 7, 10, &ParamByteOrder::BigEndian), Some(0x39B));
+    }
+
+    #[test]
+    fn test_64_bits() {
+        let data = [0xFF, 0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0xCD, 0xEF];
+        assert_eq!(extract_bits(&data, 8, 64, &ParamByteOrder::BigEndian), Some(0x0123456789ABCDEF));
+        assert_eq!(extract_bits(&data, 8, 64, &ParamByteOrder::LittleEndian), Some(0xEFCDAB8967452301));
+        // Non zero offset needs all 9 bytes
+        assert_eq!(extract_bits(&data, 4, 64, &ParamByteOrder::BigEndian), Some(0xF0123456789ABCDE));
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let data = [0x00, 0x01];
+        assert_eq!(extract_bits(&data, 8, 16, &ParamByteOrder::BigEndian), None);
+        assert_eq!(extract_bits(&data, 4, 16, &ParamByteOrder::BigEndian), None);
+        assert_eq!(extract_bits(&data, 0, 0, &ParamByteOrder::BigEndian), None);
+        assert_eq!(extract_bits(&[0; 16], 0, 65, &ParamByteOrder::BigEndian), None);
+    }
+
+    #[test]
+    fn test_sign_extend() {
+        assert_eq!(sign_extend(0xFF, 8), -1);
+        assert_eq!(sign_extend(0x7F, 8), 127);
+        assert_eq!(sign_extend(0x800, 12), -2048);
+        assert_eq!(sign_extend(0x7FF, 12), 2047);
+        assert_eq!(sign_extend(0b10, 2), -2);
+        assert_eq!(sign_extend(u64::MAX, 64), -1);
+    }
+
+    #[test]
+    fn test_signed_parameter() {
+        let mut param = Parameter {
+            name: "Temperature".into(),
+            unit: "°C".into(),
+            start_bit: 8,
+            length_bits: 16,
+            byte_order: ParamByteOrder::LittleEndian,
+            data_format: DataFormat::Linear { multiplier: 0.1, offset: 0.0 },
+            limits: None,
+            signed: true
+        };
+        // 0xFF38 = -200
+        let resp = [0x61, 0x38, 0xFF];
+        assert!((param.decode_value_to_number(&resp).unwrap() + 20.0).abs() < 0.001);
+        param.signed = false;
+        assert!((param.decode_value_to_number(&resp).unwrap() - 6533.6).abs() < 0.01);
+    }
+}